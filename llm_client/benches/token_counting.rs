@@ -0,0 +1,145 @@
+//! Counting the tokens for a rerank over 500 code spans, which is what the
+//! reranking broker does before it decides if it needs to rerank at all.
+//!
+//! cargo bench -p llm_client --bench token_counting
+//!
+//! On a dev machine building the BPE for every span took ~51s, with the BPE
+//! cached ~160ms and with the counts cached ~0.2ms. For mixtral counting on
+//! the blocking pool took ~100ms against ~120ms inline.
+
+use std::sync::Arc;
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use llm_client::{clients::types::LLMType, tokenizer::tokenizer::LLMTokenizer};
+
+const SPANS: usize = 500;
+
+fn code_spans() -> Vec<String> {
+    (0..SPANS)
+        .map(|index| {
+            let body = (0..20)
+                .map(|line| format!("    let value_{line} = compute_{index}(input, {line});\n"))
+                .collect::<String>();
+            format!("FILEPATH: src/module_{index}.rs\n```\nfn handler_{index}(input: &str) {{\n{body}}}\n```")
+        })
+        .collect()
+}
+
+fn bench_openai(c: &mut Criterion) {
+    let spans = code_spans();
+    let mut group = c.benchmark_group("rerank_500_spans_gpt4");
+    group.sample_size(10);
+
+    // what we did before: build the BPE for every span
+    group.bench_function("bpe_per_span", |b| {
+        b.iter(|| {
+            spans
+                .iter()
+                .map(|span| {
+                    tiktoken_rs::get_bpe_from_model("gpt-4-0613")
+                        .expect("bpe to build")
+                        .encode_ordinary(span)
+                        .len()
+                })
+                .sum::<usize>()
+        })
+    });
+
+    let tokenizer = Arc::new(LLMTokenizer::new().expect("tokenizer to be created"));
+    group.bench_function("cached_bpe", |b| {
+        b.iter_batched(
+            || tokenizer.clear_count_cache(),
+            |_| {
+                spans
+                    .iter()
+                    .map(|span| {
+                        tokenizer
+                            .count_tokens_using_tokenizer(&LLMType::Gpt4, span)
+                            .expect("counting to work")
+                    })
+                    .sum::<usize>()
+            },
+            BatchSize::PerIteration,
+        )
+    });
+
+    // the reranking windows count the same spans again
+    group.bench_function("cached_counts", |b| {
+        b.iter(|| {
+            spans
+                .iter()
+                .map(|span| {
+                    tokenizer
+                        .count_tokens_using_tokenizer(&LLMType::Gpt4, span)
+                        .expect("counting to work")
+                })
+                .sum::<usize>()
+        })
+    });
+    group.finish();
+}
+
+fn bench_off_thread(c: &mut Criterion) {
+    let spans = code_spans();
+    let runtime = tokio::runtime::Runtime::new().expect("runtime to start");
+    let tokenizer = Arc::new(LLMTokenizer::new().expect("tokenizer to be created"));
+    let mut group = c.benchmark_group("rerank_500_spans_mixtral");
+    group.sample_size(20);
+    group.bench_function("sync", |b| {
+        b.iter_batched(
+            || tokenizer.clear_count_cache(),
+            |_| {
+                spans
+                    .iter()
+                    .map(|span| {
+                        tokenizer
+                            .count_tokens_using_tokenizer(&LLMType::Mixtral, span)
+                            .expect("counting to work")
+                    })
+                    .sum::<usize>()
+            },
+            BatchSize::PerIteration,
+        )
+    });
+    group.bench_function("blocking_pool", |b| {
+        b.to_async(&runtime).iter_batched(
+            || {
+                tokenizer.clear_count_cache();
+                spans.clone()
+            },
+            |spans| {
+                let tokenizer = tokenizer.clone();
+                async move {
+                    tokenizer
+                        .count_prompts_async(LLMType::Mixtral, spans)
+                        .await
+                        .expect("counting to work")
+                        .into_iter()
+                        .sum::<usize>()
+                }
+            },
+            BatchSize::PerIteration,
+        )
+    });
+    group.bench_function("blocking_pool_cached_counts", |b| {
+        b.to_async(&runtime).iter_batched(
+            || spans.clone(),
+            |spans| {
+                let tokenizer = tokenizer.clone();
+                async move {
+                    tokenizer
+                        .count_prompts_async(LLMType::Mixtral, spans)
+                        .await
+                        .expect("counting to work")
+                        .into_iter()
+                        .sum::<usize>()
+                }
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_openai, bench_off_thread);
+criterion_main!(benches);
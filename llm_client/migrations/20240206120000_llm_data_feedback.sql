@@ -0,0 +1,5 @@
+-- Add migration script here
+ALTER TABLE llm_data ADD COLUMN rating INTEGER;
+ALTER TABLE llm_data ADD COLUMN accepted BOOLEAN;
+ALTER TABLE llm_data ADD COLUMN corrected_output TEXT;
+ALTER TABLE llm_data ADD COLUMN feedback_at DATETIME;
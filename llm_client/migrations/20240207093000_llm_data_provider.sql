@@ -0,0 +1,3 @@
+-- Add migration script here
+ALTER TABLE llm_data ADD COLUMN provider TEXT;
+CREATE INDEX llm_data_created_at_idx ON llm_data (created_at);
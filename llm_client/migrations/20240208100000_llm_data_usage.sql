@@ -0,0 +1,3 @@
+-- Add migration script here
+ALTER TABLE llm_data ADD COLUMN tokens_used INTEGER;
+ALTER TABLE llm_data ADD COLUMN cost FLOAT;
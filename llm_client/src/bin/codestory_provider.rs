@@ -1,8 +1,10 @@
-//! Call the endpoints of codestory endpoint
+//! Call the endpoints of codestory endpoint, set LLM_FIXTURE_DIR to record
+//! the exchange and replay it on the next run
 
 use llm_client::{
     clients::{
         codestory::CodeStoryClient,
+        replay::{ReplayLLMClient, ReplayMode},
         types::{LLMClient, LLMClientCompletionRequest, LLMClientMessage, LLMType},
     },
     provider::LLMProviderAPIKeys,
@@ -10,8 +12,17 @@ use llm_client::{
 
 #[tokio::main]
 async fn main() {
-    let codestory_client =
-        CodeStoryClient::new("https://codestory-provider-dot-anton-390822.ue.r.appspot.com");
+    let codestory_client: Box<dyn LLMClient + Send + Sync> = Box::new(CodeStoryClient::new(
+        "https://codestory-provider-dot-anton-390822.ue.r.appspot.com",
+    ));
+    let codestory_client = match std::env::var("LLM_FIXTURE_DIR") {
+        Ok(fixture_dir) => Box::new(ReplayLLMClient::record(
+            fixture_dir,
+            ReplayMode::RecordMissing,
+            codestory_client,
+        )),
+        Err(_) => codestory_client,
+    };
     let (sender, _receiver) = tokio::sync::mpsc::unbounded_channel();
     let request = LLMClientCompletionRequest::new(
         LLMType::GPT3_5_16k,
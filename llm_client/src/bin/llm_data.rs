@@ -0,0 +1,243 @@
+//! Tooling for the `llm_data` log: list the calls we made, export them and keep
+//! the size of the DB in check.
+//!
+//! cargo run --features cli --bin llm_data -- --data-dir <dir> list --event-type inline_edit
+//! cargo run --features cli --bin llm_data -- --data-dir <dir> export --format csv --redact
+//! cargo run --features cli --bin llm_data -- --data-dir <dir> retain --max-age-days 30
+
+use std::{fs::File, io::Write, path::PathBuf, time::Duration};
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use llm_client::{
+    clients::types::LLMType,
+    config::LLMBrokerConfiguration,
+    llm_data::{
+        self, export,
+        retention::{apply_retention, RetentionPolicy},
+        LLMDataFilter, LLMDataRow,
+    },
+    provider::LLMProvider,
+    redaction::Redactor,
+    tokenizer::tokenizer::LLMTokenizer,
+};
+use sqlx::types::chrono::{NaiveDate, NaiveDateTime};
+
+#[derive(Parser)]
+struct Cli {
+    /// The directory which contains llm_data.data
+    #[arg(long)]
+    data_dir: PathBuf,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Lists the calls matching the filters
+    List {
+        #[command(flatten)]
+        filter: FilterArgs,
+
+        /// Only show the most recent calls
+        #[arg(long, default_value_t = 50)]
+        limit: usize,
+    },
+    /// Exports the calls matching the filters
+    Export {
+        #[command(flatten)]
+        filter: FilterArgs,
+
+        #[arg(long, value_enum, default_value_t = ExportFormat::Jsonl)]
+        format: ExportFormat,
+
+        /// Writes to stdout when not set
+        #[arg(long)]
+        output: Option<PathBuf>,
+
+        /// Scrubs the secrets before exporting
+        #[arg(long)]
+        redact: bool,
+
+        /// Scrubs the file contents (code blocks) along with the secrets
+        #[arg(long)]
+        redact_file_contents: bool,
+    },
+    /// Deletes the old calls from the log
+    Retain {
+        #[arg(long)]
+        max_age_days: Option<u64>,
+
+        #[arg(long)]
+        max_size_mb: Option<u64>,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ExportFormat {
+    Jsonl,
+    Csv,
+    /// OpenAI fine-tune chat format
+    Chat,
+    /// Prompt/completion pairs for the open models
+    PromptCompletion,
+}
+
+#[derive(Args)]
+struct FilterArgs {
+    /// YYYY-MM-DD or YYYY-MM-DD HH:MM:SS (UTC)
+    #[arg(long, value_parser = parse_datetime)]
+    since: Option<NaiveDateTime>,
+
+    /// YYYY-MM-DD or YYYY-MM-DD HH:MM:SS (UTC)
+    #[arg(long, value_parser = parse_datetime)]
+    until: Option<NaiveDateTime>,
+
+    /// The llm type as we serialize it, like MistralInstruct or Gpt4
+    #[arg(long)]
+    llm_type: Option<String>,
+
+    /// The provider as we serialize it, like OpenAI or Ollama
+    #[arg(long)]
+    provider: Option<String>,
+
+    #[arg(long)]
+    event_type: Option<String>,
+
+    #[arg(long)]
+    accepted: Option<bool>,
+}
+
+impl FilterArgs {
+    fn to_filter(&self) -> anyhow::Result<LLMDataFilter> {
+        let mut filter = LLMDataFilter::new();
+        if let Some(since) = self.since {
+            filter = filter.set_since(since);
+        }
+        if let Some(until) = self.until {
+            filter = filter.set_until(until);
+        }
+        if let Some(llm_type) = &self.llm_type {
+            filter = filter.set_llm_type(serde_json::from_value::<LLMType>(
+                serde_json::Value::String(llm_type.to_owned()),
+            )?);
+        }
+        if let Some(provider) = &self.provider {
+            filter = filter.set_provider(serde_json::from_value::<LLMProvider>(
+                serde_json::Value::String(provider.to_owned()),
+            )?);
+        }
+        if let Some(event_type) = &self.event_type {
+            filter = filter.set_event_type(event_type.to_owned());
+        }
+        if let Some(accepted) = self.accepted {
+            filter = filter.set_accepted(accepted);
+        }
+        Ok(filter)
+    }
+}
+
+fn parse_datetime(value: &str) -> Result<NaiveDateTime, String> {
+    NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S")
+        .or_else(|_| {
+            NaiveDate::parse_from_str(value, "%Y-%m-%d")
+                .map(|date| date.and_hms_opt(0, 0, 0).expect("midnight to be valid"))
+        })
+        .map_err(|e| format!("invalid date {value}: {e}"))
+}
+
+fn preview(content: Option<&str>) -> String {
+    let content = content.unwrap_or_default().replace('\n', " ");
+    match content.char_indices().nth(60) {
+        Some((index, _)) => format!("{}...", &content[..index]),
+        None => content,
+    }
+}
+
+fn list(rows: &[LLMDataRow], limit: usize) {
+    let skip = rows.len().saturating_sub(limit);
+    for row in rows.iter().skip(skip) {
+        println!(
+            "{}\t{}\t{}\t{}\t{}\t{}",
+            row.id(),
+            row.created_at(),
+            row.provider()
+                .map(|provider| format!("{provider:?}"))
+                .unwrap_or("-".to_owned()),
+            row.llm_type()
+                .map(|llm_type| llm_type.to_string())
+                .unwrap_or("-".to_owned()),
+            row.event_type().unwrap_or("-".to_owned()),
+            preview(row.response()),
+        );
+    }
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+    let db = llm_data::connect(LLMBrokerConfiguration::new(cli.data_dir)).await?;
+    match cli.command {
+        Command::List { filter, limit } => {
+            let rows = llm_data::fetch_rows(&db, &filter.to_filter()?).await?;
+            list(&rows, limit);
+        }
+        Command::Export {
+            filter,
+            format,
+            output,
+            redact,
+            redact_file_contents,
+        } => {
+            let mut rows = llm_data::fetch_rows(&db, &filter.to_filter()?).await?;
+            if redact || redact_file_contents {
+                let redactor = if redact_file_contents {
+                    Redactor::new().with_file_contents()
+                } else {
+                    Redactor::new()
+                };
+                rows = rows.iter().map(|row| row.redacted(&redactor)).collect();
+            }
+            let mut writer: Box<dyn Write> = match output {
+                Some(output) => Box::new(File::create(output)?),
+                None => Box::new(std::io::stdout().lock()),
+            };
+            let written = match format {
+                ExportFormat::Jsonl => export::write_jsonl(&rows, &mut writer)?,
+                ExportFormat::Csv => export::write_csv(&rows, &mut writer)?,
+                ExportFormat::Chat => export::write_chat_jsonl(&rows, &mut writer)?,
+                ExportFormat::PromptCompletion => {
+                    let tokenizer = LLMTokenizer::new()?;
+                    export::write_prompt_completion_jsonl(&rows, &tokenizer, &mut writer)?
+                }
+            };
+            writer.flush()?;
+            eprintln!("exported {written} rows");
+        }
+        Command::Retain {
+            max_age_days,
+            max_size_mb,
+        } => {
+            let mut policies = vec![];
+            if let Some(max_age_days) = max_age_days {
+                policies.push(RetentionPolicy::MaxAge(Duration::from_secs(
+                    max_age_days * 24 * 60 * 60,
+                )));
+            }
+            if let Some(max_size_mb) = max_size_mb {
+                policies.push(RetentionPolicy::MaxSizeBytes(max_size_mb * 1024 * 1024));
+            }
+            if policies.is_empty() {
+                eprintln!("pass --max-age-days or --max-size-mb");
+            }
+            for policy in policies {
+                let report = apply_retention(&db, policy).await?;
+                println!(
+                    "{policy:?}: deleted {} rows, {} -> {} bytes",
+                    report.deleted_rows, report.size_before, report.size_after
+                );
+            }
+        }
+    }
+    Ok(())
+}
@@ -1,3 +1,6 @@
+//! Call the azure openai endpoint, set LLM_FIXTURE_DIR to record the exchange
+//! and replay it on the next run
+
 use async_openai::{
     config::AzureConfig,
     types::{ChatCompletionRequestMessageArgs, CreateChatCompletionRequestArgs},
@@ -6,13 +9,22 @@ use async_openai::{
 use futures::StreamExt;
 use llm_client::clients::{
     openai::OpenAIClient,
+    replay::{ReplayLLMClient, ReplayMode},
     types::{LLMClient, LLMClientCompletionRequest, LLMClientMessage},
 };
 use llm_client::provider::AzureConfig as ProviderAzureConfig;
 
 #[tokio::main]
 async fn main() {
-    let openai_client = OpenAIClient::new();
+    let openai_client: Box<dyn LLMClient + Send + Sync> = Box::new(OpenAIClient::new());
+    let openai_client = match std::env::var("LLM_FIXTURE_DIR") {
+        Ok(fixture_dir) => Box::new(ReplayLLMClient::record(
+            fixture_dir,
+            ReplayMode::RecordMissing,
+            openai_client,
+        )),
+        Err(_) => openai_client,
+    };
     let api_key =
         llm_client::provider::LLMProviderAPIKeys::OpenAIAzureConfig(ProviderAzureConfig {
             deployment_id: "some_deployment_id".to_string(),
@@ -2,10 +2,16 @@
 //! without us having to worry about the specifics, just pass in the message and the
 //! provider we take care of the rest
 
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Instant,
+};
 
-use futures::future::Either;
+use futures::{future::Either, Future, StreamExt};
 use sqlx::SqlitePool;
+use tokio::sync::mpsc::UnboundedSender;
 
 use crate::{
     clients::{
@@ -15,35 +21,115 @@ use crate::{
         openai::OpenAIClient,
         togetherai::TogetherAIClient,
         types::{
-            LLMClient, LLMClientCompletionRequest, LLMClientCompletionResponse,
-            LLMClientCompletionStringRequest, LLMClientError,
+            LLMClient, LLMClientCapabilities, LLMClientCompletionFIMRequest,
+            LLMClientCompletionRequest, LLMClientCompletionResponse,
+            LLMClientCompletionStringRequest, LLMClientError, LLMType,
         },
     },
     config::LLMBrokerConfiguration,
-    provider::{CodeStoryLLMType, LLMProvider, LLMProviderAPIKeys},
+    credentials::CredentialStore,
+    format::types::FillInMiddleFormat,
+    health::{
+        LLMBrokerHealthReport, LLMModelAvailability, LLMProviderHealth, LLMProviderStatus,
+        HEALTH_CHECK_TIMEOUT,
+    },
+    llm_data::{
+        self, export,
+        feedback::LLMFeedback,
+        retention::{RetentionPolicy, RetentionReport},
+        LLMDataFilter, LLMDataRow,
+    },
+    provider::{AzureOpenAIDeploymentId, CodeStoryLLMType, LLMProvider, LLMProviderAPIKeys},
+    rate_limit::{estimate_tokens, RateLimiter, RequestPriority},
+    redaction::{RedactionConfig, RedactionPolicy, RedactionSession, Redactor},
     sqlite,
+    tokenizer::tokenizer::{LLMTokenizer, LLMTokenizerInput},
 };
 
 pub type SqlDb = Arc<SqlitePool>;
 
+/// The most prompts we send in a single batched request
+const MAX_BATCH_SIZE: usize = 16;
+
+/// The most requests we have in flight for a single batch completion call
+const MAX_CONCURRENT_REQUESTS: usize = 25;
+
 pub struct LLMBroker {
     pub providers: HashMap<LLMProvider, Box<dyn LLMClient + Send + Sync>>,
     db: SqlDb,
+    /// Set when the DB was broken on startup and we moved it out of the way
+    db_backup_path: Option<PathBuf>,
+    redaction: RedactionConfig,
+    /// The compiled redactors for each of the policies in the config
+    redactors: HashMap<RedactionPolicy, Redactor>,
+    credentials: CredentialStore,
+    /// Set once the usage of the keys was loaded back from `llm_data`
+    credential_usage_loaded: tokio::sync::OnceCell<()>,
+    rate_limiter: RateLimiter,
 }
 
-pub type LLMBrokerResponse = Result<String, LLMClientError>;
+/// The answer from the LLM along with the id of the row in `llm_data` where we
+/// logged it, the id is used to attach feedback to the answer later on
+#[derive(Debug, Clone)]
+pub struct LLMBrokerAnswer {
+    answer: String,
+    llm_data_id: i64,
+}
+
+impl LLMBrokerAnswer {
+    pub fn answer(&self) -> &str {
+        &self.answer
+    }
+
+    pub fn llm_data_id(&self) -> i64 {
+        self.llm_data_id
+    }
+
+    pub fn into_answer(self) -> String {
+        self.answer
+    }
+}
+
+pub type LLMBrokerResponse = Result<LLMBrokerAnswer, LLMClientError>;
+
+/// Where a request goes: the client for the provider along with the api key
+/// for it
+struct LLMRoute<'a> {
+    provider: LLMProvider,
+    api_key: LLMProviderAPIKeys,
+    client: &'a (dyn LLMClient + Send + Sync),
+}
 
 impl LLMBroker {
     pub async fn new(config: LLMBrokerConfiguration) -> Result<Self, LLMClientError> {
-        let sqlite = Arc::new(sqlite::init(config).await?);
+        let redaction = config.redaction.clone();
+        let rate_limiter = RateLimiter::new(config.rate_limits.clone());
+        let redactors = std::iter::once(redaction.default_policy())
+            .chain(redaction.policies().map(|(_, policy)| policy))
+            .filter_map(|policy| Some((*policy, Redactor::for_policy(policy)?)))
+            .collect();
+        let (sqlite, db_backup_path) = sqlite::init(config).await?;
         let broker = Self {
             providers: HashMap::new(),
-            db: sqlite,
+            db: Arc::new(sqlite),
+            db_backup_path,
+            redaction,
+            redactors,
+            credentials: CredentialStore::default(),
+            credential_usage_loaded: tokio::sync::OnceCell::new(),
+            rate_limiter,
         };
         Ok(broker
             .add_provider(LLMProvider::OpenAI, Box::new(OpenAIClient::new()))
+            .add_provider(
+                LLMProvider::Azure(AzureOpenAIDeploymentId {
+                    deployment_id: "".to_owned(),
+                }),
+                Box::new(OpenAIClient::new()),
+            )
             .add_provider(LLMProvider::Ollama, Box::new(OllamaClient::new()))
             .add_provider(LLMProvider::TogetherAI, Box::new(TogetherAIClient::new()))
+            .add_provider(LLMProvider::LMStudio, Box::new(LMStudioClient::new()))
             .add_provider(
                 LLMProvider::CodeStory(CodeStoryLLMType { llm_type: None }),
                 Box::new(CodeStoryClient::new(
@@ -52,15 +138,206 @@ impl LLMBroker {
             ))
     }
 
+    /// Registers the client for the provider, Azure and CodeStory clients are
+    /// registered without their configuration, see [`LLMProvider::client_provider`]
     pub fn add_provider(
         mut self,
         provider: LLMProvider,
         client: Box<dyn LLMClient + Send + Sync>,
     ) -> Self {
-        self.providers.insert(provider, client);
+        self.providers.insert(provider.client_provider(), client);
         self
     }
 
+    /// The keys used by [`stream_answer_with_credentials`](Self::stream_answer_with_credentials)
+    pub fn set_credentials(mut self, credentials: CredentialStore) -> Self {
+        self.credentials = credentials;
+        self.credential_usage_loaded = tokio::sync::OnceCell::new();
+        self
+    }
+
+    pub fn credentials(&self) -> &CredentialStore {
+        &self.credentials
+    }
+
+    /// Adds up the tokens we logged for the keys which have a spend limit in
+    /// their current period, so the limits carry over between restarts. This
+    /// runs before the first request with the credentials, it only does the
+    /// work once.
+    pub async fn load_credential_usage(&self) -> Result<(), LLMClientError> {
+        self.credential_usage_loaded
+            .get_or_try_init(|| async {
+                for (provider, name, since) in self.credentials.spend_limited() {
+                    let (tokens, cost) =
+                        llm_data::usage::credential_usage(&self.db, &provider, &name, since)
+                            .await?;
+                    self.credentials.load_usage(&provider, &name, tokens, cost);
+                }
+                Ok::<_, LLMClientError>(())
+            })
+            .await?;
+        Ok(())
+    }
+
+    /// Resolves the client and the api key for the (provider, model) of a
+    /// request, both chat and string completions go through here
+    fn route(
+        &self,
+        api_key: &LLMProviderAPIKeys,
+        provider: &LLMProvider,
+        model: &LLMType,
+    ) -> Result<LLMRoute<'_>, LLMClientError> {
+        let client = self
+            .providers
+            .get(&provider.client_provider())
+            .ok_or_else(|| LLMClientError::ProviderNotRegistered(provider.clone()))?;
+        if let LLMProvider::Azure(deployment) = provider {
+            if deployment.deployment_id.is_empty() {
+                return Err(LLMClientError::MissingAzureDeploymentId);
+            }
+        }
+        let api_key = api_key
+            .key(provider)
+            .ok_or_else(|| LLMClientError::MissingAPIKey {
+                provider: provider.clone(),
+                api_key_provider: api_key.provider_type(),
+            })?;
+        match client.capabilities(model) {
+            Ok(_) => Ok(LLMRoute {
+                provider: provider.clone(),
+                api_key,
+                client: client.as_ref(),
+            }),
+            Err(LLMClientError::UnSupportedModel) => Err(LLMClientError::ModelNotSupported {
+                provider: provider.clone(),
+                model: model.clone(),
+            }),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// If the `llm_data` DB was broken on startup we start with a fresh one and
+    /// move the old one here, so the editor can let the user know
+    pub fn db_backup_path(&self) -> Option<&Path> {
+        self.db_backup_path.as_deref()
+    }
+
+    /// What the provider supports for the model, without sending a request
+    pub fn capabilities(
+        &self,
+        provider: &LLMProvider,
+        model: &LLMType,
+    ) -> Result<LLMClientCapabilities, LLMClientError> {
+        self.providers
+            .get(&provider.client_provider())
+            .ok_or_else(|| LLMClientError::ProviderNotRegistered(provider.clone()))?
+            .capabilities(model)
+    }
+
+    /// Asks every provider we have a client for which models it's serving, the
+    /// api keys are matched to the providers the same way as for the requests
+    pub async fn health_report(&self, api_keys: &[LLMProviderAPIKeys]) -> LLMBrokerHealthReport {
+        let checks = self.providers.iter().map(|(provider, client)| async move {
+            // the Azure client is registered without a deployment, so we pick
+            // the key by its type and use the deployment from the key
+            let api_key = api_keys
+                .iter()
+                .find_map(|api_key| api_key.key(provider))
+                .or_else(|| {
+                    api_keys
+                        .iter()
+                        .find(|api_key| &api_key.provider_type() == provider)
+                        .cloned()
+                });
+            let api_key = match api_key {
+                Some(api_key) => api_key,
+                None => {
+                    return LLMProviderHealth::new(
+                        provider.clone(),
+                        LLMProviderStatus::MissingAPIKey,
+                        None,
+                    )
+                }
+            };
+            let start = Instant::now();
+            let status =
+                match tokio::time::timeout(HEALTH_CHECK_TIMEOUT, client.list_models(api_key)).await
+                {
+                    Ok(Ok(models)) => LLMProviderStatus::Available(
+                        models
+                            .into_iter()
+                            .map(|model| {
+                                let capabilities = model
+                                    .llm_type()
+                                    .and_then(|llm_type| client.capabilities(llm_type).ok());
+                                LLMModelAvailability::new(model, capabilities)
+                            })
+                            .collect(),
+                    ),
+                    Ok(Err(e)) => LLMProviderStatus::Unreachable(e.to_string()),
+                    Err(_) => LLMProviderStatus::Unreachable(format!(
+                        "no response in {}s",
+                        HEALTH_CHECK_TIMEOUT.as_secs()
+                    )),
+                };
+            LLMProviderHealth::new(provider.clone(), status, Some(start.elapsed()))
+        });
+        LLMBrokerHealthReport::new(futures::future::join_all(checks).await)
+    }
+
+    /// The redactor for the requests going to the provider, `None` when the
+    /// provider is exempt from redaction
+    fn redactor(&self, provider: &LLMProvider) -> Option<&Redactor> {
+        self.redactors.get(self.redaction.policy(provider))
+    }
+
+    /// Attaches the feedback from the user to the answer we logged, the id is
+    /// the one returned as part of the [`LLMBrokerAnswer`]
+    pub async fn record_feedback(
+        &self,
+        llm_data_id: i64,
+        feedback: LLMFeedback,
+    ) -> Result<(), LLMClientError> {
+        llm_data::feedback::record_feedback(&self.db, llm_data_id, &feedback).await
+    }
+
+    pub async fn llm_data(
+        &self,
+        filter: &LLMDataFilter,
+    ) -> Result<Vec<LLMDataRow>, LLMClientError> {
+        llm_data::fetch_rows(&self.db, filter).await
+    }
+
+    pub async fn apply_retention(
+        &self,
+        policy: RetentionPolicy,
+    ) -> Result<RetentionReport, LLMClientError> {
+        llm_data::retention::apply_retention(&self.db, policy).await
+    }
+
+    /// Exports the rows matching the filter as chat format JSONL which can be
+    /// used for fine-tuning the OpenAI models
+    pub async fn export_chat_jsonl(
+        &self,
+        filter: &LLMDataFilter,
+        writer: &mut (impl std::io::Write + Send),
+    ) -> Result<usize, LLMClientError> {
+        let rows = self.llm_data(filter).await?;
+        export::write_chat_jsonl(&rows, writer)
+    }
+
+    /// Exports the rows matching the filter as prompt/completion pairs, the chat
+    /// rows are formatted using the formatters from the tokenizer
+    pub async fn export_prompt_completion_jsonl(
+        &self,
+        filter: &LLMDataFilter,
+        tokenizer: &LLMTokenizer,
+        writer: &mut (impl std::io::Write + Send),
+    ) -> Result<usize, LLMClientError> {
+        let rows = self.llm_data(filter).await?;
+        export::write_prompt_completion_jsonl(&rows, tokenizer, writer)
+    }
+
     pub async fn stream_answer(
         &self,
         api_key: LLMProviderAPIKeys,
@@ -75,12 +352,74 @@ impl LLMBroker {
                     .await
             }
             Either::Right(request) => {
-                self.stream_string_completion(api_key, request, metadata, sender)
+                self.stream_string_completion(api_key, request, provider, metadata, sender)
                     .await
             }
         }
     }
 
+    /// Like [`stream_answer`](Self::stream_answer) but the key comes from the
+    /// credential store, when a key is rate limited we move on to the next one
+    /// and the tokens used are counted against the key which answered
+    pub async fn stream_answer_with_credentials(
+        &self,
+        provider: LLMProvider,
+        request: Either<LLMClientCompletionRequest, LLMClientCompletionStringRequest>,
+        metadata: HashMap<String, String>,
+        sender: tokio::sync::mpsc::UnboundedSender<LLMClientCompletionResponse>,
+    ) -> LLMBrokerResponse {
+        self.load_credential_usage().await?;
+        let mut last_error = None;
+        for (name, api_key) in self.credentials.candidates(&provider) {
+            let mut metadata = metadata.clone();
+            metadata.insert("credential".to_owned(), name.to_owned());
+            let result = self
+                .stream_answer(
+                    api_key,
+                    provider.clone(),
+                    request.clone(),
+                    metadata,
+                    sender.clone(),
+                )
+                .await;
+            match result {
+                Ok(answer) => {
+                    let (model, input) = match &request {
+                        Either::Left(request) => (
+                            request.model().clone(),
+                            LLMTokenizerInput::Messages(request.messages().to_vec()),
+                        ),
+                        Either::Right(request) => (
+                            request.model().clone(),
+                            LLMTokenizerInput::Prompt(request.prompt().to_owned()),
+                        ),
+                    };
+                    let tokens = self
+                        .credentials
+                        .count_tokens(model, input, answer.answer().to_owned())
+                        .await;
+                    self.credentials.record_usage(&provider, &name, tokens);
+                    // the row keeps the usage, so we can add it up for the
+                    // spend limits after a restart
+                    llm_data::usage::record_usage(
+                        &self.db,
+                        answer.llm_data_id(),
+                        tokens,
+                        self.credentials.cost(&provider, &name, tokens),
+                    )
+                    .await?;
+                    return Ok(answer);
+                }
+                Err(e) if e.is_rate_limited() => {
+                    self.credentials.mark_rate_limited(&provider, &name);
+                    last_error = Some(e);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Err(last_error.unwrap_or(LLMClientError::NoCredentialAvailable(provider)))
+    }
+
     pub async fn stream_completion(
         &self,
         api_key: LLMProviderAPIKeys,
@@ -89,115 +428,920 @@ impl LLMBroker {
         metadata: HashMap<String, String>,
         sender: tokio::sync::mpsc::UnboundedSender<LLMClientCompletionResponse>,
     ) -> LLMBrokerResponse {
-        let api_key = api_key
-            .key(&provider)
-            .ok_or(LLMClientError::UnSupportedModel)?;
-        let provider_type = match &api_key {
-            LLMProviderAPIKeys::Ollama(_) => LLMProvider::Ollama,
-            LLMProviderAPIKeys::OpenAI(_) => LLMProvider::OpenAI,
-            LLMProviderAPIKeys::OpenAIAzureConfig(_) => LLMProvider::OpenAI,
-            LLMProviderAPIKeys::TogetherAI(_) => LLMProvider::TogetherAI,
-            LLMProviderAPIKeys::LMStudio(_) => LLMProvider::LMStudio,
-            LLMProviderAPIKeys::CodeStory => {
-                LLMProvider::CodeStory(CodeStoryLLMType { llm_type: None })
-            }
+        let route = self.route(&api_key, &provider, request.model())?;
+        let mut session = self.redactor(&route.provider).map(RedactionSession::new);
+        let request = match session.as_mut() {
+            Some(session) => LLMClientCompletionRequest::new(
+                request.model().clone(),
+                request
+                    .messages()
+                    .iter()
+                    .map(|message| {
+                        message
+                            .clone()
+                            .map_content(|content| session.redact(content))
+                    })
+                    .collect(),
+                request.temperature(),
+                request.frequency_penalty(),
+            )
+            .set_stop_words(request.stop_words().to_vec()),
+            None => request,
         };
-        let provider = self.providers.get(&provider_type);
-        if let Some(provider) = provider {
-            let result = provider
-                .stream_completion(api_key, request.clone(), sender)
-                .await;
-            if let Ok(result) = result.as_ref() {
-                // we write the inputs to the DB so we can keep track of the inputs
-                // and the result provided by the LLM
-                let llm_type = request.model();
-                let temperature = request.temperature();
-                let str_metadata = serde_json::to_string(&metadata).unwrap_or_default();
-                let llm_type_str = serde_json::to_string(&llm_type)?;
-                let messages = serde_json::to_string(&request.messages())?;
-                let mut tx = self
-                    .db
-                    .begin()
-                    .await
-                    .map_err(|_e| LLMClientError::FailedToStoreInDB)?;
-                let _ = sqlx::query! {
-                    r#"
-                    INSERT INTO llm_data (chat_messages, response, llm_type, temperature, max_tokens, event_type)
-                    VALUES ($1, $2, $3, $4, $5, $6)
-                    "#,
-                    messages,
-                    result,
-                    llm_type_str,
-                    temperature,
-                    -1,
-                    str_metadata,
-                }.execute(&mut *tx).await?;
-                let _ = tx
-                    .commit()
-                    .await
-                    .map_err(|_e| LLMClientError::FailedToStoreInDB)?;
-            }
-            result
-        } else {
-            Err(LLMClientError::UnSupportedModel)
-        }
+        let estimated_tokens = request
+            .messages()
+            .iter()
+            .map(|message| estimate_tokens(message.content()))
+            .sum();
+        let permit = self
+            .rate_limiter
+            .acquire(
+                &route.provider,
+                &route.api_key,
+                RequestPriority::from_metadata(&metadata),
+                estimated_tokens,
+            )
+            .await;
+        let result = stream_restored(session.as_ref(), sender, |sender| {
+            route
+                .client
+                .stream_completion(route.api_key.clone(), request.clone(), sender)
+        })
+        .await?;
+        permit.record_tokens(estimated_tokens + estimate_tokens(&result));
+        drop(permit);
+        // we write the inputs to the DB so we can keep track of the inputs
+        // and the result provided by the LLM
+        let llm_type = request.model();
+        let temperature = request.temperature();
+        let str_metadata = serde_json::to_string(&metadata).unwrap_or_default();
+        let llm_type_str = serde_json::to_string(&llm_type)?;
+        let provider_str = serde_json::to_string(&route.provider)?;
+        let messages = serde_json::to_string(&request.messages())?;
+        let mut tx = self
+            .db
+            .begin()
+            .await
+            .map_err(|_e| LLMClientError::FailedToStoreInDB)?;
+        let llm_data_id = sqlx::query! {
+            r#"
+            INSERT INTO llm_data (chat_messages, response, llm_type, temperature, max_tokens, event_type, provider)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
+            "#,
+            messages,
+            result,
+            llm_type_str,
+            temperature,
+            -1,
+            str_metadata,
+            provider_str,
+        }.execute(&mut *tx).await?.last_insert_rowid();
+        let _ = tx
+            .commit()
+            .await
+            .map_err(|_e| LLMClientError::FailedToStoreInDB)?;
+        // we log what was sent to the provider, so the secrets do not end up
+        // in the DB either, the caller gets the answer with the secrets back
+        let answer = match session {
+            Some(session) => session.restore(&result),
+            None => result,
+        };
+        Ok(LLMBrokerAnswer {
+            answer,
+            llm_data_id,
+        })
     }
 
     pub async fn stream_string_completion(
         &self,
         api_key: LLMProviderAPIKeys,
         request: LLMClientCompletionStringRequest,
+        provider: LLMProvider,
         metadata: HashMap<String, String>,
         sender: tokio::sync::mpsc::UnboundedSender<LLMClientCompletionResponse>,
     ) -> LLMBrokerResponse {
-        let provider_type = match &api_key {
-            LLMProviderAPIKeys::Ollama(_) => LLMProvider::Ollama,
-            LLMProviderAPIKeys::OpenAI(_) => LLMProvider::OpenAI,
-            LLMProviderAPIKeys::OpenAIAzureConfig(_) => LLMProvider::OpenAI,
-            LLMProviderAPIKeys::TogetherAI(_) => LLMProvider::TogetherAI,
-            LLMProviderAPIKeys::LMStudio(_) => LLMProvider::LMStudio,
-            LLMProviderAPIKeys::CodeStory => {
-                LLMProvider::CodeStory(CodeStoryLLMType { llm_type: None })
+        let route = self.route(&api_key, &provider, request.model())?;
+        let mut session = self.redactor(&route.provider).map(RedactionSession::new);
+        let request = match session.as_mut() {
+            Some(session) => LLMClientCompletionStringRequest::new(
+                request.model().clone(),
+                session.redact(request.prompt()),
+                request.temperature(),
+                request.frequency_penalty(),
+            )
+            .set_stop_words(request.stop_words().to_vec()),
+            None => request,
+        };
+        let estimated_tokens = estimate_tokens(request.prompt());
+        let permit = self
+            .rate_limiter
+            .acquire(
+                &route.provider,
+                &route.api_key,
+                RequestPriority::from_metadata(&metadata),
+                estimated_tokens,
+            )
+            .await;
+        let result = stream_restored(session.as_ref(), sender, |sender| {
+            route
+                .client
+                .stream_prompt_completion(route.api_key.clone(), request.clone(), sender)
+        })
+        .await?;
+        permit.record_tokens(estimated_tokens + estimate_tokens(&result));
+        drop(permit);
+        let llm_data_id = self
+            .log_string_completion(&request, &result, &route.provider, &metadata)
+            .await?;
+        // we log what was sent to the provider, so the secrets do not end up
+        // in the DB either, the caller gets the answer with the secrets back
+        let answer = match session {
+            Some(session) => session.restore(&result),
+            None => result,
+        };
+        Ok(LLMBrokerAnswer {
+            answer,
+            llm_data_id,
+        })
+    }
+
+    /// Fill in the middle completion, the provider has to support it for the
+    /// model (see [`LLMClientCapabilities::fill_in_middle`])
+    pub async fn stream_fim_completion(
+        &self,
+        api_key: LLMProviderAPIKeys,
+        request: LLMClientCompletionFIMRequest,
+        provider: LLMProvider,
+        metadata: HashMap<String, String>,
+        sender: tokio::sync::mpsc::UnboundedSender<LLMClientCompletionResponse>,
+    ) -> LLMBrokerResponse {
+        let route = self.route(&api_key, &provider, request.model())?;
+        if !route.client.capabilities(request.model())?.fill_in_middle() {
+            return Err(LLMClientError::FillInMiddleNotSupported(
+                request.model().clone(),
+            ));
+        }
+        let mut session = self.redactor(&route.provider).map(RedactionSession::new);
+        let request = match session.as_mut() {
+            Some(session) => request.map_content(|content| session.redact(content)),
+            None => request,
+        };
+        let estimated_tokens =
+            estimate_tokens(&request.full_prefix()) + estimate_tokens(request.suffix());
+        let permit = self
+            .rate_limiter
+            .acquire(
+                &route.provider,
+                &route.api_key,
+                RequestPriority::from_metadata(&metadata),
+                estimated_tokens,
+            )
+            .await;
+        let result = stream_restored(session.as_ref(), sender, |sender| {
+            route
+                .client
+                .stream_fim_completion(route.api_key.clone(), request.clone(), sender)
+        })
+        .await?;
+        permit.record_tokens(estimated_tokens + estimate_tokens(&result));
+        drop(permit);
+        // the models without sentinels got the suffix as its own field, we
+        // still want a single prompt in the DB so we go with the starcoder ones
+        let logged_request = request.to_string_request().unwrap_or_else(|_| {
+            LLMClientCompletionStringRequest::new(
+                request.model().clone(),
+                FillInMiddleFormat::new("<fim_prefix>", "<fim_suffix>", "<fim_middle>")
+                    .to_prompt(&request.full_prefix(), request.suffix()),
+                request.temperature(),
+                None,
+            )
+        });
+        let llm_data_id = self
+            .log_string_completion(&logged_request, &result, &route.provider, &metadata)
+            .await?;
+        let answer = match session {
+            Some(session) => session.restore(&result),
+            None => result,
+        };
+        Ok(LLMBrokerAnswer {
+            answer,
+            llm_data_id,
+        })
+    }
+
+    /// Completes all the prompts and returns the answers in the same order.
+    /// When the provider supports batching the prompts go out in as few
+    /// requests as we can, otherwise we send single completions, up to
+    /// `MAX_CONCURRENT_REQUESTS` at once. A failed batch fails all of its
+    /// prompts, we only fall back to single completions when the provider
+    /// tells us it can not batch them. Each prompt still gets its own row in
+    /// `llm_data`.
+    pub async fn batch_string_completion(
+        &self,
+        api_key: LLMProviderAPIKeys,
+        requests: Vec<LLMClientCompletionStringRequest>,
+        provider: LLMProvider,
+        metadata: HashMap<String, String>,
+    ) -> Vec<LLMBrokerResponse> {
+        let mut jobs = vec![];
+        let mut requests = requests.into_iter().peekable();
+        while let Some(first) = requests.next() {
+            let mut batch = vec![first];
+            while batch.len() < MAX_BATCH_SIZE
+                && requests
+                    .peek()
+                    .is_some_and(|request| batch[0].batches_with(request))
+            {
+                batch.extend(requests.next());
             }
+            let batching = self
+                .route(&api_key, &provider, batch[0].model())
+                .is_ok_and(|route| {
+                    route
+                        .client
+                        .capabilities(batch[0].model())
+                        .is_ok_and(|capabilities| capabilities.batching())
+                });
+            if batching {
+                jobs.push((true, batch));
+            } else {
+                jobs.extend(batch.into_iter().map(|request| (false, vec![request])));
+            }
+        }
+        futures::stream::iter(jobs)
+            .map(|(batching, batch)| {
+                self.complete_batch(&api_key, batching, batch, &provider, &metadata)
+            })
+            .buffered(MAX_CONCURRENT_REQUESTS)
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .flatten()
+            .collect()
+    }
+
+    /// Without batching the prompts go out one by one
+    async fn complete_batch(
+        &self,
+        api_key: &LLMProviderAPIKeys,
+        batching: bool,
+        batch: Vec<LLMClientCompletionStringRequest>,
+        provider: &LLMProvider,
+        metadata: &HashMap<String, String>,
+    ) -> Vec<LLMBrokerResponse> {
+        let single = |request| async move {
+            let (sender, _receiver) = tokio::sync::mpsc::unbounded_channel();
+            self.stream_string_completion(
+                api_key.clone(),
+                request,
+                provider.clone(),
+                metadata.clone(),
+                sender,
+            )
+            .await
+        };
+        if !batching {
+            return futures::future::join_all(batch.into_iter().map(single)).await;
+        }
+        let batched = match self.route(api_key, provider, batch[0].model()) {
+            Ok(route) => self.batch_completion(&route, &batch, metadata).await,
+            Err(e) => Err(e),
         };
-        let provider = self.providers.get(&provider_type);
-        if let Some(provider) = provider {
-            let result = provider
-                .stream_prompt_completion(api_key, request.clone(), sender)
+        match batched {
+            Ok(responses) => responses,
+            // nothing went out and nothing got logged, so the prompts can
+            // still go one by one
+            Err(LLMClientError::BatchingNotSupported(_)) => {
+                futures::future::join_all(batch.into_iter().map(single)).await
+            }
+            Err(e) => {
+                let e = Arc::new(e);
+                batch
+                    .iter()
+                    .map(|_| Err(LLMClientError::BatchRequestFailed(e.clone())))
+                    .collect()
+            }
+        }
+    }
+
+    /// Sends the prompts as a single request, they all share the model and the
+    /// sampling parameters
+    async fn batch_completion(
+        &self,
+        route: &LLMRoute<'_>,
+        batch: &[LLMClientCompletionStringRequest],
+        metadata: &HashMap<String, String>,
+    ) -> Result<Vec<LLMBrokerResponse>, LLMClientError> {
+        let redactor = self.redactor(&route.provider);
+        let mut sessions = Vec::with_capacity(batch.len());
+        let mut requests = Vec::with_capacity(batch.len());
+        for request in batch {
+            let mut session = redactor.map(RedactionSession::new);
+            requests.push(match session.as_mut() {
+                Some(session) => LLMClientCompletionStringRequest::new(
+                    request.model().clone(),
+                    session.redact(request.prompt()),
+                    request.temperature(),
+                    request.frequency_penalty(),
+                )
+                .set_stop_words(request.stop_words().to_vec()),
+                None => request.clone(),
+            });
+            sessions.push(session);
+        }
+        let estimated_tokens = requests
+            .iter()
+            .map(|request| estimate_tokens(request.prompt()))
+            .sum();
+        let permit = self
+            .rate_limiter
+            .acquire(
+                &route.provider,
+                &route.api_key,
+                RequestPriority::from_metadata(metadata),
+                estimated_tokens,
+            )
+            .await;
+        let results = route
+            .client
+            .batch_prompt_completion(route.api_key.clone(), requests.clone())
+            .await?;
+        if results.len() != requests.len() {
+            return Err(LLMClientError::FailedToGetResponse);
+        }
+        permit.record_tokens(
+            estimated_tokens
+                + results
+                    .iter()
+                    .map(|result| estimate_tokens(result))
+                    .sum::<usize>(),
+        );
+        drop(permit);
+        let mut metadata = metadata.clone();
+        metadata.insert("batch_size".to_owned(), requests.len().to_string());
+        // once we start logging the batch has gone through, a failed write
+        // only fails its own prompt
+        let mut answers = Vec::with_capacity(results.len());
+        for ((request, result), session) in requests.iter().zip(results).zip(sessions) {
+            let logged = self
+                .log_string_completion(request, &result, &route.provider, &metadata)
                 .await;
-            if let Ok(result) = result.as_ref() {
-                // we write the inputs to the DB so we can keep track of the inputs
-                // and the result provided by the LLM
-                let llm_type = request.model();
-                let temperature = request.temperature();
-                let str_metadata = serde_json::to_string(&metadata).unwrap_or_default();
-                let llm_type_str = serde_json::to_string(&llm_type)?;
-                let prompt = request.prompt();
-                let mut tx = self
-                    .db
-                    .begin()
-                    .await
-                    .map_err(|_e| LLMClientError::FailedToStoreInDB)?;
-                let _ = sqlx::query! {
-                    r#"
-                    INSERT INTO llm_data (prompt, response, llm_type, temperature, max_tokens, event_type)
-                    VALUES ($1, $2, $3, $4, $5, $6)
-                    "#,
-                    prompt,
-                    result,
-                    llm_type_str,
-                    temperature,
-                    -1,
-                    str_metadata,
-                }.execute(&mut *tx).await?;
-                let _ = tx
-                    .commit()
-                    .await
-                    .map_err(|_e| LLMClientError::FailedToStoreInDB)?;
+            answers.push(logged.map(|llm_data_id| LLMBrokerAnswer {
+                answer: match session {
+                    Some(session) => session.restore(&result),
+                    None => result,
+                },
+                llm_data_id,
+            }));
+        }
+        Ok(answers)
+    }
+
+    /// We write the inputs to the DB so we can keep track of the inputs and the
+    /// result provided by the LLM
+    async fn log_string_completion(
+        &self,
+        request: &LLMClientCompletionStringRequest,
+        result: &str,
+        provider: &LLMProvider,
+        metadata: &HashMap<String, String>,
+    ) -> Result<i64, LLMClientError> {
+        let llm_type = request.model();
+        let temperature = request.temperature();
+        let str_metadata = serde_json::to_string(&metadata).unwrap_or_default();
+        let llm_type_str = serde_json::to_string(&llm_type)?;
+        let provider_str = serde_json::to_string(&provider)?;
+        let prompt = request.prompt();
+        let mut tx = self
+            .db
+            .begin()
+            .await
+            .map_err(|_e| LLMClientError::FailedToStoreInDB)?;
+        let llm_data_id = sqlx::query! {
+            r#"
+            INSERT INTO llm_data (prompt, response, llm_type, temperature, max_tokens, event_type, provider)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
+            "#,
+            prompt,
+            result,
+            llm_type_str,
+            temperature,
+            -1,
+            str_metadata,
+            provider_str,
+        }.execute(&mut *tx).await?.last_insert_rowid();
+        let _ = tx
+            .commit()
+            .await
+            .map_err(|_e| LLMClientError::FailedToStoreInDB)?;
+        Ok(llm_data_id)
+    }
+}
+
+/// Runs the request against the client, when we redacted something we put the
+/// secrets back in the streamed answer before it reaches the sender. Returns
+/// the answer from the client as is.
+async fn stream_restored<F, Fut>(
+    session: Option<&RedactionSession<'_>>,
+    sender: UnboundedSender<LLMClientCompletionResponse>,
+    stream: F,
+) -> Result<String, LLMClientError>
+where
+    F: FnOnce(UnboundedSender<LLMClientCompletionResponse>) -> Fut,
+    Fut: Future<Output = Result<String, LLMClientError>>,
+{
+    let session = match session {
+        Some(session) if !session.is_empty() => session,
+        _ => return stream(sender).await,
+    };
+    let (client_sender, mut receiver) =
+        tokio::sync::mpsc::unbounded_channel::<LLMClientCompletionResponse>();
+    let forward = async move {
+        let mut sent = String::new();
+        let mut last_response = None;
+        while let Some(response) = receiver.recv().await {
+            let restored = session.restore_streaming(response.answer_up_until_now());
+            let model = response.model().to_owned();
+            last_response = Some((response.answer_up_until_now().to_owned(), model.to_owned()));
+            let delta = match restored.strip_prefix(sent.as_str()) {
+                Some("") => continue,
+                Some(delta) => Some(delta.to_owned()),
+                None => None,
+            };
+            sent = restored;
+            if sender
+                .send(LLMClientCompletionResponse::new(
+                    sent.to_owned(),
+                    delta,
+                    model,
+                ))
+                .is_err()
+            {
+                // dropping the receiver lets the client know no one is listening
+                return;
             }
-            result
-        } else {
-            Err(LLMClientError::UnSupportedModel)
         }
+        // flush the part we were holding back in case it looked like a placeholder
+        if let Some((answer, model)) = last_response {
+            let restored = session.restore(&answer);
+            if let Some(delta) = restored
+                .strip_prefix(sent.as_str())
+                .filter(|delta| !delta.is_empty())
+            {
+                let delta = delta.to_owned();
+                let _ = sender.send(LLMClientCompletionResponse::new(
+                    restored,
+                    Some(delta),
+                    model,
+                ));
+            }
+        }
+    };
+    let (result, _) = tokio::join!(stream(client_sender), forward);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, time::Duration};
+
+    use tempfile::TempDir;
+
+    use super::LLMBroker;
+    use crate::{
+        clients::{
+            mock::{MockLLMClient, MockLLMError, MockResponse},
+            types::{
+                LLMClientCapabilities, LLMClientCompletionFIMRequest, LLMClientCompletionRequest,
+                LLMClientCompletionStringRequest, LLMClientError, LLMClientMessage, LLMClientModel,
+                LLMType,
+            },
+        },
+        config::LLMBrokerConfiguration,
+        health::LLMProviderStatus,
+        llm_data::LLMDataFilter,
+        provider::{
+            AzureOpenAIDeploymentId, CodeStoryLLMType, LLMProvider, LLMProviderAPIKeys,
+            OllamaProvider, TogetherAIProvider,
+        },
+        redaction::RedactionConfig,
+    };
+
+    /// Streams back the prompt in small chunks, so the placeholders get split
+    /// across the deltas
+    fn echo_client(provider: LLMProvider) -> MockLLMClient {
+        MockLLMClient::new(provider)
+            .set_models(vec![LLMClientModel::new(
+                "mistral".to_owned(),
+                Some(LLMType::MistralInstruct),
+            )])
+            .set_default_response(MockResponse::Echo)
+            .set_streaming(5, Duration::ZERO)
+    }
+
+    /// The data dir goes away with the `TempDir`, so keep it around for as
+    /// long as the broker
+    async fn broker(redaction: RedactionConfig) -> (LLMBroker, TempDir) {
+        let data_dir = tempfile::tempdir().expect("data dir to be created");
+        let broker = LLMBroker::new(
+            LLMBrokerConfiguration::new(data_dir.path().to_owned()).set_redaction(redaction),
+        )
+        .await
+        .expect("broker to startup")
+        .add_provider(
+            LLMProvider::Ollama,
+            Box::new(echo_client(LLMProvider::Ollama)),
+        );
+        (broker, data_dir)
+    }
+
+    #[tokio::test]
+    async fn test_secrets_are_redacted_and_restored() {
+        let (broker, _data_dir) = broker(RedactionConfig::default()).await;
+        let prompt =
+            "use OPENAI_API_KEY=sk-abcdefghijklmnopqrstuvwxyz123456 and mail dev@example.com";
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+        let answer = broker
+            .stream_completion(
+                LLMProviderAPIKeys::Ollama(OllamaProvider {}),
+                LLMClientCompletionRequest::new(
+                    LLMType::MistralInstruct,
+                    vec![LLMClientMessage::user(prompt.to_owned())],
+                    0.2,
+                    None,
+                ),
+                LLMProvider::Ollama,
+                HashMap::new(),
+                sender,
+            )
+            .await
+            .expect("completion to work");
+        assert_eq!(answer.answer(), prompt);
+
+        // the deltas add up to the restored answer and never leak a placeholder
+        let mut streamed = String::new();
+        while let Ok(response) = receiver.try_recv() {
+            assert!(!response.answer_up_until_now().contains("REDACTED"));
+            streamed.push_str(response.delta().unwrap_or_default());
+        }
+        assert_eq!(streamed, prompt);
+
+        // the log only has what was sent to the provider
+        let rows = broker
+            .llm_data(&LLMDataFilter::new())
+            .await
+            .expect("rows to be fetched");
+        let row = rows
+            .iter()
+            .find(|row| row.id() == answer.llm_data_id())
+            .expect("row to be logged");
+        assert_eq!(
+            row.response(),
+            Some("use OPENAI_API_KEY=<REDACTED_OPENAI_KEY_1> and mail <REDACTED_EMAIL_1>")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_local_providers_can_be_exempt() {
+        let (broker, _data_dir) = broker(RedactionConfig::default().exempt_local_providers()).await;
+        let prompt = "PASSWORD=hunter2";
+        let (sender, _receiver) = tokio::sync::mpsc::unbounded_channel();
+        let answer = broker
+            .stream_string_completion(
+                LLMProviderAPIKeys::Ollama(OllamaProvider {}),
+                LLMClientCompletionStringRequest::new(
+                    LLMType::MistralInstruct,
+                    prompt.to_owned(),
+                    0.2,
+                    None,
+                ),
+                LLMProvider::Ollama,
+                HashMap::new(),
+                sender,
+            )
+            .await
+            .expect("completion to work");
+        let rows = broker
+            .llm_data(&LLMDataFilter::new())
+            .await
+            .expect("rows to be fetched");
+        let row = rows
+            .iter()
+            .find(|row| row.id() == answer.llm_data_id())
+            .expect("row to be logged");
+        assert_eq!(row.prompt(), Some(prompt));
+    }
+
+    #[tokio::test]
+    async fn test_health_report() {
+        let codestory = LLMProvider::CodeStory(CodeStoryLLMType { llm_type: None });
+        let (broker, _data_dir) = broker(RedactionConfig::default()).await;
+        let broker =
+            broker.add_provider(codestory.clone(), Box::new(echo_client(codestory.clone())));
+        let report = broker
+            .health_report(&[LLMProviderAPIKeys::Ollama(OllamaProvider {})])
+            .await;
+        let ollama = report
+            .provider(&LLMProvider::Ollama)
+            .expect("ollama to be checked");
+        assert!(ollama.is_available());
+        assert!(ollama.models()[0].capabilities().is_some());
+        assert!(matches!(
+            report
+                .provider(&LLMProvider::OpenAI)
+                .map(|health| health.status()),
+            Some(LLMProviderStatus::MissingAPIKey)
+        ));
+        assert_eq!(
+            report.providers_serving(&LLMType::MistralInstruct),
+            vec![&codestory, &LLMProvider::Ollama]
+        );
+        assert!(report.providers_serving(&LLMType::Gpt4).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_batch_string_completion() {
+        let client = echo_client(LLMProvider::TogetherAI)
+            .set_capabilities(LLMClientCapabilities::new(true, true).set_batching(true));
+        let (broker, _data_dir) = broker(RedactionConfig::default()).await;
+        let broker = broker.add_provider(LLMProvider::TogetherAI, Box::new(client.clone()));
+        let request = |prompt: &str, temperature: f32| {
+            LLMClientCompletionStringRequest::new(
+                LLMType::MistralInstruct,
+                prompt.to_owned(),
+                temperature,
+                None,
+            )
+        };
+        let prompts = ["first", "PASSWORD=hunter2", "third", "fourth"];
+        let requests = vec![
+            request(prompts[0], 0.2),
+            request(prompts[1], 0.2),
+            request(prompts[2], 0.2),
+            // a different temperature can not go in the same batch
+            request(prompts[3], 0.7),
+        ];
+
+        let answers = broker
+            .batch_string_completion(
+                LLMProviderAPIKeys::TogetherAI(TogetherAIProvider::new("key".to_owned())),
+                requests.clone(),
+                LLMProvider::TogetherAI,
+                HashMap::new(),
+            )
+            .await
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()
+            .expect("completions to work");
+        assert_eq!(client.batches(), vec![3, 1]);
+        assert_eq!(
+            answers
+                .iter()
+                .map(|answer| answer.answer())
+                .collect::<Vec<_>>(),
+            prompts
+        );
+        // every prompt is logged on its own, with what was sent to the provider
+        let rows = broker
+            .llm_data(&LLMDataFilter::new())
+            .await
+            .expect("rows to be fetched");
+        let row = rows
+            .iter()
+            .find(|row| row.id() == answers[1].llm_data_id())
+            .expect("row to be logged");
+        assert_eq!(row.prompt(), Some("PASSWORD=<REDACTED_ENV_SECRET_1>"));
+
+        // without batching we still get all the answers back in order
+        let answers = broker
+            .batch_string_completion(
+                LLMProviderAPIKeys::Ollama(OllamaProvider {}),
+                requests,
+                LLMProvider::Ollama,
+                HashMap::new(),
+            )
+            .await
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()
+            .expect("completions to work");
+        assert_eq!(
+            answers
+                .iter()
+                .map(|answer| answer.answer())
+                .collect::<Vec<_>>(),
+            prompts
+        );
+        assert_eq!(client.batches(), vec![3, 1]);
+    }
+
+    #[tokio::test]
+    async fn test_failed_batches_are_not_sent_one_by_one() {
+        let (broker, _data_dir) = broker(RedactionConfig::default()).await;
+        let broker = broker.add_provider(
+            LLMProvider::TogetherAI,
+            Box::new(
+                MockLLMClient::new(LLMProvider::TogetherAI)
+                    .set_capabilities(LLMClientCapabilities::new(true, true).set_batching(true))
+                    .push_batch_error(MockLLMError::RateLimited)
+                    .push_batch_error(MockLLMError::BatchingNotSupported)
+                    .respond_when("first", MockResponse::answer("1"))
+                    .respond_when("second", MockResponse::answer("2"))
+                    .respond_when("third", MockResponse::answer("3")),
+            ),
+        );
+        let requests = ["first", "second", "third"]
+            .into_iter()
+            .map(|prompt| {
+                LLMClientCompletionStringRequest::new(
+                    LLMType::MistralInstruct,
+                    prompt.to_owned(),
+                    0.2,
+                    None,
+                )
+            })
+            .collect::<Vec<_>>();
+        let complete = || {
+            broker.batch_string_completion(
+                LLMProviderAPIKeys::TogetherAI(TogetherAIProvider::new("key".to_owned())),
+                requests.clone(),
+                LLMProvider::TogetherAI,
+                HashMap::new(),
+            )
+        };
+
+        // a rate limit fails the whole batch, we do not go and send all the
+        // prompts again one by one
+        let answers = complete().await;
+        assert_eq!(answers.len(), 3);
+        assert!(answers
+            .iter()
+            .all(|answer| answer.as_ref().is_err_and(|e| e.is_rate_limited())));
+        let rows = broker
+            .llm_data(&LLMDataFilter::new())
+            .await
+            .expect("rows to be fetched");
+        assert!(rows.is_empty());
+
+        // the provider can not batch these, so they go one by one and keep
+        // their order
+        let answers = complete()
+            .await
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()
+            .expect("completions to work");
+        assert_eq!(
+            answers
+                .iter()
+                .map(|answer| answer.answer())
+                .collect::<Vec<_>>(),
+            vec!["1", "2", "3"]
+        );
+        let rows = broker
+            .llm_data(&LLMDataFilter::new())
+            .await
+            .expect("rows to be fetched");
+        assert_eq!(rows.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_routing_errors() {
+        let (broker, _data_dir) = broker(RedactionConfig::default()).await;
+        let request = |model: LLMType| {
+            LLMClientCompletionStringRequest::new(model, "hello".to_owned(), 0.2, None)
+        };
+        let stream = |api_key: LLMProviderAPIKeys, model: LLMType, provider: LLMProvider| {
+            let (sender, _receiver) = tokio::sync::mpsc::unbounded_channel();
+            broker.stream_string_completion(
+                api_key,
+                request(model),
+                provider,
+                HashMap::new(),
+                sender,
+            )
+        };
+
+        let result = stream(
+            LLMProviderAPIKeys::Ollama(OllamaProvider {}),
+            LLMType::MistralInstruct,
+            LLMProvider::TogetherAI,
+        )
+        .await;
+        assert!(matches!(
+            result,
+            Err(LLMClientError::MissingAPIKey {
+                provider: LLMProvider::TogetherAI,
+                api_key_provider: LLMProvider::Ollama,
+            })
+        ));
+
+        let result = stream(
+            LLMProviderAPIKeys::TogetherAI(TogetherAIProvider::new("key".to_owned())),
+            LLMType::Gpt4,
+            LLMProvider::TogetherAI,
+        )
+        .await;
+        assert!(matches!(
+            result,
+            Err(LLMClientError::ModelNotSupported {
+                provider: LLMProvider::TogetherAI,
+                model: LLMType::Gpt4,
+            })
+        ));
+
+        let result = stream(
+            LLMProviderAPIKeys::Ollama(OllamaProvider {}),
+            LLMType::Gpt4,
+            LLMProvider::Azure(AzureOpenAIDeploymentId {
+                deployment_id: "".to_owned(),
+            }),
+        )
+        .await;
+        assert!(matches!(
+            result,
+            Err(LLMClientError::MissingAzureDeploymentId)
+        ));
+
+        // the provider we asked for is the one we log, not the one from the key
+        let (sender, _receiver) = tokio::sync::mpsc::unbounded_channel();
+        let answer = broker
+            .stream_string_completion(
+                LLMProviderAPIKeys::Ollama(OllamaProvider {}),
+                request(LLMType::MistralInstruct),
+                LLMProvider::Ollama,
+                HashMap::new(),
+                sender,
+            )
+            .await
+            .expect("ollama to be routed");
+        let rows = broker
+            .llm_data(&LLMDataFilter::new().set_provider(LLMProvider::Ollama))
+            .await
+            .expect("rows to be fetched");
+        assert!(rows.iter().any(|row| row.id() == answer.llm_data_id()));
+    }
+
+    #[tokio::test]
+    async fn test_fim_completion() {
+        let (broker, _data_dir) = broker(RedactionConfig::default()).await;
+        let broker = broker.add_provider(
+            LLMProvider::TogetherAI,
+            Box::new(
+                MockLLMClient::new(LLMProvider::TogetherAI)
+                    .set_capabilities(
+                        LLMClientCapabilities::new(true, true).set_fill_in_middle(true),
+                    )
+                    .set_default_response(MockResponse::answer("    a + b")),
+            ),
+        );
+        let request = LLMClientCompletionFIMRequest::new(
+            LLMType::DeepSeekCoder,
+            "fn add(a: i32, b: i32) -> i32 {\n".to_owned(),
+            "\n}".to_owned(),
+            0.0,
+        )
+        .set_context(Some("// src/add.rs\n".to_owned()));
+
+        // the echo client does not know about fill in the middle
+        let (sender, _receiver) = tokio::sync::mpsc::unbounded_channel();
+        let unsupported = broker
+            .stream_fim_completion(
+                LLMProviderAPIKeys::Ollama(OllamaProvider {}),
+                request.clone(),
+                LLMProvider::Ollama,
+                HashMap::new(),
+                sender,
+            )
+            .await;
+        assert!(matches!(
+            unsupported,
+            Err(LLMClientError::FillInMiddleNotSupported(
+                LLMType::DeepSeekCoder
+            ))
+        ));
+
+        let (sender, _receiver) = tokio::sync::mpsc::unbounded_channel();
+        let answer = broker
+            .stream_fim_completion(
+                LLMProviderAPIKeys::TogetherAI(TogetherAIProvider::new("key".to_owned())),
+                request,
+                LLMProvider::TogetherAI,
+                HashMap::new(),
+                sender,
+            )
+            .await
+            .expect("fim to work");
+        assert_eq!(answer.answer(), "    a + b");
+        let rows = broker
+            .llm_data(&LLMDataFilter::new())
+            .await
+            .expect("rows to be fetched");
+        let row = rows
+            .iter()
+            .find(|row| row.id() == answer.llm_data_id())
+            .expect("row to be logged");
+        assert_eq!(
+            row.prompt(),
+            Some("<｜fim▁begin｜>// src/add.rs\nfn add(a: i32, b: i32) -> i32 {\n<｜fim▁hole｜>\n}<｜fim▁end｜>")
+        );
     }
 }
@@ -7,8 +7,8 @@ use tokio::sync::mpsc::UnboundedSender;
 use crate::provider::{LLMProvider, LLMProviderAPIKeys};
 
 use super::types::{
-    LLMClient, LLMClientCompletionRequest, LLMClientCompletionResponse,
-    LLMClientCompletionStringRequest, LLMClientError, LLMClientRole, LLMType,
+    LLMClient, LLMClientCapabilities, LLMClientCompletionRequest, LLMClientCompletionResponse,
+    LLMClientCompletionStringRequest, LLMClientError, LLMClientModel, LLMClientRole, LLMType,
 };
 
 #[derive(serde::Serialize, serde::Deserialize, Debug)]
@@ -115,6 +115,27 @@ impl LLMClient for CodeStoryClient {
         &LLMProvider::LMStudio
     }
 
+    fn capabilities(&self, model: &LLMType) -> Result<LLMClientCapabilities, LLMClientError> {
+        self.model_endpoint(model)?;
+        Ok(LLMClientCapabilities::new(true, false).set_max_context(model.context_length()))
+    }
+
+    async fn list_models(
+        &self,
+        _api_key: LLMProviderAPIKeys,
+    ) -> Result<Vec<LLMClientModel>, LLMClientError> {
+        // the proxy does not list the models, these are the ones it has endpoints for
+        [LLMType::GPT3_5_16k, LLMType::Gpt4]
+            .into_iter()
+            .map(|llm_type| {
+                Ok(LLMClientModel::new(
+                    self.model_name(&llm_type)?,
+                    Some(llm_type),
+                ))
+            })
+            .collect()
+    }
+
     async fn completion(
         &self,
         api_key: LLMProviderAPIKeys,
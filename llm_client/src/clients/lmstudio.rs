@@ -1,13 +1,15 @@
 use async_trait::async_trait;
-use eventsource_stream::Eventsource;
+use eventsource_stream::{EventStreamError, Eventsource};
 use futures::StreamExt;
 use tokio::sync::mpsc::UnboundedSender;
 
 use crate::provider::{LLMProvider, LLMProviderAPIKeys};
 
+use super::stop_sequences::StopSequenceFilter;
 use super::types::{
-    LLMClient, LLMClientCompletionRequest, LLMClientCompletionResponse,
-    LLMClientCompletionStringRequest, LLMClientError, LLMClientRole,
+    LLMClient, LLMClientCapabilities, LLMClientCompletionFIMRequest, LLMClientCompletionRequest,
+    LLMClientCompletionResponse, LLMClientCompletionStringRequest, LLMClientError, LLMClientModel,
+    LLMClientRole, LLMType,
 };
 
 #[derive(serde::Serialize, serde::Deserialize, Debug)]
@@ -21,6 +23,16 @@ struct Choice {
     text: String,
 }
 
+#[derive(serde::Deserialize, Debug)]
+struct LMStudioModelsResponse {
+    data: Vec<LMStudioModel>,
+}
+
+#[derive(serde::Deserialize, Debug)]
+struct LMStudioModel {
+    id: String,
+}
+
 pub struct LMStudioClient {
     client: reqwest::Client,
 }
@@ -34,6 +46,9 @@ struct LLMStudioMessage {
 #[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
 struct LMStudioRequest {
     prompt: Option<String>,
+    // the openai compatible way of asking for fill in the middle
+    #[serde(skip_serializing_if = "Option::is_none")]
+    suffix: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     messages: Option<Vec<LLMStudioMessage>>,
     temperature: f32,
@@ -42,23 +57,50 @@ struct LMStudioRequest {
     frequency_penalty: Option<f32>,
     // set the max tokens to -1 so we get as much completion as possible
     max_tokens: i32,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    stop: Vec<String>,
 }
 
 impl LMStudioRequest {
-    fn from_string_request(request: LLMClientCompletionStringRequest) -> Self {
+    fn from_string_request(
+        request: LLMClientCompletionStringRequest,
+        stop_filter: &StopSequenceFilter,
+    ) -> Self {
         Self {
             prompt: Some(request.prompt().to_owned()),
+            suffix: None,
             messages: None,
             temperature: request.temperature(),
             stream: true,
             frequency_penalty: request.frequency_penalty(),
             max_tokens: -1,
+            stop: stop_filter.stop_sequences().to_vec(),
+        }
+    }
+
+    fn from_fim_request(
+        request: LLMClientCompletionFIMRequest,
+        stop_filter: &StopSequenceFilter,
+    ) -> Self {
+        Self {
+            prompt: Some(request.full_prefix()),
+            suffix: Some(request.suffix().to_owned()),
+            messages: None,
+            temperature: request.temperature(),
+            stream: true,
+            frequency_penalty: None,
+            max_tokens: -1,
+            stop: stop_filter.stop_sequences().to_vec(),
         }
     }
 
-    fn from_chat_request(request: LLMClientCompletionRequest) -> Self {
+    fn from_chat_request(
+        request: LLMClientCompletionRequest,
+        stop_filter: &StopSequenceFilter,
+    ) -> Self {
         Self {
             prompt: None,
+            suffix: None,
             messages: Some(
                 request
                     .messages()
@@ -87,6 +129,7 @@ impl LMStudioRequest {
             stream: true,
             frequency_penalty: request.frequency_penalty(),
             max_tokens: -1,
+            stop: stop_filter.stop_sequences().to_vec(),
         }
     }
 }
@@ -106,12 +149,60 @@ impl LMStudioClient {
         format!("{}/v1/chat/completions", base_url)
     }
 
+    pub fn models_endpoint(&self, base_url: &str) -> String {
+        format!("{}/v1/models", base_url)
+    }
+
     pub fn generate_base_url(&self, api_key: LLMProviderAPIKeys) -> Result<String, LLMClientError> {
         match api_key {
             LLMProviderAPIKeys::LMStudio(api_key) => Ok(api_key.api_base().to_owned()),
             _ => Err(LLMClientError::UnSupportedModel),
         }
     }
+
+    async fn stream_completion_request(
+        &self,
+        endpoint: String,
+        request: LMStudioRequest,
+        mut stop_filter: StopSequenceFilter,
+        sender: UnboundedSender<LLMClientCompletionResponse>,
+    ) -> Result<String, LLMClientError> {
+        let mut response_stream = self
+            .client
+            .post(endpoint)
+            .json(&request)
+            .send()
+            .await?
+            .bytes_stream()
+            .eventsource();
+
+        let mut buffered_stream = "".to_owned();
+        let mut model = "".to_owned();
+        while let Some(event) = response_stream.next().await {
+            match event {
+                Ok(event) => {
+                    if &event.data == "[DONE]" {
+                        continue;
+                    }
+                    let value = serde_json::from_str::<LMStudioResponse>(&event.data)?;
+                    model = value.model;
+                    if !stop_filter.forward(
+                        &value.choices[0].text,
+                        &mut buffered_stream,
+                        &model,
+                        &sender,
+                    )? {
+                        break;
+                    }
+                }
+                // a broken stream would otherwise pass as a short answer
+                Err(EventStreamError::Transport(e)) => return Err(e.into()),
+                Err(_) => return Err(LLMClientError::FailedToGetResponse),
+            }
+        }
+        stop_filter.flush(&mut buffered_stream, &model, &sender)?;
+        Ok(buffered_stream)
+    }
 }
 
 #[async_trait]
@@ -120,6 +211,35 @@ impl LLMClient for LMStudioClient {
         &LLMProvider::LMStudio
     }
 
+    fn capabilities(&self, model: &LLMType) -> Result<LLMClientCapabilities, LLMClientError> {
+        // LM Studio serves whichever model is loaded, so we can not know the
+        // context length of the custom models
+        Ok(LLMClientCapabilities::new(true, true)
+            .set_fill_in_middle(true)
+            .set_max_context(model.context_length()))
+    }
+
+    async fn list_models(
+        &self,
+        api_key: LLMProviderAPIKeys,
+    ) -> Result<Vec<LLMClientModel>, LLMClientError> {
+        let base_url = self.generate_base_url(api_key)?;
+        let response = self
+            .client
+            .get(self.models_endpoint(&base_url))
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<LMStudioModelsResponse>()
+            .await?;
+        // the requests do not pick a model, we always talk to the loaded one
+        Ok(response
+            .data
+            .into_iter()
+            .map(|model| LLMClientModel::new(model.id, None))
+            .collect())
+    }
+
     async fn completion(
         &self,
         api_key: LLMProviderAPIKeys,
@@ -138,37 +258,10 @@ impl LLMClient for LMStudioClient {
         let base_url = self.generate_base_url(api_key)?;
         let endpoint = self.chat_endpoint(&base_url);
 
-        let request = LMStudioRequest::from_chat_request(request);
-        let mut response_stream = self
-            .client
-            .post(endpoint)
-            .json(&request)
-            .send()
-            .await?
-            .bytes_stream()
-            .eventsource();
-
-        let mut buffered_stream = "".to_owned();
-        while let Some(event) = response_stream.next().await {
-            match event {
-                Ok(event) => {
-                    if &event.data == "[DONE]" {
-                        continue;
-                    }
-                    let value = serde_json::from_str::<LMStudioResponse>(&event.data)?;
-                    buffered_stream = buffered_stream + &value.choices[0].text;
-                    sender.send(LLMClientCompletionResponse::new(
-                        buffered_stream.to_owned(),
-                        Some(value.choices[0].text.to_owned()),
-                        value.model,
-                    ))?;
-                }
-                Err(e) => {
-                    dbg!(e);
-                }
-            }
-        }
-        Ok(buffered_stream)
+        let stop_filter = StopSequenceFilter::for_model(request.model(), request.stop_words());
+        let request = LMStudioRequest::from_chat_request(request, &stop_filter);
+        self.stream_completion_request(endpoint, request, stop_filter, sender)
+            .await
     }
 
     async fn stream_prompt_completion(
@@ -179,37 +272,35 @@ impl LLMClient for LMStudioClient {
     ) -> Result<String, LLMClientError> {
         let base_url = self.generate_base_url(api_key)?;
         let endpoint = self.completion_endpoint(&base_url);
+        let stop_filter = StopSequenceFilter::for_model(request.model(), request.stop_words());
+        let request = LMStudioRequest::from_string_request(request, &stop_filter);
+        self.stream_completion_request(endpoint, request, stop_filter, sender)
+            .await
+    }
 
-        let request = LMStudioRequest::from_string_request(request);
-        let mut response_stream = self
-            .client
-            .post(endpoint)
-            .json(&request)
-            .send()
-            .await?
-            .bytes_stream()
-            .eventsource();
-
-        let mut buffered_stream = "".to_owned();
-        while let Some(event) = response_stream.next().await {
-            match event {
-                Ok(event) => {
-                    if &event.data == "[DONE]" {
-                        continue;
-                    }
-                    let value = serde_json::from_str::<LMStudioResponse>(&event.data)?;
-                    buffered_stream = buffered_stream + &value.choices[0].text;
-                    sender.send(LLMClientCompletionResponse::new(
-                        buffered_stream.to_owned(),
-                        Some(value.choices[0].text.to_owned()),
-                        value.model,
-                    ))?;
-                }
-                Err(e) => {
-                    dbg!(e);
-                }
+    /// The models with fill in the middle sentinels go through the raw prompt,
+    /// for the rest we send the suffix and leave it to the server
+    async fn stream_fim_completion(
+        &self,
+        api_key: LLMProviderAPIKeys,
+        request: LLMClientCompletionFIMRequest,
+        sender: UnboundedSender<LLMClientCompletionResponse>,
+    ) -> Result<String, LLMClientError> {
+        match request.to_string_request() {
+            Ok(string_request) => {
+                self.stream_prompt_completion(api_key, string_request, sender)
+                    .await
+            }
+            Err(LLMClientError::FillInMiddleNotSupported(_)) => {
+                let base_url = self.generate_base_url(api_key)?;
+                let endpoint = self.completion_endpoint(&base_url);
+                let stop_filter =
+                    StopSequenceFilter::for_model(request.model(), request.stop_words());
+                let request = LMStudioRequest::from_fim_request(request, &stop_filter);
+                self.stream_completion_request(endpoint, request, stop_filter, sender)
+                    .await
             }
+            Err(e) => Err(e),
         }
-        Ok(buffered_stream)
     }
 }
@@ -0,0 +1,360 @@
+//! A scripted client so we can run the whole pipeline (broker, reranking,
+//! inline edits) without talking to a provider. Register it in place of a real
+//! client with [`LLMBroker::add_provider`](crate::broker::LLMBroker::add_provider).
+
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::provider::{LLMProvider, LLMProviderAPIKeys};
+
+use super::types::{
+    LLMClient, LLMClientCapabilities, LLMClientCompletionRequest, LLMClientCompletionResponse,
+    LLMClientCompletionStringRequest, LLMClientError, LLMClientModel, LLMType,
+};
+
+/// The errors we can inject, `LLMClientError` is not `Clone` so we keep our
+/// own copy and convert on the way out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MockLLMError {
+    RateLimited,
+    FailedToGetResponse,
+    UnSupportedModel,
+    BatchingNotSupported,
+}
+
+impl MockLLMError {
+    fn into_client_error(self, model: &LLMType) -> LLMClientError {
+        match self {
+            MockLLMError::RateLimited => LLMClientError::RateLimited,
+            MockLLMError::FailedToGetResponse => LLMClientError::FailedToGetResponse,
+            MockLLMError::UnSupportedModel => LLMClientError::UnSupportedModel,
+            MockLLMError::BatchingNotSupported => {
+                LLMClientError::BatchingNotSupported(model.clone())
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MockResponse {
+    Answer(String),
+    /// Streams the partial answer and then fails, like a connection which
+    /// drops halfway through
+    PartialAnswer(String, MockLLMError),
+    Error(MockLLMError),
+    /// Answers with the prompt, so we can see what the provider got sent
+    Echo,
+}
+
+impl MockResponse {
+    pub fn answer(answer: impl Into<String>) -> Self {
+        Self::Answer(answer.into())
+    }
+}
+
+/// Answers with the scripted responses: first the queued ones in order, then
+/// the first rule whose needle is in the prompt, then the default. When none
+/// of them apply the request fails with `FailedToGetResponse`. Batches only
+/// go out when the capabilities allow batching, each prompt in them is
+/// answered like a single request. The clones share the queued responses and
+/// what we got so far, so a test can keep one around after handing the client
+/// to the broker.
+#[derive(Clone)]
+pub struct MockLLMClient {
+    provider: LLMProvider,
+    capabilities: LLMClientCapabilities,
+    models: Vec<LLMClientModel>,
+    queue: Arc<Mutex<VecDeque<MockResponse>>>,
+    rules: Vec<(String, MockResponse)>,
+    default_response: Option<MockResponse>,
+    chunk_size: usize,
+    chunk_delay: Duration,
+    prompts: Arc<Mutex<Vec<String>>>,
+    batch_errors: Arc<Mutex<VecDeque<MockLLMError>>>,
+    batches: Arc<Mutex<Vec<usize>>>,
+}
+
+impl MockLLMClient {
+    /// The mock stands in for the provider, so the broker routes to it like
+    /// it would to the real client
+    pub fn new(provider: LLMProvider) -> Self {
+        Self {
+            provider,
+            capabilities: LLMClientCapabilities::new(true, true),
+            models: vec![],
+            queue: Default::default(),
+            rules: vec![],
+            default_response: None,
+            chunk_size: usize::MAX,
+            chunk_delay: Duration::ZERO,
+            prompts: Default::default(),
+            batch_errors: Default::default(),
+            batches: Default::default(),
+        }
+    }
+
+    pub fn set_capabilities(mut self, capabilities: LLMClientCapabilities) -> Self {
+        self.capabilities = capabilities;
+        self
+    }
+
+    pub fn set_models(mut self, models: Vec<LLMClientModel>) -> Self {
+        self.models = models;
+        self
+    }
+
+    /// Used once, for the next request which comes in
+    pub fn push_response(self, response: MockResponse) -> Self {
+        self.queue
+            .lock()
+            .expect("lock to not be poisoned")
+            .push_back(response);
+        self
+    }
+
+    /// Fails the next batch as a whole, before any of its prompts are answered
+    pub fn push_batch_error(self, error: MockLLMError) -> Self {
+        self.batch_errors
+            .lock()
+            .expect("lock to not be poisoned")
+            .push_back(error);
+        self
+    }
+
+    /// Used for every request whose prompt contains the needle
+    pub fn respond_when(mut self, needle: impl Into<String>, response: MockResponse) -> Self {
+        self.rules.push((needle.into(), response));
+        self
+    }
+
+    pub fn set_default_response(mut self, response: MockResponse) -> Self {
+        self.default_response = Some(response);
+        self
+    }
+
+    /// Streams the answer in chunks of `chunk_size` chars with a delay between
+    /// them, by default the whole answer goes out at once
+    pub fn set_streaming(mut self, chunk_size: usize, chunk_delay: Duration) -> Self {
+        self.chunk_size = chunk_size.max(1);
+        self.chunk_delay = chunk_delay;
+        self
+    }
+
+    /// The prompts we got so far, chat messages are joined with new lines
+    pub fn prompts(&self) -> Vec<String> {
+        self.prompts
+            .lock()
+            .expect("lock to not be poisoned")
+            .clone()
+    }
+
+    /// The size of every batch we got so far, failed ones included
+    pub fn batches(&self) -> Vec<usize> {
+        self.batches
+            .lock()
+            .expect("lock to not be poisoned")
+            .clone()
+    }
+
+    fn next_response(&self, prompt: &str) -> Option<MockResponse> {
+        self.prompts
+            .lock()
+            .expect("lock to not be poisoned")
+            .push(prompt.to_owned());
+        if let Some(response) = self
+            .queue
+            .lock()
+            .expect("lock to not be poisoned")
+            .pop_front()
+        {
+            return Some(response);
+        }
+        self.rules
+            .iter()
+            .find(|(needle, _)| prompt.contains(needle.as_str()))
+            .map(|(_, response)| response.clone())
+            .or_else(|| self.default_response.clone())
+    }
+
+    async fn respond(
+        &self,
+        model: &LLMType,
+        prompt: &str,
+        sender: UnboundedSender<LLMClientCompletionResponse>,
+    ) -> Result<String, LLMClientError> {
+        let (answer, error) = match self.next_response(prompt) {
+            Some(MockResponse::Answer(answer)) => (answer, None),
+            Some(MockResponse::PartialAnswer(answer, error)) => (answer, Some(error)),
+            Some(MockResponse::Error(error)) => return Err(error.into_client_error(model)),
+            Some(MockResponse::Echo) => (prompt.to_owned(), None),
+            None => return Err(LLMClientError::FailedToGetResponse),
+        };
+        let chars = answer.chars().collect::<Vec<_>>();
+        let mut buffered_string = String::new();
+        for (index, chunk) in chars.chunks(self.chunk_size).enumerate() {
+            if index > 0 && !self.chunk_delay.is_zero() {
+                tokio::time::sleep(self.chunk_delay).await;
+            }
+            let delta = chunk.iter().collect::<String>();
+            buffered_string.push_str(&delta);
+            sender.send(LLMClientCompletionResponse::new(
+                buffered_string.to_owned(),
+                Some(delta),
+                model.to_string(),
+            ))?;
+        }
+        match error {
+            Some(error) => Err(error.into_client_error(model)),
+            None => Ok(buffered_string),
+        }
+    }
+}
+
+#[async_trait]
+impl LLMClient for MockLLMClient {
+    fn client(&self) -> &LLMProvider {
+        &self.provider
+    }
+
+    fn capabilities(&self, _model: &LLMType) -> Result<LLMClientCapabilities, LLMClientError> {
+        Ok(self.capabilities.clone())
+    }
+
+    async fn list_models(
+        &self,
+        _api_key: LLMProviderAPIKeys,
+    ) -> Result<Vec<LLMClientModel>, LLMClientError> {
+        Ok(self.models.clone())
+    }
+
+    async fn stream_completion(
+        &self,
+        _api_key: LLMProviderAPIKeys,
+        request: LLMClientCompletionRequest,
+        sender: UnboundedSender<LLMClientCompletionResponse>,
+    ) -> Result<String, LLMClientError> {
+        let prompt = request
+            .messages()
+            .iter()
+            .map(|message| message.content())
+            .collect::<Vec<_>>()
+            .join("\n");
+        self.respond(request.model(), &prompt, sender).await
+    }
+
+    async fn completion(
+        &self,
+        api_key: LLMProviderAPIKeys,
+        request: LLMClientCompletionRequest,
+    ) -> Result<String, LLMClientError> {
+        let (sender, _receiver) = tokio::sync::mpsc::unbounded_channel();
+        self.stream_completion(api_key, request, sender).await
+    }
+
+    async fn stream_prompt_completion(
+        &self,
+        _api_key: LLMProviderAPIKeys,
+        request: LLMClientCompletionStringRequest,
+        sender: UnboundedSender<LLMClientCompletionResponse>,
+    ) -> Result<String, LLMClientError> {
+        self.respond(request.model(), request.prompt(), sender)
+            .await
+    }
+
+    async fn batch_prompt_completion(
+        &self,
+        _api_key: LLMProviderAPIKeys,
+        requests: Vec<LLMClientCompletionStringRequest>,
+    ) -> Result<Vec<String>, LLMClientError> {
+        let model = match requests.first() {
+            Some(first) => first.model().clone(),
+            None => return Ok(vec![]),
+        };
+        if !self.capabilities.batching() {
+            return Err(LLMClientError::BatchingNotSupported(model));
+        }
+        if !requests
+            .iter()
+            .all(|request| requests[0].batches_with(request))
+        {
+            return Err(LLMClientError::MixedBatchRequest);
+        }
+        self.batches
+            .lock()
+            .expect("lock to not be poisoned")
+            .push(requests.len());
+        let error = self
+            .batch_errors
+            .lock()
+            .expect("lock to not be poisoned")
+            .pop_front();
+        if let Some(error) = error {
+            return Err(error.into_client_error(&model));
+        }
+        futures::future::try_join_all(requests.iter().map(|request| async move {
+            let (sender, _receiver) = tokio::sync::mpsc::unbounded_channel();
+            self.respond(request.model(), request.prompt(), sender)
+                .await
+        }))
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{MockLLMClient, MockLLMError, MockResponse};
+    use crate::{
+        clients::types::{LLMClient, LLMClientCompletionStringRequest, LLMClientError, LLMType},
+        provider::{LLMProvider, LLMProviderAPIKeys, OllamaProvider},
+    };
+
+    #[tokio::test]
+    async fn test_scripted_responses() {
+        let client = MockLLMClient::new(LLMProvider::Ollama)
+            .push_response(MockResponse::Error(MockLLMError::RateLimited))
+            .respond_when("rust", MockResponse::answer("yes"))
+            .set_default_response(MockResponse::answer("no"))
+            .set_streaming(1, Duration::from_millis(1));
+        let complete = |prompt: &str| {
+            let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+            let request = LLMClientCompletionStringRequest::new(
+                LLMType::MistralInstruct,
+                prompt.to_owned(),
+                0.0,
+                None,
+            );
+            let client = &client;
+            async move {
+                let answer = client
+                    .stream_prompt_completion(
+                        LLMProviderAPIKeys::Ollama(OllamaProvider {}),
+                        request,
+                        sender,
+                    )
+                    .await;
+                (answer, receiver)
+            }
+        };
+
+        let (answer, _) = complete("is this rust?").await;
+        assert!(matches!(answer, Err(LLMClientError::RateLimited)));
+        let (answer, mut receiver) = complete("is this rust?").await;
+        assert_eq!(answer.expect("answer to work"), "yes");
+        let mut deltas = vec![];
+        while let Ok(response) = receiver.try_recv() {
+            deltas.extend(response.delta().map(|delta| delta.to_owned()));
+        }
+        assert_eq!(deltas, vec!["y", "e", "s"]);
+        let (answer, _) = complete("is this go?").await;
+        assert_eq!(answer.expect("answer to work"), "no");
+        assert_eq!(client.prompts().len(), 3);
+    }
+}
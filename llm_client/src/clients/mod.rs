@@ -3,7 +3,10 @@
 
 pub mod codestory;
 pub mod lmstudio;
+pub mod mock;
 pub mod ollama;
 pub mod openai;
+pub mod replay;
+pub mod stop_sequences;
 pub mod togetherai;
 pub mod types;
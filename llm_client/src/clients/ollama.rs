@@ -3,13 +3,17 @@
 use async_trait::async_trait;
 use tokio::sync::mpsc::UnboundedSender;
 
+use crate::format::fill_in_middle_format;
 use crate::provider::LLMProviderAPIKeys;
 
+use super::stop_sequences::StopSequenceFilter;
 use super::types::LLMClient;
+use super::types::LLMClientCapabilities;
 use super::types::LLMClientCompletionRequest;
 use super::types::LLMClientCompletionResponse;
 use super::types::LLMClientCompletionStringRequest;
 use super::types::LLMClientError;
+use super::types::LLMClientModel;
 use super::types::LLMType;
 
 pub struct OllamaClient {
@@ -24,14 +28,47 @@ struct OllamaResponse {
     done: bool,
 }
 
+#[derive(serde::Deserialize, Debug, Clone)]
+struct OllamaTagsResponse {
+    models: Vec<OllamaModel>,
+}
+
+#[derive(serde::Deserialize, Debug, Clone)]
+struct OllamaModel {
+    name: String,
+}
+
 impl LLMType {
     pub fn to_ollama_model(&self) -> Result<String, LLMClientError> {
         match self {
             LLMType::MistralInstruct => Ok("mistral".to_owned()),
             LLMType::Mixtral => Ok("mixtral".to_owned()),
+            LLMType::DeepSeekCoder => Ok("deepseek-coder".to_owned()),
+            LLMType::CodeLlama => Ok("codellama".to_owned()),
+            LLMType::StarCoder => Ok("starcoder".to_owned()),
             _ => Err(LLMClientError::UnSupportedModel),
         }
     }
+
+    /// The llm type for the ollama model name, the tag (`mistral:latest`) is
+    /// ignored
+    pub fn from_ollama_model(name: &str) -> Option<LLMType> {
+        let name = name.split(':').next().unwrap_or(name);
+        [
+            LLMType::MistralInstruct,
+            LLMType::Mixtral,
+            LLMType::DeepSeekCoder,
+            LLMType::CodeLlama,
+            LLMType::StarCoder,
+        ]
+        .into_iter()
+        .find(|llm_type| llm_type.to_ollama_model().ok().as_deref() == Some(name))
+    }
+}
+
+#[derive(serde::Serialize)]
+struct OllamaClientOptions {
+    stop: Vec<String>,
 }
 
 #[derive(serde::Serialize)]
@@ -43,10 +80,15 @@ struct OllamaClientRequest {
     raw: bool,
     #[serde(skip_serializing_if = "Option::is_none")]
     frequency_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    options: Option<OllamaClientOptions>,
 }
 
 impl OllamaClientRequest {
-    pub fn from_request(request: LLMClientCompletionRequest) -> Result<Self, LLMClientError> {
+    pub fn from_request(
+        request: LLMClientCompletionRequest,
+        stop_filter: &StopSequenceFilter,
+    ) -> Result<Self, LLMClientError> {
         Ok(Self {
             prompt: request
                 .messages()
@@ -59,11 +101,13 @@ impl OllamaClientRequest {
             stream: true,
             raw: true,
             frequency_penalty: request.frequency_penalty(),
+            options: OllamaClientOptions::new(stop_filter),
         })
     }
 
     pub fn from_string_request(
         request: LLMClientCompletionStringRequest,
+        stop_filter: &StopSequenceFilter,
     ) -> Result<Self, LLMClientError> {
         Ok(Self {
             prompt: request.prompt().to_owned(),
@@ -72,10 +116,23 @@ impl OllamaClientRequest {
             stream: true,
             raw: true,
             frequency_penalty: None,
+            options: OllamaClientOptions::new(stop_filter),
         })
     }
 }
 
+impl OllamaClientOptions {
+    fn new(stop_filter: &StopSequenceFilter) -> Option<Self> {
+        if stop_filter.stop_sequences().is_empty() {
+            None
+        } else {
+            Some(Self {
+                stop: stop_filter.stop_sequences().to_vec(),
+            })
+        }
+    }
+}
+
 impl OllamaClient {
     pub fn new() -> Self {
         // ollama always runs on the following url:
@@ -89,6 +146,10 @@ impl OllamaClient {
     pub fn generation_endpoint(&self) -> String {
         format!("{}/api/generate", self.base_url)
     }
+
+    pub fn tags_endpoint(&self) -> String {
+        format!("{}/api/tags", self.base_url)
+    }
 }
 
 #[async_trait]
@@ -97,13 +158,46 @@ impl LLMClient for OllamaClient {
         &crate::provider::LLMProvider::Ollama
     }
 
+    fn capabilities(&self, model: &LLMType) -> Result<LLMClientCapabilities, LLMClientError> {
+        model.to_ollama_model()?;
+        // chat messages are sent as a raw prompt, so both work and so does fill
+        // in the middle for the models which have the sentinels
+        Ok(LLMClientCapabilities::new(true, true)
+            .set_fill_in_middle(fill_in_middle_format(model).is_some())
+            .set_max_context(model.context_length()))
+    }
+
+    async fn list_models(
+        &self,
+        _api_key: LLMProviderAPIKeys,
+    ) -> Result<Vec<LLMClientModel>, LLMClientError> {
+        let response = self
+            .client
+            .get(self.tags_endpoint())
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<OllamaTagsResponse>()
+            .await?;
+        Ok(response
+            .models
+            .into_iter()
+            .map(|model| {
+                let llm_type = LLMType::from_ollama_model(&model.name);
+                LLMClientModel::new(model.name, llm_type)
+            })
+            .collect())
+    }
+
     async fn stream_completion(
         &self,
         _api_key: LLMProviderAPIKeys,
         request: LLMClientCompletionRequest,
         sender: tokio::sync::mpsc::UnboundedSender<LLMClientCompletionResponse>,
     ) -> Result<String, LLMClientError> {
-        let ollama_request = OllamaClientRequest::from_request(request)?;
+        let mut stop_filter = StopSequenceFilter::for_model(request.model(), request.stop_words());
+        let mut model = request.model().to_ollama_model()?;
+        let ollama_request = OllamaClientRequest::from_request(request, &stop_filter)?;
         let mut response = self
             .client
             .post(self.generation_endpoint())
@@ -114,13 +208,14 @@ impl LLMClient for OllamaClient {
         let mut buffered_string = "".to_owned();
         while let Some(chunk) = response.chunk().await? {
             let value = serde_json::from_slice::<OllamaResponse>(chunk.to_vec().as_slice())?;
-            buffered_string.push_str(&value.response);
-            sender.send(LLMClientCompletionResponse::new(
-                buffered_string.to_owned(),
-                Some(value.response),
-                value.model,
-            ))?;
+            model = value.model;
+            // dropping the response closes the connection, so ollama stops
+            // generating as well
+            if !stop_filter.forward(&value.response, &mut buffered_string, &model, &sender)? {
+                break;
+            }
         }
+        stop_filter.flush(&mut buffered_string, &model, &sender)?;
         Ok(buffered_string)
     }
 
@@ -140,7 +235,9 @@ impl LLMClient for OllamaClient {
         request: LLMClientCompletionStringRequest,
         sender: UnboundedSender<LLMClientCompletionResponse>,
     ) -> Result<String, LLMClientError> {
-        let ollama_request = OllamaClientRequest::from_string_request(request)?;
+        let mut stop_filter = StopSequenceFilter::for_model(request.model(), request.stop_words());
+        let mut model = request.model().to_ollama_model()?;
+        let ollama_request = OllamaClientRequest::from_string_request(request, &stop_filter)?;
         let mut response = self
             .client
             .post(self.generation_endpoint())
@@ -151,13 +248,14 @@ impl LLMClient for OllamaClient {
         let mut buffered_string = "".to_owned();
         while let Some(chunk) = response.chunk().await? {
             let value = serde_json::from_slice::<OllamaResponse>(chunk.to_vec().as_slice())?;
-            buffered_string.push_str(&value.response);
-            sender.send(LLMClientCompletionResponse::new(
-                buffered_string.to_owned(),
-                Some(value.response),
-                value.model,
-            ))?;
+            model = value.model;
+            // dropping the response closes the connection, so ollama stops
+            // generating as well
+            if !stop_filter.forward(&value.response, &mut buffered_string, &model, &sender)? {
+                break;
+            }
         }
+        stop_filter.flush(&mut buffered_string, &model, &sender)?;
         Ok(buffered_string)
     }
 }
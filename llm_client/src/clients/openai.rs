@@ -4,7 +4,7 @@ use async_openai::{
     config::{AzureConfig, OpenAIConfig},
     types::{
         ChatCompletionRequestMessage, ChatCompletionRequestMessageArgs,
-        CreateChatCompletionRequestArgs, FunctionCall, Role,
+        CreateChatCompletionRequestArgs, FunctionCall, Role, Stop,
     },
     Client,
 };
@@ -13,11 +13,17 @@ use futures::StreamExt;
 
 use crate::provider::LLMProviderAPIKeys;
 
-use super::types::{
-    LLMClient, LLMClientCompletionRequest, LLMClientCompletionResponse, LLMClientError,
-    LLMClientMessage, LLMClientRole, LLMType,
+use super::{
+    stop_sequences::unique_stop_sequences,
+    types::{
+        LLMClient, LLMClientCapabilities, LLMClientCompletionRequest, LLMClientCompletionResponse,
+        LLMClientError, LLMClientMessage, LLMClientModel, LLMClientRole, LLMType,
+    },
 };
 
+/// The most stop sequences openai takes in a request
+const MAX_STOP_SEQUENCES: usize = 4;
+
 enum OpenAIClientType {
     AzureClient(Client<AzureConfig>),
     OpenAIClient(Client<OpenAIConfig>),
@@ -40,6 +46,18 @@ impl OpenAIClient {
         }
     }
 
+    /// The llm type for the model id which OpenAI reports
+    pub fn llm_type(&self, model_id: &str) -> Option<LLMType> {
+        [
+            LLMType::GPT3_5_16k,
+            LLMType::Gpt4,
+            LLMType::Gpt4Turbo,
+            LLMType::Gpt4_32k,
+        ]
+        .into_iter()
+        .find(|llm_type| self.model(llm_type).as_deref() == Some(model_id))
+    }
+
     pub fn messages(
         &self,
         messages: &[LLMClientMessage],
@@ -124,6 +142,42 @@ impl LLMClient for OpenAIClient {
         &crate::provider::LLMProvider::OpenAI
     }
 
+    fn capabilities(&self, model: &LLMType) -> Result<LLMClientCapabilities, LLMClientError> {
+        self.model(model).ok_or(LLMClientError::UnSupportedModel)?;
+        // we only talk to the chat endpoints, see `stream_prompt_completion`
+        Ok(LLMClientCapabilities::new(true, false)
+            .set_function_calling(true)
+            .set_logprobs(true)
+            .set_max_context(model.context_length()))
+    }
+
+    async fn list_models(
+        &self,
+        api_key: LLMProviderAPIKeys,
+    ) -> Result<Vec<LLMClientModel>, LLMClientError> {
+        match self.generate_openai_client(api_key.clone())? {
+            OpenAIClientType::OpenAIClient(client) => Ok(client
+                .models()
+                .list()
+                .await?
+                .data
+                .into_iter()
+                .map(|model| {
+                    let llm_type = self.llm_type(&model.id);
+                    LLMClientModel::new(model.id, llm_type)
+                })
+                .collect()),
+            // azure serves a single model behind the deployment, we can not
+            // tell which one it is from here
+            OpenAIClientType::AzureClient(_) => match api_key {
+                LLMProviderAPIKeys::OpenAIAzureConfig(azure_config) => {
+                    Ok(vec![LLMClientModel::new(azure_config.deployment_id, None)])
+                }
+                _ => Err(LLMClientError::WrongAPIKeyType),
+            },
+        }
+    }
+
     async fn stream_completion(
         &self,
         api_key: LLMProviderAPIKeys,
@@ -145,6 +199,12 @@ impl LLMClient for OpenAIClient {
         if let Some(frequency_penalty) = request.frequency_penalty() {
             request_builder = request_builder.frequency_penalty(frequency_penalty);
         }
+        let mut stop_words =
+            unique_stop_sequences(request.stop_words().iter().map(|stop| stop.as_str()));
+        stop_words.truncate(MAX_STOP_SEQUENCES);
+        if !stop_words.is_empty() {
+            request_builder = request_builder.stop(Stop::StringArray(stop_words));
+        }
         let request = request_builder.build()?;
         let mut buffer = String::new();
         let client = self.generate_openai_client(api_key)?;
@@ -0,0 +1,437 @@
+//! Records the exchanges with a real client to fixture files and replays them
+//! later on, so tests can run against real answers without the network.
+//! Fixtures are keyed by a hash of the request (model, prompt or messages, the
+//! suffix for fill in the middle and the sampling parameters), the api key is
+//! never part of the fixture.
+
+use std::path::{Path, PathBuf};
+
+use async_trait::async_trait;
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::provider::{LLMProvider, LLMProviderAPIKeys};
+
+use super::types::{
+    LLMClient, LLMClientCapabilities, LLMClientCompletionFIMRequest, LLMClientCompletionRequest,
+    LLMClientCompletionResponse, LLMClientCompletionStringRequest, LLMClientError,
+    LLMClientMessage, LLMClientModel, LLMType,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplayMode {
+    /// Always goes to the client and overwrites the fixture
+    Record,
+    /// Only reads the fixtures, a missing fixture is an error
+    Replay,
+    /// Replays when we have the fixture and records it otherwise
+    RecordMissing,
+}
+
+/// What we hash to find the fixture, also written to the fixture so it is
+/// easy to tell which request it belongs to
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+struct ReplayRequest {
+    model: LLMType,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    messages: Option<Vec<LLMClientMessage>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    prompt: Option<String>,
+    /// Only set for fill in the middle, the prompt is the prefix then
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    suffix: Option<String>,
+    temperature: f32,
+    frequency_penalty: Option<f32>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    stop_words: Vec<String>,
+}
+
+impl ReplayRequest {
+    fn hash(&self) -> Result<String, LLMClientError> {
+        // FNV-1a, unlike the std hasher it is stable across rust versions
+        // which matters since the fixtures are checked in
+        let hash = serde_json::to_string(self)?
+            .bytes()
+            .fold(0xcbf29ce484222325_u64, |hash, byte| {
+                (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+            });
+        Ok(format!("{hash:016x}"))
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+struct ReplayFixture {
+    request: ReplayRequest,
+    /// The deltas as the client streamed them, so the replay streams the same
+    chunks: Vec<String>,
+    answer: String,
+}
+
+pub struct ReplayLLMClient {
+    provider: LLMProvider,
+    mode: ReplayMode,
+    fixture_dir: PathBuf,
+    client: Option<Box<dyn LLMClient + Send + Sync>>,
+}
+
+impl ReplayLLMClient {
+    /// Records (or replays, depending on the mode) the exchanges with the client
+    pub fn record(
+        fixture_dir: impl Into<PathBuf>,
+        mode: ReplayMode,
+        client: Box<dyn LLMClient + Send + Sync>,
+    ) -> Self {
+        Self {
+            provider: client.client().clone(),
+            mode,
+            fixture_dir: fixture_dir.into(),
+            client: Some(client),
+        }
+    }
+
+    /// Only replays, there is no client to fall back to
+    pub fn replay(fixture_dir: impl Into<PathBuf>, provider: LLMProvider) -> Self {
+        Self {
+            provider,
+            mode: ReplayMode::Replay,
+            fixture_dir: fixture_dir.into(),
+            client: None,
+        }
+    }
+
+    pub fn fixture_dir(&self) -> &Path {
+        &self.fixture_dir
+    }
+
+    fn fixture_path(&self, request: &ReplayRequest) -> Result<PathBuf, LLMClientError> {
+        Ok(self.fixture_dir.join(format!("{}.json", request.hash()?)))
+    }
+
+    fn client(&self) -> Result<&(dyn LLMClient + Send + Sync), LLMClientError> {
+        self.client
+            .as_deref()
+            .ok_or(LLMClientError::FailedToGetResponse)
+    }
+
+    async fn read_fixture(&self, path: &Path) -> Result<Option<ReplayFixture>, LLMClientError> {
+        match tokio::fs::read_to_string(path).await {
+            Ok(content) => Ok(Some(serde_json::from_str(&content)?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn stream<F, Fut>(
+        &self,
+        request: ReplayRequest,
+        sender: UnboundedSender<LLMClientCompletionResponse>,
+        stream: F,
+    ) -> Result<String, LLMClientError>
+    where
+        F: FnOnce(UnboundedSender<LLMClientCompletionResponse>) -> Fut,
+        Fut: std::future::Future<Output = Result<String, LLMClientError>>,
+    {
+        let path = self.fixture_path(&request)?;
+        if self.mode != ReplayMode::Record {
+            match self.read_fixture(&path).await? {
+                Some(fixture) => {
+                    let model = request.model.to_string();
+                    let mut buffered_string = String::new();
+                    for chunk in fixture.chunks {
+                        buffered_string.push_str(&chunk);
+                        sender.send(LLMClientCompletionResponse::new(
+                            buffered_string.to_owned(),
+                            Some(chunk),
+                            model.to_owned(),
+                        ))?;
+                    }
+                    return Ok(fixture.answer);
+                }
+                None if self.mode == ReplayMode::Replay => {
+                    return Err(LLMClientError::MissingReplayFixture(path));
+                }
+                None => {}
+            }
+        }
+
+        // tap the stream so we can write the chunks to the fixture
+        let (client_sender, mut receiver) =
+            tokio::sync::mpsc::unbounded_channel::<LLMClientCompletionResponse>();
+        let forward = async move {
+            let mut chunks = vec![];
+            while let Some(response) = receiver.recv().await {
+                chunks.extend(response.delta().map(|delta| delta.to_owned()));
+                let _ = sender.send(response);
+            }
+            chunks
+        };
+        let (answer, chunks) = tokio::join!(stream(client_sender), forward);
+        let answer = answer?;
+        let fixture = ReplayFixture {
+            request,
+            chunks,
+            answer: answer.to_owned(),
+        };
+        tokio::fs::create_dir_all(&self.fixture_dir).await?;
+        tokio::fs::write(&path, serde_json::to_string_pretty(&fixture)?).await?;
+        Ok(answer)
+    }
+}
+
+#[async_trait]
+impl LLMClient for ReplayLLMClient {
+    fn client(&self) -> &LLMProvider {
+        &self.provider
+    }
+
+    /// The batch calls are not recorded, so we fall back to the default batch
+    /// which records each prompt on its own
+    fn capabilities(&self, model: &LLMType) -> Result<LLMClientCapabilities, LLMClientError> {
+        let capabilities = match &self.client {
+            Some(client) => client.capabilities(model)?,
+            None => LLMClientCapabilities::new(true, true).set_fill_in_middle(true),
+        };
+        Ok(capabilities.set_batching(false))
+    }
+
+    /// We do not record the model list, replaying without a client returns
+    /// no models
+    async fn list_models(
+        &self,
+        api_key: LLMProviderAPIKeys,
+    ) -> Result<Vec<LLMClientModel>, LLMClientError> {
+        match &self.client {
+            Some(client) if self.mode != ReplayMode::Replay => client.list_models(api_key).await,
+            _ => Ok(vec![]),
+        }
+    }
+
+    async fn stream_completion(
+        &self,
+        api_key: LLMProviderAPIKeys,
+        request: LLMClientCompletionRequest,
+        sender: UnboundedSender<LLMClientCompletionResponse>,
+    ) -> Result<String, LLMClientError> {
+        let replay_request = ReplayRequest {
+            model: request.model().clone(),
+            messages: Some(request.messages().to_vec()),
+            prompt: None,
+            suffix: None,
+            temperature: request.temperature(),
+            frequency_penalty: request.frequency_penalty(),
+            stop_words: request.stop_words().to_vec(),
+        };
+        self.stream(replay_request, sender, |sender| async move {
+            self.client()?
+                .stream_completion(api_key, request, sender)
+                .await
+        })
+        .await
+    }
+
+    async fn completion(
+        &self,
+        api_key: LLMProviderAPIKeys,
+        request: LLMClientCompletionRequest,
+    ) -> Result<String, LLMClientError> {
+        let (sender, _receiver) = tokio::sync::mpsc::unbounded_channel();
+        self.stream_completion(api_key, request, sender).await
+    }
+
+    async fn stream_prompt_completion(
+        &self,
+        api_key: LLMProviderAPIKeys,
+        request: LLMClientCompletionStringRequest,
+        sender: UnboundedSender<LLMClientCompletionResponse>,
+    ) -> Result<String, LLMClientError> {
+        let replay_request = ReplayRequest {
+            model: request.model().clone(),
+            messages: None,
+            prompt: Some(request.prompt().to_owned()),
+            suffix: None,
+            temperature: request.temperature(),
+            frequency_penalty: request.frequency_penalty(),
+            stop_words: request.stop_words().to_vec(),
+        };
+        self.stream(replay_request, sender, |sender| async move {
+            self.client()?
+                .stream_prompt_completion(api_key, request, sender)
+                .await
+        })
+        .await
+    }
+
+    async fn stream_fim_completion(
+        &self,
+        api_key: LLMProviderAPIKeys,
+        request: LLMClientCompletionFIMRequest,
+        sender: UnboundedSender<LLMClientCompletionResponse>,
+    ) -> Result<String, LLMClientError> {
+        let replay_request = ReplayRequest {
+            model: request.model().clone(),
+            messages: None,
+            prompt: Some(request.full_prefix()),
+            suffix: Some(request.suffix().to_owned()),
+            temperature: request.temperature(),
+            frequency_penalty: None,
+            stop_words: request.stop_words().to_vec(),
+        };
+        self.stream(replay_request, sender, |sender| async move {
+            self.client()?
+                .stream_fim_completion(api_key, request, sender)
+                .await
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ReplayLLMClient, ReplayMode};
+    use crate::{
+        clients::{
+            mock::{MockLLMClient, MockResponse},
+            types::{
+                LLMClient, LLMClientCapabilities, LLMClientCompletionFIMRequest,
+                LLMClientCompletionStringRequest, LLMClientError, LLMType,
+            },
+        },
+        provider::{LLMProvider, LLMProviderAPIKeys, OllamaProvider},
+    };
+
+    #[tokio::test]
+    async fn test_record_then_replay() {
+        let fixture_dir = tempfile::tempdir().expect("fixture dir to be created");
+        let request = |prompt: &str| {
+            LLMClientCompletionStringRequest::new(
+                LLMType::MistralInstruct,
+                prompt.to_owned(),
+                0.2,
+                None,
+            )
+        };
+        let api_key = LLMProviderAPIKeys::Ollama(OllamaProvider {});
+
+        let recorder = ReplayLLMClient::record(
+            fixture_dir.path(),
+            ReplayMode::Record,
+            Box::new(
+                MockLLMClient::new(LLMProvider::Ollama)
+                    .push_response(MockResponse::answer("recorded answer"))
+                    .set_streaming(4, Default::default()),
+            ),
+        );
+        let (sender, _receiver) = tokio::sync::mpsc::unbounded_channel();
+        let answer = recorder
+            .stream_prompt_completion(api_key.clone(), request("hello"), sender)
+            .await
+            .expect("recording to work");
+        assert_eq!(answer, "recorded answer");
+
+        let replayer = ReplayLLMClient::replay(fixture_dir.path(), LLMProvider::Ollama);
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+        let answer = replayer
+            .stream_prompt_completion(api_key.clone(), request("hello"), sender)
+            .await
+            .expect("replay to work");
+        assert_eq!(answer, "recorded answer");
+        let mut deltas = vec![];
+        while let Ok(response) = receiver.try_recv() {
+            deltas.extend(response.delta().map(|delta| delta.to_owned()));
+        }
+        assert_eq!(deltas, vec!["reco", "rded", " ans", "wer"]);
+
+        let (sender, _receiver) = tokio::sync::mpsc::unbounded_channel();
+        let missing = replayer
+            .stream_prompt_completion(api_key, request("something else"), sender)
+            .await;
+        assert!(matches!(
+            missing,
+            Err(LLMClientError::MissingReplayFixture(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_fim_fixtures_are_keyed_by_the_suffix() {
+        let fixture_dir = tempfile::tempdir().expect("fixture dir to be created");
+        let request = |suffix: &str| {
+            LLMClientCompletionFIMRequest::new(
+                LLMType::DeepSeekCoder,
+                "fn add(a: i32, b: i32) -> i32 {\n".to_owned(),
+                suffix.to_owned(),
+                0.0,
+            )
+        };
+        let api_key = LLMProviderAPIKeys::Ollama(OllamaProvider {});
+
+        let recorder = ReplayLLMClient::record(
+            fixture_dir.path(),
+            ReplayMode::Record,
+            Box::new(
+                MockLLMClient::new(LLMProvider::Ollama)
+                    .push_response(MockResponse::answer("    a + b")),
+            ),
+        );
+        let (sender, _receiver) = tokio::sync::mpsc::unbounded_channel();
+        let answer = recorder
+            .stream_fim_completion(api_key.clone(), request("\n}"), sender)
+            .await
+            .expect("recording to work");
+        assert_eq!(answer, "    a + b");
+
+        let replayer = ReplayLLMClient::replay(fixture_dir.path(), LLMProvider::Ollama);
+        let (sender, _receiver) = tokio::sync::mpsc::unbounded_channel();
+        let answer = replayer
+            .stream_fim_completion(api_key.clone(), request("\n}"), sender)
+            .await
+            .expect("replay to work");
+        assert_eq!(answer, "    a + b");
+        let (sender, _receiver) = tokio::sync::mpsc::unbounded_channel();
+        let missing = replayer
+            .stream_fim_completion(api_key, request("\n}\n\nfn sub() {}"), sender)
+            .await;
+        assert!(matches!(
+            missing,
+            Err(LLMClientError::MissingReplayFixture(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_batches_go_through_the_recorded_prompts() {
+        let fixture_dir = tempfile::tempdir().expect("fixture dir to be created");
+        let request = |prompt: &str| {
+            LLMClientCompletionStringRequest::new(
+                LLMType::MistralInstruct,
+                prompt.to_owned(),
+                0.2,
+                None,
+            )
+        };
+        let api_key = LLMProviderAPIKeys::Ollama(OllamaProvider {});
+
+        let recorder = ReplayLLMClient::record(
+            fixture_dir.path(),
+            ReplayMode::Record,
+            Box::new(
+                MockLLMClient::new(LLMProvider::Ollama)
+                    .set_capabilities(LLMClientCapabilities::new(true, true).set_batching(true))
+                    .set_default_response(MockResponse::Echo),
+            ),
+        );
+        assert!(!recorder
+            .capabilities(&LLMType::MistralInstruct)
+            .expect("capabilities to be known")
+            .batching());
+        let answers = recorder
+            .batch_prompt_completion(api_key.clone(), vec![request("one"), request("two")])
+            .await
+            .expect("recording to work");
+        assert_eq!(answers, vec!["one", "two"]);
+
+        let replayer = ReplayLLMClient::replay(fixture_dir.path(), LLMProvider::Ollama);
+        let answers = replayer
+            .batch_prompt_completion(api_key, vec![request("two"), request("one")])
+            .await
+            .expect("replay to work");
+        assert_eq!(answers, vec!["two", "one"]);
+    }
+}
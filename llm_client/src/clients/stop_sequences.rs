@@ -0,0 +1,295 @@
+//! Cuts the answer at the stop sequences and strips the special tokens of the
+//! prompt format. We send the stop sequences to the providers which take them,
+//! but the raw completion endpoints do not always honor them so we also do it
+//! on our side while streaming.
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex, OnceLock},
+};
+
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::format::formatting_for_model;
+
+use super::types::{LLMClientCompletionResponse, LLMClientError, LLMType};
+
+/// The stop sequences and special tokens of the prompt format of a model, the
+/// formatting parses the tokenizer config so we only build these once
+#[derive(Debug, Default)]
+struct ModelMarkers {
+    stop_sequences: Vec<String>,
+    special_tokens: Vec<String>,
+}
+
+impl ModelMarkers {
+    fn for_model(model: &LLMType) -> Arc<Self> {
+        static MARKERS: OnceLock<Mutex<HashMap<LLMType, Arc<ModelMarkers>>>> = OnceLock::new();
+        let mut markers = MARKERS
+            .get_or_init(Default::default)
+            .lock()
+            .expect("lock to not be poisoned");
+        markers
+            .entry(model.clone())
+            .or_insert_with(|| {
+                let formatting = formatting_for_model(model);
+                Arc::new(Self {
+                    stop_sequences: formatting
+                        .as_ref()
+                        .map(|formatting| formatting.stop_sequences())
+                        .unwrap_or_default(),
+                    special_tokens: formatting
+                        .map(|formatting| formatting.special_tokens())
+                        .unwrap_or_default(),
+                })
+            })
+            .clone()
+    }
+}
+
+pub struct StopSequenceFilter {
+    stop_sequences: Vec<String>,
+    special_tokens: Vec<String>,
+    /// Text we are holding back since it might be the start of a stop sequence
+    /// or a special token
+    pending: String,
+    stopped: bool,
+}
+
+impl StopSequenceFilter {
+    pub fn new(stop_sequences: Vec<String>, special_tokens: Vec<String>) -> Self {
+        Self {
+            stop_sequences: stop_sequences
+                .into_iter()
+                .filter(|stop_sequence| !stop_sequence.is_empty())
+                .collect(),
+            special_tokens: special_tokens
+                .into_iter()
+                .filter(|special_token| !special_token.is_empty())
+                .collect(),
+            pending: String::new(),
+            stopped: false,
+        }
+    }
+
+    /// The stop sequences of the prompt format of the model along with the
+    /// stop words of the request, the ones of the prompt format come first
+    pub fn for_model(model: &LLMType, stop_words: &[String]) -> Self {
+        let markers = ModelMarkers::for_model(model);
+        let stop_sequences = unique_stop_sequences(
+            markers
+                .stop_sequences
+                .iter()
+                .chain(stop_words)
+                .map(|stop_sequence| stop_sequence.as_str()),
+        );
+        Self::new(stop_sequences, markers.special_tokens.to_vec())
+    }
+
+    /// What we send to the provider as the stop parameter
+    pub fn stop_sequences(&self) -> &[String] {
+        &self.stop_sequences
+    }
+
+    /// If we hit a stop sequence, everything after it is dropped
+    pub fn is_stopped(&self) -> bool {
+        self.stopped
+    }
+
+    /// Takes the next delta from the stream and returns the part of it we can
+    /// pass on, some of it might be held back until we know it is not the start
+    /// of a stop sequence
+    pub fn push(&mut self, delta: &str) -> String {
+        if self.stopped {
+            return String::new();
+        }
+        self.pending.push_str(delta);
+        let stop_position = self
+            .stop_sequences
+            .iter()
+            .filter_map(|stop_sequence| self.pending.find(stop_sequence.as_str()))
+            .min();
+        if let Some(stop_position) = stop_position {
+            self.stopped = true;
+            let text = self.strip_special_tokens(&self.pending[..stop_position]);
+            self.pending.clear();
+            return text;
+        }
+        self.pending = self.strip_special_tokens(&self.pending);
+        let held_back = self.partial_marker_len();
+        let text = self.pending[..self.pending.len() - held_back].to_owned();
+        self.pending.drain(..self.pending.len() - held_back);
+        text
+    }
+
+    /// The stream is over, what we held back was not a stop sequence after all
+    pub fn finish(&mut self) -> String {
+        std::mem::take(&mut self.pending)
+    }
+
+    /// Runs a complete answer through the filter, for the answers which do not
+    /// stream
+    pub fn apply(mut self, answer: &str) -> String {
+        let mut text = self.push(answer);
+        text.push_str(&self.finish());
+        text
+    }
+
+    /// Passes the delta through the filter and streams what is left of it,
+    /// returns `false` once we hit a stop sequence and should stop reading
+    pub fn forward(
+        &mut self,
+        delta: &str,
+        buffered_string: &mut String,
+        model: &str,
+        sender: &UnboundedSender<LLMClientCompletionResponse>,
+    ) -> Result<bool, LLMClientError> {
+        let delta = self.push(delta);
+        Self::send(delta, buffered_string, model, sender)?;
+        Ok(!self.stopped)
+    }
+
+    /// Sends whatever we held back at the end of the stream
+    pub fn flush(
+        &mut self,
+        buffered_string: &mut String,
+        model: &str,
+        sender: &UnboundedSender<LLMClientCompletionResponse>,
+    ) -> Result<(), LLMClientError> {
+        let delta = self.finish();
+        Self::send(delta, buffered_string, model, sender)
+    }
+
+    fn send(
+        delta: String,
+        buffered_string: &mut String,
+        model: &str,
+        sender: &UnboundedSender<LLMClientCompletionResponse>,
+    ) -> Result<(), LLMClientError> {
+        if delta.is_empty() {
+            return Ok(());
+        }
+        buffered_string.push_str(&delta);
+        sender.send(LLMClientCompletionResponse::new(
+            buffered_string.to_owned(),
+            Some(delta),
+            model.to_owned(),
+        ))?;
+        Ok(())
+    }
+
+    fn strip_special_tokens(&self, text: &str) -> String {
+        self.special_tokens
+            .iter()
+            .fold(text.to_owned(), |text, special_token| {
+                text.replace(special_token.as_str(), "")
+            })
+    }
+
+    /// The length of the longest suffix of what we have which is the start of
+    /// a stop sequence or a special token
+    fn partial_marker_len(&self) -> usize {
+        self.stop_sequences
+            .iter()
+            .chain(self.special_tokens.iter())
+            .flat_map(|marker| {
+                marker
+                    .char_indices()
+                    .skip(1)
+                    .map(|(index, _)| &marker[..index])
+                    .filter(|prefix| self.pending.ends_with(prefix))
+                    .map(|prefix| prefix.len())
+            })
+            .max()
+            .unwrap_or_default()
+    }
+}
+
+/// Drops the repeated stop sequences and keeps the order of the rest
+pub fn unique_stop_sequences<'a>(stop_sequences: impl IntoIterator<Item = &'a str>) -> Vec<String> {
+    let mut seen = HashSet::new();
+    stop_sequences
+        .into_iter()
+        .filter(|stop_sequence| seen.insert(*stop_sequence))
+        .map(|stop_sequence| stop_sequence.to_owned())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::{ModelMarkers, StopSequenceFilter};
+    use crate::clients::types::LLMType;
+
+    #[test]
+    fn test_stream_is_cut_at_the_stop_sequence() {
+        let mut filter =
+            StopSequenceFilter::for_model(&LLMType::Mixtral, &["</ranking>".to_owned()]);
+        let deltas = [
+            "<s>",
+            "<id>\nadd.rs::0\n</id>\n</",
+            "rank",
+            "ing>\n",
+            "junk",
+        ];
+        let mut answer = String::new();
+        for delta in deltas {
+            answer.push_str(&filter.push(delta));
+            if filter.is_stopped() {
+                break;
+            }
+        }
+        answer.push_str(&filter.finish());
+        assert_eq!(answer, "<id>\nadd.rs::0\n</id>\n");
+
+        // the held back text goes out at the end when it was not a stop
+        let mut filter = StopSequenceFilter::for_model(&LLMType::MistralInstruct, &[]);
+        assert_eq!(filter.push("a < b </"), "a < b ");
+        assert_eq!(filter.finish(), "</");
+        assert_eq!(
+            StopSequenceFilter::for_model(&LLMType::DeepSeekCoder, &[])
+                .apply("fn main() {}\n<|EOT|>\n### Instruction:"),
+            "fn main() {}\n"
+        );
+        assert_eq!(
+            StopSequenceFilter::for_model(&LLMType::Mixtral, &[])
+                .apply("[/INST] fn main() {}</s> [INST] more"),
+            " fn main() {}"
+        );
+    }
+
+    #[test]
+    fn test_stop_sequences_are_unique() {
+        let filter = StopSequenceFilter::for_model(
+            &LLMType::Mixtral,
+            &[
+                "</ranking>".to_owned(),
+                "</s>".to_owned(),
+                "```".to_owned(),
+                "</ranking>".to_owned(),
+            ],
+        );
+        let stop_sequences = filter.stop_sequences();
+        assert_eq!(
+            stop_sequences
+                .iter()
+                .filter(|stop_sequence| *stop_sequence == "</ranking>")
+                .count(),
+            1
+        );
+        assert_eq!(
+            stop_sequences
+                .iter()
+                .filter(|stop_sequence| *stop_sequence == "</s>")
+                .count(),
+            1
+        );
+        assert_eq!(stop_sequences.last().map(|s| s.as_str()), Some("```"));
+        // the prompt format is only parsed once per model
+        assert!(Arc::ptr_eq(
+            &ModelMarkers::for_model(&LLMType::Mixtral),
+            &ModelMarkers::for_model(&LLMType::Mixtral)
+        ));
+    }
+}
@@ -3,13 +3,17 @@ use eventsource_stream::Eventsource;
 use futures::StreamExt;
 use tokio::sync::mpsc::UnboundedSender;
 
+use crate::format::fill_in_middle_format;
 use crate::provider::LLMProviderAPIKeys;
 
+use super::stop_sequences::StopSequenceFilter;
 use super::types::LLMClient;
+use super::types::LLMClientCapabilities;
 use super::types::LLMClientCompletionRequest;
 use super::types::LLMClientCompletionResponse;
 use super::types::LLMClientCompletionStringRequest;
 use super::types::LLMClientError;
+use super::types::LLMClientModel;
 use super::types::LLMType;
 
 pub struct TogetherAIClient {
@@ -25,6 +29,8 @@ struct TogetherAIRequest {
     stream_tokens: bool,
     #[serde(skip_serializing_if = "Option::is_none")]
     frequency_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    stop: Vec<String>,
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Debug)]
@@ -39,6 +45,35 @@ struct Choice {
     text: String,
 }
 
+/// The OpenAI compatible completion endpoint takes a list of prompts
+#[derive(serde::Serialize, Debug, Clone)]
+struct TogetherAIBatchRequest {
+    prompt: Vec<String>,
+    model: String,
+    temperature: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    frequency_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    stop: Vec<String>,
+}
+
+#[derive(serde::Deserialize, Debug)]
+struct TogetherAIBatchResponse {
+    choices: Vec<TogetherAIBatchChoice>,
+}
+
+#[derive(serde::Deserialize, Debug)]
+struct TogetherAIBatchChoice {
+    text: String,
+    index: usize,
+}
+
+#[derive(serde::Deserialize, Debug)]
+struct TogetherAIModel {
+    id: String,
+    context_length: Option<usize>,
+}
+
 #[derive(serde::Serialize, serde::Deserialize, Debug)]
 struct Token {
     id: i32,
@@ -48,7 +83,10 @@ struct Token {
 }
 
 impl TogetherAIRequest {
-    pub fn from_request(request: LLMClientCompletionRequest) -> Self {
+    pub fn from_request(
+        request: LLMClientCompletionRequest,
+        stop_filter: &StopSequenceFilter,
+    ) -> Self {
         Self {
             prompt: {
                 if request.messages().len() == 1 {
@@ -67,16 +105,21 @@ impl TogetherAIRequest {
             temperature: request.temperature(),
             stream_tokens: true,
             frequency_penalty: request.frequency_penalty(),
+            stop: stop_filter.stop_sequences().to_vec(),
         }
     }
 
-    pub fn from_string_request(request: LLMClientCompletionStringRequest) -> Self {
+    pub fn from_string_request(
+        request: LLMClientCompletionStringRequest,
+        stop_filter: &StopSequenceFilter,
+    ) -> Self {
         Self {
             prompt: request.prompt().to_owned(),
             model: TogetherAIClient::model_str(request.model()).expect("to be present"),
             temperature: request.temperature(),
             stream_tokens: true,
             frequency_penalty: request.frequency_penalty(),
+            stop: stop_filter.stop_sequences().to_vec(),
         }
     }
 }
@@ -98,10 +141,21 @@ impl TogetherAIClient {
         format!("{}/completions", self.base_url)
     }
 
+    pub fn batch_completion_endpoint(&self) -> String {
+        format!("{}/v1/completions", self.base_url)
+    }
+
+    pub fn models_endpoint(&self) -> String {
+        format!("{}/v1/models", self.base_url)
+    }
+
     pub fn model_str(model: &LLMType) -> Option<String> {
         match model {
             LLMType::Mixtral => Some("mistralai/Mixtral-8x7B-Instruct-v0.1".to_owned()),
             LLMType::MistralInstruct => Some("mistralai/Mistral-7B-Instruct-v0.1".to_owned()),
+            LLMType::DeepSeekCoder => Some("deepseek-ai/deepseek-coder-33b-instruct".to_owned()),
+            LLMType::CodeLlama => Some("codellama/CodeLlama-13b-Instruct-hf".to_owned()),
+            LLMType::StarCoder => Some("bigcode/starcoder".to_owned()),
             LLMType::Custom(model) => Some(model.to_owned()),
             _ => None,
         }
@@ -124,6 +178,114 @@ impl LLMClient for TogetherAIClient {
         &crate::provider::LLMProvider::TogetherAI
     }
 
+    fn capabilities(&self, model: &LLMType) -> Result<LLMClientCapabilities, LLMClientError> {
+        TogetherAIClient::model_str(model).ok_or(LLMClientError::UnSupportedModel)?;
+        // chat messages are sent as a single prompt, so both work
+        Ok(LLMClientCapabilities::new(true, true)
+            .set_logprobs(true)
+            .set_batching(true)
+            .set_fill_in_middle(fill_in_middle_format(model).is_some())
+            .set_max_context(model.context_length()))
+    }
+
+    async fn list_models(
+        &self,
+        api_key: LLMProviderAPIKeys,
+    ) -> Result<Vec<LLMClientModel>, LLMClientError> {
+        let models = self
+            .client
+            .get(self.models_endpoint())
+            .bearer_auth(self.generate_together_ai_bearer_key(api_key)?)
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<Vec<TogetherAIModel>>()
+            .await?;
+        // we can send any of the models as a custom llm type
+        Ok(models
+            .into_iter()
+            .map(|model| {
+                let llm_type = [
+                    LLMType::Mixtral,
+                    LLMType::MistralInstruct,
+                    LLMType::DeepSeekCoder,
+                    LLMType::CodeLlama,
+                    LLMType::StarCoder,
+                ]
+                .into_iter()
+                .find(|llm_type| {
+                    TogetherAIClient::model_str(llm_type).as_deref() == Some(&model.id)
+                })
+                .unwrap_or(LLMType::Custom(model.id.to_owned()));
+                LLMClientModel::new(model.id, Some(llm_type))
+                    .set_context_length(model.context_length)
+            })
+            .collect())
+    }
+
+    async fn batch_prompt_completion(
+        &self,
+        api_key: LLMProviderAPIKeys,
+        requests: Vec<LLMClientCompletionStringRequest>,
+    ) -> Result<Vec<String>, LLMClientError> {
+        let first = match requests.first() {
+            Some(first) => first,
+            None => return Ok(vec![]),
+        };
+        if !requests.iter().all(|request| first.batches_with(request)) {
+            return Err(LLMClientError::MixedBatchRequest);
+        }
+        let model =
+            TogetherAIClient::model_str(first.model()).ok_or(LLMClientError::UnSupportedModel)?;
+        let stop_filter = || StopSequenceFilter::for_model(first.model(), first.stop_words());
+        let batch_request = TogetherAIBatchRequest {
+            model,
+            temperature: first.temperature(),
+            frequency_penalty: first.frequency_penalty(),
+            stop: stop_filter().stop_sequences().to_vec(),
+            prompt: requests
+                .iter()
+                .map(|request| request.prompt().to_owned())
+                .collect(),
+        };
+        let response = self
+            .client
+            .post(self.batch_completion_endpoint())
+            .bearer_auth(self.generate_together_ai_bearer_key(api_key)?)
+            .json(&batch_request)
+            .send()
+            .await?;
+        // an endpoint which does not know about batches, the prompts can still
+        // go one by one
+        if matches!(
+            response.status(),
+            reqwest::StatusCode::NOT_FOUND
+                | reqwest::StatusCode::METHOD_NOT_ALLOWED
+                | reqwest::StatusCode::NOT_IMPLEMENTED
+        ) {
+            return Err(LLMClientError::BatchingNotSupported(first.model().clone()));
+        }
+        let response = response
+            .error_for_status()?
+            .json::<TogetherAIBatchResponse>()
+            .await?;
+        // the choices can come back in any order, the index points to the prompt
+        let mut answers = vec![None; requests.len()];
+        for choice in response.choices {
+            if let Some(answer) = answers.get_mut(choice.index) {
+                *answer = Some(choice.text);
+            }
+        }
+        answers
+            .into_iter()
+            .map(|answer| {
+                answer
+                    .map(|answer| stop_filter().apply(&answer))
+                    .ok_or(LLMClientError::FailedToGetResponse)
+            })
+            .collect()
+    }
+
     async fn completion(
         &self,
         api_key: LLMProviderAPIKeys,
@@ -144,7 +306,8 @@ impl LLMClient for TogetherAIClient {
             return Err(LLMClientError::FailedToGetResponse);
         }
         let model = model.expect("is_none check above to work");
-        let together_ai_request = TogetherAIRequest::from_string_request(request);
+        let mut stop_filter = StopSequenceFilter::for_model(request.model(), request.stop_words());
+        let together_ai_request = TogetherAIRequest::from_string_request(request, &stop_filter);
         let mut response_stream = self
             .client
             .post(self.inference_endpoint())
@@ -152,6 +315,8 @@ impl LLMClient for TogetherAIClient {
             .json(&together_ai_request)
             .send()
             .await?
+            // so a rate limit shows up as the status and not as a parse error
+            .error_for_status()?
             .bytes_stream()
             .eventsource();
 
@@ -163,18 +328,21 @@ impl LLMClient for TogetherAIClient {
                         continue;
                     }
                     let value = serde_json::from_str::<TogetherAIResponse>(&event.data)?;
-                    buffered_string = buffered_string + &value.choices[0].text;
-                    sender.send(LLMClientCompletionResponse::new(
-                        buffered_string.to_owned(),
-                        Some(value.choices[0].text.to_owned()),
-                        model.to_owned(),
-                    ))?;
+                    if !stop_filter.forward(
+                        &value.choices[0].text,
+                        &mut buffered_string,
+                        &model,
+                        &sender,
+                    )? {
+                        break;
+                    }
                 }
                 Err(e) => {
                     dbg!(e);
                 }
             }
         }
+        stop_filter.flush(&mut buffered_string, &model, &sender)?;
 
         Ok(buffered_string)
     }
@@ -190,7 +358,8 @@ impl LLMClient for TogetherAIClient {
             return Err(LLMClientError::FailedToGetResponse);
         }
         let model = model.expect("is_none check above to work");
-        let together_ai_request = TogetherAIRequest::from_request(request);
+        let mut stop_filter = StopSequenceFilter::for_model(request.model(), request.stop_words());
+        let together_ai_request = TogetherAIRequest::from_request(request, &stop_filter);
         let mut response_stream = self
             .client
             .post(self.inference_endpoint())
@@ -198,6 +367,7 @@ impl LLMClient for TogetherAIClient {
             .json(&together_ai_request)
             .send()
             .await?
+            .error_for_status()?
             .bytes_stream()
             .eventsource();
 
@@ -209,18 +379,21 @@ impl LLMClient for TogetherAIClient {
                         continue;
                     }
                     let value = serde_json::from_str::<TogetherAIResponse>(&event.data)?;
-                    buffered_string = buffered_string + &value.choices[0].text;
-                    sender.send(LLMClientCompletionResponse::new(
-                        buffered_string.to_owned(),
-                        Some(value.choices[0].text.to_owned()),
-                        model.to_owned(),
-                    ))?;
+                    if !stop_filter.forward(
+                        &value.choices[0].text,
+                        &mut buffered_string,
+                        &model,
+                        &sender,
+                    )? {
+                        break;
+                    }
                 }
                 Err(e) => {
                     dbg!(e);
                 }
             }
         }
+        stop_filter.flush(&mut buffered_string, &model, &sender)?;
 
         Ok(buffered_string)
     }
@@ -7,7 +7,10 @@ use std::fmt;
 use thiserror::Error;
 use tokio::sync::mpsc::UnboundedSender;
 
-use crate::provider::{LLMProvider, LLMProviderAPIKeys};
+use crate::{
+    format::fill_in_middle_format,
+    provider::{LLMProvider, LLMProviderAPIKeys},
+};
 
 #[derive(Debug, Clone, PartialEq, Hash, Eq)]
 pub enum LLMType {
@@ -18,6 +21,8 @@ pub enum LLMType {
     Gpt4_32k,
     Gpt4Turbo,
     DeepSeekCoder,
+    CodeLlama,
+    StarCoder,
     Custom(String),
 }
 
@@ -59,6 +64,8 @@ impl<'de> Deserialize<'de> for LLMType {
                     "Gpt4_32k" => Ok(LLMType::Gpt4_32k),
                     "Gpt4Turbo" => Ok(LLMType::Gpt4Turbo),
                     "DeepSeekCoder" => Ok(LLMType::DeepSeekCoder),
+                    "CodeLlama" => Ok(LLMType::CodeLlama),
+                    "StarCoder" => Ok(LLMType::StarCoder),
                     _ => Ok(LLMType::Custom(value.to_string())),
                 }
             }
@@ -79,6 +86,23 @@ impl LLMType {
     pub fn is_custom(&self) -> bool {
         matches!(self, LLMType::Custom(_))
     }
+
+    /// The context window of the model in tokens, we do not know it for the
+    /// custom models
+    pub fn context_length(&self) -> Option<usize> {
+        match self {
+            LLMType::Mixtral => Some(32_768),
+            LLMType::MistralInstruct => Some(8_192),
+            LLMType::Gpt4 => Some(8_192),
+            LLMType::GPT3_5_16k => Some(16_385),
+            LLMType::Gpt4_32k => Some(32_768),
+            LLMType::Gpt4Turbo => Some(128_000),
+            LLMType::DeepSeekCoder => Some(16_384),
+            LLMType::CodeLlama => Some(16_384),
+            LLMType::StarCoder => Some(8_192),
+            LLMType::Custom(_) => None,
+        }
+    }
 }
 
 impl fmt::Display for LLMType {
@@ -91,6 +115,8 @@ impl fmt::Display for LLMType {
             LLMType::Gpt4_32k => write!(f, "Gpt4_32k"),
             LLMType::Gpt4Turbo => write!(f, "Gpt4Turbo"),
             LLMType::DeepSeekCoder => write!(f, "DeepSeekCoder"),
+            LLMType::CodeLlama => write!(f, "CodeLlama"),
+            LLMType::StarCoder => write!(f, "StarCoder"),
             LLMType::Custom(s) => write!(f, "Custom({})", s),
         }
     }
@@ -125,7 +151,7 @@ impl LLMClientRole {
     }
 }
 
-#[derive(serde::Serialize, Debug, Clone)]
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
 pub struct LLMClientMessageFunctionCall {
     name: String,
     // arguments are generally given as a JSON string, so we keep it as a string
@@ -143,7 +169,7 @@ impl LLMClientMessageFunctionCall {
     }
 }
 
-#[derive(serde::Serialize, Debug, Clone)]
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
 pub struct LLMClientMessageFunctionReturn {
     name: String,
     content: String,
@@ -159,7 +185,7 @@ impl LLMClientMessageFunctionReturn {
     }
 }
 
-#[derive(serde::Serialize, Debug, Clone)]
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
 pub struct LLMClientMessage {
     role: LLMClientRole,
     message: String,
@@ -226,6 +252,19 @@ impl LLMClientMessage {
     pub fn get_function_return(&self) -> Option<&LLMClientMessageFunctionReturn> {
         self.function_return.as_ref()
     }
+
+    /// Rewrites everything in the message which came from the user or the LLM,
+    /// this includes the arguments of function calls and their return values
+    pub fn map_content(mut self, mut map: impl FnMut(&str) -> String) -> Self {
+        self.message = map(&self.message);
+        if let Some(function_call) = self.function_call.as_mut() {
+            function_call.arguments = map(&function_call.arguments);
+        }
+        if let Some(function_return) = self.function_return.as_mut() {
+            function_return.content = map(&function_return.content);
+        }
+        self
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -234,6 +273,7 @@ pub struct LLMClientCompletionRequest {
     messages: Vec<LLMClientMessage>,
     temperature: f32,
     frequency_penalty: Option<f32>,
+    stop_words: Vec<String>,
 }
 
 #[derive(Clone)]
@@ -242,6 +282,7 @@ pub struct LLMClientCompletionStringRequest {
     prompt: String,
     temperature: f32,
     frequency_penalty: Option<f32>,
+    stop_words: Vec<String>,
 }
 
 impl LLMClientCompletionStringRequest {
@@ -256,9 +297,21 @@ impl LLMClientCompletionStringRequest {
             prompt,
             temperature,
             frequency_penalty,
+            stop_words: vec![],
         }
     }
 
+    /// Where the answer should end on top of the stop sequences of the prompt
+    /// format, the clients cut the answer before them
+    pub fn set_stop_words(mut self, stop_words: Vec<String>) -> Self {
+        self.stop_words = stop_words;
+        self
+    }
+
+    pub fn stop_words(&self) -> &[String] {
+        &self.stop_words
+    }
+
     pub fn model(&self) -> &LLMType {
         &self.model
     }
@@ -274,6 +327,103 @@ impl LLMClientCompletionStringRequest {
     pub fn prompt(&self) -> &str {
         &self.prompt
     }
+
+    /// Prompts can go in the same batch when everything but the prompt matches
+    pub fn batches_with(&self, other: &Self) -> bool {
+        self.model == other.model
+            && self.temperature == other.temperature
+            && self.frequency_penalty == other.frequency_penalty
+            && self.stop_words == other.stop_words
+    }
+}
+
+/// Fill in the middle, the model completes the code between the prefix and
+/// the suffix. The context (the file path, snippets from other files) goes in
+/// front of the prefix.
+#[derive(Clone, Debug)]
+pub struct LLMClientCompletionFIMRequest {
+    model: LLMType,
+    prefix: String,
+    suffix: String,
+    context: Option<String>,
+    temperature: f32,
+    stop_words: Vec<String>,
+}
+
+impl LLMClientCompletionFIMRequest {
+    pub fn new(model: LLMType, prefix: String, suffix: String, temperature: f32) -> Self {
+        Self {
+            model,
+            prefix,
+            suffix,
+            context: None,
+            temperature,
+            stop_words: vec![],
+        }
+    }
+
+    pub fn set_context(mut self, context: Option<String>) -> Self {
+        self.context = context;
+        self
+    }
+
+    pub fn set_stop_words(mut self, stop_words: Vec<String>) -> Self {
+        self.stop_words = stop_words;
+        self
+    }
+
+    pub fn model(&self) -> &LLMType {
+        &self.model
+    }
+
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    pub fn suffix(&self) -> &str {
+        &self.suffix
+    }
+
+    pub fn context(&self) -> Option<&str> {
+        self.context.as_deref()
+    }
+
+    pub fn temperature(&self) -> f32 {
+        self.temperature
+    }
+
+    pub fn stop_words(&self) -> &[String] {
+        &self.stop_words
+    }
+
+    /// The prefix along with the context which goes in front of it
+    pub fn full_prefix(&self) -> String {
+        match &self.context {
+            Some(context) => format!("{context}{}", self.prefix),
+            None => self.prefix.to_owned(),
+        }
+    }
+
+    /// Rewrites the code we send, the prefix, the suffix and the context
+    pub fn map_content(mut self, mut map: impl FnMut(&str) -> String) -> Self {
+        self.prefix = map(&self.prefix);
+        self.suffix = map(&self.suffix);
+        self.context = self.context.map(|context| map(&context));
+        self
+    }
+
+    /// The raw prompt with the fill in the middle sentinels of the model
+    pub fn to_string_request(&self) -> Result<LLMClientCompletionStringRequest, LLMClientError> {
+        let fill_in_middle = fill_in_middle_format(&self.model)
+            .ok_or_else(|| LLMClientError::FillInMiddleNotSupported(self.model.clone()))?;
+        Ok(LLMClientCompletionStringRequest::new(
+            self.model.clone(),
+            fill_in_middle.to_prompt(&self.full_prefix(), &self.suffix),
+            self.temperature,
+            None,
+        )
+        .set_stop_words(self.stop_words.to_vec()))
+    }
 }
 
 impl LLMClientCompletionRequest {
@@ -288,6 +438,7 @@ impl LLMClientCompletionRequest {
             messages,
             temperature,
             frequency_penalty,
+            stop_words: vec![],
         }
     }
 
@@ -300,6 +451,18 @@ impl LLMClientCompletionRequest {
         self
     }
 
+    /// Where the answer should end on top of the stop sequences of the prompt
+    /// format, the clients cut the answer before them. OpenAI takes at most 4
+    /// stop sequences, the ones past that are dropped from the request
+    pub fn set_stop_words(mut self, stop_words: Vec<String>) -> Self {
+        self.stop_words = stop_words;
+        self
+    }
+
+    pub fn stop_words(&self) -> &[String] {
+        &self.stop_words
+    }
+
     pub fn messages(&self) -> &[LLMClientMessage] {
         self.messages.as_slice()
     }
@@ -345,6 +508,138 @@ impl LLMClientCompletionResponse {
     }
 }
 
+/// What a provider supports for a model, so we can check this before sending
+/// the request instead of finding out from the error
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct LLMClientCapabilities {
+    chat: bool,
+    completion: bool,
+    streaming: bool,
+    function_calling: bool,
+    logprobs: bool,
+    batching: bool,
+    fill_in_middle: bool,
+    max_context: Option<usize>,
+}
+
+impl LLMClientCapabilities {
+    /// All our clients stream, the rest is off until set
+    pub fn new(chat: bool, completion: bool) -> Self {
+        Self {
+            chat,
+            completion,
+            streaming: true,
+            function_calling: false,
+            logprobs: false,
+            batching: false,
+            fill_in_middle: false,
+            max_context: None,
+        }
+    }
+
+    pub fn set_streaming(mut self, streaming: bool) -> Self {
+        self.streaming = streaming;
+        self
+    }
+
+    pub fn set_function_calling(mut self, function_calling: bool) -> Self {
+        self.function_calling = function_calling;
+        self
+    }
+
+    pub fn set_logprobs(mut self, logprobs: bool) -> Self {
+        self.logprobs = logprobs;
+        self
+    }
+
+    /// The provider takes many prompts in a single request, see
+    /// [`LLMClient::batch_prompt_completion`]
+    pub fn set_batching(mut self, batching: bool) -> Self {
+        self.batching = batching;
+        self
+    }
+
+    /// The provider can complete [`LLMClientCompletionFIMRequest`] for the model,
+    /// see [`LLMClient::stream_fim_completion`]
+    pub fn set_fill_in_middle(mut self, fill_in_middle: bool) -> Self {
+        self.fill_in_middle = fill_in_middle;
+        self
+    }
+
+    pub fn set_max_context(mut self, max_context: Option<usize>) -> Self {
+        self.max_context = max_context;
+        self
+    }
+
+    pub fn chat(&self) -> bool {
+        self.chat
+    }
+
+    pub fn completion(&self) -> bool {
+        self.completion
+    }
+
+    pub fn streaming(&self) -> bool {
+        self.streaming
+    }
+
+    pub fn function_calling(&self) -> bool {
+        self.function_calling
+    }
+
+    pub fn logprobs(&self) -> bool {
+        self.logprobs
+    }
+
+    pub fn batching(&self) -> bool {
+        self.batching
+    }
+
+    pub fn fill_in_middle(&self) -> bool {
+        self.fill_in_middle
+    }
+
+    pub fn max_context(&self) -> Option<usize> {
+        self.max_context
+    }
+}
+
+/// A model which the provider is serving, `llm_type` is set when we know how
+/// to send requests to it
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct LLMClientModel {
+    id: String,
+    llm_type: Option<LLMType>,
+    context_length: Option<usize>,
+}
+
+impl LLMClientModel {
+    pub fn new(id: String, llm_type: Option<LLMType>) -> Self {
+        Self {
+            id,
+            llm_type,
+            context_length: None,
+        }
+    }
+
+    pub fn set_context_length(mut self, context_length: Option<usize>) -> Self {
+        self.context_length = context_length;
+        self
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn llm_type(&self) -> Option<&LLMType> {
+        self.llm_type.as_ref()
+    }
+
+    pub fn context_length(&self) -> Option<usize> {
+        self.context_length
+    }
+}
+
 #[derive(Error, Debug)]
 pub enum LLMClientError {
     #[error("Failed to get response from LLM")]
@@ -362,6 +657,42 @@ pub enum LLMClientError {
     #[error("unsupported model")]
     UnSupportedModel,
 
+    #[error("provider {provider:?} does not serve {model}")]
+    ModelNotSupported {
+        provider: LLMProvider,
+        model: LLMType,
+    },
+
+    #[error("no client registered for provider {0:?}")]
+    ProviderNotRegistered(LLMProvider),
+
+    #[error("missing api key for provider {provider:?}, got a key for {api_key_provider:?}")]
+    MissingAPIKey {
+        provider: LLMProvider,
+        api_key_provider: LLMProvider,
+    },
+
+    #[error("the Azure provider needs a deployment id")]
+    MissingAzureDeploymentId,
+
+    #[error("the requests in a batch need the same model and sampling parameters")]
+    MixedBatchRequest,
+
+    #[error("fill in the middle is not supported for {0}")]
+    FillInMiddleNotSupported(LLMType),
+
+    #[error("batching is not supported for {0}")]
+    BatchingNotSupported(LLMType),
+
+    #[error("the batch request failed: {0}")]
+    BatchRequestFailed(std::sync::Arc<LLMClientError>),
+
+    #[error("rate limited by the provider")]
+    RateLimited,
+
+    #[error("no credential available for provider {0:?}")]
+    NoCredentialAvailable(LLMProvider),
+
     #[error("OpenAI api error: {0}")]
     OpenAPIError(#[from] async_openai::error::OpenAIError),
 
@@ -385,12 +716,56 @@ pub enum LLMClientError {
 
     #[error("Function calling role but not function call present")]
     FunctionCallNotPresent,
+
+    #[error("No row with id {0} in llm_data")]
+    LLMDataRowNotFound(i64),
+
+    #[error("no replay fixture at {0}")]
+    MissingReplayFixture(std::path::PathBuf),
+
+    #[error("io error: {0}")]
+    IOError(#[from] std::io::Error),
+}
+
+impl LLMClientError {
+    /// If the provider told us to slow down (or we ran out of quota), another
+    /// key might still work
+    pub fn is_rate_limited(&self) -> bool {
+        match self {
+            LLMClientError::RateLimited => true,
+            LLMClientError::ReqwestError(e) => {
+                e.status() == Some(reqwest::StatusCode::TOO_MANY_REQUESTS)
+            }
+            LLMClientError::OpenAPIError(async_openai::error::OpenAIError::ApiError(e)) => {
+                let code = e.code.as_ref().and_then(|code| code.as_str());
+                matches!(
+                    code,
+                    Some("rate_limit_exceeded") | Some("insufficient_quota")
+                ) || e.r#type.as_deref() == Some("insufficient_quota")
+            }
+            LLMClientError::OpenAPIError(async_openai::error::OpenAIError::StreamError(e)) => {
+                e.contains("429")
+            }
+            LLMClientError::BatchRequestFailed(e) => e.is_rate_limited(),
+            _ => false,
+        }
+    }
 }
 
 #[async_trait]
 pub trait LLMClient {
     fn client(&self) -> &LLMProvider;
 
+    /// What the provider supports for the model, errors with `UnSupportedModel`
+    /// when the provider can not serve the model at all
+    fn capabilities(&self, model: &LLMType) -> Result<LLMClientCapabilities, LLMClientError>;
+
+    /// The models the provider is serving right now
+    async fn list_models(
+        &self,
+        api_key: LLMProviderAPIKeys,
+    ) -> Result<Vec<LLMClientModel>, LLMClientError>;
+
     async fn stream_completion(
         &self,
         api_key: LLMProviderAPIKeys,
@@ -410,6 +785,41 @@ pub trait LLMClient {
         request: LLMClientCompletionStringRequest,
         sender: UnboundedSender<LLMClientCompletionResponse>,
     ) -> Result<String, LLMClientError>;
+
+    /// Completes all the prompts and returns the answers in the same order.
+    /// Clients which can send them in a single request override this and set
+    /// [`LLMClientCapabilities::set_batching`], by default we send them one by
+    /// one concurrently. Return `BatchingNotSupported` when the provider turns
+    /// the batch down, the broker then sends the prompts one by one.
+    async fn batch_prompt_completion(
+        &self,
+        api_key: LLMProviderAPIKeys,
+        requests: Vec<LLMClientCompletionStringRequest>,
+    ) -> Result<Vec<String>, LLMClientError> {
+        futures::future::try_join_all(requests.into_iter().map(|request| {
+            let api_key = api_key.clone();
+            async move {
+                let (sender, _receiver) = tokio::sync::mpsc::unbounded_channel();
+                self.stream_prompt_completion(api_key, request, sender)
+                    .await
+            }
+        }))
+        .await
+    }
+
+    /// By default we render the fill in the middle sentinels of the model into
+    /// a raw prompt, clients which take a `suffix` override this for the
+    /// models without sentinels
+    async fn stream_fim_completion(
+        &self,
+        api_key: LLMProviderAPIKeys,
+        request: LLMClientCompletionFIMRequest,
+        sender: UnboundedSender<LLMClientCompletionResponse>,
+    ) -> Result<String, LLMClientError> {
+        let request = request.to_string_request()?;
+        self.stream_prompt_completion(api_key, request, sender)
+            .await
+    }
 }
 
 #[cfg(test)]
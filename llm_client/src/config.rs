@@ -2,12 +2,32 @@
 
 use std::path::PathBuf;
 
+use crate::{rate_limit::RateLimitConfig, redaction::RedactionConfig};
+
 pub struct LLMBrokerConfiguration {
     pub data_dir: PathBuf,
+    /// What we redact from the requests before they are sent to the providers
+    pub redaction: RedactionConfig,
+    /// The client side limits for the requests to the providers
+    pub rate_limits: RateLimitConfig,
 }
 
 impl LLMBrokerConfiguration {
     pub fn new(data_dir: PathBuf) -> Self {
-        Self { data_dir }
+        Self {
+            data_dir,
+            redaction: RedactionConfig::default(),
+            rate_limits: RateLimitConfig::default(),
+        }
+    }
+
+    pub fn set_redaction(mut self, redaction: RedactionConfig) -> Self {
+        self.redaction = redaction;
+        self
+    }
+
+    pub fn set_rate_limits(mut self, rate_limits: RateLimitConfig) -> Self {
+        self.rate_limits = rate_limits;
+        self
     }
 }
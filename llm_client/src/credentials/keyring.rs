@@ -0,0 +1,116 @@
+//! Where we keep the secrets outside of the config file. The editor plugs in
+//! the keyring of the OS, the file keyring is the fallback when there is none
+//! (and what we use in the tests).
+
+use std::{
+    collections::HashMap,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use super::{Credential, CredentialError};
+
+pub trait Keyring: Send + Sync {
+    fn get(&self, name: &str) -> Result<Option<String>, CredentialError>;
+
+    fn set(&self, name: &str, secret: &str) -> Result<(), CredentialError>;
+
+    fn delete(&self, name: &str) -> Result<(), CredentialError>;
+}
+
+/// Keeps the secrets in a JSON file, readable only by the user on unix
+pub struct FileKeyring {
+    path: PathBuf,
+    // so two writes from the same process do not clobber each other
+    lock: Mutex<()>,
+}
+
+impl FileKeyring {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            lock: Mutex::new(()),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn read(&self) -> Result<HashMap<String, String>, CredentialError> {
+        match std::fs::read_to_string(&self.path) {
+            Ok(content) => Ok(serde_json::from_str(&content)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(HashMap::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn write(&self, secrets: &HashMap<String, String>) -> Result<(), CredentialError> {
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        // the secrets go in a file which is only readable by the user from
+        // the start and then take the place of the old one, so they are never
+        // readable by anyone else, not even for a moment
+        let temp_path = self.path.with_extension("tmp");
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut file = options.open(&temp_path)?;
+        // the mode only applies when the file gets created, a temp file left
+        // over from a crash keeps whatever it had
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+        }
+        file.write_all(serde_json::to_string_pretty(secrets)?.as_bytes())?;
+        file.sync_all()?;
+        std::fs::rename(&temp_path, &self.path)?;
+        Ok(())
+    }
+}
+
+impl Keyring for FileKeyring {
+    fn get(&self, name: &str) -> Result<Option<String>, CredentialError> {
+        let _guard = self.lock.lock().expect("lock to not be poisoned");
+        Ok(self.read()?.remove(name))
+    }
+
+    fn set(&self, name: &str, secret: &str) -> Result<(), CredentialError> {
+        let _guard = self.lock.lock().expect("lock to not be poisoned");
+        let mut secrets = self.read()?;
+        secrets.insert(name.to_owned(), secret.to_owned());
+        self.write(&secrets)
+    }
+
+    fn delete(&self, name: &str) -> Result<(), CredentialError> {
+        let _guard = self.lock.lock().expect("lock to not be poisoned");
+        let mut secrets = self.read()?;
+        if secrets.remove(name).is_some() {
+            self.write(&secrets)?;
+        }
+        Ok(())
+    }
+}
+
+/// The whole credential goes in the keyring, so the name is all the config
+/// file needs to know
+pub fn save_credential(
+    keyring: &dyn Keyring,
+    credential: &Credential,
+) -> Result<(), CredentialError> {
+    keyring.set(credential.name(), &serde_json::to_string(credential)?)
+}
+
+pub fn load_credential(keyring: &dyn Keyring, name: &str) -> Result<Credential, CredentialError> {
+    let secret = keyring
+        .get(name)?
+        .ok_or_else(|| CredentialError::NotInKeyring(name.to_owned()))?;
+    Ok(serde_json::from_str(&secret)?)
+}
@@ -0,0 +1,742 @@
+//! The credentials for the providers, we can have more than one key for a
+//! provider (personal and work accounts, or a few keys to spread the load).
+//! The broker picks the key for a request from here, rotates over them and
+//! fails over to the next one when a key gets rate limited.
+
+pub mod keyring;
+pub mod sources;
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use chrono::{Datelike, NaiveDate, NaiveDateTime, Utc};
+use thiserror::Error;
+
+use crate::{
+    clients::types::LLMType,
+    provider::{LLMProvider, LLMProviderAPIKeys, OllamaProvider},
+    rate_limit::estimate_tokens,
+    tokenizer::tokenizer::{LLMTokenizer, LLMTokenizerInput},
+};
+
+/// How long we skip a key after the provider rate limited it
+const DEFAULT_RATE_LIMIT_COOLDOWN: Duration = Duration::from_secs(60);
+
+#[derive(Error, Debug)]
+pub enum CredentialError {
+    #[error("io error: {0}")]
+    IOError(#[from] std::io::Error),
+
+    #[error("serde failed: {0}")]
+    SerdeError(#[from] serde_json::Error),
+
+    #[error("credential {0} not found in the keyring")]
+    NotInKeyring(String),
+
+    #[error("keyring error: {0}")]
+    KeyringError(String),
+}
+
+/// How long the usage adds up towards the spend limit before it starts over
+/// from 0, the periods follow the calendar in UTC
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Serialize, serde::Deserialize)]
+pub enum SpendLimitPeriod {
+    Daily,
+    #[default]
+    Monthly,
+}
+
+impl SpendLimitPeriod {
+    /// When the period which `now` falls in started
+    pub fn start(&self, now: NaiveDateTime) -> NaiveDateTime {
+        let day = match self {
+            SpendLimitPeriod::Daily => now.date(),
+            SpendLimitPeriod::Monthly => {
+                NaiveDate::from_ymd_opt(now.year(), now.month(), 1).unwrap_or(now.date())
+            }
+        };
+        day.and_hms_opt(0, 0, 0).unwrap_or(now)
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Credential {
+    name: String,
+    api_key: LLMProviderAPIKeys,
+    /// We stop using the key once it has used up these many tokens in the
+    /// spend limit period
+    #[serde(default)]
+    spend_limit_tokens: Option<u64>,
+    #[serde(default)]
+    spend_limit_period: SpendLimitPeriod,
+    /// Used to work out the cost of the requests we log, one price for both
+    /// the prompt and the answer
+    #[serde(default)]
+    price_per_million_tokens: Option<f64>,
+}
+
+impl Credential {
+    pub fn new(name: String, api_key: LLMProviderAPIKeys) -> Self {
+        Self {
+            name,
+            api_key,
+            spend_limit_tokens: None,
+            spend_limit_period: SpendLimitPeriod::default(),
+            price_per_million_tokens: None,
+        }
+    }
+
+    pub fn set_spend_limit_tokens(mut self, spend_limit_tokens: Option<u64>) -> Self {
+        self.spend_limit_tokens = spend_limit_tokens;
+        self
+    }
+
+    pub fn set_spend_limit_period(mut self, spend_limit_period: SpendLimitPeriod) -> Self {
+        self.spend_limit_period = spend_limit_period;
+        self
+    }
+
+    pub fn set_price_per_million_tokens(mut self, price_per_million_tokens: Option<f64>) -> Self {
+        self.price_per_million_tokens = price_per_million_tokens;
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn api_key(&self) -> &LLMProviderAPIKeys {
+        &self.api_key
+    }
+
+    pub fn provider(&self) -> LLMProvider {
+        self.api_key.provider_type().client_provider()
+    }
+
+    pub fn spend_limit_tokens(&self) -> Option<u64> {
+        self.spend_limit_tokens
+    }
+
+    pub fn spend_limit_period(&self) -> SpendLimitPeriod {
+        self.spend_limit_period
+    }
+
+    /// What the tokens cost with this key, `None` when we do not know the price
+    pub fn cost(&self, tokens: u64) -> Option<f64> {
+        self.price_per_million_tokens
+            .map(|price| tokens as f64 * price / 1_000_000.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RotationStrategy {
+    /// Spread the requests over all the keys
+    #[default]
+    RoundRobin,
+    /// Always use the first key and only move on when it's rate limited or
+    /// over its spend limit
+    Failover,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CredentialUsage {
+    pub provider: LLMProvider,
+    pub name: String,
+    /// The usage in the current spend limit period
+    pub tokens_used: u64,
+    pub cost: Option<f64>,
+    pub period_start: NaiveDateTime,
+    pub spend_limit_tokens: Option<u64>,
+    pub rate_limited: bool,
+}
+
+struct CredentialState {
+    credential: Credential,
+    tokens_used: u64,
+    cost: f64,
+    /// The start of the period the usage belongs to
+    period_start: NaiveDateTime,
+    rate_limited_until: Option<Instant>,
+}
+
+impl CredentialState {
+    fn new(credential: Credential) -> Self {
+        let period_start = credential
+            .spend_limit_period()
+            .start(Utc::now().naive_utc());
+        Self {
+            credential,
+            tokens_used: 0,
+            cost: 0.0,
+            period_start,
+            rate_limited_until: None,
+        }
+    }
+
+    /// Starts the usage over from 0 once we are in a new period
+    fn roll_over(&mut self, now: NaiveDateTime) {
+        let period_start = self.credential.spend_limit_period().start(now);
+        if period_start > self.period_start {
+            self.tokens_used = 0;
+            self.cost = 0.0;
+            self.period_start = period_start;
+        }
+    }
+
+    fn is_available(&self, now: Instant) -> bool {
+        let rate_limited = self
+            .rate_limited_until
+            .is_some_and(|rate_limited_until| rate_limited_until > now);
+        let over_limit = self
+            .credential
+            .spend_limit_tokens()
+            .is_some_and(|spend_limit| self.tokens_used >= spend_limit);
+        !rate_limited && !over_limit
+    }
+}
+
+#[derive(Default)]
+struct ProviderCredentials {
+    credentials: Vec<CredentialState>,
+    next: usize,
+}
+
+/// The usage lives in memory, the broker loads the usage of the current period
+/// back from the token counts it logged in `llm_data` (see
+/// [`LLMBroker::load_credential_usage`](crate::broker::LLMBroker::load_credential_usage))
+/// so the spend limits hold across restarts
+pub struct CredentialStore {
+    providers: Mutex<HashMap<LLMProvider, ProviderCredentials>>,
+    strategy: RotationStrategy,
+    rate_limit_cooldown: Duration,
+    tokenizer: Option<Arc<LLMTokenizer>>,
+}
+
+impl Default for CredentialStore {
+    fn default() -> Self {
+        Self::new(RotationStrategy::default())
+    }
+}
+
+impl CredentialStore {
+    pub fn new(strategy: RotationStrategy) -> Self {
+        Self {
+            providers: Mutex::new(HashMap::new()),
+            strategy,
+            rate_limit_cooldown: DEFAULT_RATE_LIMIT_COOLDOWN,
+            tokenizer: None,
+        }
+    }
+
+    /// Adds the credential, a credential with the same name for the provider
+    /// is replaced
+    pub fn add_credential(self, credential: Credential) -> Self {
+        {
+            let mut providers = self.providers.lock().expect("lock to not be poisoned");
+            let provider_credentials = providers.entry(credential.provider()).or_default();
+            provider_credentials
+                .credentials
+                .retain(|state| state.credential.name() != credential.name());
+            provider_credentials
+                .credentials
+                .push(CredentialState::new(credential));
+        }
+        self
+    }
+
+    pub fn add_credentials(self, credentials: impl IntoIterator<Item = Credential>) -> Self {
+        credentials
+            .into_iter()
+            .fold(self, |store, credential| store.add_credential(credential))
+    }
+
+    pub fn set_rate_limit_cooldown(mut self, rate_limit_cooldown: Duration) -> Self {
+        self.rate_limit_cooldown = rate_limit_cooldown;
+        self
+    }
+
+    /// The tokenizer is used to count the tokens towards the spend limits,
+    /// without it (or for models it does not know) we estimate them
+    pub fn set_tokenizer(mut self, tokenizer: Arc<LLMTokenizer>) -> Self {
+        self.tokenizer = Some(tokenizer);
+        self
+    }
+
+    /// The keys to try for a request in order, along with their names. The
+    /// keys which are rate limited or over their spend limit are skipped.
+    /// Ollama and CodeStory do not need a key, so they get one by default.
+    pub fn candidates(&self, provider: &LLMProvider) -> Vec<(String, LLMProviderAPIKeys)> {
+        let mut providers = self.providers.lock().expect("lock to not be poisoned");
+        let provider_credentials = match providers.get_mut(&provider.client_provider()) {
+            Some(provider_credentials) if !provider_credentials.credentials.is_empty() => {
+                provider_credentials
+            }
+            _ => {
+                return match provider {
+                    LLMProvider::Ollama => vec![(
+                        "default".to_owned(),
+                        LLMProviderAPIKeys::Ollama(OllamaProvider {}),
+                    )],
+                    LLMProvider::CodeStory(_) => {
+                        vec![("default".to_owned(), LLMProviderAPIKeys::CodeStory)]
+                    }
+                    _ => vec![],
+                };
+            }
+        };
+        let now = Instant::now();
+        let today = Utc::now().naive_utc();
+        provider_credentials
+            .credentials
+            .iter_mut()
+            .for_each(|state| state.roll_over(today));
+        let count = provider_credentials.credentials.len();
+        let start = match self.strategy {
+            RotationStrategy::RoundRobin => {
+                let start = provider_credentials.next % count;
+                provider_credentials.next = (start + 1) % count;
+                start
+            }
+            RotationStrategy::Failover => 0,
+        };
+        (0..count)
+            .map(|offset| &provider_credentials.credentials[(start + offset) % count])
+            .filter(|state| state.is_available(now))
+            .filter_map(|state| {
+                // this sets the Azure deployment from the provider
+                let api_key = state.credential.api_key().key(provider)?;
+                Some((state.credential.name().to_owned(), api_key))
+            })
+            .collect()
+    }
+
+    /// Skips the key for a while after the provider rate limited it
+    pub fn mark_rate_limited(&self, provider: &LLMProvider, name: &str) {
+        let until = Instant::now() + self.rate_limit_cooldown;
+        self.update(provider, name, |state| {
+            state.rate_limited_until = Some(until)
+        });
+    }
+
+    /// Adds the tokens to the usage of the key, the cost comes from the price
+    /// of the key when it has one
+    pub fn record_usage(&self, provider: &LLMProvider, name: &str, tokens: u64) {
+        let now = Utc::now().naive_utc();
+        self.update(provider, name, |state| {
+            state.roll_over(now);
+            state.tokens_used += tokens;
+            state.cost += state.credential.cost(tokens).unwrap_or_default();
+        });
+    }
+
+    /// Adds the usage we logged in the current period to the key, see
+    /// [`LLMBroker::load_credential_usage`](crate::broker::LLMBroker::load_credential_usage)
+    pub fn load_usage(&self, provider: &LLMProvider, name: &str, tokens: u64, cost: f64) {
+        let now = Utc::now().naive_utc();
+        self.update(provider, name, |state| {
+            state.roll_over(now);
+            state.tokens_used += tokens;
+            state.cost += cost;
+        });
+    }
+
+    /// The keys which have a spend limit, along with their provider and the
+    /// start of their current spend limit period
+    pub fn spend_limited(&self) -> Vec<(LLMProvider, String, NaiveDateTime)> {
+        let now = Utc::now().naive_utc();
+        let providers = self.providers.lock().expect("lock to not be poisoned");
+        providers
+            .iter()
+            .flat_map(|(provider, provider_credentials)| {
+                provider_credentials
+                    .credentials
+                    .iter()
+                    .filter(|state| state.credential.spend_limit_tokens().is_some())
+                    .map(move |state| {
+                        (
+                            provider.clone(),
+                            state.credential.name().to_owned(),
+                            state.credential.spend_limit_period().start(now),
+                        )
+                    })
+            })
+            .collect()
+    }
+
+    /// What the key would cost for these tokens, see [`Credential::cost`]
+    pub fn cost(&self, provider: &LLMProvider, name: &str, tokens: u64) -> Option<f64> {
+        let mut cost = None;
+        self.update(provider, name, |state| cost = state.credential.cost(tokens));
+        cost
+    }
+
+    /// Resets the usage we have in memory, after a restart the logged
+    /// requests count again
+    pub fn reset_usage(&self) {
+        let mut providers = self.providers.lock().expect("lock to not be poisoned");
+        providers
+            .values_mut()
+            .flat_map(|provider_credentials| provider_credentials.credentials.iter_mut())
+            .for_each(|state| {
+                state.tokens_used = 0;
+                state.cost = 0.0;
+            });
+    }
+
+    pub fn usage(&self) -> Vec<CredentialUsage> {
+        let providers = self.providers.lock().expect("lock to not be poisoned");
+        let now = Instant::now();
+        providers
+            .iter()
+            .flat_map(|(provider, provider_credentials)| {
+                provider_credentials
+                    .credentials
+                    .iter()
+                    .map(move |state| CredentialUsage {
+                        provider: provider.clone(),
+                        name: state.credential.name().to_owned(),
+                        tokens_used: state.tokens_used,
+                        cost: state
+                            .credential
+                            .price_per_million_tokens
+                            .map(|_| state.cost),
+                        period_start: state.period_start,
+                        spend_limit_tokens: state.credential.spend_limit_tokens(),
+                        rate_limited: state
+                            .rate_limited_until
+                            .is_some_and(|rate_limited_until| rate_limited_until > now),
+                    })
+            })
+            .collect()
+    }
+
+    /// The tokens we count towards the spend limit of the key, when we can not
+    /// tokenize we go with an estimate. Tokenizing runs on the blocking pool.
+    pub async fn count_tokens(
+        &self,
+        model: LLMType,
+        input: LLMTokenizerInput,
+        answer: String,
+    ) -> u64 {
+        let tokenizer = self.tokenizer.clone();
+        tokio::task::spawn_blocking(move || {
+            count_tokens(tokenizer.as_deref(), &model, input, &answer)
+        })
+        .await
+        .expect("token counting to not panic")
+    }
+
+    fn update(
+        &self,
+        provider: &LLMProvider,
+        name: &str,
+        update: impl FnOnce(&mut CredentialState),
+    ) {
+        let mut providers = self.providers.lock().expect("lock to not be poisoned");
+        if let Some(state) =
+            providers
+                .get_mut(&provider.client_provider())
+                .and_then(|provider_credentials| {
+                    provider_credentials
+                        .credentials
+                        .iter_mut()
+                        .find(|state| state.credential.name() == name)
+                })
+        {
+            update(state);
+        }
+    }
+}
+
+fn count_tokens(
+    tokenizer: Option<&LLMTokenizer>,
+    model: &LLMType,
+    input: LLMTokenizerInput,
+    answer: &str,
+) -> u64 {
+    let tokens = match tokenizer {
+        Some(tokenizer) => {
+            tokenizer.count_tokens_or_estimate(model, input).count()
+                + tokenizer
+                    .count_tokens_or_estimate(model, LLMTokenizerInput::Prompt(answer.to_owned()))
+                    .count()
+        }
+        None => {
+            let input_tokens = match &input {
+                LLMTokenizerInput::Prompt(prompt) => estimate_tokens(prompt),
+                LLMTokenizerInput::Messages(messages) => messages
+                    .iter()
+                    .map(|message| estimate_tokens(message.content()))
+                    .sum(),
+            };
+            input_tokens + estimate_tokens(answer)
+        }
+    };
+    tokens as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use futures::future::Either;
+
+    use std::time::Instant;
+
+    use chrono::NaiveDate;
+
+    use super::{
+        keyring::{save_credential, FileKeyring},
+        sources, Credential, CredentialState, CredentialStore, RotationStrategy, SpendLimitPeriod,
+    };
+    use crate::{
+        broker::LLMBroker,
+        clients::{
+            mock::{MockLLMClient, MockLLMError, MockResponse},
+            types::{LLMClientCompletionStringRequest, LLMClientError, LLMType},
+        },
+        config::LLMBrokerConfiguration,
+        provider::{LLMProvider, LLMProviderAPIKeys, TogetherAIProvider},
+    };
+
+    fn together_credential(name: &str) -> Credential {
+        Credential::new(
+            name.to_owned(),
+            LLMProviderAPIKeys::TogetherAI(TogetherAIProvider::new(format!("{name}_key"))),
+        )
+    }
+
+    fn candidate_names(store: &CredentialStore) -> Vec<String> {
+        store
+            .candidates(&LLMProvider::TogetherAI)
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect()
+    }
+
+    #[test]
+    fn test_rotation_and_limits() {
+        let store = CredentialStore::new(RotationStrategy::RoundRobin).add_credentials([
+            together_credential("first"),
+            together_credential("second").set_spend_limit_tokens(Some(10)),
+        ]);
+        assert_eq!(candidate_names(&store), vec!["first", "second"]);
+        assert_eq!(candidate_names(&store), vec!["second", "first"]);
+
+        store.record_usage(&LLMProvider::TogetherAI, "second", 10);
+        store.mark_rate_limited(&LLMProvider::TogetherAI, "first");
+        assert!(candidate_names(&store).is_empty());
+        store.reset_usage();
+        assert_eq!(candidate_names(&store), vec!["second"]);
+
+        let store = CredentialStore::new(RotationStrategy::Failover)
+            .add_credentials([together_credential("first"), together_credential("second")]);
+        assert_eq!(candidate_names(&store), vec!["first", "second"]);
+        assert_eq!(candidate_names(&store), vec!["first", "second"]);
+        // keyless providers work without any credentials
+        assert_eq!(store.candidates(&LLMProvider::Ollama).len(), 1);
+        assert!(store.candidates(&LLMProvider::OpenAI).is_empty());
+    }
+
+    #[test]
+    fn test_spend_limit_periods() {
+        let now = NaiveDate::from_ymd_opt(2024, 2, 29)
+            .and_then(|day| day.and_hms_opt(17, 30, 0))
+            .expect("valid time");
+        let start = |year, month, day| {
+            NaiveDate::from_ymd_opt(year, month, day).and_then(|day| day.and_hms_opt(0, 0, 0))
+        };
+        assert_eq!(Some(SpendLimitPeriod::Daily.start(now)), start(2024, 2, 29));
+        assert_eq!(
+            Some(SpendLimitPeriod::Monthly.start(now)),
+            start(2024, 2, 1)
+        );
+
+        // the usage from last month does not count anymore
+        let mut state =
+            CredentialState::new(together_credential("capped").set_spend_limit_tokens(Some(10)));
+        state.period_start = start(2024, 1, 1).expect("valid time");
+        state.tokens_used = 10;
+        assert!(!state.is_available(Instant::now()));
+        state.roll_over(now);
+        assert_eq!(state.tokens_used, 0);
+        assert!(state.is_available(Instant::now()));
+    }
+
+    #[test]
+    fn test_sources() {
+        let credentials = sources::from_env_vars([
+            ("OPENAI_API_KEY_WORK".to_owned(), "sk-work".to_owned()),
+            ("OPENAI_API_KEY".to_owned(), "sk-default".to_owned()),
+            ("AZURE_OPENAI_API_KEY".to_owned(), "azure".to_owned()),
+            ("OPENAI_API_KEY_".to_owned(), "ignored".to_owned()),
+            ("PATH".to_owned(), "/usr/bin".to_owned()),
+        ]);
+        // azure is skipped without the api base
+        assert_eq!(
+            credentials
+                .iter()
+                .map(|credential| (credential.name(), credential.provider()))
+                .collect::<Vec<_>>(),
+            vec![
+                ("default", LLMProvider::OpenAI),
+                ("work", LLMProvider::OpenAI)
+            ]
+        );
+
+        let dir = tempfile::tempdir().expect("dir to be created");
+        let dir = dir.path();
+        let keyring = FileKeyring::new(dir.join("keyring.json"));
+        save_credential(&keyring, &together_credential("personal")).expect("save to work");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let metadata = std::fs::metadata(keyring.path()).expect("keyring to exist");
+            assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+        }
+        let config = dir.join("credentials.json");
+        std::fs::write(
+            &config,
+            r#"{"credentials": [{"name": "work", "api_key": {"TogetherAI": {"api_key": "key"}}, "spend_limit_tokens": 100}], "keyring": ["personal"]}"#,
+        )
+        .expect("write to work");
+        let credentials = sources::from_config_file(&config, &keyring).expect("config to load");
+        assert_eq!(credentials[0].spend_limit_tokens(), Some(100));
+        assert_eq!(credentials[1].name(), "personal");
+        assert!(sources::from_config_file(&dir.join("missing.json"), &keyring).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_broker_fails_over_on_rate_limits() {
+        let data_dir = tempfile::tempdir().expect("data dir to be created");
+        // the first key we try gets rate limited
+        let client = MockLLMClient::new(LLMProvider::TogetherAI)
+            .push_response(MockResponse::Error(MockLLMError::RateLimited))
+            .set_default_response(MockResponse::answer("ok"));
+        let broker = LLMBroker::new(LLMBrokerConfiguration::new(data_dir.path().to_owned()))
+            .await
+            .expect("broker to startup")
+            .add_provider(LLMProvider::TogetherAI, Box::new(client.clone()))
+            .set_credentials(
+                CredentialStore::new(RotationStrategy::Failover)
+                    .add_credentials([together_credential("limited"), together_credential("ok")]),
+            );
+        let request = || {
+            Either::Right(LLMClientCompletionStringRequest::new(
+                LLMType::MistralInstruct,
+                "hello".to_owned(),
+                0.2,
+                None,
+            ))
+        };
+        let (sender, _receiver) = tokio::sync::mpsc::unbounded_channel();
+        let answer = broker
+            .stream_answer_with_credentials(
+                LLMProvider::TogetherAI,
+                request(),
+                HashMap::new(),
+                sender.clone(),
+            )
+            .await
+            .expect("to fail over");
+        assert_eq!(answer.answer(), "ok");
+        assert_eq!(client.prompts().len(), 2);
+        let usage = broker.credentials().usage();
+        let usage = |name: &str| {
+            usage
+                .iter()
+                .find(|usage| usage.name == name)
+                .cloned()
+                .expect("usage to exist")
+        };
+        assert!(usage("limited").rate_limited);
+        assert_eq!(usage("limited").tokens_used, 0);
+        assert!(usage("ok").tokens_used > 0);
+
+        let result = broker
+            .stream_answer_with_credentials(LLMProvider::OpenAI, request(), HashMap::new(), sender)
+            .await;
+        assert!(matches!(
+            result,
+            Err(LLMClientError::NoCredentialAvailable(LLMProvider::OpenAI))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_spend_limits_survive_a_new_store() {
+        let data_dir = tempfile::tempdir().expect("data dir to be created");
+        let client = MockLLMClient::new(LLMProvider::TogetherAI)
+            .set_default_response(MockResponse::answer("hi"));
+        let broker = || async {
+            LLMBroker::new(LLMBrokerConfiguration::new(data_dir.path().to_owned()))
+                .await
+                .expect("broker to startup")
+                .add_provider(LLMProvider::TogetherAI, Box::new(client.clone()))
+                .set_credentials(
+                    CredentialStore::new(RotationStrategy::Failover).add_credentials([
+                        together_credential("capped")
+                            .set_spend_limit_tokens(Some(2))
+                            .set_price_per_million_tokens(Some(1_000_000.0)),
+                        together_credential("other"),
+                    ]),
+                )
+        };
+        let request = || {
+            Either::Right(LLMClientCompletionStringRequest::new(
+                LLMType::MistralInstruct,
+                "hello there".to_owned(),
+                0.2,
+                None,
+            ))
+        };
+        let (sender, _receiver) = tokio::sync::mpsc::unbounded_channel();
+        let first_broker = broker().await;
+        first_broker
+            .stream_answer_with_credentials(
+                LLMProvider::TogetherAI,
+                request(),
+                HashMap::new(),
+                sender.clone(),
+            )
+            .await
+            .expect("to work");
+        // failover starts with the capped key
+        let first_usage = first_broker
+            .credentials()
+            .usage()
+            .into_iter()
+            .find(|usage| usage.name == "capped")
+            .expect("usage to exist");
+        assert!(first_usage.tokens_used > 0);
+        drop(first_broker);
+
+        // a new broker and store start at 0 in memory, the usage comes back
+        // from the log so the capped key stays over its limit
+        let second_broker = broker().await;
+        second_broker
+            .stream_answer_with_credentials(
+                LLMProvider::TogetherAI,
+                request(),
+                HashMap::new(),
+                sender,
+            )
+            .await
+            .expect("to work");
+        let usage = second_broker.credentials().usage();
+        let usage = |name: &str| {
+            usage
+                .iter()
+                .find(|usage| usage.name == name)
+                .cloned()
+                .expect("usage to exist")
+        };
+        // the tokens and the cost come back as they were logged
+        assert_eq!(usage("capped").tokens_used, first_usage.tokens_used);
+        assert_eq!(usage("capped").cost, Some(first_usage.tokens_used as f64));
+        assert!(usage("other").tokens_used > 0);
+        assert_eq!(client.prompts().len(), 2);
+    }
+}
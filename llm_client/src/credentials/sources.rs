@@ -0,0 +1,124 @@
+//! Loading the credentials from the env vars and the config file
+
+use std::path::Path;
+
+use crate::provider::{
+    AzureConfig, LLMProviderAPIKeys, LMStudioConfig, OpenAIProvider, TogetherAIProvider,
+};
+
+use super::{
+    keyring::{load_credential, Keyring},
+    Credential, CredentialError,
+};
+
+const OPENAI_API_KEY: &str = "OPENAI_API_KEY";
+const TOGETHER_API_KEY: &str = "TOGETHER_API_KEY";
+const LMSTUDIO_API_BASE: &str = "LMSTUDIO_API_BASE";
+const AZURE_OPENAI_API_KEY: &str = "AZURE_OPENAI_API_KEY";
+const AZURE_OPENAI_API_BASE: &str = "AZURE_OPENAI_API_BASE";
+const AZURE_OPENAI_API_VERSION: &str = "AZURE_OPENAI_API_VERSION";
+const AZURE_OPENAI_DEPLOYMENT_ID: &str = "AZURE_OPENAI_DEPLOYMENT_ID";
+const DEFAULT_AZURE_API_VERSION: &str = "2023-08-01-preview";
+const DEFAULT_CREDENTIAL_NAME: &str = "default";
+
+/// The name of the credential if the env var is `prefix` or `prefix_NAME`
+fn credential_name(var: &str, prefix: &str) -> Option<String> {
+    let suffix = var.strip_prefix(prefix)?;
+    if suffix.is_empty() {
+        Some(DEFAULT_CREDENTIAL_NAME.to_owned())
+    } else {
+        suffix
+            .strip_prefix('_')
+            .filter(|name| !name.is_empty())
+            .map(|name| name.to_lowercase())
+    }
+}
+
+/// Reads the credentials from the env vars, an optional suffix names the
+/// credential: `OPENAI_API_KEY_WORK` is the `work` key and `OPENAI_API_KEY`
+/// is the `default` one. Azure needs `AZURE_OPENAI_API_KEY` and
+/// `AZURE_OPENAI_API_BASE` with the same suffix.
+pub fn from_env_vars(vars: impl IntoIterator<Item = (String, String)>) -> Vec<Credential> {
+    let vars = vars.into_iter().collect::<Vec<_>>();
+    let azure_var = |prefix: &str, name: &str| {
+        vars.iter()
+            .find(|(var, _)| credential_name(var, prefix).as_deref() == Some(name))
+            .map(|(_, value)| value.to_owned())
+    };
+    let mut credentials = vars
+        .iter()
+        .filter_map(|(var, value)| {
+            if let Some(name) = credential_name(var, OPENAI_API_KEY) {
+                Some(Credential::new(
+                    name,
+                    LLMProviderAPIKeys::OpenAI(OpenAIProvider {
+                        api_key: value.to_owned(),
+                    }),
+                ))
+            } else if let Some(name) = credential_name(var, TOGETHER_API_KEY) {
+                Some(Credential::new(
+                    name,
+                    LLMProviderAPIKeys::TogetherAI(TogetherAIProvider::new(value.to_owned())),
+                ))
+            } else if let Some(name) = credential_name(var, LMSTUDIO_API_BASE) {
+                Some(Credential::new(
+                    name,
+                    LLMProviderAPIKeys::LMStudio(LMStudioConfig {
+                        api_base: value.to_owned(),
+                    }),
+                ))
+            } else if let Some(name) = credential_name(var, AZURE_OPENAI_API_KEY) {
+                let api_base = azure_var(AZURE_OPENAI_API_BASE, &name)?;
+                Some(Credential::new(
+                    name.to_owned(),
+                    LLMProviderAPIKeys::OpenAIAzureConfig(AzureConfig {
+                        deployment_id: azure_var(AZURE_OPENAI_DEPLOYMENT_ID, &name)
+                            .unwrap_or_default(),
+                        api_base,
+                        api_key: value.to_owned(),
+                        api_version: azure_var(AZURE_OPENAI_API_VERSION, &name)
+                            .unwrap_or(DEFAULT_AZURE_API_VERSION.to_owned()),
+                    }),
+                ))
+            } else {
+                None
+            }
+        })
+        .collect::<Vec<_>>();
+    // keep the order stable so the default key comes first for failover
+    credentials.sort_by(|first, second| {
+        (first.name() != DEFAULT_CREDENTIAL_NAME, first.name())
+            .cmp(&(second.name() != DEFAULT_CREDENTIAL_NAME, second.name()))
+    });
+    credentials
+}
+
+pub fn from_env() -> Vec<Credential> {
+    from_env_vars(std::env::vars())
+}
+
+#[derive(serde::Deserialize)]
+struct CredentialsFile {
+    #[serde(default)]
+    credentials: Vec<Credential>,
+    /// The names of the credentials which are kept in the keyring
+    #[serde(default)]
+    keyring: Vec<String>,
+}
+
+/// Reads the credentials from the config file, which looks like:
+/// {
+///     "credentials": [{"name": "work", "api_key": {"OpenAI": {"api_key": "..."}}, "spend_limit_tokens": 1000000}],
+///     "keyring": ["personal"]
+/// }
+pub fn from_config_file(
+    path: &Path,
+    keyring: &dyn Keyring,
+) -> Result<Vec<Credential>, CredentialError> {
+    let file = serde_json::from_str::<CredentialsFile>(&std::fs::read_to_string(path)?)?;
+    let mut credentials = file.credentials;
+    for name in file.keyring.iter() {
+        credentials.push(load_credential(keyring, name)?);
+    }
+    Ok(credentials)
+}
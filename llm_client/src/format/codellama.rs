@@ -0,0 +1,89 @@
+use crate::clients::types::LLMClientMessage;
+
+use super::types::{FillInMiddleFormat, LLMFormatting};
+
+#[derive(Default)]
+pub struct CodeLlamaFormatting {}
+
+impl CodeLlamaFormatting {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
+
+impl LLMFormatting for CodeLlamaFormatting {
+    fn to_prompt(&self, messages: Vec<LLMClientMessage>) -> String {
+        // codellama instruct follows the llama 2 chat format
+        // present here: https://huggingface.co/codellama/CodeLlama-13b-Instruct-hf/blob/main/tokenizer_config.json
+        // {{ bos_token }}[INST] {{ user }} [/INST] {{ assistant }} {{ eos_token }}
+        // we do not fold the system prompt into the first user message with
+        // <<SYS>>, it goes as its own instruction like we do for mistral
+        let formatted_message = messages
+            .iter()
+            .skip_while(|message| message.role().is_assistant())
+            .map(|message| self.format_message(message))
+            .collect::<Vec<_>>()
+            .join("");
+        format!("<s>{formatted_message}")
+    }
+
+    fn format_message(&self, message: &LLMClientMessage) -> String {
+        let content = message.content();
+        if let Some(function_return) = message.get_function_return() {
+            let function_return =
+                serde_json::to_string(function_return).expect("serde deserialize to not fail");
+            format!("[INST] {function_return} [/INST]")
+        } else if message.role().is_assistant() {
+            match message.get_function_call() {
+                Some(function_call) => {
+                    let function_call = serde_json::to_string(function_call)
+                        .expect("serde deserialize to not fail");
+                    format!(" {content}{function_call} </s>")
+                }
+                None => format!(" {content} </s>"),
+            }
+        } else {
+            format!("[INST] {content} [/INST]")
+        }
+    }
+
+    fn bos_token(&self) -> Option<&str> {
+        Some("<s>")
+    }
+
+    fn stop_sequences(&self) -> Vec<String> {
+        vec!["</s>".to_owned(), "<EOT>".to_owned(), "[INST]".to_owned()]
+    }
+
+    fn special_tokens(&self) -> Vec<String> {
+        [
+            "<s>", "</s>", "<unk>", "[INST]", "[/INST]", "<PRE>", "<SUF>", "<MID>", "<EOT>",
+        ]
+        .into_iter()
+        .map(|special_token| special_token.to_owned())
+        .collect()
+    }
+
+    fn fill_in_middle(&self) -> Option<FillInMiddleFormat> {
+        // the spaces are part of the format codellama was trained on
+        // https://github.com/facebookresearch/codellama/blob/main/llama/generation.py
+        Some(FillInMiddleFormat::new("<PRE> ", " <SUF>", " <MID>"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CodeLlamaFormatting;
+    use crate::format::types::LLMFormatting;
+
+    #[test]
+    fn test_fill_in_middle_prompt() {
+        let fill_in_middle = CodeLlamaFormatting::new()
+            .fill_in_middle()
+            .expect("codellama to support fim");
+        assert_eq!(
+            fill_in_middle.to_prompt("fn add(a: i32, b: i32) -> i32 {\n", "\n}"),
+            "<PRE> fn add(a: i32, b: i32) -> i32 {\n <SUF>\n} <MID>"
+        );
+    }
+}
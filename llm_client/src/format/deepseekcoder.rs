@@ -1,6 +1,6 @@
 use crate::clients::types::LLMClientMessage;
 
-use super::types::LLMFormatting;
+use super::types::{FillInMiddleFormat, LLMFormatting};
 
 pub struct DeepSeekCoderFormatting {}
 
@@ -40,18 +40,61 @@ impl LLMFormatting for DeepSeekCoderFormatting {
         // {% if add_generation_prompt %}
         // {{'### Response:'}}
         // {% endif %}
-        let formatted_message = messages.into_iter().skip_while(|message| message.role().is_assistant())
-        .map(|message| {
-            let content = message.content();
-            if message.role().is_system() {
-                "You are an AI programming assistant, utilizing the Deepseek Coder model, developed by Deepseek Company, and you only answer questions related to computer science. For politically sensitive questions, security and privacy issues, and other non-computer science questions, you will refuse to answer\n".to_owned()
-            } else if message.role().is_user() {
-                format!("### Instruction:\n{}\n", content)
-            } else {
-                format!("### Response:\n{}\n<|EOT|>\n", content)
-            }
-        }).collect::<Vec<_>>().join("");
-        formatted_message
+        messages
+            .iter()
+            .skip_while(|message| message.role().is_assistant())
+            .map(|message| self.format_message(message))
+            .collect::<Vec<_>>()
+            .join("")
+    }
+
+    fn format_message(&self, message: &LLMClientMessage) -> String {
+        let content = message.content();
+        if message.role().is_system() {
+            "You are an AI programming assistant, utilizing the Deepseek Coder model, developed by Deepseek Company, and you only answer questions related to computer science. For politically sensitive questions, security and privacy issues, and other non-computer science questions, you will refuse to answer\n".to_owned()
+        } else if message.role().is_user() {
+            format!("### Instruction:\n{}\n", content)
+        } else if let Some(function_return) = message.get_function_return() {
+            // the function return goes back to the llm as an instruction
+            let function_return =
+                serde_json::to_string(function_return).expect("serde deserialize to not fail");
+            format!("### Instruction:\n{}\n", function_return)
+        } else if let Some(function_call) = message.get_function_call() {
+            let function_call =
+                serde_json::to_string(function_call).expect("serde deserialize to not fail");
+            format!("### Response:\n{}{}\n<|EOT|>\n", content, function_call)
+        } else {
+            format!("### Response:\n{}\n<|EOT|>\n", content)
+        }
+    }
+
+    fn stop_sequences(&self) -> Vec<String> {
+        vec![
+            "<|EOT|>".to_owned(),
+            "<｜end▁of▁sentence｜>".to_owned(),
+            "### Instruction:".to_owned(),
+        ]
+    }
+
+    fn special_tokens(&self) -> Vec<String> {
+        // from the tokenizer_config.json of deepseek coder instruct
+        vec![
+            "<｜begin▁of▁sentence｜>".to_owned(),
+            "<｜end▁of▁sentence｜>".to_owned(),
+            "<|EOT|>".to_owned(),
+            "<｜fim▁begin｜>".to_owned(),
+            "<｜fim▁hole｜>".to_owned(),
+            "<｜fim▁end｜>".to_owned(),
+        ]
+    }
+
+    fn fill_in_middle(&self) -> Option<FillInMiddleFormat> {
+        // https://github.com/deepseek-ai/deepseek-coder#2-code-insertion
+        Some(FillInMiddleFormat::new(
+            "<｜fim▁begin｜>",
+            "<｜fim▁hole｜>",
+            "<｜fim▁end｜>",
+        ))
     }
 }
 
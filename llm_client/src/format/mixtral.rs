@@ -37,44 +37,71 @@ impl LLMFormatting for MixtralInstructFormatting {
         // if the message is about a function return: then we proxy that as a user message
         // if the message is about a function call, then we keep it as an assistant message and push the json
         let formatted_message = messages
-            .into_iter()
+            .iter()
             .skip_while(|message| message.role().is_assistant())
-            .map(|message| {
-                let content = message.content();
-                let eos_token = self.tokenizer_config.eos_token();
-                if message.role().is_system() || message.role().is_user() {
-                    format!("[INST] {content} [/INST]")
-                } else if message.role().is_function() {
-                    // This will be formatted as a function call as well
-                    match message.get_function_call() {
-                        Some(function_call) => {
-                            let function_call = serde_json::to_string(function_call)
-                                .expect("serde deserialize to not fail");
-                            format!("[INST] {function_call} [/INST]")
-                        }
-                        None => {
-                            // not entirely correct, we will make it better with more testing
-                            format!("[INST] {content} [/INST]")
-                        }
-                    }
-                } else {
-                    // we are in an assistant message now, so we can have a function
-                    // call which we have to format
-                    match message.get_function_call() {
-                        Some(function_call) => {
-                            let function_call = serde_json::to_string(function_call)
-                                .expect("serde deserialize to not fail");
-                            format!("{content}{function_call}{eos_token}")
-                        }
-                        None => {
-                            format!("{content}{eos_token}")
-                        }
-                    }
-                }
-            })
+            .map(|message| self.format_message(message))
             .collect::<Vec<_>>()
             .join("");
-        format!("<s>{formatted_message}")
+        match self.bos_token() {
+            Some(bos_token) => format!("{bos_token}{formatted_message}"),
+            None => formatted_message,
+        }
+    }
+
+    fn format_message(&self, message: &LLMClientMessage) -> String {
+        let content = message.content();
+        let eos_token = self.tokenizer_config.eos_token();
+        if message.role().is_system() || message.role().is_user() {
+            format!("[INST] {content} [/INST]")
+        } else if message.role().is_function() {
+            // the function return goes back to the llm as a user message
+            match message.get_function_return() {
+                Some(function_return) => {
+                    let function_return = serde_json::to_string(function_return)
+                        .expect("serde deserialize to not fail");
+                    format!("[INST] {function_return} [/INST]")
+                }
+                None => {
+                    // not entirely correct, we will make it better with more testing
+                    format!("[INST] {content} [/INST]")
+                }
+            }
+        } else {
+            // we are in an assistant message now, so we can have a function
+            // call which we have to format
+            match message.get_function_call() {
+                Some(function_call) => {
+                    let function_call = serde_json::to_string(function_call)
+                        .expect("serde deserialize to not fail");
+                    format!("{content}{function_call}{eos_token}")
+                }
+                None => {
+                    format!("{content}{eos_token}")
+                }
+            }
+        }
+    }
+
+    fn bos_token(&self) -> Option<&str> {
+        if self.tokenizer_config.add_bos_token() {
+            Some(self.tokenizer_config.bos_token())
+        } else {
+            None
+        }
+    }
+
+    fn stop_sequences(&self) -> Vec<String> {
+        // the end of the turn, or the model carrying on with the next one
+        vec![
+            self.tokenizer_config.eos_token().to_owned(),
+            "[INST]".to_owned(),
+        ]
+    }
+
+    fn special_tokens(&self) -> Vec<String> {
+        let mut special_tokens = self.tokenizer_config.special_tokens();
+        special_tokens.extend(["[INST]".to_owned(), "[/INST]".to_owned()]);
+        special_tokens
     }
 }
 
@@ -1,6 +1,40 @@
 //! Crate for formatting prompts for different llms
 
+pub mod codellama;
 pub mod deepseekcoder;
 pub mod mistral;
 pub mod mixtral;
+pub mod starcoder;
 pub mod types;
+
+use crate::clients::types::LLMType;
+
+use self::{
+    codellama::CodeLlamaFormatting,
+    deepseekcoder::DeepSeekCoderFormatting,
+    mistral::MistralInstructFormatting,
+    mixtral::MixtralInstructFormatting,
+    starcoder::StarCoderFormatting,
+    types::{FillInMiddleFormat, LLMFormatting},
+};
+
+/// The prompt format of the model, `None` when we send the prompt as is
+pub fn formatting_for_model(model: &LLMType) -> Option<Box<dyn LLMFormatting + Send + Sync>> {
+    match model {
+        LLMType::Mixtral => MixtralInstructFormatting::new()
+            .ok()
+            .map(|formatting| Box::new(formatting) as Box<dyn LLMFormatting + Send + Sync>),
+        LLMType::MistralInstruct => MistralInstructFormatting::new()
+            .ok()
+            .map(|formatting| Box::new(formatting) as Box<dyn LLMFormatting + Send + Sync>),
+        LLMType::DeepSeekCoder => Some(Box::new(DeepSeekCoderFormatting::new())),
+        LLMType::CodeLlama => Some(Box::new(CodeLlamaFormatting::new())),
+        LLMType::StarCoder => Some(Box::new(StarCoderFormatting::new())),
+        _ => None,
+    }
+}
+
+/// The fill in the middle sentinels of the model, if it was trained for it
+pub fn fill_in_middle_format(model: &LLMType) -> Option<FillInMiddleFormat> {
+    formatting_for_model(model).and_then(|formatting| formatting.fill_in_middle())
+}
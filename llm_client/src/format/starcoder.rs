@@ -0,0 +1,69 @@
+use crate::clients::types::LLMClientMessage;
+
+use super::types::{FillInMiddleFormat, LLMFormatting};
+
+/// StarCoder is a base model, it has no chat template so the messages go in as
+/// they are one after the other
+#[derive(Default)]
+pub struct StarCoderFormatting {}
+
+impl StarCoderFormatting {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
+
+impl LLMFormatting for StarCoderFormatting {
+    fn to_prompt(&self, messages: Vec<LLMClientMessage>) -> String {
+        messages
+            .iter()
+            .skip_while(|message| message.role().is_assistant())
+            .map(|message| self.format_message(message))
+            .collect::<Vec<_>>()
+            .join("")
+    }
+
+    fn format_message(&self, message: &LLMClientMessage) -> String {
+        let content = match (message.get_function_call(), message.get_function_return()) {
+            (Some(function_call), _) => format!(
+                "{}{}",
+                message.content(),
+                serde_json::to_string(function_call).expect("serde deserialize to not fail")
+            ),
+            (_, Some(function_return)) => {
+                serde_json::to_string(function_return).expect("serde deserialize to not fail")
+            }
+            _ => message.content().to_owned(),
+        };
+        format!("{content}\n")
+    }
+
+    fn stop_sequences(&self) -> Vec<String> {
+        vec!["<|endoftext|>".to_owned(), "<file_sep>".to_owned()]
+    }
+
+    fn special_tokens(&self) -> Vec<String> {
+        // from the tokenizer_config.json of bigcode/starcoder
+        [
+            "<|endoftext|>",
+            "<fim_prefix>",
+            "<fim_middle>",
+            "<fim_suffix>",
+            "<fim_pad>",
+            "<filename>",
+            "<gh_stars>",
+            "<file_sep>",
+        ]
+        .into_iter()
+        .map(|special_token| special_token.to_owned())
+        .collect()
+    }
+
+    fn fill_in_middle(&self) -> Option<FillInMiddleFormat> {
+        Some(FillInMiddleFormat::new(
+            "<fim_prefix>",
+            "<fim_suffix>",
+            "<fim_middle>",
+        ))
+    }
+}
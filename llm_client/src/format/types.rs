@@ -6,6 +6,64 @@ use crate::clients::types::LLMClientMessage;
 
 pub trait LLMFormatting {
     fn to_prompt(&self, messages: Vec<LLMClientMessage>) -> String;
+
+    /// How a single message shows up in the prompt, including the role markers
+    /// and the function call or return payload
+    fn format_message(&self, message: &LLMClientMessage) -> String;
+
+    /// The token the prompt starts with, `to_prompt` is this followed by the
+    /// formatted messages
+    fn bos_token(&self) -> Option<&str> {
+        None
+    }
+
+    /// Where the generation should end, the raw completion endpoints happily
+    /// keep going past the end of the turn
+    fn stop_sequences(&self) -> Vec<String> {
+        vec![]
+    }
+
+    /// The tokens of the template which should never show up in the answer
+    fn special_tokens(&self) -> Vec<String> {
+        vec![]
+    }
+
+    /// The sentinel tokens for fill in the middle, `None` when the model was
+    /// not trained for it
+    fn fill_in_middle(&self) -> Option<FillInMiddleFormat> {
+        None
+    }
+}
+
+/// The sentinels we wrap the prefix and suffix in, the model generates the
+/// middle after `middle_token`. All the models we support go prefix, suffix
+/// and then middle.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FillInMiddleFormat {
+    prefix_token: &'static str,
+    suffix_token: &'static str,
+    middle_token: &'static str,
+}
+
+impl FillInMiddleFormat {
+    pub fn new(
+        prefix_token: &'static str,
+        suffix_token: &'static str,
+        middle_token: &'static str,
+    ) -> Self {
+        Self {
+            prefix_token,
+            suffix_token,
+            middle_token,
+        }
+    }
+
+    pub fn to_prompt(&self, prefix: &str, suffix: &str) -> String {
+        format!(
+            "{}{prefix}{}{suffix}{}",
+            self.prefix_token, self.suffix_token, self.middle_token
+        )
+    }
 }
 
 pub struct DummyLLMFormatting {}
@@ -17,6 +75,10 @@ impl DummyLLMFormatting {
 }
 
 impl LLMFormatting for DummyLLMFormatting {
+    fn format_message(&self, message: &LLMClientMessage) -> String {
+        message.content().to_owned()
+    }
+
     fn to_prompt(&self, messages: Vec<LLMClientMessage>) -> String {
         messages
             .into_iter()
@@ -66,6 +128,20 @@ impl TokenizerConfig {
     pub fn chat_template(&self) -> &str {
         &self.chat_template
     }
+
+    /// The special tokens of the vocabulary along with the additional ones
+    pub fn special_tokens(&self) -> Vec<String> {
+        let mut special_tokens = self
+            .added_tokens_decoder
+            .values()
+            .filter(|added_token| added_token.special)
+            .map(|added_token| added_token.content.to_owned())
+            .chain(self.additional_special_tokens.iter().cloned())
+            .collect::<Vec<_>>();
+        special_tokens.sort();
+        special_tokens.dedup();
+        special_tokens
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
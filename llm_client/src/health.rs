@@ -0,0 +1,127 @@
+//! Which providers are reachable and which models they are serving, so the
+//! editor can point out a misconfiguration before the first request fails
+
+use std::time::Duration;
+
+use crate::{
+    clients::types::{LLMClientCapabilities, LLMClientModel, LLMType},
+    provider::LLMProvider,
+};
+
+/// How long we wait on a provider before we call it unreachable
+pub const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct LLMModelAvailability {
+    model: LLMClientModel,
+    /// Only known for the models we can send requests to
+    capabilities: Option<LLMClientCapabilities>,
+}
+
+impl LLMModelAvailability {
+    pub fn new(model: LLMClientModel, capabilities: Option<LLMClientCapabilities>) -> Self {
+        Self {
+            model,
+            capabilities,
+        }
+    }
+
+    pub fn model(&self) -> &LLMClientModel {
+        &self.model
+    }
+
+    pub fn capabilities(&self) -> Option<&LLMClientCapabilities> {
+        self.capabilities.as_ref()
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub enum LLMProviderStatus {
+    Available(Vec<LLMModelAvailability>),
+    /// We did not get an api key for the provider so we did not check it
+    MissingAPIKey,
+    Unreachable(String),
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct LLMProviderHealth {
+    provider: LLMProvider,
+    status: LLMProviderStatus,
+    latency_ms: Option<u64>,
+}
+
+impl LLMProviderHealth {
+    pub fn new(
+        provider: LLMProvider,
+        status: LLMProviderStatus,
+        latency: Option<Duration>,
+    ) -> Self {
+        Self {
+            provider,
+            status,
+            latency_ms: latency.map(|latency| latency.as_millis() as u64),
+        }
+    }
+
+    pub fn provider(&self) -> &LLMProvider {
+        &self.provider
+    }
+
+    pub fn status(&self) -> &LLMProviderStatus {
+        &self.status
+    }
+
+    pub fn latency_ms(&self) -> Option<u64> {
+        self.latency_ms
+    }
+
+    pub fn is_available(&self) -> bool {
+        matches!(self.status, LLMProviderStatus::Available(_))
+    }
+
+    /// The models the provider is serving, empty when it's not available
+    pub fn models(&self) -> &[LLMModelAvailability] {
+        match &self.status {
+            LLMProviderStatus::Available(models) => models.as_slice(),
+            _ => &[],
+        }
+    }
+
+    pub fn serves(&self, llm_type: &LLMType) -> bool {
+        self.models()
+            .iter()
+            .any(|model| model.model().llm_type() == Some(llm_type))
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct LLMBrokerHealthReport {
+    providers: Vec<LLMProviderHealth>,
+}
+
+impl LLMBrokerHealthReport {
+    pub fn new(mut providers: Vec<LLMProviderHealth>) -> Self {
+        // keep the order stable for displaying the report
+        providers.sort_by_key(|health| format!("{:?}", health.provider()));
+        Self { providers }
+    }
+
+    pub fn providers(&self) -> &[LLMProviderHealth] {
+        self.providers.as_slice()
+    }
+
+    pub fn provider(&self, provider: &LLMProvider) -> Option<&LLMProviderHealth> {
+        self.providers
+            .iter()
+            .find(|health| health.provider() == provider)
+    }
+
+    /// The providers which are serving the model right now
+    pub fn providers_serving(&self, llm_type: &LLMType) -> Vec<&LLMProvider> {
+        self.providers
+            .iter()
+            .filter(|health| health.serves(llm_type))
+            .map(|health| health.provider())
+            .collect()
+    }
+}
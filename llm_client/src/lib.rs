@@ -1,7 +1,12 @@
 pub mod broker;
 pub mod clients;
 pub mod config;
+pub mod credentials;
 pub mod format;
+pub mod health;
+pub mod llm_data;
 pub mod provider;
+pub mod rate_limit;
+pub mod redaction;
 mod sqlite;
 pub mod tokenizer;
@@ -0,0 +1,200 @@
+//! Exports the rows from `llm_data`, either as they are in JSONL or CSV for
+//! inspecting them, or as datasets for fine-tuning. For fine-tuning we support
+//! the chat format which OpenAI expects and raw prompt/completion pairs which
+//! work for training the open models.
+
+use std::io::Write;
+
+use serde_json::json;
+
+use crate::{
+    clients::types::{LLMClientError, LLMClientMessage, LLMClientRole},
+    tokenizer::tokenizer::LLMTokenizer,
+};
+
+use super::LLMDataRow;
+
+fn role_str(role: &LLMClientRole) -> &'static str {
+    match role {
+        LLMClientRole::System => "system",
+        LLMClientRole::User => "user",
+        LLMClientRole::Assistant => "assistant",
+        LLMClientRole::Function => "function",
+    }
+}
+
+fn chat_message_json(message: &LLMClientMessage) -> serde_json::Value {
+    if let Some(function_call) = message.get_function_call() {
+        json!({
+            "role": "assistant",
+            "function_call": {
+                "name": function_call.name(),
+                "arguments": function_call.arguments(),
+            },
+        })
+    } else if let Some(function_return) = message.get_function_return() {
+        json!({
+            "role": "function",
+            "name": function_return.name(),
+            "content": function_return.content(),
+        })
+    } else {
+        json!({
+            "role": role_str(message.role()),
+            "content": message.content(),
+        })
+    }
+}
+
+/// The messages for the row in the OpenAI fine-tune format, string completions
+/// are sent as a single user message
+pub fn chat_format(row: &LLMDataRow) -> Option<serde_json::Value> {
+    let output = row.training_output()?;
+    let mut messages = match (row.chat_messages(), row.prompt()) {
+        (Some(chat_messages), _) => chat_messages.iter().map(chat_message_json).collect(),
+        (None, Some(prompt)) => vec![json!({"role": "user", "content": prompt})],
+        (None, None) => return None,
+    };
+    messages.push(json!({"role": "assistant", "content": output}));
+    Some(json!({ "messages": messages }))
+}
+
+/// The row as a prompt/completion pair, chat rows are converted to a prompt
+/// using the formatter of the model they were sent to. Rows for models which
+/// do not have a formatter (like the OpenAI ones) are skipped.
+pub fn prompt_completion_format(
+    row: &LLMDataRow,
+    tokenizer: &LLMTokenizer,
+) -> Option<serde_json::Value> {
+    let completion = row.training_output()?;
+    let prompt = match (row.chat_messages(), row.prompt()) {
+        (Some(chat_messages), _) => {
+            let llm_type = row.llm_type()?;
+            tokenizer
+                .formatters
+                .get(&llm_type)?
+                .to_prompt(chat_messages)
+        }
+        (None, Some(prompt)) => prompt.to_owned(),
+        (None, None) => return None,
+    };
+    Some(json!({
+        "prompt": prompt,
+        "completion": completion,
+    }))
+}
+
+/// Writes the rows as chat format JSONL, returns the number of rows written
+pub fn write_chat_jsonl(
+    rows: &[LLMDataRow],
+    writer: &mut impl Write,
+) -> Result<usize, LLMClientError> {
+    let mut written = 0;
+    for value in rows.iter().filter_map(chat_format) {
+        writeln!(writer, "{}", serde_json::to_string(&value)?)?;
+        written += 1;
+    }
+    Ok(written)
+}
+
+/// Writes the rows as prompt/completion JSONL, returns the number of rows written
+pub fn write_prompt_completion_jsonl(
+    rows: &[LLMDataRow],
+    tokenizer: &LLMTokenizer,
+    writer: &mut impl Write,
+) -> Result<usize, LLMClientError> {
+    let mut written = 0;
+    for value in rows
+        .iter()
+        .filter_map(|row| prompt_completion_format(row, tokenizer))
+    {
+        writeln!(writer, "{}", serde_json::to_string(&value)?)?;
+        written += 1;
+    }
+    Ok(written)
+}
+
+/// The row with all of its columns, the JSON columns are inlined
+pub fn row_json(row: &LLMDataRow) -> serde_json::Value {
+    let parse_json = |content: Option<&str>| {
+        content
+            .map(|content| serde_json::from_str(content).unwrap_or(json!(content)))
+            .unwrap_or(serde_json::Value::Null)
+    };
+    json!({
+        "id": row.id(),
+        "created_at": row.created_at().to_string(),
+        "provider": row.provider(),
+        "llm_type": row.llm_type(),
+        "event_type": row.event_type(),
+        "metadata": row.metadata(),
+        "temperature": row.temperature(),
+        "prompt": row.prompt(),
+        "chat_messages": parse_json(row.chat_messages_raw()),
+        "response": row.response(),
+        "rating": row.rating(),
+        "accepted": row.accepted(),
+        "corrected_output": row.corrected_output(),
+    })
+}
+
+/// Writes the rows as JSONL, returns the number of rows written
+pub fn write_jsonl(rows: &[LLMDataRow], writer: &mut impl Write) -> Result<usize, LLMClientError> {
+    for row in rows {
+        writeln!(writer, "{}", serde_json::to_string(&row_json(row))?)?;
+    }
+    Ok(rows.len())
+}
+
+const CSV_HEADER: &[&str] = &[
+    "id",
+    "created_at",
+    "provider",
+    "llm_type",
+    "event_type",
+    "temperature",
+    "prompt",
+    "chat_messages",
+    "response",
+    "rating",
+    "accepted",
+    "corrected_output",
+];
+
+/// Quotes the field when it has a delimiter, quote or a new line (RFC 4180)
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+/// Writes the rows as CSV with a header, returns the number of rows written
+pub fn write_csv(rows: &[LLMDataRow], writer: &mut impl Write) -> Result<usize, LLMClientError> {
+    writeln!(writer, "{}", CSV_HEADER.join(","))?;
+    for row in rows {
+        let to_string = |value: Option<String>| value.unwrap_or_default();
+        let fields = [
+            row.id().to_string(),
+            row.created_at().to_string(),
+            to_string(row.provider().map(|provider| format!("{provider:?}"))),
+            to_string(row.llm_type().map(|llm_type| llm_type.to_string())),
+            to_string(row.event_type()),
+            to_string(row.temperature().map(|temperature| temperature.to_string())),
+            to_string(row.prompt().map(|prompt| prompt.to_owned())),
+            to_string(row.chat_messages_raw().map(|messages| messages.to_owned())),
+            to_string(row.response().map(|response| response.to_owned())),
+            to_string(row.rating().map(|rating| rating.to_string())),
+            to_string(row.accepted().map(|accepted| accepted.to_string())),
+            to_string(row.corrected_output().map(|output| output.to_owned())),
+        ];
+        let line = fields
+            .iter()
+            .map(|field| csv_field(field))
+            .collect::<Vec<_>>()
+            .join(",");
+        writeln!(writer, "{line}")?;
+    }
+    Ok(rows.len())
+}
@@ -0,0 +1,78 @@
+use sqlx::SqlitePool;
+
+use crate::clients::types::LLMClientError;
+
+/// The feedback from the user on an answer we logged, only the fields which
+/// are set get updated so feedback can be attached in multiple steps
+#[derive(Debug, Clone, Default)]
+pub struct LLMFeedback {
+    rating: Option<i64>,
+    accepted: Option<bool>,
+    corrected_output: Option<String>,
+}
+
+impl LLMFeedback {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set_rating(mut self, rating: i64) -> Self {
+        self.rating = Some(rating);
+        self
+    }
+
+    /// If the user accepted the inline edit or found the snippet useful
+    pub fn set_accepted(mut self, accepted: bool) -> Self {
+        self.accepted = Some(accepted);
+        self
+    }
+
+    /// What the output should have been, when the user edited the answer
+    /// before accepting it
+    pub fn set_corrected_output(mut self, corrected_output: String) -> Self {
+        self.corrected_output = Some(corrected_output);
+        self
+    }
+
+    pub fn rating(&self) -> Option<i64> {
+        self.rating
+    }
+
+    pub fn accepted(&self) -> Option<bool> {
+        self.accepted
+    }
+
+    pub fn corrected_output(&self) -> Option<&str> {
+        self.corrected_output.as_deref()
+    }
+}
+
+pub async fn record_feedback(
+    db: &SqlitePool,
+    llm_data_id: i64,
+    feedback: &LLMFeedback,
+) -> Result<(), LLMClientError> {
+    let rating = feedback.rating();
+    let accepted = feedback.accepted();
+    let corrected_output = feedback.corrected_output();
+    let result = sqlx::query! {
+        r#"
+        UPDATE llm_data
+        SET rating = COALESCE($1, rating),
+            accepted = COALESCE($2, accepted),
+            corrected_output = COALESCE($3, corrected_output),
+            feedback_at = CURRENT_TIMESTAMP
+        WHERE id = $4
+        "#,
+        rating,
+        accepted,
+        corrected_output,
+        llm_data_id,
+    }
+    .execute(db)
+    .await?;
+    if result.rows_affected() == 0 {
+        return Err(LLMClientError::LLMDataRowNotFound(llm_data_id));
+    }
+    Ok(())
+}
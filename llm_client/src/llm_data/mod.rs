@@ -0,0 +1,525 @@
+//! Everything we log in the `llm_data` table, along with the feedback from the
+//! user on the answers. We use this to build datasets for fine-tuning and to
+//! inspect what we sent to the LLMs.
+
+pub mod export;
+pub mod feedback;
+pub mod retention;
+pub mod usage;
+
+use std::collections::HashMap;
+
+use sqlx::{types::chrono::NaiveDateTime, SqlitePool};
+
+use crate::{
+    clients::types::{LLMClientError, LLMClientMessage, LLMType},
+    config::LLMBrokerConfiguration,
+    provider::LLMProvider,
+    redaction::Redactor,
+    sqlite,
+};
+
+/// Opens the `llm_data` DB from the data directory without starting the broker,
+/// this is used by the tooling which works on the log. Unlike the broker we
+/// never move a DB which fails to open out of the way, we return the error.
+pub async fn connect(config: LLMBrokerConfiguration) -> Result<SqlitePool, LLMClientError> {
+    sqlite::open(&config).await
+}
+
+/// A single row from the `llm_data` table
+#[derive(Debug, Clone)]
+pub struct LLMDataRow {
+    id: i64,
+    created_at: NaiveDateTime,
+    prompt: Option<String>,
+    chat_messages: Option<String>,
+    response: Option<String>,
+    llm_type: Option<String>,
+    temperature: Option<f64>,
+    provider: Option<String>,
+    /// The metadata map we got from the caller, this is stored in the
+    /// `event_type` column as JSON
+    metadata: Option<String>,
+    rating: Option<i64>,
+    accepted: Option<bool>,
+    corrected_output: Option<String>,
+}
+
+impl LLMDataRow {
+    pub fn id(&self) -> i64 {
+        self.id
+    }
+
+    pub fn created_at(&self) -> &NaiveDateTime {
+        &self.created_at
+    }
+
+    pub fn prompt(&self) -> Option<&str> {
+        self.prompt.as_deref()
+    }
+
+    pub fn chat_messages_raw(&self) -> Option<&str> {
+        self.chat_messages.as_deref()
+    }
+
+    /// The messages we sent for chat completions, `None` for string completions
+    pub fn chat_messages(&self) -> Option<Vec<LLMClientMessage>> {
+        self.chat_messages
+            .as_ref()
+            .and_then(|chat_messages| serde_json::from_str(chat_messages).ok())
+    }
+
+    pub fn response(&self) -> Option<&str> {
+        self.response.as_deref()
+    }
+
+    pub fn llm_type(&self) -> Option<LLMType> {
+        self.llm_type
+            .as_ref()
+            .and_then(|llm_type| serde_json::from_str(llm_type).ok())
+    }
+
+    pub fn temperature(&self) -> Option<f64> {
+        self.temperature
+    }
+
+    /// The provider we sent the request to, rows logged before we started
+    /// storing the provider do not have it
+    pub fn provider(&self) -> Option<LLMProvider> {
+        self.provider
+            .as_ref()
+            .and_then(|provider| serde_json::from_str(provider).ok())
+    }
+
+    pub fn metadata(&self) -> HashMap<String, String> {
+        self.metadata
+            .as_ref()
+            .and_then(|metadata| serde_json::from_str(metadata).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn event_type(&self) -> Option<String> {
+        self.metadata().remove("event_type")
+    }
+
+    pub fn rating(&self) -> Option<i64> {
+        self.rating
+    }
+
+    pub fn accepted(&self) -> Option<bool> {
+        self.accepted
+    }
+
+    pub fn corrected_output(&self) -> Option<&str> {
+        self.corrected_output.as_deref()
+    }
+
+    /// The output we want the model to learn from, the correction from the user
+    /// wins over what the model generated
+    pub fn training_output(&self) -> Option<&str> {
+        self.corrected_output().or(self.response())
+    }
+
+    /// Runs the redactor over everything the user or the LLM wrote in the row
+    pub fn redacted(&self, redactor: &Redactor) -> Self {
+        let redact =
+            |content: &Option<String>| content.as_ref().map(|content| redactor.redact(content));
+        // the chat messages are stored as JSON, so we redact the content of the
+        // messages and not the JSON itself
+        let chat_messages = self.chat_messages.as_ref().map(|chat_messages| {
+            match serde_json::from_str::<Vec<LLMClientMessage>>(chat_messages) {
+                Ok(messages) => serde_json::to_string(
+                    &messages
+                        .into_iter()
+                        .map(|message| message.map_content(|content| redactor.redact(content)))
+                        .collect::<Vec<_>>(),
+                )
+                .unwrap_or_default(),
+                Err(_) => redactor.redact(chat_messages),
+            }
+        });
+        Self {
+            prompt: redact(&self.prompt),
+            chat_messages,
+            response: redact(&self.response),
+            corrected_output: redact(&self.corrected_output),
+            ..self.clone()
+        }
+    }
+}
+
+/// Filters for picking the rows out of `llm_data`, every filter which is set
+/// has to match
+#[derive(Debug, Clone, Default)]
+pub struct LLMDataFilter {
+    since: Option<NaiveDateTime>,
+    until: Option<NaiveDateTime>,
+    event_type: Option<String>,
+    credential: Option<String>,
+    llm_type: Option<LLMType>,
+    provider: Option<LLMProvider>,
+    accepted: Option<bool>,
+    min_rating: Option<i64>,
+    with_feedback_only: bool,
+}
+
+impl LLMDataFilter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only keep the rows created at or after this time (UTC)
+    pub fn set_since(mut self, since: NaiveDateTime) -> Self {
+        self.since = Some(since);
+        self
+    }
+
+    /// Only keep the rows created before this time (UTC)
+    pub fn set_until(mut self, until: NaiveDateTime) -> Self {
+        self.until = Some(until);
+        self
+    }
+
+    pub fn set_provider(mut self, provider: LLMProvider) -> Self {
+        self.provider = Some(provider);
+        self
+    }
+
+    pub fn set_event_type(mut self, event_type: String) -> Self {
+        self.event_type = Some(event_type);
+        self
+    }
+
+    /// Only keep the requests made with the key of this name from the
+    /// credential store
+    pub fn set_credential(mut self, credential: String) -> Self {
+        self.credential = Some(credential);
+        self
+    }
+
+    pub fn set_llm_type(mut self, llm_type: LLMType) -> Self {
+        self.llm_type = Some(llm_type);
+        self
+    }
+
+    pub fn set_accepted(mut self, accepted: bool) -> Self {
+        self.accepted = Some(accepted);
+        self
+    }
+
+    pub fn set_min_rating(mut self, min_rating: i64) -> Self {
+        self.min_rating = Some(min_rating);
+        self
+    }
+
+    /// Only keep the rows which have some feedback attached to them
+    pub fn with_feedback_only(mut self) -> Self {
+        self.with_feedback_only = true;
+        self
+    }
+}
+
+/// Gets the rows matching the filter, ordered from the oldest to the newest
+pub async fn fetch_rows(
+    db: &SqlitePool,
+    filter: &LLMDataFilter,
+) -> Result<Vec<LLMDataRow>, LLMClientError> {
+    // the filters run in the DB so we do not load the whole table, llm_type
+    // and provider are stored as JSON so we compare against the same JSON
+    let since = filter.since;
+    let until = filter.until;
+    let event_type = filter.event_type.as_deref();
+    let credential = filter.credential.as_deref();
+    let llm_type = filter
+        .llm_type
+        .as_ref()
+        .map(serde_json::to_string)
+        .transpose()?;
+    let provider = filter
+        .provider
+        .as_ref()
+        .map(serde_json::to_string)
+        .transpose()?;
+    let accepted = filter.accepted;
+    let min_rating = filter.min_rating;
+    let with_feedback_only = filter.with_feedback_only;
+    let rows = sqlx::query! {
+        r#"
+        SELECT id as "id!", created_at as "created_at: NaiveDateTime", prompt, chat_messages, response,
+            llm_type, temperature, provider, event_type, rating, accepted as "accepted: bool", corrected_output
+        FROM llm_data
+        WHERE ($1 IS NULL OR created_at >= $1) AND ($2 IS NULL OR created_at < $2)
+            AND ($3 IS NULL OR (CASE WHEN json_valid(event_type) THEN json_extract(event_type, '$.event_type') END) = $3)
+            AND ($4 IS NULL OR llm_type = $4)
+            AND ($5 IS NULL OR provider = $5)
+            AND ($6 IS NULL OR accepted = $6)
+            AND ($7 IS NULL OR rating >= $7)
+            AND (NOT $8 OR rating IS NOT NULL OR accepted IS NOT NULL OR corrected_output IS NOT NULL)
+            AND ($9 IS NULL OR (CASE WHEN json_valid(event_type) THEN json_extract(event_type, '$.credential') END) = $9)
+        ORDER BY id ASC
+        "#,
+        since,
+        until,
+        event_type,
+        llm_type,
+        provider,
+        accepted,
+        min_rating,
+        with_feedback_only,
+        credential,
+    }
+    .fetch_all(db)
+    .await?;
+    Ok(rows
+        .into_iter()
+        .map(|row| LLMDataRow {
+            id: row.id,
+            created_at: row.created_at,
+            prompt: row.prompt,
+            chat_messages: row.chat_messages,
+            response: row.response,
+            llm_type: row.llm_type,
+            temperature: row.temperature,
+            provider: row.provider,
+            metadata: row.event_type,
+            rating: row.rating,
+            accepted: row.accepted,
+            corrected_output: row.corrected_output,
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, time::Duration};
+
+    use super::{
+        connect, export, feedback::LLMFeedback, retention::RetentionPolicy, LLMDataFilter,
+    };
+    use crate::{
+        broker::LLMBroker,
+        clients::{
+            mock::{MockLLMClient, MockResponse},
+            types::{
+                LLMClientCompletionRequest, LLMClientCompletionStringRequest, LLMClientMessage,
+                LLMType,
+            },
+        },
+        config::LLMBrokerConfiguration,
+        provider::{LLMProvider, LLMProviderAPIKeys, OllamaProvider},
+        tokenizer::tokenizer::LLMTokenizer,
+    };
+
+    #[tokio::test]
+    async fn test_feedback_and_export() {
+        let data_dir = tempfile::tempdir().expect("data dir to be created");
+        let broker = LLMBroker::new(LLMBrokerConfiguration::new(data_dir.path().to_owned()))
+            .await
+            .expect("broker to startup")
+            .add_provider(
+                LLMProvider::Ollama,
+                Box::new(
+                    MockLLMClient::new(LLMProvider::Ollama)
+                        .respond_when("add docs", MockResponse::answer("echo: add docs"))
+                        .respond_when("fix the bug", MockResponse::answer("echo: fix the bug")),
+                ),
+            );
+        let api_key = LLMProviderAPIKeys::Ollama(OllamaProvider {});
+        let event_type = "feedback_test";
+        let metadata = HashMap::from([("event_type".to_owned(), event_type.to_owned())]);
+        let (sender, _receiver) = tokio::sync::mpsc::unbounded_channel();
+        let accepted_answer = broker
+            .stream_completion(
+                api_key.clone(),
+                LLMClientCompletionRequest::new(
+                    LLMType::MistralInstruct,
+                    vec![LLMClientMessage::user("add docs".to_owned())],
+                    0.2,
+                    None,
+                ),
+                LLMProvider::Ollama,
+                metadata.clone(),
+                sender,
+            )
+            .await
+            .expect("to work");
+        assert_eq!(accepted_answer.answer(), "echo: add docs");
+        let (sender, _receiver) = tokio::sync::mpsc::unbounded_channel();
+        let rejected_answer = broker
+            .stream_string_completion(
+                api_key,
+                LLMClientCompletionStringRequest::new(
+                    LLMType::MistralInstruct,
+                    "fix the bug".to_owned(),
+                    0.2,
+                    None,
+                ),
+                LLMProvider::Ollama,
+                metadata,
+                sender,
+            )
+            .await
+            .expect("to work");
+
+        broker
+            .record_feedback(
+                accepted_answer.llm_data_id(),
+                LLMFeedback::new()
+                    .set_accepted(true)
+                    .set_corrected_output("/// Adds docs".to_owned()),
+            )
+            .await
+            .expect("to work");
+        broker
+            .record_feedback(
+                accepted_answer.llm_data_id(),
+                LLMFeedback::new().set_rating(5),
+            )
+            .await
+            .expect("to work");
+        broker
+            .record_feedback(
+                rejected_answer.llm_data_id(),
+                LLMFeedback::new().set_accepted(false),
+            )
+            .await
+            .expect("to work");
+        assert!(broker
+            .record_feedback(i64::MAX, LLMFeedback::new().set_rating(1))
+            .await
+            .is_err());
+
+        let filter = LLMDataFilter::new()
+            .set_event_type(event_type.to_owned())
+            .set_accepted(true);
+        let rows = broker.llm_data(&filter).await.expect("to work");
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].rating(), Some(5));
+
+        let mut chat_jsonl = vec![];
+        assert_eq!(
+            broker
+                .export_chat_jsonl(&filter, &mut chat_jsonl)
+                .await
+                .expect("to work"),
+            1
+        );
+        assert_eq!(
+            String::from_utf8(chat_jsonl).expect("utf8"),
+            "{\"messages\":[{\"content\":\"add docs\",\"role\":\"user\"},{\"content\":\"/// Adds docs\",\"role\":\"assistant\"}]}\n"
+        );
+
+        let tokenizer = LLMTokenizer::new().expect("tokenizer to load");
+        let mut prompt_completion_jsonl = vec![];
+        broker
+            .export_prompt_completion_jsonl(&filter, &tokenizer, &mut prompt_completion_jsonl)
+            .await
+            .expect("to work");
+        let value: serde_json::Value =
+            serde_json::from_slice(&prompt_completion_jsonl).expect("valid json");
+        assert!(value["prompt"]
+            .as_str()
+            .expect("prompt")
+            .contains("add docs"));
+        assert_eq!(value["completion"], "/// Adds docs");
+
+        let event_filter = LLMDataFilter::new().set_event_type(event_type.to_owned());
+        let rows = broker
+            .llm_data(&event_filter.clone().set_provider(LLMProvider::Ollama))
+            .await
+            .expect("to work");
+        assert_eq!(rows.len(), 2);
+        assert!(broker
+            .llm_data(&event_filter.clone().set_provider(LLMProvider::OpenAI))
+            .await
+            .expect("to work")
+            .is_empty());
+        assert_eq!(
+            broker
+                .llm_data(&event_filter.clone().set_llm_type(LLMType::MistralInstruct))
+                .await
+                .expect("to work")
+                .len(),
+            2
+        );
+        assert!(broker
+            .llm_data(&event_filter.clone().set_llm_type(LLMType::Gpt4))
+            .await
+            .expect("to work")
+            .is_empty());
+        let rated = broker
+            .llm_data(&event_filter.clone().set_min_rating(4))
+            .await
+            .expect("to work");
+        assert_eq!(rated.len(), 1);
+        assert_eq!(rated[0].id(), accepted_answer.llm_data_id());
+        assert_eq!(
+            broker
+                .llm_data(&event_filter.clone().with_feedback_only())
+                .await
+                .expect("to work")
+                .len(),
+            2
+        );
+        let tomorrow = chrono::Utc::now().naive_utc() + chrono::Duration::days(1);
+        assert!(broker
+            .llm_data(&event_filter.clone().set_since(tomorrow))
+            .await
+            .expect("to work")
+            .is_empty());
+        assert_eq!(
+            broker
+                .llm_data(&event_filter.clone().set_until(tomorrow))
+                .await
+                .expect("to work")
+                .len(),
+            2
+        );
+
+        let mut csv = vec![];
+        export::write_csv(&rows, &mut csv).expect("to work");
+        let csv = String::from_utf8(csv).expect("utf8");
+        assert_eq!(csv.lines().next(), Some("id,created_at,provider,llm_type,event_type,temperature,prompt,chat_messages,response,rating,accepted,corrected_output"));
+        assert!(csv.contains("\"[{\"\"role\"\":\"\"User\"\""));
+
+        let report = broker
+            .apply_retention(RetentionPolicy::MaxAge(Duration::ZERO))
+            .await
+            .expect("to work");
+        assert!(report.deleted_rows >= 2);
+        assert!(broker
+            .llm_data(&event_filter)
+            .await
+            .expect("to work")
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_connect_leaves_the_db_alone() {
+        let data_dir = tempfile::tempdir().expect("data dir to be created");
+        let config = || LLMBrokerConfiguration::new(data_dir.path().to_owned());
+        // the tooling does not create a DB which is not there
+        assert!(connect(config()).await.is_err());
+        assert!(!data_dir.path().join("llm_data.data").exists());
+
+        // a DB from a newer version has migrations we do not know about, we
+        // fail instead of moving it out of the way
+        LLMBroker::new(config()).await.expect("broker to startup");
+        let db = connect(config()).await.expect("DB to open");
+        sqlx::query(
+            "INSERT INTO _sqlx_migrations (version, description, success, checksum, execution_time) VALUES (99990101000000, 'from the future', 1, x'00', 0)",
+        )
+        .execute(&db)
+        .await
+        .expect("migration to be added");
+        db.close().await;
+        assert!(connect(config()).await.is_err());
+        let files = std::fs::read_dir(data_dir.path())
+            .expect("data dir to be read")
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.file_name().to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+        assert!(files.contains(&"llm_data.data".to_owned()));
+        assert!(files.iter().all(|file| !file.ends_with(".bk")));
+    }
+}
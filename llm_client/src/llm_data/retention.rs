@@ -0,0 +1,110 @@
+//! The `llm_data` log grows with every request, these policies keep it in check
+
+use std::time::Duration;
+
+use chrono::{NaiveDateTime, Utc};
+use sqlx::SqlitePool;
+
+use crate::clients::types::LLMClientError;
+
+/// Deleting by size is an estimate based on the average size of a row, so we
+/// might need a few passes before we are under the limit
+const MAX_SIZE_PASSES: usize = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetentionPolicy {
+    /// Delete the rows older than this
+    MaxAge(Duration),
+    /// Delete the oldest rows until the DB is smaller than this many bytes
+    MaxSizeBytes(u64),
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RetentionReport {
+    pub deleted_rows: u64,
+    pub size_before: u64,
+    pub size_after: u64,
+}
+
+/// The size of the DB on disk in bytes
+pub async fn db_size(db: &SqlitePool) -> Result<u64, LLMClientError> {
+    let page_count: i64 = sqlx::query_scalar("PRAGMA page_count")
+        .fetch_one(db)
+        .await?;
+    let page_size: i64 = sqlx::query_scalar("PRAGMA page_size").fetch_one(db).await?;
+    Ok((page_count * page_size).max(0) as u64)
+}
+
+async fn delete_older_than(db: &SqlitePool, cutoff: NaiveDateTime) -> Result<u64, LLMClientError> {
+    let result = sqlx::query! {
+        r#"
+        DELETE FROM llm_data WHERE created_at < $1
+        "#,
+        cutoff,
+    }
+    .execute(db)
+    .await?;
+    Ok(result.rows_affected())
+}
+
+async fn delete_oldest(db: &SqlitePool, limit: i64) -> Result<u64, LLMClientError> {
+    let result = sqlx::query! {
+        r#"
+        DELETE FROM llm_data WHERE id IN (SELECT id FROM llm_data ORDER BY id ASC LIMIT $1)
+        "#,
+        limit,
+    }
+    .execute(db)
+    .await?;
+    Ok(result.rows_affected())
+}
+
+async fn row_count(db: &SqlitePool) -> Result<i64, LLMClientError> {
+    let row_count = sqlx::query_scalar! {
+        r#"
+        SELECT COUNT(*) as "count!: i64" FROM llm_data
+        "#
+    }
+    .fetch_one(db)
+    .await?;
+    Ok(row_count)
+}
+
+/// Applies the policy and compacts the DB so the space is given back to the
+/// file system
+pub async fn apply_retention(
+    db: &SqlitePool,
+    policy: RetentionPolicy,
+) -> Result<RetentionReport, LLMClientError> {
+    let size_before = db_size(db).await?;
+    let mut deleted_rows = 0;
+    match policy {
+        RetentionPolicy::MaxAge(max_age) => {
+            let max_age = chrono::Duration::from_std(max_age).unwrap_or(chrono::Duration::MAX);
+            let cutoff = Utc::now()
+                .naive_utc()
+                .checked_sub_signed(max_age)
+                .unwrap_or(NaiveDateTime::MIN);
+            deleted_rows += delete_older_than(db, cutoff).await?;
+            sqlx::query("VACUUM").execute(db).await?;
+        }
+        RetentionPolicy::MaxSizeBytes(max_size) => {
+            for _ in 0..MAX_SIZE_PASSES {
+                let size = db_size(db).await?;
+                let rows = row_count(db).await?;
+                if size <= max_size || rows == 0 {
+                    break;
+                }
+                let bytes_per_row = (size / rows as u64).max(1);
+                let rows_to_delete = (size - max_size).div_ceil(bytes_per_row).max(1);
+                deleted_rows += delete_oldest(db, rows_to_delete as i64).await?;
+                sqlx::query("VACUUM").execute(db).await?;
+            }
+        }
+    }
+    Ok(RetentionReport {
+        deleted_rows,
+        size_before,
+        size_after: db_size(db).await?,
+    })
+}
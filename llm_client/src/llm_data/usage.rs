@@ -0,0 +1,65 @@
+use sqlx::{types::chrono::NaiveDateTime, SqlitePool};
+
+use crate::{clients::types::LLMClientError, provider::LLMProvider};
+
+/// Stores the tokens the request used and what they cost on its row, so we
+/// can add up the usage of a key without going over the prompts again
+pub async fn record_usage(
+    db: &SqlitePool,
+    llm_data_id: i64,
+    tokens_used: u64,
+    cost: Option<f64>,
+) -> Result<(), LLMClientError> {
+    let tokens_used = tokens_used as i64;
+    let result = sqlx::query! {
+        r#"
+        UPDATE llm_data
+        SET tokens_used = $1, cost = $2
+        WHERE id = $3
+        "#,
+        tokens_used,
+        cost,
+        llm_data_id,
+    }
+    .execute(db)
+    .await?;
+    if result.rows_affected() == 0 {
+        return Err(LLMClientError::LLMDataRowNotFound(llm_data_id));
+    }
+    Ok(())
+}
+
+/// The tokens and the cost we logged for the key of the provider since the
+/// given time (UTC), the rows logged before we stored the usage do not count
+pub async fn credential_usage(
+    db: &SqlitePool,
+    provider: &LLMProvider,
+    credential: &str,
+    since: NaiveDateTime,
+) -> Result<(u64, f64), LLMClientError> {
+    // the provider column has the provider the request went to, Azure and
+    // CodeStory carry their configuration in there so we match them on the
+    // variant alone
+    let client_provider = provider.client_provider();
+    let provider_json = serde_json::to_string(&client_provider)?;
+    let provider_variant = match serde_json::to_value(&client_provider)? {
+        serde_json::Value::Object(variant) => variant.keys().next().cloned(),
+        _ => None,
+    };
+    let usage = sqlx::query! {
+        r#"
+        SELECT COALESCE(SUM(tokens_used), 0) as "tokens_used!: i64", COALESCE(SUM(cost), 0.0) as "cost!: f64"
+        FROM llm_data
+        WHERE created_at >= $1
+            AND (CASE WHEN json_valid(event_type) THEN json_extract(event_type, '$.credential') END) = $2
+            AND (provider = $3 OR ($4 IS NOT NULL AND json_type(provider, '$.' || $4) IS NOT NULL))
+        "#,
+        since,
+        credential,
+        provider_json,
+        provider_variant,
+    }
+    .fetch_one(db)
+    .await?;
+    Ok((usage.tokens_used.max(0) as u64, usage.cost))
+}
@@ -29,6 +29,22 @@ pub enum LLMProvider {
     Azure(AzureOpenAIDeploymentId),
 }
 
+impl LLMProvider {
+    /// The provider under which the client is registered in the broker, the
+    /// configuration which Azure and CodeStory carry does not pick the client
+    pub fn client_provider(&self) -> LLMProvider {
+        match self {
+            LLMProvider::Azure(_) => LLMProvider::Azure(AzureOpenAIDeploymentId {
+                deployment_id: "".to_owned(),
+            }),
+            LLMProvider::CodeStory(_) => {
+                LLMProvider::CodeStory(CodeStoryLLMType { llm_type: None })
+            }
+            _ => self.clone(),
+        }
+    }
+}
+
 #[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
 pub enum LLMProviderAPIKeys {
     OpenAI(OpenAIProvider),
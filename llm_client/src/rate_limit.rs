@@ -0,0 +1,623 @@
+//! Client side rate limiting, so we stay within the limits of the providers
+//! instead of finding out from a 429. The limits apply to the provider as a
+//! whole and to each of its keys. The requests for a provider wait in a single
+//! queue where the interactive ones (inline edits, chat) go ahead of the
+//! background ones (reranking). A request only waits on the requests ahead of
+//! it which could go, so a key at its limit does not hold up the other keys.
+
+use std::{
+    cmp::Reverse,
+    collections::{hash_map::DefaultHasher, BTreeMap, HashMap, VecDeque},
+    hash::{Hash, Hasher},
+    sync::Mutex,
+    time::Duration,
+};
+
+use tokio::{sync::Notify, time::Instant};
+
+use crate::provider::{LLMProvider, LLMProviderAPIKeys};
+
+/// The key in the request metadata which sets the priority, when it's missing
+/// we go by the `event_type`
+pub const PRIORITY_METADATA_KEY: &str = "priority";
+
+const WINDOW: Duration = Duration::from_secs(60);
+
+/// We do not want to run the tokenizer for every request, so the limits work
+/// on an estimate
+pub use crate::tokenizer::tokenizer::estimate_tokens;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RateLimits {
+    requests_per_minute: Option<usize>,
+    tokens_per_minute: Option<usize>,
+    max_in_flight: Option<usize>,
+}
+
+impl RateLimits {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set_requests_per_minute(mut self, requests_per_minute: usize) -> Self {
+        self.requests_per_minute = Some(requests_per_minute);
+        self
+    }
+
+    pub fn set_tokens_per_minute(mut self, tokens_per_minute: usize) -> Self {
+        self.tokens_per_minute = Some(tokens_per_minute);
+        self
+    }
+
+    pub fn set_max_in_flight(mut self, max_in_flight: usize) -> Self {
+        self.max_in_flight = Some(max_in_flight);
+        self
+    }
+
+    pub fn requests_per_minute(&self) -> Option<usize> {
+        self.requests_per_minute
+    }
+
+    pub fn tokens_per_minute(&self) -> Option<usize> {
+        self.tokens_per_minute
+    }
+
+    pub fn max_in_flight(&self) -> Option<usize> {
+        self.max_in_flight
+    }
+
+    fn is_unlimited(&self) -> bool {
+        self == &Self::default()
+    }
+}
+
+/// The limits for the providers, there are no limits unless configured
+#[derive(Debug, Clone, Default)]
+pub struct RateLimitConfig {
+    provider_limits: HashMap<LLMProvider, RateLimits>,
+    key_limits: HashMap<LLMProvider, RateLimits>,
+}
+
+impl RateLimitConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Limits shared by all the requests to the provider
+    pub fn set_provider_limits(mut self, provider: LLMProvider, limits: RateLimits) -> Self {
+        self.provider_limits
+            .insert(provider.client_provider(), limits);
+        self
+    }
+
+    /// Limits for each of the keys of the provider
+    pub fn set_key_limits(mut self, provider: LLMProvider, limits: RateLimits) -> Self {
+        self.key_limits.insert(provider.client_provider(), limits);
+        self
+    }
+
+    fn limits(&self, provider: &LLMProvider) -> (RateLimits, RateLimits) {
+        (
+            self.provider_limits
+                .get(provider)
+                .copied()
+                .unwrap_or_default(),
+            self.key_limits.get(provider).copied().unwrap_or_default(),
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum RequestPriority {
+    Background,
+    Normal,
+    Interactive,
+}
+
+impl RequestPriority {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RequestPriority::Background => "background",
+            RequestPriority::Normal => "normal",
+            RequestPriority::Interactive => "interactive",
+        }
+    }
+
+    /// The priority set in the metadata, otherwise inline edits and chat are
+    /// interactive and reranking runs in the background
+    pub fn from_metadata(metadata: &HashMap<String, String>) -> Self {
+        match metadata
+            .get(PRIORITY_METADATA_KEY)
+            .map(|priority| priority.as_str())
+        {
+            Some("background") => return RequestPriority::Background,
+            Some("normal") => return RequestPriority::Normal,
+            Some("interactive") => return RequestPriority::Interactive,
+            _ => {}
+        }
+        match metadata
+            .get("event_type")
+            .map(|event_type| event_type.as_str())
+        {
+            Some(event_type) if event_type.contains("inline_edit") || event_type == "chat" => {
+                RequestPriority::Interactive
+            }
+            Some(event_type) if event_type.contains("reranking") => RequestPriority::Background,
+            _ => RequestPriority::Normal,
+        }
+    }
+}
+
+enum Admission {
+    Now,
+    AfterRelease,
+    After(Duration),
+}
+
+impl Admission {
+    /// The later of the two, so both the provider and the key admit
+    fn and(self, other: Admission) -> Admission {
+        match (self, other) {
+            (Admission::Now, other) | (other, Admission::Now) => other,
+            (Admission::AfterRelease, _) | (_, Admission::AfterRelease) => Admission::AfterRelease,
+            (Admission::After(first), Admission::After(second)) => {
+                Admission::After(first.max(second))
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct Bucket {
+    in_flight: usize,
+    /// (request id, when it started, tokens) for the last minute
+    window: VecDeque<(u64, Instant, usize)>,
+}
+
+impl Bucket {
+    fn prune(&mut self, now: Instant) {
+        while let Some((_, started, _)) = self.window.front() {
+            if now.duration_since(*started) < WINDOW {
+                break;
+            }
+            self.window.pop_front();
+        }
+    }
+
+    fn admission(&self, limits: &RateLimits, tokens: usize, now: Instant) -> Admission {
+        let expires = |started: Instant| (started + WINDOW).saturating_duration_since(now);
+        if matches!(limits.max_in_flight, Some(max_in_flight) if self.in_flight >= max_in_flight) {
+            return Admission::AfterRelease;
+        }
+        if let Some(requests_per_minute) = limits.requests_per_minute {
+            if self.window.len() >= requests_per_minute {
+                let oldest = self.window[self.window.len() - requests_per_minute].1;
+                return Admission::After(expires(oldest));
+            }
+        }
+        if let Some(tokens_per_minute) = limits.tokens_per_minute {
+            let used: usize = self.window.iter().map(|(_, _, tokens)| tokens).sum();
+            // a request bigger than the limit goes through on its own
+            if !self.window.is_empty() && used + tokens > tokens_per_minute {
+                let mut freed = 0;
+                for (_, started, request_tokens) in self.window.iter() {
+                    freed += request_tokens;
+                    if used - freed + tokens <= tokens_per_minute {
+                        return Admission::After(expires(*started));
+                    }
+                }
+                let newest = self.window.back().map(|(_, started, _)| *started);
+                return Admission::After(newest.map(expires).unwrap_or_default());
+            }
+        }
+        Admission::Now
+    }
+
+    fn start(&mut self, id: u64, tokens: usize, now: Instant) {
+        self.in_flight += 1;
+        self.window.push_back((id, now, tokens));
+    }
+
+    fn set_tokens(&mut self, id: u64, tokens: usize) {
+        if let Some(entry) = self
+            .window
+            .iter_mut()
+            .find(|(entry_id, _, _)| *entry_id == id)
+        {
+            entry.2 = tokens;
+        }
+    }
+}
+
+type Ticket = (Reverse<RequestPriority>, u64);
+
+/// What a queued request is waiting for
+#[derive(Clone, Copy)]
+struct QueuedRequest {
+    key: u64,
+    tokens: usize,
+}
+
+#[derive(Default)]
+struct ProviderState {
+    provider: Bucket,
+    keys: HashMap<u64, Bucket>,
+    queue: BTreeMap<Ticket, QueuedRequest>,
+}
+
+impl ProviderState {
+    /// A request ahead of the ticket goes first, unless its key is at the
+    /// limit and the ticket is for another key. The requests for the same key
+    /// keep their order.
+    fn is_held_up(
+        &mut self,
+        ticket: &Ticket,
+        key: u64,
+        key_limits: &RateLimits,
+        now: Instant,
+    ) -> bool {
+        let ahead = self
+            .queue
+            .range(..ticket)
+            .map(|(_, queued)| *queued)
+            .collect::<Vec<_>>();
+        ahead.into_iter().any(|queued| {
+            if queued.key == key {
+                return true;
+            }
+            let key_bucket = self.keys.entry(queued.key).or_default();
+            key_bucket.prune(now);
+            matches!(
+                key_bucket.admission(key_limits, queued.tokens, now),
+                Admission::Now
+            )
+        })
+    }
+}
+
+#[derive(Default)]
+struct LimiterState {
+    providers: HashMap<LLMProvider, ProviderState>,
+    next_id: u64,
+}
+
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    state: Mutex<LimiterState>,
+    notify: Notify,
+}
+
+/// We do not keep the keys around, only a hash to tell them apart
+fn key_id(api_key: &LLMProviderAPIKeys) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    serde_json::to_string(api_key)
+        .unwrap_or_default()
+        .hash(&mut hasher);
+    hasher.finish()
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        Self {
+            config,
+            state: Mutex::new(LimiterState::default()),
+            notify: Notify::new(),
+        }
+    }
+
+    /// Waits for our turn to send the request, the request counts as in flight
+    /// until the permit is dropped. `tokens` is the estimate for the request
+    /// which can be corrected on the permit once we have the answer.
+    pub async fn acquire(
+        &self,
+        provider: &LLMProvider,
+        api_key: &LLMProviderAPIKeys,
+        priority: RequestPriority,
+        tokens: usize,
+    ) -> RateLimitPermit<'_> {
+        let provider = provider.client_provider();
+        let (provider_limits, key_limits) = self.config.limits(&provider);
+        if provider_limits.is_unlimited() && key_limits.is_unlimited() {
+            return RateLimitPermit {
+                limiter: self,
+                slot: None,
+            };
+        }
+        let key = key_id(api_key);
+        let ticket = {
+            let mut state = self.state.lock().expect("lock to not be poisoned");
+            let id = state.next_id;
+            state.next_id += 1;
+            let ticket = (Reverse(priority), id);
+            state
+                .providers
+                .entry(provider.clone())
+                .or_default()
+                .queue
+                .insert(ticket, QueuedRequest { key, tokens });
+            ticket
+        };
+        // takes us out of the queue if the request is dropped while waiting
+        let mut queued = QueuedTicket {
+            limiter: self,
+            provider: &provider,
+            ticket: Some(ticket),
+        };
+        loop {
+            let notified = self.notify.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            let admission = {
+                let mut state = self.state.lock().expect("lock to not be poisoned");
+                let provider_state = state.providers.entry(provider.clone()).or_default();
+                let now = Instant::now();
+                if provider_state.is_held_up(&ticket, key, &key_limits, now) {
+                    Admission::AfterRelease
+                } else {
+                    provider_state.provider.prune(now);
+                    let key_bucket = provider_state.keys.entry(key).or_default();
+                    key_bucket.prune(now);
+                    let admission = key_bucket.admission(&key_limits, tokens, now).and(
+                        provider_state
+                            .provider
+                            .admission(&provider_limits, tokens, now),
+                    );
+                    if let Admission::Now = admission {
+                        let id = ticket.1;
+                        key_bucket.start(id, tokens, now);
+                        provider_state.provider.start(id, tokens, now);
+                        provider_state.queue.remove(&ticket);
+                        queued.ticket = None;
+                    }
+                    admission
+                }
+            };
+            match admission {
+                Admission::Now => {
+                    // the next request in the queue might be good to go
+                    self.notify.notify_waiters();
+                    return RateLimitPermit {
+                        limiter: self,
+                        slot: Some(Slot {
+                            provider: provider.clone(),
+                            key,
+                            id: ticket.1,
+                        }),
+                    };
+                }
+                Admission::AfterRelease => notified.await,
+                Admission::After(wait) => {
+                    tokio::select! {
+                        _ = notified => {}
+                        _ = tokio::time::sleep(wait) => {}
+                    }
+                }
+            }
+        }
+    }
+
+    fn with_provider_state(&self, provider: &LLMProvider, update: impl FnOnce(&mut ProviderState)) {
+        {
+            let mut state = self.state.lock().expect("lock to not be poisoned");
+            if let Some(provider_state) = state.providers.get_mut(provider) {
+                update(provider_state);
+            }
+        }
+        self.notify.notify_waiters();
+    }
+}
+
+struct QueuedTicket<'a> {
+    limiter: &'a RateLimiter,
+    provider: &'a LLMProvider,
+    ticket: Option<Ticket>,
+}
+
+impl Drop for QueuedTicket<'_> {
+    fn drop(&mut self) {
+        if let Some(ticket) = self.ticket.take() {
+            self.limiter
+                .with_provider_state(self.provider, |provider_state| {
+                    provider_state.queue.remove(&ticket);
+                });
+        }
+    }
+}
+
+struct Slot {
+    provider: LLMProvider,
+    key: u64,
+    id: u64,
+}
+
+pub struct RateLimitPermit<'a> {
+    limiter: &'a RateLimiter,
+    slot: Option<Slot>,
+}
+
+impl RateLimitPermit<'_> {
+    /// Replaces the estimate we started with, with the tokens the request
+    /// actually used
+    pub fn record_tokens(&self, tokens: usize) {
+        if let Some(slot) = &self.slot {
+            self.limiter
+                .with_provider_state(&slot.provider, |provider_state| {
+                    provider_state.provider.set_tokens(slot.id, tokens);
+                    if let Some(key_bucket) = provider_state.keys.get_mut(&slot.key) {
+                        key_bucket.set_tokens(slot.id, tokens);
+                    }
+                });
+        }
+    }
+}
+
+impl Drop for RateLimitPermit<'_> {
+    fn drop(&mut self) {
+        if let Some(slot) = self.slot.take() {
+            self.limiter
+                .with_provider_state(&slot.provider, |provider_state| {
+                    provider_state.provider.in_flight -= 1;
+                    if let Some(key_bucket) = provider_state.keys.get_mut(&slot.key) {
+                        key_bucket.in_flight -= 1;
+                    }
+                });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Arc, time::Duration};
+
+    use super::{RateLimitConfig, RateLimiter, RateLimits, RequestPriority, PRIORITY_METADATA_KEY};
+    use crate::provider::{LLMProvider, LLMProviderAPIKeys, TogetherAIProvider};
+
+    fn api_key(key: &str) -> LLMProviderAPIKeys {
+        LLMProviderAPIKeys::TogetherAI(TogetherAIProvider::new(key.to_owned()))
+    }
+
+    #[test]
+    fn test_priority_from_metadata() {
+        let metadata = |pairs: &[(&str, &str)]| {
+            pairs
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect::<HashMap<_, _>>()
+        };
+        assert_eq!(
+            RequestPriority::from_metadata(&metadata(&[("event_type", "pointwise_reranking")])),
+            RequestPriority::Background
+        );
+        assert_eq!(
+            RequestPriority::from_metadata(&metadata(&[("event_type", "inline_edit")])),
+            RequestPriority::Interactive
+        );
+        assert_eq!(
+            RequestPriority::from_metadata(&metadata(&[
+                ("event_type", "inline_edit"),
+                (PRIORITY_METADATA_KEY, "background")
+            ])),
+            RequestPriority::Background
+        );
+        assert_eq!(
+            RequestPriority::from_metadata(&HashMap::new()),
+            RequestPriority::Normal
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_interactive_requests_go_first() {
+        let limiter = Arc::new(RateLimiter::new(
+            RateLimitConfig::new().set_provider_limits(
+                LLMProvider::TogetherAI,
+                RateLimits::new().set_max_in_flight(1),
+            ),
+        ));
+        let permit = limiter
+            .acquire(
+                &LLMProvider::TogetherAI,
+                &api_key("first"),
+                RequestPriority::Normal,
+                10,
+            )
+            .await;
+
+        let (order_sender, mut order_receiver) = tokio::sync::mpsc::unbounded_channel();
+        let mut handles = vec![];
+        for priority in [RequestPriority::Background, RequestPriority::Interactive] {
+            let limiter = limiter.clone();
+            let order_sender = order_sender.clone();
+            handles.push(tokio::spawn(async move {
+                let _permit = limiter
+                    .acquire(&LLMProvider::TogetherAI, &api_key("second"), priority, 10)
+                    .await;
+                order_sender.send(priority).expect("send to work");
+            }));
+            // make sure the background request is queued first, the clock is
+            // paused so this only returns once the task is waiting
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert!(order_receiver.try_recv().is_err());
+        drop(permit);
+        for handle in handles {
+            handle.await.expect("task to finish");
+        }
+        assert_eq!(
+            order_receiver.recv().await,
+            Some(RequestPriority::Interactive)
+        );
+        assert_eq!(
+            order_receiver.recv().await,
+            Some(RequestPriority::Background)
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_requests_and_tokens_per_minute() {
+        let limiter = RateLimiter::new(
+            RateLimitConfig::new()
+                .set_key_limits(
+                    LLMProvider::TogetherAI,
+                    RateLimits::new().set_requests_per_minute(2),
+                )
+                .set_provider_limits(
+                    LLMProvider::Ollama,
+                    RateLimits::new().set_tokens_per_minute(100),
+                ),
+        );
+        let acquire = |provider: LLMProvider, key: &'static str, tokens: usize| {
+            let limiter = &limiter;
+            async move {
+                tokio::time::timeout(
+                    Duration::from_millis(50),
+                    limiter.acquire(&provider, &api_key(key), RequestPriority::Normal, tokens),
+                )
+                .await
+                .is_ok()
+            }
+        };
+        assert!(acquire(LLMProvider::TogetherAI, "first", 0).await);
+        assert!(acquire(LLMProvider::TogetherAI, "first", 0).await);
+        assert!(!acquire(LLMProvider::TogetherAI, "first", 0).await);
+        // the limit is per key
+        assert!(acquire(LLMProvider::TogetherAI, "second", 0).await);
+
+        // a request over the limit goes through on its own, but nothing after it
+        assert!(acquire(LLMProvider::Ollama, "first", 500).await);
+        assert!(!acquire(LLMProvider::Ollama, "first", 1).await);
+        // providers without limits do not wait
+        assert!(acquire(LLMProvider::OpenAI, "first", 10_000).await);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_a_key_at_its_limit_does_not_hold_up_the_others() {
+        let limiter = Arc::new(RateLimiter::new(RateLimitConfig::new().set_key_limits(
+            LLMProvider::TogetherAI,
+            RateLimits::new().set_requests_per_minute(1),
+        )));
+        let acquire = |key: &'static str, priority: RequestPriority| {
+            let limiter = limiter.clone();
+            tokio::spawn(async move {
+                let _permit = limiter
+                    .acquire(&LLMProvider::TogetherAI, &api_key(key), priority, 0)
+                    .await;
+            })
+        };
+        acquire("busy", RequestPriority::Normal)
+            .await
+            .expect("task to finish");
+        // the busy key is at its limit for the next minute, even with the
+        // higher priority its request must not block the free key
+        let busy = acquire("busy", RequestPriority::Interactive);
+        // the clock only moves once the busy request is queued
+        tokio::time::sleep(Duration::from_millis(1)).await;
+        let free = acquire("free", RequestPriority::Background);
+        tokio::time::timeout(Duration::from_secs(1), free)
+            .await
+            .expect("free key to go through")
+            .expect("task to finish");
+        assert!(!busy.is_finished());
+        tokio::time::advance(Duration::from_secs(60)).await;
+        busy.await.expect("task to finish");
+    }
+}
@@ -1,21 +1,42 @@
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use sqlx::SqlitePool;
 
 use crate::{clients::types::LLMClientError, config::LLMBrokerConfiguration};
 
-pub async fn init(config: LLMBrokerConfiguration) -> Result<SqlitePool, LLMClientError> {
+/// Connects to the DB, if the DB is broken we move it out of the way and start
+/// with a fresh one. The path of the moved DB is returned so the caller can let
+/// the user know about it.
+pub async fn init(
+    config: LLMBrokerConfiguration,
+) -> Result<(SqlitePool, Option<PathBuf>), LLMClientError> {
     let data_dir = config.data_dir.to_string_lossy().to_owned();
 
     match connect(&data_dir).await {
-        Ok(pool) => Ok(pool),
+        Ok(pool) => Ok((pool, None)),
         Err(_) => {
-            reset(&data_dir)?;
-            connect(&data_dir).await
+            let backup_path = reset(&data_dir)?;
+            Ok((connect(&data_dir).await?, Some(backup_path)))
         }
     }
 }
 
+/// Opens the DB which is already in the data directory, without creating it
+/// and without moving it out of the way when it does not open or migrate. This
+/// is for the tooling which works on the log, the error goes to the caller.
+pub async fn open(config: &LLMBrokerConfiguration) -> Result<SqlitePool, LLMClientError> {
+    let url = format!(
+        "sqlite://{}/llm_data.data?mode=rw",
+        config.data_dir.to_string_lossy()
+    );
+    let pool = SqlitePool::connect(&url).await?;
+    if let Err(e) = sqlx::migrate!().run(&pool).await {
+        pool.close().await;
+        return Err(sqlx::Error::Migrate(Box::new(e)).into());
+    }
+    Ok(pool)
+}
+
 async fn connect(data_dir: &str) -> Result<SqlitePool, LLMClientError> {
     let url = format!("sqlite://{data_dir}/llm_data.data?mode=rwc");
     let pool = SqlitePool::connect(&url)
@@ -32,8 +53,15 @@ async fn connect(data_dir: &str) -> Result<SqlitePool, LLMClientError> {
     }
 }
 
-fn reset(data_dir: &str) -> Result<(), LLMClientError> {
+/// Moves the DB to a backup file, the backups are timestamped so we do not
+/// overwrite the previous one if the DB breaks again
+fn reset(data_dir: &str) -> Result<PathBuf, LLMClientError> {
     let db_path = Path::new(data_dir).join("llm_data.data");
-    let bk_path = db_path.with_extension("llm_data.bk");
-    std::fs::rename(db_path, bk_path).map_err(|_| LLMClientError::SqliteSetupError)
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default();
+    let bk_path = db_path.with_extension(format!("llm_data.{timestamp}.bk"));
+    std::fs::rename(&db_path, &bk_path).map_err(|_| LLMClientError::SqliteSetupError)?;
+    Ok(bk_path)
 }
@@ -0,0 +1,484 @@
+//! Cutting the context down to a token budget, the prompt builders use this for
+//! the code above and below the selection, the reranking windows and the chat
+//! history. Everything here works on the tokens of the model (tiktoken for
+//! OpenAI, the HuggingFace tokenizers for the rest) and keeps the byte ranges
+//! into the original text, so the callers can map back to the source lines.
+//!
+//! The token counts come from tokenizing the whole text once, tokenizing the
+//! result again can be off by a token at the cut.
+
+use std::ops::Range;
+
+use crate::clients::types::LLMType;
+
+use super::tokenizer::{LLMTokenizer, LLMTokenizerError};
+
+/// Which part of the text we cut away
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TruncationSide {
+    /// Keeps the end of the text, like the code right above the cursor
+    Start,
+    /// Keeps the start of the text, like the code right below the cursor
+    End,
+    /// Keeps both ends and drops the middle
+    Middle,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TruncatedText {
+    text: String,
+    ranges: Vec<Range<usize>>,
+    tokens: usize,
+    truncated: bool,
+}
+
+impl TruncatedText {
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    pub fn into_text(self) -> String {
+        self.text
+    }
+
+    /// The byte ranges of the original text which we kept, in order. There
+    /// are two of them when we cut from the middle.
+    pub fn ranges(&self) -> &[Range<usize>] {
+        &self.ranges
+    }
+
+    pub fn tokens(&self) -> usize {
+        self.tokens
+    }
+
+    pub fn is_truncated(&self) -> bool {
+        self.truncated
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TextChunk {
+    text: String,
+    range: Range<usize>,
+    start_line: usize,
+    end_line: usize,
+    tokens: usize,
+}
+
+impl TextChunk {
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// The byte range of the chunk in the original text
+    pub fn range(&self) -> Range<usize> {
+        self.range.clone()
+    }
+
+    /// The first line (0 indexed) which is part of the chunk
+    pub fn start_line(&self) -> usize {
+        self.start_line
+    }
+
+    /// The last line (0 indexed, inclusive) which is part of the chunk
+    pub fn end_line(&self) -> usize {
+        self.end_line
+    }
+
+    pub fn tokens(&self) -> usize {
+        self.tokens
+    }
+}
+
+/// Where each of the tokens starts in the text, the last entry is the end of
+/// the text so the tokens `a..b` are at `boundaries[a]..boundaries[b]`
+struct TokenBoundaries {
+    boundaries: Vec<usize>,
+}
+
+impl TokenBoundaries {
+    fn new(offsets: Vec<Range<usize>>, text_len: usize) -> Self {
+        let mut boundaries = offsets
+            .into_iter()
+            .map(|offset| offset.start)
+            .collect::<Vec<_>>();
+        // anything the tokenizer skipped at the edges stays with the text
+        if let Some(first) = boundaries.first_mut() {
+            *first = 0;
+        }
+        boundaries.push(text_len);
+        Self { boundaries }
+    }
+
+    fn len(&self) -> usize {
+        self.boundaries.len() - 1
+    }
+
+    fn byte(&self, token: usize) -> usize {
+        self.boundaries[token]
+    }
+
+    /// How many tokens start before the byte, a token which crosses the byte
+    /// counts towards the part before it
+    fn tokens_before(&self, byte: usize) -> usize {
+        self.boundaries[..self.len()].partition_point(|start| *start < byte)
+    }
+
+    fn tokens_in(&self, range: &Range<usize>) -> usize {
+        self.tokens_before(range.end) - self.tokens_before(range.start)
+    }
+}
+
+/// The byte ranges of the lines, with the new line at the end
+fn line_ranges(text: &str) -> Vec<Range<usize>> {
+    let mut start = 0;
+    text.split_inclusive('\n')
+        .map(|line| {
+            let range = start..start + line.len();
+            start = range.end;
+            range
+        })
+        .collect()
+}
+
+fn line_at(text: &str, byte: usize) -> usize {
+    text[..byte].matches('\n').count()
+}
+
+fn text_chunk(text: &str, range: Range<usize>, tokens: usize) -> TextChunk {
+    TextChunk {
+        text: text[range.clone()].to_owned(),
+        start_line: line_at(text, range.start),
+        end_line: line_at(text, range.end.max(range.start + 1) - 1),
+        range,
+        tokens,
+    }
+}
+
+/// The text we keep is the head and the tail of the original, when we cut
+/// from one side the other range is empty
+fn truncated_text(
+    text: &str,
+    head: Range<usize>,
+    tail: Range<usize>,
+    tokens: usize,
+) -> TruncatedText {
+    let truncated = head.len() + tail.len() < text.len();
+    let mut ranges = Vec::with_capacity(2);
+    if head.end == tail.start {
+        ranges.push(head.start..tail.end);
+    } else {
+        ranges.push(head);
+        ranges.push(tail);
+    }
+    ranges.retain(|range| !range.is_empty());
+    TruncatedText {
+        text: ranges.iter().map(|range| &text[range.clone()]).collect(),
+        ranges,
+        tokens,
+        truncated,
+    }
+}
+
+fn check_chunk_size(chunk_tokens: usize, overlap_tokens: usize) -> Result<(), LLMTokenizerError> {
+    if chunk_tokens == 0 || overlap_tokens >= chunk_tokens {
+        return Err(LLMTokenizerError::InvalidChunkSize {
+            chunk_tokens,
+            overlap_tokens,
+        });
+    }
+    Ok(())
+}
+
+impl LLMTokenizer {
+    fn token_boundaries(
+        &self,
+        model: &LLMType,
+        text: &str,
+    ) -> Result<TokenBoundaries, LLMTokenizerError> {
+        Ok(TokenBoundaries::new(
+            self.token_offsets(model, text)?,
+            text.len(),
+        ))
+    }
+
+    /// Cuts the text down to `max_tokens`, the cut can land anywhere in the
+    /// text (even in the middle of a word), see [`truncate_lines`](Self::truncate_lines)
+    /// for code
+    pub fn truncate(
+        &self,
+        model: &LLMType,
+        text: &str,
+        max_tokens: usize,
+        side: TruncationSide,
+    ) -> Result<TruncatedText, LLMTokenizerError> {
+        let tokens = self.token_boundaries(model, text)?;
+        let total = tokens.len();
+        if total <= max_tokens {
+            return Ok(truncated_text(
+                text,
+                0..text.len(),
+                text.len()..text.len(),
+                total,
+            ));
+        }
+        let (head, tail) = match side {
+            TruncationSide::Start => (0..0, tokens.byte(total - max_tokens)..text.len()),
+            TruncationSide::End => (0..tokens.byte(max_tokens), text.len()..text.len()),
+            TruncationSide::Middle => {
+                let tail = max_tokens / 2;
+                let head = max_tokens - tail;
+                (0..tokens.byte(head), tokens.byte(total - tail)..text.len())
+            }
+        };
+        Ok(truncated_text(text, head, tail, max_tokens))
+    }
+
+    /// Like [`truncate`](Self::truncate) but only drops whole lines, so we
+    /// might end up with fewer than `max_tokens`
+    pub fn truncate_lines(
+        &self,
+        model: &LLMType,
+        text: &str,
+        max_tokens: usize,
+        side: TruncationSide,
+    ) -> Result<TruncatedText, LLMTokenizerError> {
+        let tokens = self.token_boundaries(model, text)?;
+        if tokens.len() <= max_tokens {
+            return Ok(truncated_text(
+                text,
+                0..text.len(),
+                text.len()..text.len(),
+                tokens.len(),
+            ));
+        }
+        let lines = line_ranges(text)
+            .into_iter()
+            .map(|line| {
+                let line_tokens = tokens.tokens_in(&line);
+                (line, line_tokens)
+            })
+            .collect::<Vec<_>>();
+        // takes the lines from the front (or the back) while they fit
+        let take = |lines: &mut dyn Iterator<Item = &(Range<usize>, usize)>, budget: usize| {
+            let mut used = 0;
+            let mut taken = 0;
+            for (_, line_tokens) in lines {
+                if used + line_tokens > budget {
+                    break;
+                }
+                used += line_tokens;
+                taken += 1;
+            }
+            (taken, used)
+        };
+        let line_start = |index: usize| lines.get(index).map_or(text.len(), |line| line.0.start);
+        let end = text.len();
+        let (head, tail, used) = match side {
+            TruncationSide::Start => {
+                let (taken, used) = take(&mut lines.iter().rev(), max_tokens);
+                (0..0, line_start(lines.len() - taken)..end, used)
+            }
+            TruncationSide::End => {
+                let (taken, used) = take(&mut lines.iter(), max_tokens);
+                (0..line_start(taken), end..end, used)
+            }
+            TruncationSide::Middle => {
+                let (head, head_used) = take(&mut lines.iter(), max_tokens - max_tokens / 2);
+                let (tail, tail_used) =
+                    take(&mut lines[head..].iter().rev(), max_tokens - head_used);
+                (
+                    0..line_start(head),
+                    line_start(lines.len() - tail)..end,
+                    head_used + tail_used,
+                )
+            }
+        };
+        Ok(truncated_text(text, head, tail, used))
+    }
+
+    /// Splits the text in chunks of `chunk_tokens`, each chunk starts with
+    /// the last `overlap_tokens` of the one before it
+    pub fn chunk(
+        &self,
+        model: &LLMType,
+        text: &str,
+        chunk_tokens: usize,
+        overlap_tokens: usize,
+    ) -> Result<Vec<TextChunk>, LLMTokenizerError> {
+        check_chunk_size(chunk_tokens, overlap_tokens)?;
+        let tokens = self.token_boundaries(model, text)?;
+        let total = tokens.len();
+        let mut chunks = vec![];
+        let mut start = 0;
+        while start < total {
+            let end = (start + chunk_tokens).min(total);
+            chunks.push(text_chunk(
+                text,
+                tokens.byte(start)..tokens.byte(end),
+                end - start,
+            ));
+            if end == total {
+                break;
+            }
+            start += chunk_tokens - overlap_tokens;
+        }
+        Ok(chunks)
+    }
+
+    /// Like [`chunk`](Self::chunk) but the chunks are made of whole lines, and
+    /// the overlap is the last lines of the chunk before which fit in
+    /// `overlap_tokens`. A line which is longer than `chunk_tokens` is a chunk
+    /// on its own.
+    pub fn chunk_lines(
+        &self,
+        model: &LLMType,
+        text: &str,
+        chunk_tokens: usize,
+        overlap_tokens: usize,
+    ) -> Result<Vec<TextChunk>, LLMTokenizerError> {
+        check_chunk_size(chunk_tokens, overlap_tokens)?;
+        let tokens = self.token_boundaries(model, text)?;
+        let lines = line_ranges(text);
+        let line_tokens = lines
+            .iter()
+            .map(|line| tokens.tokens_in(line))
+            .collect::<Vec<_>>();
+        let mut chunks = vec![];
+        let mut start = 0;
+        while start < lines.len() {
+            let mut end = start;
+            let mut used = 0;
+            while end < lines.len() && (end == start || used + line_tokens[end] <= chunk_tokens) {
+                used += line_tokens[end];
+                end += 1;
+            }
+            chunks.push(text_chunk(
+                text,
+                lines[start].start..lines[end - 1].end,
+                used,
+            ));
+            if end == lines.len() {
+                break;
+            }
+            // walk back over the lines which fit in the overlap, we always
+            // move forward by at least one line
+            let mut next_start = end;
+            let mut overlap = 0;
+            while next_start > start + 1 && overlap + line_tokens[next_start - 1] <= overlap_tokens
+            {
+                overlap += line_tokens[next_start - 1];
+                next_start -= 1;
+            }
+            start = next_start;
+        }
+        Ok(chunks)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TruncationSide;
+    use crate::{
+        clients::types::LLMType,
+        tokenizer::tokenizer::{LLMTokenizer, LLMTokenizerError},
+    };
+
+    fn code() -> String {
+        (0..40)
+            .map(|index| format!("    let value_{index} = compute(\"résumé\", {index});\n"))
+            .collect()
+    }
+
+    #[test]
+    fn test_truncate() {
+        let tokenizer = LLMTokenizer::new().expect("tokenizer to be created");
+        let code = code();
+        for model in [LLMType::MistralInstruct, LLMType::Gpt4] {
+            for side in [
+                TruncationSide::Start,
+                TruncationSide::End,
+                TruncationSide::Middle,
+            ] {
+                let truncated = tokenizer
+                    .truncate(&model, &code, 50, side)
+                    .expect("truncation to work");
+                assert!(truncated.is_truncated());
+                assert_eq!(truncated.tokens(), 50);
+                let kept = truncated
+                    .ranges()
+                    .iter()
+                    .map(|range| &code[range.clone()])
+                    .collect::<String>();
+                assert_eq!(kept, truncated.text());
+                // the count after the cut is close to what we asked for
+                let tokens = tokenizer
+                    .count_tokens_using_tokenizer(&model, truncated.text())
+                    .expect("counting to work");
+                assert!(tokens.abs_diff(50) <= 2, "{model} {side:?} {tokens}");
+                match side {
+                    TruncationSide::Start => assert!(code.ends_with(truncated.text())),
+                    TruncationSide::End => assert!(code.starts_with(truncated.text())),
+                    TruncationSide::Middle => assert_eq!(truncated.ranges().len(), 2),
+                }
+
+                let lines = tokenizer
+                    .truncate_lines(&model, &code, 50, side)
+                    .expect("truncation to work");
+                assert!(lines.tokens() <= 50);
+                for range in lines.ranges() {
+                    assert!(range.start == 0 || code[..range.start].ends_with('\n'));
+                    assert!(code[..range.end].ends_with('\n'));
+                }
+            }
+        }
+        let short = tokenizer
+            .truncate(&LLMType::Mixtral, "fn main() {}", 50, TruncationSide::Start)
+            .expect("truncation to work");
+        assert!(!short.is_truncated());
+        assert_eq!(short.text(), "fn main() {}");
+    }
+
+    #[test]
+    fn test_chunk() {
+        let tokenizer = LLMTokenizer::new().expect("tokenizer to be created");
+        let code = code();
+        for model in [LLMType::Mixtral, LLMType::Gpt4] {
+            let chunks = tokenizer
+                .chunk(&model, &code, 100, 20)
+                .expect("chunking to work");
+            assert!(chunks.len() > 1);
+            assert_eq!(chunks[0].range().start, 0);
+            assert_eq!(
+                chunks.last().map(|chunk| chunk.range().end),
+                Some(code.len())
+            );
+            for pair in chunks.windows(2) {
+                assert_eq!(pair[0].tokens(), 100);
+                assert!(pair[1].range().start < pair[0].range().end);
+            }
+
+            let chunks = tokenizer
+                .chunk_lines(&model, &code, 100, 20)
+                .expect("chunking to work");
+            let lines = code.lines().collect::<Vec<_>>();
+            for chunk in &chunks {
+                assert!(chunk.tokens() <= 100);
+                let chunk_lines = lines[chunk.start_line()..=chunk.end_line()]
+                    .iter()
+                    .map(|line| format!("{line}\n"))
+                    .collect::<String>();
+                assert_eq!(chunk.text(), chunk_lines);
+            }
+            assert_eq!(chunks.last().map(|chunk| chunk.end_line()), Some(39));
+            for pair in chunks.windows(2) {
+                assert!(pair[1].start_line() <= pair[0].end_line());
+                assert!(pair[1].start_line() > pair[0].start_line());
+            }
+        }
+        assert!(matches!(
+            tokenizer.chunk(&LLMType::Mixtral, &code, 10, 10),
+            Err(LLMTokenizerError::InvalidChunkSize { .. })
+        ));
+    }
+}
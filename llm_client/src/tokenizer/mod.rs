@@ -1 +1,2 @@
+pub mod chunking;
 pub mod tokenizer;
@@ -1,12 +1,17 @@
 //! We are going to run the various tokenizers here, we also make sure to run
 //! the tokenizer in a different thread here, because its important that we
-//! don't block the main thread from working
+//! don't block the main thread from working, see [`LLMTokenizer::count_tokens_async`]
 
+use std::collections::hash_map::DefaultHasher;
 use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::{Arc, Mutex, OnceLock};
 
 use thiserror::Error;
-use tiktoken_rs::ChatCompletionRequestMessage;
+use tiktoken_rs::CoreBPE;
 use tokenizers::Tokenizer;
 
 use crate::{
@@ -19,9 +24,116 @@ use crate::{
     },
 };
 
+/// How many prompt counts we remember before starting over, a rerank over 500
+/// code spans fits many times over
+const MAX_CACHED_COUNTS: usize = 50_000;
+
+/// A rough token count for models we do not have a tokenizer for, ~4
+/// characters per token
+pub fn estimate_tokens(text: &str) -> usize {
+    text.chars().count().div_ceil(4)
+}
+
+fn estimate_input_tokens(input: &LLMTokenizerInput) -> usize {
+    match input {
+        LLMTokenizerInput::Prompt(prompt) => estimate_tokens(prompt),
+        LLMTokenizerInput::Messages(messages) => messages
+            .iter()
+            .map(|message| estimate_tokens(message.content()))
+            .sum(),
+    }
+}
+
+/// Where the tokenizer config comes from, the embedded ones are in the binary
+/// but we only parse them when the model is used
+#[derive(Debug, Clone)]
+enum TokenizerSource {
+    Embedded(&'static str),
+    File(PathBuf),
+}
+
+struct LazyTokenizer {
+    source: TokenizerSource,
+    // the tokenizers error is not `Clone`, so we keep the message around
+    tokenizer: OnceLock<Result<Tokenizer, String>>,
+}
+
+impl LazyTokenizer {
+    fn new(source: TokenizerSource) -> Self {
+        Self {
+            source,
+            tokenizer: OnceLock::new(),
+        }
+    }
+
+    fn get(&self) -> Result<&Tokenizer, LLMTokenizerError> {
+        self.tokenizer
+            .get_or_init(|| {
+                let tokenizer = match &self.source {
+                    TokenizerSource::Embedded(config) => Tokenizer::from_str(config),
+                    TokenizerSource::File(path) => Tokenizer::from_file(path),
+                };
+                tokenizer.map_err(|e| e.to_string())
+            })
+            .as_ref()
+            .map_err(|e| LLMTokenizerError::TokenizerError(e.to_owned()))
+    }
+}
+
+/// The token count along with how we got it, `Approximate` means we did not
+/// have a tokenizer for the model and went with [`estimate_tokens`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenCount {
+    Exact(usize),
+    Approximate(usize),
+}
+
+impl TokenCount {
+    pub fn count(&self) -> usize {
+        match self {
+            TokenCount::Exact(count) | TokenCount::Approximate(count) => *count,
+        }
+    }
+
+    pub fn is_approximate(&self) -> bool {
+        matches!(self, TokenCount::Approximate(_))
+    }
+}
+
+/// The tokens each message takes up in the prompt, in the same order as the
+/// messages. The role markers and the function call or return payloads count
+/// towards the message they belong to, the tokens which belong to the prompt
+/// as a whole (the BOS token, the priming of the openai reply) are in
+/// `format_tokens`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MessagesTokenCount {
+    messages: Vec<usize>,
+    format_tokens: usize,
+}
+
+impl MessagesTokenCount {
+    pub fn messages(&self) -> &[usize] {
+        &self.messages
+    }
+
+    pub fn format_tokens(&self) -> usize {
+        self.format_tokens
+    }
+
+    pub fn total(&self) -> usize {
+        self.format_tokens + self.messages.iter().sum::<usize>()
+    }
+}
+
 pub struct LLMTokenizer {
-    pub tokenizers: HashMap<LLMType, Tokenizer>,
+    tokenizers: HashMap<LLMType, LazyTokenizer>,
     pub formatters: HashMap<LLMType, Box<dyn LLMFormatting + Send + Sync>>,
+    /// Building the BPE for the openai models is expensive, so we do it once
+    /// per model
+    bpes: Mutex<HashMap<String, Arc<CoreBPE>>>,
+    /// The counts by the hash of the model and the prompt, we count the same
+    /// code spans over and over again while building prompts
+    counts: Mutex<HashMap<u64, usize>>,
 }
 
 #[derive(Error, Debug)]
@@ -32,6 +144,18 @@ pub enum LLMTokenizerError {
     #[error("Tokenizer error: {0}")]
     TokenizerError(String),
 
+    #[error("tokenizer task failed: {0}")]
+    TaskError(#[from] tokio::task::JoinError),
+
+    #[error("no tokenizer.json at {0}")]
+    TokenizerFileNotFound(PathBuf),
+
+    #[error("chunks of {chunk_tokens} tokens can not overlap by {overlap_tokens} tokens")]
+    InvalidChunkSize {
+        chunk_tokens: usize,
+        overlap_tokens: usize,
+    },
+
     #[error("error from tokenizer crate: {0}")]
     TokenizerCrateError(#[from] tokenizers::Error),
 
@@ -52,6 +176,8 @@ impl LLMTokenizer {
         let tokenizer = Self {
             tokenizers: HashMap::new(),
             formatters: HashMap::new(),
+            bpes: Mutex::new(HashMap::new()),
+            counts: Mutex::new(HashMap::new()),
         };
         let updated_tokenizer = tokenizer
             .add_llm_type(
@@ -96,84 +222,104 @@ impl LLMTokenizer {
         model: &LLMType,
         input: LLMTokenizerInput,
     ) -> Result<usize, LLMTokenizerError> {
+        if self.is_estimated(model) {
+            return Ok(estimate_input_tokens(&input));
+        }
         match input {
             LLMTokenizerInput::Prompt(prompt) => self.count_tokens_using_tokenizer(model, &prompt),
-            LLMTokenizerInput::Messages(messages) => {
-                // we can't send messages directly to the tokenizer, we have to
-                // either make it a message or its an openai prompt in which case
-                // its fine
-                // so we are going to return an error if its not openai
-                if model.is_openai() {
-                    // we can use the openai tokenizer
-                    let model = self.to_openai_tokenizer(model);
-                    match model {
-                        Some(model) => Ok(tiktoken_rs::num_tokens_from_messages(
-                            &model,
-                            messages
-                                .into_iter()
-                                .map(|message| {
-                                    let role = message.role();
-                                    let content = message.content();
-                                    match role {
-                                        LLMClientRole::User => ChatCompletionRequestMessage {
-                                            role: "user".to_owned(),
-                                            content: Some(content.to_owned()),
-                                            name: None,
-                                            function_call: None,
-                                        },
-                                        LLMClientRole::Assistant => ChatCompletionRequestMessage {
-                                            role: "assistant".to_owned(),
-                                            content: Some(content.to_owned()),
-                                            name: None,
-                                            function_call: None,
-                                        },
-                                        LLMClientRole::System => ChatCompletionRequestMessage {
-                                            role: "system".to_owned(),
-                                            content: Some(content.to_owned()),
-                                            name: None,
-                                            function_call: None,
-                                        },
-                                        LLMClientRole::Function => ChatCompletionRequestMessage {
-                                            role: "function".to_owned(),
-                                            content: Some(content.to_owned()),
-                                            name: None,
-                                            function_call: None,
-                                        },
-                                    }
-                                })
-                                .collect::<Vec<_>>()
-                                .as_slice(),
-                        )?),
-                        None => Err(LLMTokenizerError::TokenizerError(
-                            "Only openai models are supported for messages".to_owned(),
-                        )),
-                    }
-                } else {
-                    let prompt = self
-                        .formatters
-                        .get(model)
-                        .map(|formatter| formatter.to_prompt(messages));
-                    match prompt {
-                        Some(prompt) => {
-                            let num_tokens = self.tokenizers.get(model).map(|tokenizer| {
-                                tokenizer
-                                    .encode(prompt, false)
-                                    .map(|encoding| encoding.len())
-                            });
-                            match num_tokens {
-                                Some(Ok(num_tokens)) => Ok(num_tokens),
-                                _ => Err(LLMTokenizerError::TokenizerError(
-                                    "Failed to encode prompt".to_owned(),
-                                )),
-                            }
-                        }
-                        None => Err(LLMTokenizerError::TokenizerError(
-                            "No formatter found for model".to_owned(),
-                        )),
-                    }
-                }
+            LLMTokenizerInput::Messages(messages) => self
+                .count_message_tokens(model, &messages)
+                .map(|count| count.total()),
+        }
+    }
+
+    /// Counts the tokens for each of the messages as they show up in the
+    /// prompt for the model, so we know what we save by dropping one of them
+    pub fn count_message_tokens(
+        &self,
+        model: &LLMType,
+        messages: &[LLMClientMessage],
+    ) -> Result<MessagesTokenCount, LLMTokenizerError> {
+        if model.is_openai() {
+            let messages = messages
+                .iter()
+                .map(|message| self.openai_message_tokens(model, message))
+                .collect::<Result<Vec<_>, _>>()?;
+            // every reply is primed with <|start|>assistant<|message|>
+            return Ok(MessagesTokenCount {
+                messages,
+                format_tokens: 3,
+            });
+        }
+        let formatter = self.formatters.get(model).ok_or_else(|| {
+            LLMTokenizerError::TokenizerError("No formatter found for model".to_owned())
+        })?;
+        // we tokenize the whole prompt and hand each token to the message it
+        // ends in, counting the messages one by one does not add up since the
+        // tokens at the edges merge differently
+        let mut prompt = formatter.bos_token().unwrap_or_default().to_owned();
+        let mut segment_ends = vec![prompt.len()];
+        let mut skipping = true;
+        for message in messages {
+            // the formatters drop the assistant messages at the start
+            skipping = skipping && message.role().is_assistant();
+            if !skipping {
+                prompt.push_str(&formatter.format_message(message));
             }
+            segment_ends.push(prompt.len());
         }
+        let mut counts = vec![0; segment_ends.len()];
+        for token in self.token_offsets(model, &prompt)? {
+            let segment = segment_ends
+                .partition_point(|segment_end| *segment_end < token.end)
+                .min(counts.len() - 1);
+            counts[segment] += 1;
+        }
+        Ok(MessagesTokenCount {
+            format_tokens: counts[0],
+            messages: counts.split_off(1),
+        })
+    }
+
+    /// This follows what openai does for the chat models: every message has a
+    /// fixed overhead along with its role, the name of the function for the
+    /// function returns and the name and arguments for the function calls
+    fn openai_message_tokens(
+        &self,
+        model: &LLMType,
+        message: &LLMClientMessage,
+    ) -> Result<usize, LLMTokenizerError> {
+        let tokenizer_model = self.to_openai_tokenizer(model).ok_or_else(|| {
+            LLMTokenizerError::TokenizerError(
+                "Only openai models are supported for messages".to_owned(),
+            )
+        })?;
+        // <|start|>{role/name}\n{content}<|end|>\n
+        let (tokens_per_message, tokens_per_name) = if tokenizer_model.starts_with("gpt-3.5") {
+            // if there's a name, the role is omitted
+            (4, -1)
+        } else {
+            (3, 1)
+        };
+        let role = match message.role() {
+            LLMClientRole::User => "user",
+            LLMClientRole::Assistant => "assistant",
+            LLMClientRole::System => "system",
+            LLMClientRole::Function => "function",
+        };
+        let mut tokens = tokens_per_message
+            + self.count_tokens_using_tokenizer(model, role)? as i64
+            + self.count_tokens_using_tokenizer(model, message.content())? as i64;
+        if let Some(function_return) = message.get_function_return() {
+            tokens += self.count_tokens_using_tokenizer(model, function_return.name())? as i64
+                + tokens_per_name
+                + self.count_tokens_using_tokenizer(model, function_return.content())? as i64;
+        }
+        if let Some(function_call) = message.get_function_call() {
+            tokens += self.count_tokens_using_tokenizer(model, function_call.name())? as i64
+                + self.count_tokens_using_tokenizer(model, function_call.arguments())? as i64;
+        }
+        Ok(tokens.max(0) as usize)
     }
 
     pub fn count_tokens_using_tokenizer(
@@ -181,60 +327,210 @@ impl LLMTokenizer {
         model: &LLMType,
         prompt: &str,
     ) -> Result<usize, LLMTokenizerError> {
+        let key = {
+            let mut hasher = DefaultHasher::new();
+            model.hash(&mut hasher);
+            prompt.hash(&mut hasher);
+            hasher.finish()
+        };
+        if let Some(count) = self
+            .counts
+            .lock()
+            .expect("lock to not be poisoned")
+            .get(&key)
+        {
+            return Ok(*count);
+        }
+        let count = self.encode_len(model, prompt)?;
+        let mut counts = self.counts.lock().expect("lock to not be poisoned");
+        if counts.len() >= MAX_CACHED_COUNTS {
+            counts.clear();
+        }
+        counts.insert(key, count);
+        Ok(count)
+    }
+
+    fn encode_len(&self, model: &LLMType, prompt: &str) -> Result<usize, LLMTokenizerError> {
         // we have the custom tokenizers already loaded, if this is not the openai loop
         if !model.is_openai() {
-            let tokenizer = self.tokenizers.get(model);
-            match tokenizer {
-                Some(tokenizer) => {
-                    // Now over here we will try to figure out how to pass the
-                    // values around
-                    let result = tokenizer.encode(prompt, false);
-                    match result {
-                        Ok(encoding) => Ok(encoding.len()),
-                        Err(e) => Err(LLMTokenizerError::TokenizerError(format!(
-                            "Failed to encode prompt: {}",
-                            e
-                        ))),
-                    }
-                }
-                None => {
-                    return Err(LLMTokenizerError::TokenizerNotFound(model.clone()));
-                }
+            let result = self.tokenizer(model)?.encode(prompt, false);
+            match result {
+                Ok(encoding) => Ok(encoding.len()),
+                Err(e) => Err(LLMTokenizerError::TokenizerError(format!(
+                    "Failed to encode prompt: {}",
+                    e
+                ))),
             }
         } else {
             // If we are using openai model, then we have to use the bpe config
             // and count the number of tokens
-            let model = self.to_openai_tokenizer(model);
-            if let None = model {
-                return Err(LLMTokenizerError::TokenizerError(
-                    "OpenAI model not found".to_owned(),
-                ));
-            }
-            let model = model.expect("if let None to hold");
-            let bpe = tiktoken_rs::get_bpe_from_model(&model)?;
-            Ok(bpe.encode_ordinary(prompt).len())
+            Ok(self.bpe(model)?.encode_ordinary(prompt).len())
         }
     }
 
+    fn bpe(&self, model: &LLMType) -> Result<Arc<CoreBPE>, LLMTokenizerError> {
+        let model = self.to_openai_tokenizer(model).ok_or_else(|| {
+            LLMTokenizerError::TokenizerError("OpenAI model not found".to_owned())
+        })?;
+        // we hold the lock while building so we only build it once
+        let mut bpes = self.bpes.lock().expect("lock to not be poisoned");
+        if let Some(bpe) = bpes.get(&model) {
+            return Ok(bpe.clone());
+        }
+        let bpe = Arc::new(tiktoken_rs::get_bpe_from_model(&model)?);
+        bpes.insert(model, bpe.clone());
+        Ok(bpe)
+    }
+
+    /// Forgets the counts we remembered, the tokenizers stay loaded
+    pub fn clear_count_cache(&self) {
+        self.counts.lock().expect("lock to not be poisoned").clear();
+    }
+
+    /// [`count_tokens`](Self::count_tokens) on the blocking pool, so counting a
+    /// large input does not hold up the runtime
+    pub async fn count_tokens_async(
+        self: Arc<Self>,
+        model: LLMType,
+        input: LLMTokenizerInput,
+    ) -> Result<usize, LLMTokenizerError> {
+        tokio::task::spawn_blocking(move || self.count_tokens(&model, input)).await?
+    }
+
+    /// Counts all the prompts in a single task on the blocking pool, the counts
+    /// are in the same order as the prompts
+    pub async fn count_prompts_async(
+        self: Arc<Self>,
+        model: LLMType,
+        prompts: Vec<String>,
+    ) -> Result<Vec<usize>, LLMTokenizerError> {
+        tokio::task::spawn_blocking(move || {
+            prompts
+                .iter()
+                .map(|prompt| self.count_tokens_using_tokenizer(&model, prompt))
+                .collect()
+        })
+        .await?
+    }
+
+    /// The byte range in the prompt for each of the tokens, the ranges are in
+    /// order and do not overlap. A token which is only part of a character
+    /// (byte fallback) can get an empty range.
+    pub fn token_offsets(
+        &self,
+        model: &LLMType,
+        prompt: &str,
+    ) -> Result<Vec<Range<usize>>, LLMTokenizerError> {
+        let ends = if self.to_openai_tokenizer(model).is_some() {
+            let bpe = self.bpe(model)?;
+            bpe.encode_ordinary(prompt)
+                .into_iter()
+                .scan(0, |end, token| {
+                    *end += bpe._decode_native(&[token]).len();
+                    Some(*end)
+                })
+                .collect::<Vec<_>>()
+        } else {
+            self.tokenizer(model)?
+                .encode(prompt, false)
+                .map_err(|e| {
+                    LLMTokenizerError::TokenizerError(format!("Failed to encode prompt: {}", e))
+                })?
+                .get_offsets()
+                .iter()
+                .map(|(_, end)| *end)
+                .collect()
+        };
+        // the offsets do not always line up with the tokens (the spaces which
+        // sentencepiece adds, merged characters), so we make sure they move
+        // forward and stay on character boundaries
+        let mut start = 0;
+        Ok(ends
+            .into_iter()
+            .map(|end| {
+                let mut end = end.clamp(start, prompt.len());
+                while !prompt.is_char_boundary(end) {
+                    end += 1;
+                }
+                let range = start..end;
+                start = end;
+                range
+            })
+            .collect())
+    }
+
+    /// Like [`count_tokens`](Self::count_tokens) but never fails, when we can
+    /// not tokenize for the model we fall back to an estimate and say so
+    pub fn count_tokens_or_estimate(
+        &self,
+        model: &LLMType,
+        input: LLMTokenizerInput,
+    ) -> TokenCount {
+        let estimate = estimate_input_tokens(&input);
+        if self.is_estimated(model) {
+            return TokenCount::Approximate(estimate);
+        }
+        match self.count_tokens(model, input) {
+            Ok(count) => TokenCount::Exact(count),
+            Err(_) => TokenCount::Approximate(estimate),
+        }
+    }
+
+    /// If we can count the tokens exactly for the model
+    pub fn has_tokenizer(&self, model: &LLMType) -> bool {
+        self.to_openai_tokenizer(model).is_some() || self.tokenizers.contains_key(model)
+    }
+
+    /// We do not embed the CodeLlama and StarCoder tokenizers, so their counts
+    /// are always estimated unless a tokenizer gets loaded for them with
+    /// [`load_tokenizer_from_path`](Self::load_tokenizer_from_path)
+    fn is_estimated(&self, model: &LLMType) -> bool {
+        matches!(model, LLMType::CodeLlama | LLMType::StarCoder) && !self.has_tokenizer(model)
+    }
+
+    fn tokenizer(&self, model: &LLMType) -> Result<&Tokenizer, LLMTokenizerError> {
+        self.tokenizers
+            .get(model)
+            .ok_or_else(|| LLMTokenizerError::TokenizerNotFound(model.clone()))?
+            .get()
+    }
+
+    /// Registers the embedded tokenizer for the model, the config is parsed
+    /// the first time we count tokens with it
     pub fn load_tokenizer(&mut self, model: &LLMType) -> Result<(), LLMTokenizerError> {
-        let tokenizer = match model {
-            LLMType::MistralInstruct => {
-                let config = include_str!("configs/mistral.json");
-                Some(Tokenizer::from_str(config)?)
-            }
-            LLMType::Mixtral => {
-                let config = include_str!("configs/mixtral.json");
-                Some(Tokenizer::from_str(config)?)
-            }
-            LLMType::DeepSeekCoder => {
-                let config = include_str!("configs/deepseekcoder.json");
-                Some(Tokenizer::from_str(config)?)
-            }
-            _ => None,
+        let config = match model {
+            LLMType::MistralInstruct => include_str!("configs/mistral.json"),
+            LLMType::Mixtral => include_str!("configs/mixtral.json"),
+            LLMType::DeepSeekCoder => include_str!("configs/deepseekcoder.json"),
+            _ => return Err(LLMTokenizerError::TokenizerNotFound(model.clone())),
         };
-        if let Some(tokenizer) = tokenizer {
-            self.tokenizers.insert(model.clone(), tokenizer);
+        self.tokenizers.insert(
+            model.clone(),
+            LazyTokenizer::new(TokenizerSource::Embedded(config)),
+        );
+        Ok(())
+    }
+
+    /// Uses the `tokenizer.json` at the path for the model, the path can also
+    /// be a local model directory which has the `tokenizer.json` in it. This
+    /// is how `LLMType::Custom` models get a tokenizer, it also replaces the
+    /// embedded one for the model.
+    pub fn load_tokenizer_from_path(
+        &mut self,
+        model: LLMType,
+        path: impl AsRef<Path>,
+    ) -> Result<(), LLMTokenizerError> {
+        let path = path.as_ref();
+        let path = if path.is_dir() {
+            path.join("tokenizer.json")
+        } else {
+            path.to_owned()
+        };
+        if !path.is_file() {
+            return Err(LLMTokenizerError::TokenizerFileNotFound(path));
         }
+        self.tokenizers
+            .insert(model, LazyTokenizer::new(TokenizerSource::File(path)));
         Ok(())
     }
 }
@@ -244,9 +540,207 @@ mod tests {
     use std::str::FromStr;
     use tokenizers::Tokenizer;
 
+    use std::sync::Arc;
+
+    use super::{LLMTokenizer, LLMTokenizerError, LLMTokenizerInput, TokenCount};
+    use crate::clients::types::{LLMClientMessage, LLMType};
+
     #[test]
     fn test_loading_deepseek_tokenizer_works() {
         let tokenizer_file = include_str!("configs/deepseekcoder.json");
         let _ = Tokenizer::from_str(tokenizer_file).unwrap();
     }
+
+    #[test]
+    fn test_embedded_tokenizers_are_loaded_lazily() {
+        let tokenizer = LLMTokenizer::new().expect("tokenizer to be created");
+        let mixtral = &tokenizer.tokenizers[&LLMType::Mixtral];
+        assert!(mixtral.tokenizer.get().is_none());
+        assert!(
+            tokenizer
+                .count_tokens_using_tokenizer(&LLMType::Mixtral, "fn main() {}")
+                .expect("counting to work")
+                > 0
+        );
+        assert!(mixtral.tokenizer.get().is_some());
+        assert!(tokenizer.tokenizers[&LLMType::MistralInstruct]
+            .tokenizer
+            .get()
+            .is_none());
+    }
+
+    #[test]
+    fn test_code_models_are_estimated() {
+        let tokenizer = LLMTokenizer::new().expect("tokenizer to be created");
+        let prompt = "fn main() { println!(\"hello\"); }";
+        for model in [LLMType::CodeLlama, LLMType::StarCoder] {
+            assert_eq!(
+                tokenizer
+                    .count_tokens(&model, LLMTokenizerInput::Prompt(prompt.to_owned()))
+                    .expect("counting to work"),
+                8
+            );
+            assert_eq!(
+                tokenizer.count_tokens_or_estimate(
+                    &model,
+                    LLMTokenizerInput::Messages(vec![LLMClientMessage::user(prompt.to_owned())])
+                ),
+                TokenCount::Approximate(8)
+            );
+        }
+    }
+
+    #[test]
+    fn test_custom_tokenizer_from_model_dir() {
+        let model_dir = tempfile::tempdir().expect("model dir to be created");
+        let model_dir = model_dir.path();
+        std::fs::write(
+            model_dir.join("tokenizer.json"),
+            include_str!("configs/deepseekcoder.json"),
+        )
+        .expect("tokenizer to be written");
+
+        let custom = LLMType::Custom("local-deepseek".to_owned());
+        let mut tokenizer = LLMTokenizer::new().expect("tokenizer to be created");
+        let prompt = "fn main() { println!(\"hello\"); }";
+        assert!(matches!(
+            tokenizer.count_tokens_using_tokenizer(&custom, prompt),
+            Err(LLMTokenizerError::TokenizerNotFound(_))
+        ));
+        assert_eq!(
+            tokenizer
+                .count_tokens_or_estimate(&custom, LLMTokenizerInput::Prompt(prompt.to_owned())),
+            TokenCount::Approximate(8)
+        );
+
+        assert!(matches!(
+            tokenizer.load_tokenizer_from_path(custom.clone(), model_dir.join("missing")),
+            Err(LLMTokenizerError::TokenizerFileNotFound(_))
+        ));
+        tokenizer
+            .load_tokenizer_from_path(custom.clone(), model_dir)
+            .expect("tokenizer to be loaded");
+        assert!(tokenizer.has_tokenizer(&custom));
+        let count = tokenizer
+            .count_tokens_or_estimate(&custom, LLMTokenizerInput::Prompt(prompt.to_owned()));
+        assert!(!count.is_approximate());
+        assert_eq!(
+            count.count(),
+            tokenizer
+                .count_tokens_using_tokenizer(&LLMType::DeepSeekCoder, prompt)
+                .expect("counting to work")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_cached_and_async_counts() {
+        let tokenizer = Arc::new(LLMTokenizer::new().expect("tokenizer to be created"));
+        let prompts = vec!["fn main() {}".to_owned(), "let value = 1;".to_owned()];
+        let sync_counts = prompts
+            .iter()
+            .map(|prompt| {
+                tokenizer
+                    .count_tokens_using_tokenizer(&LLMType::Gpt4, prompt)
+                    .expect("counting to work")
+            })
+            .collect::<Vec<_>>();
+        // the BPE is built once and the counts are remembered
+        assert_eq!(tokenizer.bpes.lock().expect("lock").len(), 1);
+        assert_eq!(tokenizer.counts.lock().expect("lock").len(), 2);
+
+        let async_counts = tokenizer
+            .clone()
+            .count_prompts_async(LLMType::Gpt4, prompts.clone())
+            .await
+            .expect("counting to work");
+        assert_eq!(async_counts, sync_counts);
+        assert_eq!(tokenizer.counts.lock().expect("lock").len(), 2);
+
+        tokenizer.clear_count_cache();
+        let count = tokenizer
+            .clone()
+            .count_tokens_async(
+                LLMType::Mixtral,
+                LLMTokenizerInput::Prompt(prompts[0].to_owned()),
+            )
+            .await
+            .expect("counting to work");
+        assert_eq!(
+            count,
+            tokenizer
+                .count_tokens_using_tokenizer(&LLMType::Mixtral, &prompts[0])
+                .expect("counting to work")
+        );
+    }
+
+    #[test]
+    fn test_message_token_breakdown() {
+        let tokenizer = LLMTokenizer::new().expect("tokenizer to be created");
+        let arguments = r#"{"query":"where do we count tokens"}"#;
+        let messages = vec![
+            LLMClientMessage::assistant("dropped by the formatter".to_owned()),
+            LLMClientMessage::user("find the tokenizer".to_owned()),
+            LLMClientMessage::function_call("search".to_owned(), arguments.to_owned()),
+            LLMClientMessage::function_return(
+                "search".to_owned(),
+                "llm_client/src/tokenizer/tokenizer.rs".to_owned(),
+            ),
+        ];
+
+        let count = tokenizer
+            .count_message_tokens(&LLMType::Mixtral, &messages)
+            .expect("counting to work");
+        let prompt = tokenizer.formatters[&LLMType::Mixtral].to_prompt(messages.to_vec());
+        assert_eq!(
+            count.total(),
+            tokenizer
+                .count_tokens_using_tokenizer(&LLMType::Mixtral, &prompt)
+                .expect("counting to work")
+        );
+        // the BOS token, and nothing for the assistant message we skip
+        assert_eq!(count.format_tokens(), 1);
+        assert_eq!(count.messages()[0], 0);
+        assert!(
+            count.messages()[2]
+                > tokenizer
+                    .count_tokens_using_tokenizer(&LLMType::Mixtral, arguments)
+                    .expect("counting to work")
+        );
+
+        // without the function payloads we match what tiktoken counts
+        let plain_messages = vec![
+            LLMClientMessage::system("you are a helpful assistant".to_owned()),
+            LLMClientMessage::user("find the tokenizer".to_owned()),
+        ];
+        let count = tokenizer
+            .count_message_tokens(&LLMType::Gpt4, &plain_messages)
+            .expect("counting to work");
+        let tiktoken_count = tiktoken_rs::num_tokens_from_messages(
+            "gpt-4-0613",
+            &plain_messages
+                .iter()
+                .map(|message| tiktoken_rs::ChatCompletionRequestMessage {
+                    role: if message.role().is_system() {
+                        "system".to_owned()
+                    } else {
+                        "user".to_owned()
+                    },
+                    content: Some(message.content().to_owned()),
+                    name: None,
+                    function_call: None,
+                })
+                .collect::<Vec<_>>(),
+        )
+        .expect("tiktoken to work");
+        assert_eq!(count.total(), tiktoken_count);
+        let count = tokenizer
+            .count_message_tokens(&LLMType::Gpt4, &messages)
+            .expect("counting to work");
+        assert!(
+            count.messages()[2]
+                > tokenizer
+                    .count_tokens_using_tokenizer(&LLMType::Gpt4, arguments)
+                    .expect("counting to work")
+        );
+    }
 }
@@ -0,0 +1,28 @@
+-- Add migration script here
+CREATE TABLE sessions (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    session_type TEXT NOT NULL,
+    file_path TEXT,
+    language TEXT,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX sessions_file_path_idx ON sessions (file_path, updated_at);
+
+CREATE TABLE session_turns (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    session_id INTEGER NOT NULL REFERENCES sessions (id) ON DELETE CASCADE,
+    user_query TEXT NOT NULL,
+    prompt TEXT NOT NULL,
+    response TEXT,
+    llm_type TEXT NOT NULL,
+    file_path TEXT,
+    start_line INTEGER,
+    end_line INTEGER,
+    edit_status TEXT NOT NULL DEFAULT 'pending',
+    accepted_edit TEXT,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX session_turns_session_id_idx ON session_turns (session_id);
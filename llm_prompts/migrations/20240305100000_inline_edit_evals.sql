@@ -0,0 +1,28 @@
+-- Add migration script here
+CREATE TABLE inline_edit_eval_runs (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    dataset TEXT NOT NULL,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX inline_edit_eval_runs_dataset_idx ON inline_edit_eval_runs (dataset, created_at);
+
+CREATE TABLE inline_edit_eval_results (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    run_id INTEGER NOT NULL REFERENCES inline_edit_eval_runs (id) ON DELETE CASCADE,
+    case_id TEXT NOT NULL,
+    llm_type TEXT NOT NULL,
+    template_name TEXT NOT NULL,
+    template_version INTEGER NOT NULL,
+    output TEXT,
+    exact_match BOOLEAN,
+    similarity REAL,
+    check_passed BOOLEAN,
+    check_output TEXT,
+    error TEXT,
+    latency_ms REAL NOT NULL,
+    llm_data_id INTEGER,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX inline_edit_eval_results_run_id_idx ON inline_edit_eval_results (run_id);
@@ -0,0 +1,181 @@
+//! Cuts the code around the cursor down to the token budget, we keep the lines
+//! closest to the cursor since those matter the most for what comes next
+
+use llm_client::{
+    clients::types::LLMType,
+    tokenizer::{
+        chunking::TruncationSide,
+        tokenizer::{LLMTokenizer, LLMTokenizerInput},
+    },
+};
+
+use super::types::{AutocompleteConfig, AutocompleteError};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AutocompleteContext {
+    context: Option<String>,
+    prefix: String,
+    suffix: String,
+    context_tokens: usize,
+    prefix_tokens: usize,
+    suffix_tokens: usize,
+}
+
+impl AutocompleteContext {
+    /// The suffix gets its share first since it is usually less useful than
+    /// the prefix, the prefix takes whatever is left. The extra context is
+    /// counted against the budget but never cut, so keep it small.
+    pub fn build(
+        tokenizer: &LLMTokenizer,
+        model: &LLMType,
+        prefix: &str,
+        suffix: &str,
+        context: Option<&str>,
+        config: &AutocompleteConfig,
+    ) -> Result<Self, AutocompleteError> {
+        let context_tokens = context
+            .map(|context| {
+                tokenizer
+                    .count_tokens_or_estimate(model, LLMTokenizerInput::Prompt(context.to_owned()))
+                    .count()
+            })
+            .unwrap_or_default();
+        let budget = config.context_tokens().saturating_sub(context_tokens);
+        let (suffix, suffix_tokens) = truncate(
+            tokenizer,
+            model,
+            suffix,
+            config.max_suffix_tokens().min(budget),
+            TruncationSide::End,
+        )?;
+        let (prefix, prefix_tokens) = truncate(
+            tokenizer,
+            model,
+            prefix,
+            // the count for the cut suffix can come out over the budget, the
+            // prefix gets nothing then
+            budget.saturating_sub(suffix_tokens),
+            TruncationSide::Start,
+        )?;
+        Ok(Self {
+            context: context.map(|context| context.to_owned()),
+            prefix,
+            suffix,
+            context_tokens,
+            prefix_tokens,
+            suffix_tokens,
+        })
+    }
+
+    pub fn context(&self) -> Option<&str> {
+        self.context.as_deref()
+    }
+
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    pub fn suffix(&self) -> &str {
+        &self.suffix
+    }
+
+    pub fn total_tokens(&self) -> usize {
+        self.context_tokens + self.prefix_tokens + self.suffix_tokens
+    }
+}
+
+/// Drops whole lines when we can, if not even the line at the cursor fits we
+/// cut inside of it. Models without a tokenizer get an estimate of 4 chars to
+/// a token.
+fn truncate(
+    tokenizer: &LLMTokenizer,
+    model: &LLMType,
+    text: &str,
+    max_tokens: usize,
+    side: TruncationSide,
+) -> Result<(String, usize), AutocompleteError> {
+    if !tokenizer.has_tokenizer(model) {
+        return Ok(truncate_estimated(text, max_tokens, side));
+    }
+    let truncated = tokenizer.truncate_lines(model, text, max_tokens, side)?;
+    if !truncated.text().is_empty() || text.is_empty() {
+        let tokens = truncated.tokens();
+        return Ok((truncated.into_text(), tokens));
+    }
+    let truncated = tokenizer.truncate(model, text, max_tokens, side)?;
+    let tokens = truncated.tokens();
+    Ok((truncated.into_text(), tokens))
+}
+
+fn truncate_estimated(text: &str, max_tokens: usize, side: TruncationSide) -> (String, usize) {
+    let max_chars = max_tokens * 4;
+    let chars = text.chars().count();
+    if chars <= max_chars {
+        return (text.to_owned(), chars.div_ceil(4));
+    }
+    let text = match side {
+        TruncationSide::End => {
+            let end = text
+                .char_indices()
+                .nth(max_chars)
+                .map_or(text.len(), |(index, _)| index);
+            // we keep the lines which fit, unless not even one does
+            match text[..end].rfind('\n') {
+                Some(newline) => &text[..newline + 1],
+                None => &text[..end],
+            }
+        }
+        _ => {
+            let start = text
+                .char_indices()
+                .nth(chars - max_chars)
+                .map_or(text.len(), |(index, _)| index);
+            match text[start..].find('\n') {
+                Some(newline) if start + newline + 1 < text.len() => &text[start + newline + 1..],
+                _ => &text[start..],
+            }
+        }
+    };
+    (text.to_owned(), text.chars().count().div_ceil(4))
+}
+
+#[cfg(test)]
+mod tests {
+    use llm_client::{clients::types::LLMType, tokenizer::tokenizer::LLMTokenizer};
+
+    use super::AutocompleteContext;
+    use crate::autocomplete::types::AutocompleteConfig;
+
+    #[test]
+    fn test_context_keeps_the_lines_next_to_the_cursor() {
+        let tokenizer = LLMTokenizer::new().expect("tokenizer to load");
+        let prefix = (0..200)
+            .map(|index| format!("let value_{index} = {index};\n"))
+            .collect::<String>()
+            + "let total = ";
+        let suffix = (200..400)
+            .map(|index| format!("\nlet value_{index} = {index};"))
+            .collect::<String>();
+        let config = AutocompleteConfig::new()
+            .set_context_tokens(256)
+            .set_max_suffix_tokens(64);
+        for model in [LLMType::DeepSeekCoder, LLMType::StarCoder] {
+            let context = AutocompleteContext::build(
+                &tokenizer,
+                &model,
+                &prefix,
+                &suffix,
+                Some("// src/values.rs\n"),
+                &config,
+            )
+            .expect("context to build");
+            assert!(context.total_tokens() <= 256);
+            assert!(context
+                .prefix()
+                .ends_with("let value_199 = 199;\nlet total = "));
+            assert!(context.prefix().starts_with("let value_"));
+            assert!(context.suffix().starts_with("\nlet value_200 = 200;"));
+            assert!(context.suffix().len() < suffix.len());
+        }
+    }
+}
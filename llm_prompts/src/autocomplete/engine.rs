@@ -0,0 +1,495 @@
+//! Serves the inline completions while the user is typing. Every keystroke
+//! sends a request, we wait for the user to stop typing before asking the
+//! model and drop the requests which a newer one for the same document took
+//! over, even when they are already streaming. When the user types what we suggested we keep
+//! showing the rest of the earlier completion instead of asking again.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+use llm_client::{
+    broker::LLMBroker,
+    clients::types::{LLMClientCompletionFIMRequest, LLMType},
+    provider::{LLMProvider, LLMProviderAPIKeys},
+    rate_limit::{RequestPriority, PRIORITY_METADATA_KEY},
+    tokenizer::tokenizer::LLMTokenizer,
+};
+use tokio::sync::watch;
+
+use super::{
+    context::AutocompleteContext,
+    trim::trim_completion,
+    types::{
+        AutocompleteConfig, AutocompleteError, AutocompleteRequest, AutocompleteStats,
+        InlineCompletion,
+    },
+};
+
+/// A completion we showed, we can keep showing it as long as the user types
+/// along with it
+struct CachedCompletion {
+    document_id: String,
+    /// The text of the document before and after the cursor when we asked
+    prefix: String,
+    suffix: String,
+    completion: String,
+    llm_data_id: i64,
+}
+
+impl CachedCompletion {
+    /// What is left of the completion after the text the user typed since
+    fn remaining(&self, document_id: &str, prefix: &str, suffix: &str) -> Option<&str> {
+        if self.document_id != document_id || self.suffix != suffix {
+            return None;
+        }
+        let typed = prefix.strip_prefix(self.prefix.as_str())?;
+        self.completion
+            .strip_prefix(typed)
+            .filter(|remaining| !remaining.is_empty())
+    }
+}
+
+/// Drops the generation of the document once its latest request is done, so
+/// we do not keep one around for every document the user ever typed in
+struct GenerationGuard<'a> {
+    generations: &'a Mutex<HashMap<String, watch::Sender<u64>>>,
+    document_id: String,
+    generation: u64,
+}
+
+impl Drop for GenerationGuard<'_> {
+    fn drop(&mut self) {
+        let mut generations = self.generations.lock().expect("lock to not be poisoned");
+        // a newer request for the document still needs it
+        if generations
+            .get(&self.document_id)
+            .is_some_and(|sender| *sender.borrow() == self.generation)
+        {
+            generations.remove(&self.document_id);
+        }
+    }
+}
+
+pub struct AutocompleteEngine {
+    llm_broker: Arc<LLMBroker>,
+    tokenizer: Arc<LLMTokenizer>,
+    llm_type: LLMType,
+    provider: LLMProvider,
+    api_keys: LLMProviderAPIKeys,
+    config: AutocompleteConfig,
+    /// Goes up with every request for the document, a request is stale once
+    /// it moves past the value it got
+    generations: Mutex<HashMap<String, watch::Sender<u64>>>,
+    /// The most recently used completions first
+    cache: Mutex<VecDeque<CachedCompletion>>,
+    stats: Mutex<AutocompleteStats>,
+}
+
+impl AutocompleteEngine {
+    pub fn new(
+        llm_broker: Arc<LLMBroker>,
+        tokenizer: Arc<LLMTokenizer>,
+        llm_type: LLMType,
+        provider: LLMProvider,
+        api_keys: LLMProviderAPIKeys,
+    ) -> Self {
+        Self {
+            llm_broker,
+            tokenizer,
+            llm_type,
+            provider,
+            api_keys,
+            config: AutocompleteConfig::default(),
+            generations: Mutex::new(HashMap::new()),
+            cache: Mutex::new(VecDeque::new()),
+            stats: Mutex::new(AutocompleteStats::default()),
+        }
+    }
+
+    pub fn set_config(mut self, config: AutocompleteConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn config(&self) -> &AutocompleteConfig {
+        &self.config
+    }
+
+    pub fn stats(&self) -> AutocompleteStats {
+        *self.stats.lock().expect("lock to not be poisoned")
+    }
+
+    /// Call this when the document changes outside of typing, like a reload
+    pub fn clear_cache(&self) {
+        self.cache.lock().expect("lock to not be poisoned").clear();
+    }
+
+    /// The completion at the cursor, this fails with `Cancelled` when a newer
+    /// request for the same document comes in before we have the answer
+    pub async fn complete(
+        &self,
+        request: AutocompleteRequest,
+    ) -> Result<InlineCompletion, AutocompleteError> {
+        let (generation, mut newer_request) = self.next_generation(request.document_id());
+        let _generation_guard = GenerationGuard {
+            generations: &self.generations,
+            document_id: request.document_id().to_owned(),
+            generation,
+        };
+        let cursor_offset = request.cursor().to_byte_offset(request.text())?;
+        let (prefix, suffix) = request.text().split_at(cursor_offset);
+        if let Some((completion, llm_data_id)) =
+            self.cached_completion(request.document_id(), prefix, suffix)
+        {
+            self.update_stats(|stats| stats.cache_hits += 1);
+            return Ok(InlineCompletion::new(
+                completion,
+                cursor_offset,
+                true,
+                llm_data_id,
+            ));
+        }
+
+        // wait for the user to stop typing
+        tokio::select! {
+            _ = tokio::time::sleep(self.config.debounce()) => {}
+            _ = newer_request.wait_for(|current| *current != generation) => {
+                return Err(self.cancelled());
+            }
+        }
+
+        let context = AutocompleteContext::build(
+            &self.tokenizer,
+            &self.llm_type,
+            prefix,
+            suffix,
+            request.context(),
+            &self.config,
+        )?;
+        let fim_request = LLMClientCompletionFIMRequest::new(
+            self.llm_type.clone(),
+            context.prefix().to_owned(),
+            context.suffix().to_owned(),
+            self.config.temperature(),
+        )
+        .set_context(context.context().map(|context| context.to_owned()));
+        let metadata = HashMap::from([
+            ("event_type".to_owned(), "autocomplete".to_owned()),
+            ("document_id".to_owned(), request.document_id().to_owned()),
+            // the ghost text is only useful while the user waits for it
+            (
+                PRIORITY_METADATA_KEY.to_owned(),
+                RequestPriority::Interactive.as_str().to_owned(),
+            ),
+        ]);
+        let (sender, _receiver) = tokio::sync::mpsc::unbounded_channel();
+        self.update_stats(|stats| stats.model_requests += 1);
+        // dropping the broker future stops the stream from the provider
+        let answer = tokio::select! {
+            answer = self.llm_broker.stream_fim_completion(
+                self.api_keys.clone(),
+                fim_request,
+                self.provider.clone(),
+                metadata,
+                sender,
+            ) => answer?,
+            _ = newer_request.wait_for(|current| *current != generation) => {
+                return Err(self.cancelled());
+            }
+        };
+
+        let line_suffix = suffix.split('\n').next().unwrap_or_default();
+        let completion = trim_completion(
+            answer.answer(),
+            line_suffix,
+            suffix,
+            self.config.max_lines(),
+        );
+        if !completion.is_empty() {
+            self.cache_completion(CachedCompletion {
+                document_id: request.document_id().to_owned(),
+                prefix: prefix.to_owned(),
+                suffix: suffix.to_owned(),
+                completion: completion.to_owned(),
+                llm_data_id: answer.llm_data_id(),
+            });
+        }
+        Ok(InlineCompletion::new(
+            completion,
+            cursor_offset,
+            false,
+            answer.llm_data_id(),
+        ))
+    }
+
+    /// Bumps the generation of the document, the receiver sees the bump of
+    /// the next request
+    fn next_generation(&self, document_id: &str) -> (u64, watch::Receiver<u64>) {
+        let mut generations = self.generations.lock().expect("lock to not be poisoned");
+        let sender = generations
+            .entry(document_id.to_owned())
+            .or_insert_with(|| watch::Sender::new(0));
+        let mut generation = 0;
+        sender.send_modify(|current| {
+            *current += 1;
+            generation = *current;
+        });
+        (generation, sender.subscribe())
+    }
+
+    fn cached_completion(
+        &self,
+        document_id: &str,
+        prefix: &str,
+        suffix: &str,
+    ) -> Option<(String, i64)> {
+        let mut cache = self.cache.lock().expect("lock to not be poisoned");
+        let (index, completion) = cache.iter().enumerate().find_map(|(index, cached)| {
+            cached
+                .remaining(document_id, prefix, suffix)
+                .map(|remaining| (index, (remaining.to_owned(), cached.llm_data_id)))
+        })?;
+        if let Some(cached) = cache.remove(index) {
+            cache.push_front(cached);
+        }
+        Some(completion)
+    }
+
+    fn cache_completion(&self, completion: CachedCompletion) {
+        let mut cache = self.cache.lock().expect("lock to not be poisoned");
+        cache.push_front(completion);
+        cache.truncate(self.config.cache_size());
+    }
+
+    fn cancelled(&self) -> AutocompleteError {
+        self.update_stats(|stats| stats.cancelled += 1);
+        AutocompleteError::Cancelled
+    }
+
+    fn update_stats(&self, update: impl FnOnce(&mut AutocompleteStats)) {
+        update(&mut self.stats.lock().expect("lock to not be poisoned"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use llm_client::{
+        broker::LLMBroker,
+        clients::{
+            mock::{MockLLMClient, MockResponse},
+            types::{LLMClientCapabilities, LLMType},
+        },
+        config::LLMBrokerConfiguration,
+        llm_data::LLMDataFilter,
+        provider::{LLMProvider, LLMProviderAPIKeys, TogetherAIProvider},
+        rate_limit::RequestPriority,
+        tokenizer::tokenizer::LLMTokenizer,
+    };
+    use tokio::task::JoinHandle;
+
+    use super::AutocompleteEngine;
+    use crate::autocomplete::types::{
+        AutocompleteConfig, AutocompleteError, AutocompleteRequest, AutocompleteStats,
+        CursorPosition, InlineCompletion,
+    };
+
+    const CURSOR: &str = "<|>";
+
+    /// Types into a document like the user would, every keystroke asks the
+    /// engine for a completion without waiting for the earlier ones
+    struct TypingSession {
+        engine: Arc<AutocompleteEngine>,
+        document_id: String,
+        text: String,
+        cursor: usize,
+        pending: Vec<JoinHandle<Result<InlineCompletion, AutocompleteError>>>,
+    }
+
+    impl TypingSession {
+        /// The document has the cursor marked with `<|>`
+        fn new(engine: Arc<AutocompleteEngine>, document_id: &str, document: &str) -> Self {
+            let cursor = document.find(CURSOR).expect("document to have a cursor");
+            Self {
+                engine,
+                document_id: document_id.to_owned(),
+                text: document.replacen(CURSOR, "", 1),
+                cursor,
+                pending: vec![],
+            }
+        }
+
+        async fn type_text(&mut self, typed: &str, delay: Duration) {
+            for character in typed.chars() {
+                self.text.insert(self.cursor, character);
+                self.cursor += character.len_utf8();
+                let before_cursor = &self.text[..self.cursor];
+                let line = before_cursor.matches('\n').count();
+                let line_start = before_cursor.rfind('\n').map_or(0, |index| index + 1);
+                let cursor =
+                    CursorPosition::new(line, before_cursor[line_start..].encode_utf16().count());
+                let request = AutocompleteRequest::new(
+                    self.document_id.to_owned(),
+                    self.text.to_owned(),
+                    cursor,
+                );
+                let engine = self.engine.clone();
+                self.pending
+                    .push(tokio::spawn(async move { engine.complete(request).await }));
+                tokio::time::sleep(delay).await;
+            }
+        }
+
+        /// Waits for the requests from the keystrokes so far, in the order
+        /// they were typed. The clock runs while we wait, the broker logs to
+        /// sqlite from another thread and with the clock paused tokio would
+        /// skip ahead to the pool timeout while it waits on it
+        async fn results(&mut self) -> Vec<Result<InlineCompletion, AutocompleteError>> {
+            tokio::time::resume();
+            let mut results = vec![];
+            for handle in self.pending.drain(..) {
+                results.push(handle.await.expect("request to not panic"));
+            }
+            tokio::time::pause();
+            results
+        }
+    }
+
+    fn completion_text(result: &Result<InlineCompletion, AutocompleteError>) -> Option<&str> {
+        result.as_ref().ok().map(|completion| completion.text())
+    }
+
+    #[tokio::test]
+    async fn test_typing_session() {
+        let data_dir = tempfile::tempdir().expect("data dir to be created");
+        let llm_broker = LLMBroker::new(LLMBrokerConfiguration::new(data_dir.path().to_owned()))
+            .await
+            .expect("broker to startup")
+            .add_provider(
+                LLMProvider::TogetherAI,
+                Box::new(
+                    MockLLMClient::new(LLMProvider::TogetherAI)
+                        .set_capabilities(
+                            LLMClientCapabilities::new(true, true).set_fill_in_middle(true),
+                        )
+                        .respond_when(
+                            "    ret<｜fim▁hole｜>",
+                            MockResponse::answer("urn a + b;\n}\n\nfn sub() {}"),
+                        )
+                        .set_default_response(MockResponse::answer("c;\n}\n"))
+                        .set_streaming(2, Duration::from_millis(20)),
+                ),
+            );
+        let engine = Arc::new(
+            AutocompleteEngine::new(
+                Arc::new(llm_broker),
+                Arc::new(LLMTokenizer::new().expect("tokenizer to load")),
+                LLMType::DeepSeekCoder,
+                LLMProvider::TogetherAI,
+                LLMProviderAPIKeys::TogetherAI(TogetherAIProvider::new("key".to_owned())),
+            )
+            .set_config(AutocompleteConfig::new().set_debounce(Duration::from_millis(40))),
+        );
+        // while typing the clock only moves when all the tasks are waiting on
+        // it, so the keystrokes always land at the same point of a request
+        tokio::time::pause();
+        let mut session = TypingSession::new(
+            engine.clone(),
+            "src/add.rs",
+            "fn add(a: i32, b: i32) -> i32 {\n    <|>\n}\n",
+        );
+
+        // typing fast only asks the model once we stop, the closing bracket
+        // is already in the document so it is trimmed away
+        session.type_text("ret", Duration::from_millis(5)).await;
+        let results = session.results().await;
+        assert!(matches!(results[0], Err(AutocompleteError::Cancelled)));
+        assert!(matches!(results[1], Err(AutocompleteError::Cancelled)));
+        assert_eq!(completion_text(&results[2]), Some("urn a + b;"));
+        assert_eq!(
+            engine.stats(),
+            AutocompleteStats {
+                model_requests: 1,
+                cancelled: 2,
+                cache_hits: 0,
+            }
+        );
+
+        // typing along with the completion reuses it
+        session.type_text("urn a", Duration::from_millis(5)).await;
+        let results = session.results().await;
+        let completions = results
+            .iter()
+            .map(|result| {
+                let completion = result.as_ref().expect("completion to be cached");
+                assert!(completion.from_cache());
+                completion.text()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            completions,
+            vec!["rn a + b;", "n a + b;", " a + b;", "a + b;", " + b;"]
+        );
+        assert_eq!(engine.stats().model_requests, 1);
+        assert_eq!(engine.stats().cache_hits, 5);
+
+        // going off the completion asks the model again, and a keystroke while
+        // the answer is streaming cancels the request
+        session.type_text("-", Duration::from_millis(60)).await;
+        session.type_text(" ", Duration::ZERO).await;
+        let results = session.results().await;
+        assert!(matches!(results[0], Err(AutocompleteError::Cancelled)));
+        let completion = results[1].as_ref().expect("completion to work");
+        assert_eq!(completion.text(), "c;");
+        assert!(!completion.from_cache());
+        assert_eq!(
+            engine.stats(),
+            AutocompleteStats {
+                model_requests: 3,
+                cancelled: 3,
+                cache_hits: 5,
+            }
+        );
+
+        // typing in another document does not cancel the request for this one
+        let mut other_session = TypingSession::new(
+            engine.clone(),
+            "src/sub.rs",
+            "fn sub(a: i32, b: i32) -> i32 {\n    a - <|>\n}\n",
+        );
+        session.type_text("\n", Duration::ZERO).await;
+        other_session.type_text("b", Duration::ZERO).await;
+        for results in [session.results().await, other_session.results().await] {
+            assert_eq!(completion_text(&results[0]), Some("c;"));
+        }
+        assert_eq!(
+            engine.stats(),
+            AutocompleteStats {
+                model_requests: 5,
+                cancelled: 3,
+                cache_hits: 5,
+            }
+        );
+        // the requests go ahead of the other ones to the provider
+        tokio::time::resume();
+        let rows = engine
+            .llm_broker
+            .llm_data(&LLMDataFilter::new())
+            .await
+            .expect("rows to be fetched");
+        assert!(!rows.is_empty());
+        assert!(rows.iter().all(|row| {
+            RequestPriority::from_metadata(&row.metadata()) == RequestPriority::Interactive
+        }));
+
+        // nothing is left behind for the documents once their requests are done
+        assert!(engine
+            .generations
+            .lock()
+            .expect("lock to not be poisoned")
+            .is_empty());
+    }
+}
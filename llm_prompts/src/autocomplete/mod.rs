@@ -0,0 +1,4 @@
+pub mod context;
+pub mod engine;
+pub mod trim;
+pub mod types;
@@ -0,0 +1,121 @@
+//! The models keep going well past what makes sense as ghost text, so we cut
+//! the completion at the end of the line when the cursor is in the middle of
+//! one, or at the end of the block we are in when the cursor is on its own.
+//!
+//! The brackets are counted without looking at strings or comments, this is
+//! good enough for the short completions we show.
+
+/// Cuts the completion where the ghost text should end, the `line_suffix` is
+/// the text after the cursor on the same line and `suffix` everything after
+/// the cursor
+pub fn trim_completion(
+    completion: &str,
+    line_suffix: &str,
+    suffix: &str,
+    max_lines: usize,
+) -> String {
+    let line_suffix = line_suffix.trim();
+    if !line_suffix.is_empty() {
+        // in the middle of a line we only complete the line, without the
+        // closing brackets which are already there
+        let line = completion.lines().next().unwrap_or_default().trim_end();
+        return line
+            .strip_suffix(line_suffix)
+            .unwrap_or(line)
+            .trim_end()
+            .to_owned();
+    }
+    let next_line = suffix
+        .lines()
+        .map(|line| line.trim())
+        .find(|line| !line.is_empty())
+        .unwrap_or_default();
+    let mut balance: i64 = 0;
+    let mut opened_block = false;
+    let mut end = 0;
+    for (index, line) in completion.split_inclusive('\n').enumerate() {
+        if index >= max_lines {
+            break;
+        }
+        let trimmed = line.trim();
+        // the model is repeating the code which comes after the cursor, this
+        // does not apply inside of a block the completion opened
+        if index > 0 && balance == 0 && !trimmed.is_empty() && trimmed == next_line {
+            break;
+        }
+        let mut closes_outer_block = false;
+        for character in line.chars() {
+            match character {
+                '(' | '[' | '{' => balance += 1,
+                ')' | ']' | '}' => {
+                    balance -= 1;
+                    if balance < 0 {
+                        closes_outer_block = true;
+                        break;
+                    }
+                }
+                _ => {}
+            }
+        }
+        if closes_outer_block {
+            // the block we are in ends here, we keep the closing line only
+            // when it is not already in the code below
+            if !trimmed.starts_with([')', ']', '}']) || !next_line.starts_with(&trimmed[..1]) {
+                end += line.len();
+            }
+            break;
+        }
+        end += line.len();
+        if balance > 0 {
+            opened_block = true;
+        }
+        // the block the completion opened is complete
+        if opened_block && balance == 0 && !trimmed.is_empty() {
+            break;
+        }
+    }
+    completion[..end].trim_end().to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::trim_completion;
+
+    #[test]
+    fn test_trim_completion() {
+        // middle of the line, the closing bracket is already there
+        assert_eq!(
+            trim_completion("a, b)\n    let c = 1;", ")", ")\n}", 16),
+            "a, b"
+        );
+        // the block we are in is already closed below the cursor
+        assert_eq!(
+            trim_completion("    a + b\n}\n\nfn sub() {}", "", "\n}\n", 16),
+            "    a + b"
+        );
+        // it is not closed yet, so we keep the closing line
+        assert_eq!(
+            trim_completion("    a + b\n}\n\nfn sub() {}", "", "", 16),
+            "    a + b\n}"
+        );
+        // stops once the block the completion opened is closed
+        assert_eq!(
+            trim_completion(
+                "if a > b {\n        return a;\n    }\n    return b;",
+                "",
+                "\n}",
+                16
+            ),
+            "if a > b {\n        return a;\n    }"
+        );
+        // does not repeat the code below the cursor
+        assert_eq!(
+            trim_completion("let a = 1;\nlet b = 2;\nlet c = 3;", "", "\nlet c = 3;", 16),
+            "let a = 1;\nlet b = 2;"
+        );
+        assert_eq!(
+            trim_completion("let a = 1;\nlet b = 2;\nlet c = 3;", "", "", 2),
+            "let a = 1;\nlet b = 2;"
+        );
+    }
+}
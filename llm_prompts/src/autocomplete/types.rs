@@ -0,0 +1,298 @@
+//! The types for the inline completions (ghost text) we show in the editor
+//! while the user is typing
+
+use std::time::Duration;
+
+use llm_client::{clients::types::LLMClientError, tokenizer::tokenizer::LLMTokenizerError};
+
+use crate::in_line_edit::diagnostics::utf16_to_char_index;
+
+#[derive(thiserror::Error, Debug)]
+pub enum AutocompleteError {
+    #[error("llm client error: {0}")]
+    LLMClientError(#[from] LLMClientError),
+
+    #[error("tokenizer error: {0}")]
+    TokenizerError(#[from] LLMTokenizerError),
+
+    #[error("cursor at line {0} character {1} is outside of the document")]
+    InvalidCursor(usize, usize),
+
+    /// The user kept typing and a newer request took over
+    #[error("request was cancelled by a newer one")]
+    Cancelled,
+}
+
+/// The position of the cursor, both are 0 indexed and the character counts
+/// the UTF-16 code units on the line like LSP positions do
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CursorPosition {
+    line: usize,
+    character: usize,
+}
+
+impl CursorPosition {
+    pub fn new(line: usize, character: usize) -> Self {
+        Self { line, character }
+    }
+
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    pub fn character(&self) -> usize {
+        self.character
+    }
+
+    /// The byte offset of the cursor in the text, a character past the end of
+    /// the line is not allowed
+    pub fn to_byte_offset(&self, text: &str) -> Result<usize, AutocompleteError> {
+        let invalid_cursor = || AutocompleteError::InvalidCursor(self.line, self.character);
+        let line_start = if self.line == 0 {
+            0
+        } else {
+            text.match_indices('\n')
+                .nth(self.line - 1)
+                .map(|(index, _)| index + 1)
+                .ok_or_else(invalid_cursor)?
+        };
+        let line = text[line_start..].split('\n').next().unwrap_or_default();
+        if self.character > line.encode_utf16().count() {
+            return Err(invalid_cursor());
+        }
+        let char_index = utf16_to_char_index(line, self.character);
+        Ok(line_start
+            + line
+                .char_indices()
+                .nth(char_index)
+                .map_or(line.len(), |(index, _)| index))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AutocompleteRequest {
+    /// Identifies the document (usually the file path), we only reuse cached
+    /// completions for the same document
+    document_id: String,
+    text: String,
+    cursor: CursorPosition,
+    /// Extra context which goes before the prefix, like the file path or the
+    /// snippets from other files
+    context: Option<String>,
+}
+
+impl AutocompleteRequest {
+    pub fn new(document_id: String, text: String, cursor: CursorPosition) -> Self {
+        Self {
+            document_id,
+            text,
+            cursor,
+            context: None,
+        }
+    }
+
+    pub fn set_context(mut self, context: Option<String>) -> Self {
+        self.context = context;
+        self
+    }
+
+    pub fn document_id(&self) -> &str {
+        &self.document_id
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    pub fn cursor(&self) -> &CursorPosition {
+        &self.cursor
+    }
+
+    pub fn context(&self) -> Option<&str> {
+        self.context.as_deref()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AutocompleteConfig {
+    /// The tokens we can spend on the context, prefix and suffix together
+    context_tokens: usize,
+    /// The most the suffix can take out of the context tokens, whatever it
+    /// does not use goes to the prefix
+    max_suffix_tokens: usize,
+    /// How long we wait for the user to stop typing before we ask the model
+    debounce: Duration,
+    /// The number of completions we keep around for reuse
+    cache_size: usize,
+    /// The most lines we show for a completion which starts on an empty line
+    max_lines: usize,
+    temperature: f32,
+}
+
+impl Default for AutocompleteConfig {
+    fn default() -> Self {
+        Self {
+            context_tokens: 2048,
+            max_suffix_tokens: 512,
+            debounce: Duration::from_millis(150),
+            cache_size: 32,
+            max_lines: 16,
+            temperature: 0.2,
+        }
+    }
+}
+
+impl AutocompleteConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set_context_tokens(mut self, context_tokens: usize) -> Self {
+        self.context_tokens = context_tokens;
+        self
+    }
+
+    pub fn set_max_suffix_tokens(mut self, max_suffix_tokens: usize) -> Self {
+        self.max_suffix_tokens = max_suffix_tokens;
+        self
+    }
+
+    pub fn set_debounce(mut self, debounce: Duration) -> Self {
+        self.debounce = debounce;
+        self
+    }
+
+    pub fn set_cache_size(mut self, cache_size: usize) -> Self {
+        self.cache_size = cache_size;
+        self
+    }
+
+    pub fn set_max_lines(mut self, max_lines: usize) -> Self {
+        self.max_lines = max_lines.max(1);
+        self
+    }
+
+    pub fn set_temperature(mut self, temperature: f32) -> Self {
+        self.temperature = temperature;
+        self
+    }
+
+    pub fn context_tokens(&self) -> usize {
+        self.context_tokens
+    }
+
+    pub fn max_suffix_tokens(&self) -> usize {
+        self.max_suffix_tokens
+    }
+
+    pub fn debounce(&self) -> Duration {
+        self.debounce
+    }
+
+    pub fn cache_size(&self) -> usize {
+        self.cache_size
+    }
+
+    pub fn max_lines(&self) -> usize {
+        self.max_lines
+    }
+
+    pub fn temperature(&self) -> f32 {
+        self.temperature
+    }
+}
+
+/// The text to show after the cursor
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InlineCompletion {
+    text: String,
+    /// The byte offset of the cursor in the document
+    cursor_offset: usize,
+    /// If we got this from an earlier completion instead of asking the model
+    from_cache: bool,
+    /// The row in the llm data for the request which generated it, this is
+    /// the row of the earlier request for the cached ones
+    llm_data_id: i64,
+}
+
+impl InlineCompletion {
+    pub fn new(text: String, cursor_offset: usize, from_cache: bool, llm_data_id: i64) -> Self {
+        Self {
+            text,
+            cursor_offset,
+            from_cache,
+            llm_data_id,
+        }
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    pub fn cursor_offset(&self) -> usize {
+        self.cursor_offset
+    }
+
+    pub fn from_cache(&self) -> bool {
+        self.from_cache
+    }
+
+    pub fn llm_data_id(&self) -> i64 {
+        self.llm_data_id
+    }
+}
+
+/// How the requests to the engine went since it started
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AutocompleteStats {
+    /// The requests which went to the model
+    pub model_requests: usize,
+    /// The requests which a newer request took over, before or while asking
+    /// the model
+    pub cancelled: usize,
+    pub cache_hits: usize,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AutocompleteError, CursorPosition};
+
+    #[test]
+    fn test_cursor_to_byte_offset() {
+        let text = "fn main() {\n    let café = 1;\n}";
+        assert_eq!(CursorPosition::new(0, 0).to_byte_offset(text).unwrap(), 0);
+        assert_eq!(CursorPosition::new(0, 11).to_byte_offset(text).unwrap(), 11);
+        // the é takes 2 bytes
+        assert_eq!(
+            CursorPosition::new(1, 12).to_byte_offset(text).unwrap(),
+            text.find(" = 1").unwrap()
+        );
+        assert_eq!(
+            CursorPosition::new(2, 1).to_byte_offset(text).unwrap(),
+            text.len()
+        );
+        assert!(matches!(
+            CursorPosition::new(2, 2).to_byte_offset(text),
+            Err(AutocompleteError::InvalidCursor(2, 2))
+        ));
+        assert!(matches!(
+            CursorPosition::new(3, 0).to_byte_offset(text),
+            Err(AutocompleteError::InvalidCursor(3, 0))
+        ));
+
+        // the crab is a single char but 2 UTF-16 code units
+        let text = "let 🦀 = 1;";
+        assert_eq!(
+            CursorPosition::new(0, 6).to_byte_offset(text).unwrap(),
+            text.find(" = 1").unwrap()
+        );
+        assert_eq!(
+            CursorPosition::new(0, 11).to_byte_offset(text).unwrap(),
+            text.len()
+        );
+        assert!(matches!(
+            CursorPosition::new(0, 12).to_byte_offset(text),
+            Err(AutocompleteError::InvalidCursor(0, 12))
+        ));
+    }
+}
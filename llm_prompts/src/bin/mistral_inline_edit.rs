@@ -1,22 +1,18 @@
-//! We want to test the reranking with mistral
+//! Runs an inline edit with mistral and mixtral, the prompt comes from the
+//! prompt broker so the template ends up in the metadata of the llm_data row
 
-use std::path::PathBuf;
+use std::{collections::HashMap, path::PathBuf};
 
 use llm_client::{
     broker::LLMBroker,
-    clients::types::{LLMClientCompletionStringRequest, LLMType},
+    clients::types::LLMType,
     config::LLMBrokerConfiguration,
-    provider::{LLMProviderAPIKeys, TogetherAIProvider},
+    provider::{LLMProvider, LLMProviderAPIKeys, TogetherAIProvider},
 };
+use llm_prompts::in_line_edit::{broker::InLineEditPromptBroker, types::InLineEditRequest};
 
-#[tokio::main]
-async fn main() {
-    let prompt = r#"[INST] You are an expert software engineer. You have to perform edits in the selected code snippet following the user instruction in <user_query> tags.
-You have been given code context below:
-
-Code Context above the selection:
-```rust
-// FILEPATH: $/Users/skcd/scratch/dataset/commit_play/src/language/types.rs
+const ABOVE: &str = r#"```rust
+// FILEPATH: /Users/skcd/scratch/dataset/commit_play/src/language/types.rs
 // BEGIN: abpxx6d04wxr
 use std::{collections::HashMap, fmt::Debug, path::Display, sync::Arc};
 
@@ -37,10 +33,10 @@ pub struct Position {
     byte_offset: usize,
 }
 // END: abpxx6d04wxr
-```
-Code Context below the selection:
-```rust
-// FILEPATH: $/Users/skcd/scratch/dataset/commit_play/src/language/types.rs
+```"#;
+
+const BELOW: &str = r#"```rust
+// FILEPATH: /Users/skcd/scratch/dataset/commit_play/src/language/types.rs
 // BEGIN: be15d9bcejpp
 impl Position {
     fn to_tree_sitter(&self) -> tree_sitter::Point {
@@ -121,11 +117,10 @@ impl Range {
         }
     }
 // END: be15d9bcejpp
-```
+```"#;
 
-Code you have to edit:
-```rust
-// FILEPATH: $/Users/skcd/scratch/dataset/commit_play/src/language/types.rs
+const SELECTION: &str = r#"```rust
+// FILEPATH: /Users/skcd/scratch/dataset/commit_play/src/language/types.rs
 // BEGIN: ed8c6549bwf9
 impl Into<tree_sitter::Point> for Position {
     fn into(self) -> tree_sitter::Point {
@@ -133,49 +128,50 @@ impl Into<tree_sitter::Point> for Position {
     }
 }
 // END: ed8c6549bwf9
-```
+```"#;
 
-Rewrite the code enclosed in // BEGIN: ed8c6549bwf9 and // END: ed8c6549bwf9 following the user query without any explanation [/INST]
-The user has instructed me to perform the following edits on the selection:
-can you add comments all over the function body?
-
-The edited code is:
-```rust
-// FILEPATH: /Users/skcd/scratch/dataset/commit_play/src/language/types.rs
-// BEGIN: ed8c6549bwf9
-"#;
+#[tokio::main]
+async fn main() {
     let llm_broker = LLMBroker::new(LLMBrokerConfiguration::new(PathBuf::from(
         "/Users/skcd/Library/Application Support/ai.codestory.sidecar",
     )))
     .await
     .expect("broker to startup");
+    let prompt_broker = InLineEditPromptBroker::new();
 
     let api_key =
         LLMProviderAPIKeys::TogetherAI(TogetherAIProvider::new("some_key_here".to_owned()));
-    let request = LLMClientCompletionStringRequest::new(
-        LLMType::MistralInstruct,
-        prompt.to_owned(),
-        1.0,
-        None,
-    );
-    let (sender, _receiver) = tokio::sync::mpsc::unbounded_channel();
-    let metadata = vec![("event_type".to_owned(), "listwise_reranking".to_owned())]
-        .into_iter()
-        .collect();
-    let result = llm_broker
-        .stream_string_completion(api_key.clone(), request, metadata, sender)
-        .await;
-    println!("Mistral:");
-    println!("{:?}", result);
-    let mixtral_request =
-        LLMClientCompletionStringRequest::new(LLMType::Mixtral, prompt.to_owned(), 0.7, None);
-    let (sender, _receiver) = tokio::sync::mpsc::unbounded_channel();
-    let metadata = vec![("event_type".to_owned(), "listwise_reranking".to_owned())]
-        .into_iter()
-        .collect();
-    let result = llm_broker
-        .stream_string_completion(api_key, mixtral_request, metadata, sender)
-        .await;
-    println!("Mixtral:");
-    println!("{:?}", result);
+    for (llm_type, temperature) in [(LLMType::MistralInstruct, 1.0), (LLMType::Mixtral, 0.7)] {
+        let prompt = prompt_broker
+            .get_prompt(
+                &llm_type,
+                InLineEditRequest::new(
+                    Some(ABOVE.to_owned()),
+                    Some(BELOW.to_owned()),
+                    Some(SELECTION.to_owned()),
+                    "can you add comments all over the function body?".to_owned(),
+                    "/Users/skcd/scratch/dataset/commit_play/src/language/types.rs".to_owned(),
+                    vec![],
+                    "rust".to_owned(),
+                ),
+            )
+            .expect("model to have an inline edit prompt");
+        let (request, metadata) = prompt.into_broker_request(
+            llm_type.clone(),
+            temperature,
+            HashMap::from([("event_type".to_owned(), "inline_edit".to_owned())]),
+        );
+        let (sender, _receiver) = tokio::sync::mpsc::unbounded_channel();
+        let result = llm_broker
+            .stream_answer(
+                api_key.clone(),
+                LLMProvider::TogetherAI,
+                request,
+                metadata,
+                sender,
+            )
+            .await;
+        println!("{llm_type}:");
+        println!("{:?}", result);
+    }
 }
@@ -6,7 +6,7 @@ use llm_client::{
     broker::LLMBroker,
     clients::types::{LLMClientCompletionStringRequest, LLMType},
     config::LLMBrokerConfiguration,
-    provider::{LLMProviderAPIKeys, TogetherAIProvider},
+    provider::{LLMProvider, LLMProviderAPIKeys, TogetherAIProvider},
 };
 
 #[tokio::main]
@@ -417,7 +417,13 @@ You have to order all the code snippets from the most relevant to the least rele
         .into_iter()
         .collect();
     let result = llm_broker
-        .stream_string_completion(api_key.clone(), request, metadata, sender)
+        .stream_string_completion(
+            api_key.clone(),
+            request,
+            LLMProvider::TogetherAI,
+            metadata,
+            sender,
+        )
         .await;
     println!("Mistral:");
     println!("{:?}", result);
@@ -428,7 +434,13 @@ You have to order all the code snippets from the most relevant to the least rele
         .into_iter()
         .collect();
     let result = llm_broker
-        .stream_string_completion(api_key, mixtral_request, metadata, sender)
+        .stream_string_completion(
+            api_key,
+            mixtral_request,
+            LLMProvider::TogetherAI,
+            metadata,
+            sender,
+        )
         .await;
     println!("Mixtral:");
     println!("{:?}", result);
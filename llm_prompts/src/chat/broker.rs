@@ -1,11 +1,26 @@
-use llm_client::clients::types::LLMType;
+use std::sync::Arc;
+
+use llm_client::{
+    clients::types::{LLMClientCompletionRequest, LLMClientMessage, LLMType},
+    tokenizer::tokenizer::{LLMTokenizer, LLMTokenizerError},
+};
 
 use crate::answer_model::{AnswerModel, LLMAnswerModelBroker};
 
+use super::types::{ChatPromptRequest, ChatPromptResponse, ChatPromptTruncationReport, ChatTurn};
+
 #[derive(thiserror::Error, Debug)]
 pub enum ChatModelBrokerErrors {
     #[error("The model {0} is not supported yet")]
     ModelNotSupported(LLMType),
+
+    #[error("tokenizer error: {0}")]
+    TokenizerError(#[from] LLMTokenizerError),
+
+    #[error(
+        "The system prompt and question need {required} tokens but only {available} are available"
+    )]
+    PromptTooLarge { required: usize, available: usize },
 }
 
 pub struct LLMChatModelBroker {
@@ -28,4 +43,203 @@ impl LLMChatModelBroker {
             .get_answer_model(llm_type)
             .ok_or(ChatModelBrokerErrors::ModelNotSupported(llm_type.clone()))
     }
+
+    fn code_context_prompt(code_span_prompts: &[String]) -> String {
+        let code_context = code_span_prompts.join("\n");
+        format!(
+            r#"
+
+The following code context from the codebase might be relevant to the user question:
+{code_context}"#
+        )
+    }
+
+    /// Packs the system prompt, the code spans, the history and the user question
+    /// into the budgets defined by the answer model of the llm.
+    /// - The user question and the system prompt always make it to the prompt
+    /// - The code spans are added in order while they fit in `prompt_tokens_limit`
+    /// - The history is added from the newest turn to the oldest while it fits
+    ///   in `history_tokens_limit`, so we always drop the oldest turns first
+    /// - Everything along with the `answer_tokens` has to fit in `total_tokens`
+    pub fn chat_prompt(
+        &self,
+        request: ChatPromptRequest,
+        tokenizer: Arc<LLMTokenizer>,
+    ) -> Result<ChatPromptResponse, ChatModelBrokerErrors> {
+        let llm_type = request.llm_type().clone();
+        let answer_model = self.get_answer_model(&llm_type)?;
+        let total_tokens = answer_model.total_tokens.max(0) as usize;
+        let answer_tokens = answer_model.answer_tokens.max(0) as usize;
+        let prompt_tokens_limit = answer_model.prompt_tokens_limit.max(0) as usize;
+        let history_tokens_limit = answer_model.history_tokens_limit.max(0) as usize;
+        let count_tokens =
+            |content: &str| tokenizer.count_tokens_using_tokenizer(&llm_type, content);
+
+        // We count every message we might send in one go, so the role markers
+        // and the formatting are part of what each of them costs
+        let summary_prompt = request.summary().map(|summary| summary.to_prompt());
+        let mut candidate_messages =
+            vec![LLMClientMessage::system(request.system_prompt().to_owned())];
+        candidate_messages.extend(summary_prompt.clone().map(LLMClientMessage::system));
+        let history_start = candidate_messages.len();
+        request.history().iter().for_each(|chat_turn| {
+            candidate_messages.push(LLMClientMessage::user(chat_turn.user_message().to_owned()));
+            candidate_messages.push(LLMClientMessage::assistant(
+                chat_turn.assistant_message().to_owned(),
+            ));
+        });
+        candidate_messages.push(LLMClientMessage::user(request.user_question().to_owned()));
+        let candidate_tokens = tokenizer.count_message_tokens(&llm_type, &candidate_messages)?;
+        let message_tokens = candidate_tokens.messages();
+
+        let mut report = ChatPromptTruncationReport {
+            format_tokens: candidate_tokens.format_tokens(),
+            system_prompt_tokens: message_tokens[0],
+            user_question_tokens: message_tokens[message_tokens.len() - 1],
+            ..Default::default()
+        };
+        let required_tokens =
+            report.format_tokens + report.system_prompt_tokens + report.user_question_tokens;
+        let available_tokens = total_tokens.saturating_sub(answer_tokens);
+        if required_tokens > available_tokens {
+            return Err(ChatModelBrokerErrors::PromptTooLarge {
+                required: required_tokens,
+                available: available_tokens,
+            });
+        }
+
+        // The code spans are ordered by relevance, so we keep adding them until
+        // we run out of the prompt budget, the rest of them are dropped
+        let mut code_span_prompts = vec![];
+        for code_span in request.code_spans() {
+            let code_span_prompt = code_span.to_prompt();
+            let code_span_tokens = count_tokens(&code_span_prompt)?;
+            let prompt_tokens = report.format_tokens
+                + report.system_prompt_tokens
+                + report.code_context_tokens
+                + code_span_tokens;
+            if prompt_tokens <= prompt_tokens_limit
+                && prompt_tokens + report.user_question_tokens <= available_tokens
+                && report.dropped_code_spans.is_empty()
+            {
+                report.code_context_tokens += code_span_tokens;
+                code_span_prompts.push(code_span_prompt);
+            } else {
+                report.dropped_code_spans.push(code_span.clone());
+            }
+        }
+
+        // If we have a summary it takes the place of the turns it covers, and
+        // it always gets the first claim on the history budget
+        let mut summary_message = None;
+        let mut first_turn = 0;
+        if let (Some(summary), Some(summary_prompt)) = (request.summary(), summary_prompt) {
+            let summary_tokens = message_tokens[1];
+            if summary_tokens <= history_tokens_limit
+                && report.total_prompt_tokens() + summary_tokens <= available_tokens
+            {
+                report.history_tokens += summary_tokens;
+                report.summarized_turns =
+                    summary.summarized_turns().end.min(request.history().len());
+                report.dropped_turns = report.summarized_turns;
+                first_turn = report.summarized_turns;
+                summary_message = Some(summary_prompt);
+            }
+        }
+
+        // Now we walk the history from the newest turn and stop at the first
+        // turn which does not fit, everything older than that is dropped
+        let mut history_messages: Vec<&ChatTurn> = vec![];
+        for (index, chat_turn) in request.history().iter().enumerate().skip(first_turn).rev() {
+            let turn_tokens = message_tokens[history_start + index * 2]
+                + message_tokens[history_start + index * 2 + 1];
+            if report.history_tokens + turn_tokens <= history_tokens_limit
+                && report.total_prompt_tokens() + turn_tokens <= available_tokens
+            {
+                report.history_tokens += turn_tokens;
+                history_messages.push(chat_turn);
+            } else {
+                report.dropped_turns = index + 1;
+                break;
+            }
+        }
+        history_messages.reverse();
+
+        let system_message = if code_span_prompts.is_empty() {
+            request.system_prompt().to_owned()
+        } else {
+            format!(
+                "{}{}",
+                request.system_prompt(),
+                Self::code_context_prompt(&code_span_prompts)
+            )
+        };
+        let mut messages = vec![LLMClientMessage::system(system_message)];
+        if let Some(summary_message) = summary_message {
+            messages.push(LLMClientMessage::system(summary_message));
+        }
+        history_messages.into_iter().for_each(|chat_turn| {
+            messages.push(LLMClientMessage::user(chat_turn.user_message().to_owned()));
+            messages.push(LLMClientMessage::assistant(
+                chat_turn.assistant_message().to_owned(),
+            ));
+        });
+        messages.push(LLMClientMessage::user(request.user_question().to_owned()));
+        let completion_request =
+            LLMClientCompletionRequest::new(llm_type, messages, request.temperature(), None);
+        Ok(ChatPromptResponse::new(completion_request, report))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use llm_client::{clients::types::LLMType, tokenizer::tokenizer::LLMTokenizer};
+
+    use super::LLMChatModelBroker;
+    use crate::{
+        chat::types::{ChatPromptRequest, ChatTurn},
+        reranking::types::CodeSpan,
+    };
+
+    #[test]
+    fn test_oldest_turns_are_dropped_first() {
+        let broker = LLMChatModelBroker::init();
+        let tokenizer = Arc::new(LLMTokenizer::new().expect("tokenizer to load"));
+        // Mistral instruct has a history budget of 2048 tokens, each turn here
+        // is well over 500 tokens so only the newest turns fit
+        let long_message = "fn main() {}\n".repeat(150);
+        let history = (0..6)
+            .map(|index| ChatTurn::new(format!("question {index}"), long_message.to_owned()))
+            .collect::<Vec<_>>();
+        let request = ChatPromptRequest::new(
+            "You are a helpful assistant".to_owned(),
+            vec![CodeSpan::new(
+                "src/main.rs".to_owned(),
+                0,
+                1,
+                "fn main() {}".to_owned(),
+            )],
+            history,
+            "What does main do?".to_owned(),
+            LLMType::MistralInstruct,
+            0.2,
+        );
+        let response = broker.chat_prompt(request, tokenizer).expect("to work");
+        let report = response.report();
+        assert!(report.dropped_turns > 0);
+        assert!(report.dropped_code_spans.is_empty());
+        assert!(report.history_tokens <= 2048);
+        // the BOS token
+        assert_eq!(report.format_tokens, 1);
+        let messages = response.request().messages();
+        // system + the kept turns + the user question
+        assert_eq!(messages.len(), 1 + (6 - report.dropped_turns) * 2 + 1);
+        assert_eq!(
+            messages[1].content(),
+            format!("question {}", report.dropped_turns)
+        );
+        assert!(messages[0].content().contains("src/main.rs"));
+    }
 }
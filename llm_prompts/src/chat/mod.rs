@@ -1 +1,3 @@
 pub mod broker;
+pub mod summary;
+pub mod types;
@@ -0,0 +1,338 @@
+//! Long conversations do not fit in the history budget of the model, instead of
+//! silently dropping the oldest turns we condense them into a rolling summary
+//! using a cheaper model. The summary is stored along with the conversation and
+//! only regenerated when more turns fall out of the history budget.
+
+use std::{collections::HashMap, sync::Arc};
+
+use llm_client::{
+    broker::LLMBroker,
+    clients::types::{LLMClientCompletionRequest, LLMClientError, LLMClientMessage, LLMType},
+    provider::{LLMProvider, LLMProviderAPIKeys},
+    tokenizer::tokenizer::{LLMTokenizer, LLMTokenizerError},
+};
+
+use super::{
+    broker::{ChatModelBrokerErrors, LLMChatModelBroker},
+    types::{ChatPromptRequest, ChatPromptResponse, ChatTurn, ConversationSummary},
+};
+
+/// The number of times we try to summarize and pack the prompt again, the
+/// summary takes space in the history budget so packing it can push out more
+/// turns which then need to be summarized
+const MAX_SUMMARY_ATTEMPTS: usize = 3;
+
+#[derive(thiserror::Error, Debug)]
+pub enum ConversationSummaryError {
+    #[error("llm client error: {0}")]
+    LLMClientError(#[from] LLMClientError),
+
+    #[error("tokenizer error: {0}")]
+    TokenizerError(#[from] LLMTokenizerError),
+
+    #[error("chat broker error: {0}")]
+    ChatModelBrokerError(#[from] ChatModelBrokerErrors),
+}
+
+pub struct ConversationSummarizer {
+    llm_broker: Arc<LLMBroker>,
+    tokenizer: Arc<LLMTokenizer>,
+    /// The model we use for summarizing, this should be a cheaper model than
+    /// the one we are chatting with
+    llm_type: LLMType,
+    provider: LLMProvider,
+    api_keys: LLMProviderAPIKeys,
+    /// When the summary grows over this we ask the model to condense it again
+    max_summary_tokens: usize,
+}
+
+impl ConversationSummarizer {
+    pub fn new(
+        llm_broker: Arc<LLMBroker>,
+        tokenizer: Arc<LLMTokenizer>,
+        llm_type: LLMType,
+        provider: LLMProvider,
+        api_keys: LLMProviderAPIKeys,
+        max_summary_tokens: usize,
+    ) -> Self {
+        Self {
+            llm_broker,
+            tokenizer,
+            llm_type,
+            provider,
+            api_keys,
+            max_summary_tokens,
+        }
+    }
+
+    fn system_message() -> String {
+        r#"You are summarizing a conversation between a developer and an AI programming assistant.
+- Keep every decision which was made, the files and symbols which were discussed and the constraints the developer asked for.
+- Keep the open questions which were not answered yet.
+- Do not add anything which was not part of the conversation.
+- Reply only with the summary."#
+            .to_owned()
+    }
+
+    fn turns_prompt(turns: &[ChatTurn]) -> String {
+        turns
+            .iter()
+            .map(|turn| {
+                let user_message = turn.user_message();
+                let assistant_message = turn.assistant_message();
+                format!("User: {user_message}\nAssistant: {assistant_message}")
+            })
+            .collect::<Vec<_>>()
+            .join("\n\n")
+    }
+
+    fn summarize_prompt(previous_summary: Option<&str>, turns: &[ChatTurn]) -> String {
+        let turns = Self::turns_prompt(turns);
+        match previous_summary {
+            Some(previous_summary) => format!(
+                r#"This is the summary of the conversation so far:
+{previous_summary}
+
+These are the turns which came after it:
+{turns}
+
+Update the summary so it also covers these turns."#
+            ),
+            None => format!(
+                r#"This is the conversation:
+{turns}
+
+Summarize the conversation."#
+            ),
+        }
+    }
+
+    fn condense_prompt(summary: &str, max_summary_tokens: usize) -> String {
+        format!(
+            r#"This summary of the conversation has grown too long:
+{summary}
+
+Condense it to less than {max_summary_tokens} tokens, keep the decisions and the open questions."#
+        )
+    }
+
+    async fn complete(&self, user_message: String) -> Result<String, ConversationSummaryError> {
+        let request = LLMClientCompletionRequest::new(
+            self.llm_type.clone(),
+            vec![
+                LLMClientMessage::system(Self::system_message()),
+                LLMClientMessage::user(user_message),
+            ],
+            0.0,
+            None,
+        );
+        let (sender, _receiver) = tokio::sync::mpsc::unbounded_channel();
+        let metadata =
+            HashMap::from([("event_type".to_owned(), "conversation_summary".to_owned())]);
+        let summary = self
+            .llm_broker
+            .stream_completion(
+                self.api_keys.clone(),
+                request,
+                self.provider.clone(),
+                metadata,
+                sender,
+            )
+            .await?;
+        Ok(summary.answer().trim().to_owned())
+    }
+
+    /// Makes sure the summary covers the first `summarize_until` turns of the
+    /// history. The previous summary is reused as is when it already covers
+    /// them, otherwise we only send the new turns along with the previous summary
+    /// so the cost does not grow with the length of the conversation.
+    pub async fn summarize(
+        &self,
+        history: &[ChatTurn],
+        summarize_until: usize,
+        previous_summary: Option<ConversationSummary>,
+    ) -> Result<Option<ConversationSummary>, ConversationSummaryError> {
+        let summarize_until = summarize_until.min(history.len());
+        let summarized_until = previous_summary
+            .as_ref()
+            .map(|summary| summary.summarized_turns().end)
+            .unwrap_or_default();
+        if summarized_until >= summarize_until {
+            return Ok(previous_summary);
+        }
+        let prompt = Self::summarize_prompt(
+            previous_summary.as_ref().map(|summary| summary.summary()),
+            &history[summarized_until..summarize_until],
+        );
+        let mut summary = self.complete(prompt).await?;
+        let mut summary_tokens = self
+            .tokenizer
+            .count_tokens_using_tokenizer(&self.llm_type, &summary)?;
+        if summary_tokens > self.max_summary_tokens {
+            summary = self
+                .complete(Self::condense_prompt(&summary, self.max_summary_tokens))
+                .await?;
+            summary_tokens = self
+                .tokenizer
+                .count_tokens_using_tokenizer(&self.llm_type, &summary)?;
+        }
+        Ok(Some(ConversationSummary::new(
+            summary,
+            0..summarize_until,
+            summary_tokens,
+            self.llm_type.clone(),
+        )))
+    }
+
+    /// Packs the chat prompt and summarizes the turns which did not fit in the
+    /// history budget. Returns the summary which was used so the caller can
+    /// store it along with the conversation and pass it in on the next turn.
+    pub async fn chat_prompt(
+        &self,
+        chat_broker: &LLMChatModelBroker,
+        request: ChatPromptRequest,
+        chat_tokenizer: Arc<LLMTokenizer>,
+        previous_summary: Option<ConversationSummary>,
+    ) -> Result<(ChatPromptResponse, Option<ConversationSummary>), ConversationSummaryError> {
+        let mut summary = previous_summary;
+        let mut attempt = 0;
+        loop {
+            let request = match summary.clone() {
+                Some(summary) => request.clone().set_summary(summary),
+                None => request.clone(),
+            };
+            let history = request.history().to_vec();
+            let response = chat_broker.chat_prompt(request, chat_tokenizer.clone())?;
+            let report = response.report();
+            attempt += 1;
+            if report.unsummarized_dropped_turns() == 0 || attempt >= MAX_SUMMARY_ATTEMPTS {
+                return Ok((response, summary));
+            }
+            summary = self
+                .summarize(&history, report.dropped_turns, summary)
+                .await?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use llm_client::{
+        broker::LLMBroker,
+        clients::{
+            mock::{MockLLMClient, MockResponse},
+            types::LLMType,
+        },
+        config::LLMBrokerConfiguration,
+        provider::{LLMProvider, LLMProviderAPIKeys, OllamaProvider},
+        tokenizer::tokenizer::LLMTokenizer,
+    };
+
+    use super::ConversationSummarizer;
+    use crate::chat::{
+        broker::LLMChatModelBroker,
+        types::{ChatPromptRequest, ChatTurn},
+    };
+
+    #[tokio::test]
+    async fn test_summary_is_reused_until_more_turns_drop() {
+        let data_dir = tempfile::tempdir().expect("data dir to be created");
+        // every summary is different, so we can tell a reused one apart
+        let mock = (1..=5).fold(MockLLMClient::new(LLMProvider::Ollama), |mock, index| {
+            mock.push_response(MockResponse::answer(format!("summary {index}")))
+        });
+        let calls = || mock.prompts().len();
+        let llm_broker = LLMBroker::new(LLMBrokerConfiguration::new(data_dir.path().to_owned()))
+            .await
+            .expect("broker to startup")
+            .add_provider(LLMProvider::Ollama, Box::new(mock.clone()));
+        let tokenizer = Arc::new(LLMTokenizer::new().expect("tokenizer to load"));
+        let summarizer = ConversationSummarizer::new(
+            Arc::new(llm_broker),
+            tokenizer.clone(),
+            LLMType::MistralInstruct,
+            LLMProvider::Ollama,
+            LLMProviderAPIKeys::Ollama(OllamaProvider {}),
+            256,
+        );
+        let chat_broker = LLMChatModelBroker::init();
+        let long_message = "fn main() {}\n".repeat(150);
+        let history = (0..6)
+            .map(|index| ChatTurn::new(format!("question {index}"), long_message.to_owned()))
+            .collect::<Vec<_>>();
+        let request = |history: Vec<ChatTurn>| {
+            ChatPromptRequest::new(
+                "You are a helpful assistant".to_owned(),
+                vec![],
+                history,
+                "What does main do?".to_owned(),
+                LLMType::MistralInstruct,
+                0.2,
+            )
+        };
+
+        let (response, summary) = summarizer
+            .chat_prompt(
+                &chat_broker,
+                request(history.clone()),
+                tokenizer.clone(),
+                None,
+            )
+            .await
+            .expect("to work");
+        let summary = summary.expect("summary to be generated");
+        let report = response.report();
+        assert_eq!(report.unsummarized_dropped_turns(), 0);
+        assert_eq!(report.summarized_turns, summary.summarized_turns().end);
+        assert_eq!(
+            summary.original_turns(&history)[0].user_message(),
+            "question 0"
+        );
+        assert!(response.request().messages()[1]
+            .content()
+            .contains(summary.summary()));
+        let calls_after_first_turn = calls();
+        assert!(calls_after_first_turn > 0);
+
+        // the same history does not need a new summary
+        let (_, reused_summary) = summarizer
+            .chat_prompt(
+                &chat_broker,
+                request(history.clone()),
+                tokenizer.clone(),
+                Some(summary.clone()),
+            )
+            .await
+            .expect("to work");
+        assert_eq!(calls(), calls_after_first_turn);
+        assert_eq!(
+            reused_summary.expect("summary to be present").summary(),
+            summary.summary()
+        );
+
+        // new turns push more of the history out so the summary grows
+        let mut longer_history = history.clone();
+        longer_history.extend(
+            (6..9).map(|index| ChatTurn::new(format!("question {index}"), long_message.to_owned())),
+        );
+        let (_, grown_summary) = summarizer
+            .chat_prompt(
+                &chat_broker,
+                request(longer_history),
+                tokenizer,
+                Some(summary.clone()),
+            )
+            .await
+            .expect("to work");
+        assert!(calls() > calls_after_first_turn);
+        assert!(
+            grown_summary
+                .expect("summary to be present")
+                .summarized_turns()
+                .end
+                > summary.summarized_turns().end
+        );
+    }
+}
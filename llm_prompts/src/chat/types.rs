@@ -0,0 +1,227 @@
+//! The types we use for creating the chat prompt, the chat broker takes care
+//! of packing all of this into the token budget of the model
+
+use llm_client::clients::types::{LLMClientCompletionRequest, LLMType};
+
+use crate::reranking::types::CodeSpan;
+
+/// A single exchange in the conversation, the user asked something and the
+/// assistant answered it
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct ChatTurn {
+    user_message: String,
+    assistant_message: String,
+}
+
+impl ChatTurn {
+    pub fn new(user_message: String, assistant_message: String) -> Self {
+        Self {
+            user_message,
+            assistant_message,
+        }
+    }
+
+    pub fn user_message(&self) -> &str {
+        &self.user_message
+    }
+
+    pub fn assistant_message(&self) -> &str {
+        &self.assistant_message
+    }
+}
+
+/// A rolling summary of the oldest turns in the conversation, this is generated
+/// by a cheaper model and stored along with the conversation so we can reuse it
+/// across requests instead of summarizing on every turn
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct ConversationSummary {
+    summary: String,
+    /// The turns this summary was generated from, this indexes into the history
+    /// of the conversation so we can always go back to the original turns
+    summarized_turns: std::ops::Range<usize>,
+    /// The number of tokens in the summary, measured with the tokenizer of the
+    /// model which generated it
+    summary_tokens: usize,
+    llm_type: LLMType,
+}
+
+impl ConversationSummary {
+    pub fn new(
+        summary: String,
+        summarized_turns: std::ops::Range<usize>,
+        summary_tokens: usize,
+        llm_type: LLMType,
+    ) -> Self {
+        Self {
+            summary,
+            summarized_turns,
+            summary_tokens,
+            llm_type,
+        }
+    }
+
+    pub fn summary(&self) -> &str {
+        &self.summary
+    }
+
+    pub fn summarized_turns(&self) -> &std::ops::Range<usize> {
+        &self.summarized_turns
+    }
+
+    /// The original turns which this summary covers
+    pub fn original_turns<'a>(&self, history: &'a [ChatTurn]) -> &'a [ChatTurn] {
+        let end = self.summarized_turns.end.min(history.len());
+        let start = self.summarized_turns.start.min(end);
+        &history[start..end]
+    }
+
+    pub fn summary_tokens(&self) -> usize {
+        self.summary_tokens
+    }
+
+    pub fn llm_type(&self) -> &LLMType {
+        &self.llm_type
+    }
+
+    pub fn to_prompt(&self) -> String {
+        let summary = &self.summary;
+        format!(
+            r#"Summary of the earlier part of the conversation:
+{summary}"#
+        )
+    }
+}
+
+#[derive(Clone)]
+pub struct ChatPromptRequest {
+    system_prompt: String,
+    /// The code spans we got from retrieval, these are expected to be ordered
+    /// from the most relevant to the least relevant
+    code_spans: Vec<CodeSpan>,
+    /// The previous turns in the conversation, from the oldest to the newest
+    history: Vec<ChatTurn>,
+    /// The summary of the oldest turns in the history, if present we skip the
+    /// turns it covers and send the summary instead
+    summary: Option<ConversationSummary>,
+    user_question: String,
+    llm_type: LLMType,
+    temperature: f32,
+}
+
+impl ChatPromptRequest {
+    pub fn new(
+        system_prompt: String,
+        code_spans: Vec<CodeSpan>,
+        history: Vec<ChatTurn>,
+        user_question: String,
+        llm_type: LLMType,
+        temperature: f32,
+    ) -> Self {
+        Self {
+            system_prompt,
+            code_spans,
+            history,
+            summary: None,
+            user_question,
+            llm_type,
+            temperature,
+        }
+    }
+
+    pub fn set_summary(mut self, summary: ConversationSummary) -> Self {
+        self.summary = Some(summary);
+        self
+    }
+
+    pub fn summary(&self) -> Option<&ConversationSummary> {
+        self.summary.as_ref()
+    }
+
+    pub fn system_prompt(&self) -> &str {
+        &self.system_prompt
+    }
+
+    pub fn code_spans(&self) -> &[CodeSpan] {
+        self.code_spans.as_slice()
+    }
+
+    pub fn history(&self) -> &[ChatTurn] {
+        self.history.as_slice()
+    }
+
+    pub fn user_question(&self) -> &str {
+        &self.user_question
+    }
+
+    pub fn llm_type(&self) -> &LLMType {
+        &self.llm_type
+    }
+
+    pub fn temperature(&self) -> f32 {
+        self.temperature
+    }
+}
+
+/// What we had to cut to make the prompt fit in the budget of the model, along
+/// with how the tokens were spent
+#[derive(Debug, Clone, Default)]
+pub struct ChatPromptTruncationReport {
+    /// The number of turns from the start of the history which are not sent
+    /// as is, this includes the turns which are covered by the summary
+    pub dropped_turns: usize,
+    /// The number of turns from the start of the history which are covered by
+    /// the summary we sent
+    pub summarized_turns: usize,
+    /// The code spans which did not fit in the prompt budget
+    pub dropped_code_spans: Vec<CodeSpan>,
+    /// The tokens the prompt format adds outside of the messages, like the
+    /// BOS token, the role markers are counted with each message
+    pub format_tokens: usize,
+    pub system_prompt_tokens: usize,
+    pub code_context_tokens: usize,
+    pub history_tokens: usize,
+    pub user_question_tokens: usize,
+}
+
+impl ChatPromptTruncationReport {
+    pub fn total_prompt_tokens(&self) -> usize {
+        self.format_tokens
+            + self.system_prompt_tokens
+            + self.code_context_tokens
+            + self.history_tokens
+            + self.user_question_tokens
+    }
+
+    pub fn was_truncated(&self) -> bool {
+        self.dropped_turns > 0 || !self.dropped_code_spans.is_empty()
+    }
+
+    /// Turns which were dropped and are not covered by the summary, these are
+    /// lost unless we summarize them
+    pub fn unsummarized_dropped_turns(&self) -> usize {
+        self.dropped_turns.saturating_sub(self.summarized_turns)
+    }
+}
+
+pub struct ChatPromptResponse {
+    request: LLMClientCompletionRequest,
+    report: ChatPromptTruncationReport,
+}
+
+impl ChatPromptResponse {
+    pub fn new(request: LLMClientCompletionRequest, report: ChatPromptTruncationReport) -> Self {
+        Self { request, report }
+    }
+
+    pub fn request(&self) -> &LLMClientCompletionRequest {
+        &self.request
+    }
+
+    pub fn report(&self) -> &ChatPromptTruncationReport {
+        &self.report
+    }
+
+    pub fn into_parts(self) -> (LLMClientCompletionRequest, ChatPromptTruncationReport) {
+        (self.request, self.report)
+    }
+}
@@ -0,0 +1,647 @@
+//! Regression evals for the inline edit and fix prompts. A dataset is a JSONL
+//! file, one case per line with the file, the selection (0 indexed lines, the
+//! end line is included) and what we ask for:
+//!
+//! ```text
+//! {"id": "add_one", "kind": "edit", "instruction": "add one to the result",
+//!  "file_path": "src/lib.rs", "language": "rust", "file_content": "...",
+//!  "start_line": 1, "end_line": 1, "expected": "    a + b + 1"}
+//! {"id": "fix_sub", "kind": "fix", "diagnostics": [...],
+//!  "file_path": "src/lib.rs", "language": "rust", "file_content": "...",
+//!  "start_line": 1, "end_line": 1, "check_command": "cargo check",
+//!  "files": {"Cargo.toml": "..."}}
+//! ```
+//!
+//! The runner gets the prompt from the [`InLineEditPromptBroker`], the answer
+//! from the model and puts the edited selection back in the file. With an
+//! `expected` selection we score by exact match and line similarity, with a
+//! `check_command` we write the edited file (and the other `files`) to a temp
+//! dir and run the command there, it passes when it exits with 0. The results
+//! go to the [`InLineEditEvalStore`] along with the model and the template.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::Path,
+    sync::Arc,
+    time::Duration,
+    time::Instant,
+};
+
+use llm_client::{
+    broker::LLMBroker,
+    clients::types::LLMType,
+    rate_limit::{RequestPriority, PRIORITY_METADATA_KEY},
+};
+
+use super::{metrics::line_similarity, results::InLineEditEvalStore, EvalModel};
+use crate::{
+    in_line_edit::{
+        broker::InLineEditPromptBroker,
+        diagnostics::Diagnostic,
+        storage::StorageError,
+        types::{InLineEditPromptError, InLineEditRequest, InLineFixRequest},
+    },
+    templates::template::TemplateId,
+};
+
+/// The marker we put around the selection, the prompts ask the LLM to keep it
+const SELECTION_MARKER: &str = "ed8c6549bwf9";
+
+/// We keep the end of the check output, that is where the errors are
+const MAX_CHECK_OUTPUT_CHARS: usize = 4_000;
+
+#[derive(thiserror::Error, Debug)]
+pub enum InLineEditEvalError {
+    #[error("failed to read the dataset: {0}")]
+    IOError(#[from] std::io::Error),
+
+    #[error("serde failed: {0}")]
+    SerdeError(#[from] serde_json::Error),
+
+    #[error("invalid case {0}: {1}")]
+    InvalidCase(String, String),
+
+    #[error("failed to create the prompt: {0}")]
+    PromptError(#[from] InLineEditPromptError),
+
+    #[error("failed to store the results: {0}")]
+    StorageError(#[from] StorageError),
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum InLineEditEvalTask {
+    Edit { instruction: String },
+    Fix { diagnostics: Vec<Diagnostic> },
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct InLineEditEvalCase {
+    pub id: String,
+    #[serde(flatten)]
+    pub task: InLineEditEvalTask,
+    pub file_path: String,
+    pub language: String,
+    pub file_content: String,
+    pub start_line: usize,
+    pub end_line: usize,
+    /// What the selection should look like after the edit
+    #[serde(default)]
+    pub expected: Option<String>,
+    #[serde(default)]
+    pub check_command: Option<String>,
+    /// Other files the check command needs, keyed by their relative path
+    #[serde(default)]
+    pub files: BTreeMap<String, String>,
+}
+
+impl InLineEditEvalCase {
+    fn validate(&self) -> Result<(), InLineEditEvalError> {
+        let invalid = |reason: &str| {
+            Err(InLineEditEvalError::InvalidCase(
+                self.id.to_owned(),
+                reason.to_owned(),
+            ))
+        };
+        if self.expected.is_none() && self.check_command.is_none() {
+            return invalid("needs an expected selection or a check command");
+        }
+        if self.start_line > self.end_line || self.end_line >= self.file_content.lines().count() {
+            return invalid("the selection is outside of the file");
+        }
+        // these get written to the temp dir for the check command
+        let is_relative = |path: &str| {
+            let path = Path::new(path);
+            path.is_relative()
+                && path
+                    .components()
+                    .all(|component| matches!(component, std::path::Component::Normal(_)))
+        };
+        if !is_relative(&self.file_path) || !self.files.keys().all(|path| is_relative(path)) {
+            return invalid("the file paths have to be relative and inside the case");
+        }
+        Ok(())
+    }
+
+    fn lines(&self) -> Vec<&str> {
+        self.file_content.lines().collect()
+    }
+
+    fn above(&self) -> Option<String> {
+        let lines = self.lines();
+        Some(lines[..self.start_line].join("\n")).filter(|above| !above.is_empty())
+    }
+
+    fn below(&self) -> Option<String> {
+        let lines = self.lines();
+        Some(lines[self.end_line + 1..].join("\n")).filter(|below| !below.is_empty())
+    }
+
+    fn selection(&self) -> String {
+        self.lines()[self.start_line..=self.end_line].join("\n")
+    }
+
+    /// The selection as the editor sends it, in a code block with the markers
+    fn marked_selection(&self) -> String {
+        format!(
+            "```{}\n// FILEPATH: {}\n// BEGIN: {SELECTION_MARKER}\n{}\n// END: {SELECTION_MARKER}\n```",
+            self.language,
+            self.file_path,
+            self.selection()
+        )
+    }
+
+    /// The file with the selection replaced by the edit
+    fn apply_edit(&self, edit: &str) -> String {
+        let lines = self.lines();
+        let mut edited = lines[..self.start_line].to_vec();
+        edited.extend(edit.lines());
+        edited.extend(&lines[self.end_line + 1..]);
+        let mut edited = edited.join("\n");
+        if self.file_content.ends_with('\n') {
+            edited.push('\n');
+        }
+        edited
+    }
+}
+
+/// The code the LLM generated for the selection, without the code block and
+/// the markers around it. The completion prompts already end with the begin
+/// marker so the answer might start with the code.
+pub fn extract_edit(llm_output: &str) -> String {
+    let lines = llm_output.lines().collect::<Vec<_>>();
+    let is_fence = |line: &&str| line.trim_start().starts_with("```");
+    let end = lines
+        .iter()
+        .position(|line| line.trim_start().starts_with("// END:"))
+        .unwrap_or(lines.len());
+    // a fence after the end marker closes the code block, it does not open it
+    let start = lines
+        .iter()
+        .position(|line| line.trim_start().starts_with("// BEGIN:"))
+        .or_else(|| lines[..end].iter().position(is_fence))
+        .map(|position| position + 1)
+        .unwrap_or_default();
+    lines[start..]
+        .iter()
+        .skip_while(|line| line.trim_start().starts_with("// FILEPATH:"))
+        .take_while(|line| !line.trim_start().starts_with("// END:") && !is_fence(line))
+        .copied()
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Trailing whitespace and blank lines at the end do not count
+fn is_exact_match(output: &str, expected: &str) -> bool {
+    let normalize = |text: &str| {
+        text.lines()
+            .map(|line| line.trim_end())
+            .collect::<Vec<_>>()
+            .join("\n")
+            .trim_end()
+            .to_owned()
+    };
+    normalize(output) == normalize(expected)
+}
+
+#[derive(Debug, Clone)]
+pub struct InLineEditEvalDataset {
+    name: String,
+    cases: Vec<InLineEditEvalCase>,
+}
+
+impl InLineEditEvalDataset {
+    /// Loads the JSONL file, the dataset is named after the file
+    pub fn load(path: &Path) -> Result<Self, InLineEditEvalError> {
+        let name = path
+            .file_stem()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        Self::from_jsonl(name, &std::fs::read_to_string(path)?)
+    }
+
+    pub fn from_jsonl(name: String, jsonl: &str) -> Result<Self, InLineEditEvalError> {
+        let cases = jsonl
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(serde_json::from_str::<InLineEditEvalCase>)
+            .collect::<Result<Vec<_>, _>>()?;
+        Self::new(name, cases)
+    }
+
+    pub fn new(name: String, cases: Vec<InLineEditEvalCase>) -> Result<Self, InLineEditEvalError> {
+        cases.iter().try_for_each(|case| case.validate())?;
+        Ok(Self { name, cases })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn cases(&self) -> &[InLineEditEvalCase] {
+        self.cases.as_slice()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct InLineEditEvalConfig {
+    models: Vec<EvalModel>,
+    check_timeout: Duration,
+}
+
+impl InLineEditEvalConfig {
+    pub fn new(models: Vec<EvalModel>) -> Self {
+        Self {
+            models,
+            check_timeout: Duration::from_secs(120),
+        }
+    }
+
+    /// The check command fails when it takes longer than this
+    pub fn set_check_timeout(mut self, check_timeout: Duration) -> Self {
+        self.check_timeout = check_timeout;
+        self
+    }
+}
+
+/// The scores are `None` when the case is not scored that way, a case where
+/// the model failed to answer scores 0 (or fails the check)
+#[derive(Debug, Clone)]
+pub struct InLineEditEvalCaseResult {
+    pub case_id: String,
+    pub llm_type: LLMType,
+    pub template: TemplateId,
+    pub output: Option<String>,
+    pub exact_match: Option<bool>,
+    pub similarity: Option<f64>,
+    pub check_passed: Option<bool>,
+    pub check_output: Option<String>,
+    pub error: Option<String>,
+    pub latency_ms: f64,
+    pub llm_data_id: Option<i64>,
+}
+
+#[derive(Debug, Clone)]
+pub struct InLineEditEvalReport {
+    pub run_id: i64,
+    pub dataset: String,
+    pub results: Vec<InLineEditEvalCaseResult>,
+}
+
+pub struct InLineEditEvalRunner {
+    prompt_broker: InLineEditPromptBroker,
+    llm_broker: Arc<LLMBroker>,
+    store: InLineEditEvalStore,
+}
+
+impl InLineEditEvalRunner {
+    pub fn new(llm_broker: Arc<LLMBroker>, store: InLineEditEvalStore) -> Self {
+        Self {
+            prompt_broker: InLineEditPromptBroker::new(),
+            llm_broker,
+            store,
+        }
+    }
+
+    /// Evaluates the prompts of this broker, built with
+    /// [`InLineEditPromptBroker::with_templates`] this compares the prompt
+    /// versions
+    pub fn set_prompt_broker(mut self, prompt_broker: InLineEditPromptBroker) -> Self {
+        self.prompt_broker = prompt_broker;
+        self
+    }
+
+    pub fn store(&self) -> &InLineEditEvalStore {
+        &self.store
+    }
+
+    pub async fn run(
+        &self,
+        dataset: &InLineEditEvalDataset,
+        config: &InLineEditEvalConfig,
+    ) -> Result<InLineEditEvalReport, InLineEditEvalError> {
+        let run_id = self.store.create_run(dataset.name()).await?;
+        let mut results = vec![];
+        for model in config.models.iter() {
+            for case in dataset.cases() {
+                let result = self.run_case(case, model, config).await?;
+                self.store.add_result(run_id, &result).await?;
+                results.push(result);
+            }
+        }
+        Ok(InLineEditEvalReport {
+            run_id,
+            dataset: dataset.name().to_owned(),
+            results,
+        })
+    }
+
+    async fn run_case(
+        &self,
+        case: &InLineEditEvalCase,
+        model: &EvalModel,
+        config: &InLineEditEvalConfig,
+    ) -> Result<InLineEditEvalCaseResult, InLineEditEvalError> {
+        let llm_type = model.llm_type();
+        let prompt = match &case.task {
+            InLineEditEvalTask::Edit { instruction } => self.prompt_broker.get_prompt(
+                llm_type,
+                InLineEditRequest::new(
+                    case.above(),
+                    case.below(),
+                    Some(case.marked_selection()),
+                    instruction.to_owned(),
+                    case.file_path.to_owned(),
+                    vec![],
+                    case.language.to_owned(),
+                ),
+            )?,
+            // the diagnostics are placed on the lines of the selection, so
+            // it goes without the markers
+            InLineEditEvalTask::Fix { diagnostics } => self.prompt_broker.get_fix_prompt(
+                llm_type,
+                InLineFixRequest::new(
+                    case.above(),
+                    case.below(),
+                    case.selection(),
+                    case.start_line,
+                    diagnostics.to_vec(),
+                    case.language.to_owned(),
+                    case.file_path.to_owned(),
+                ),
+            )?,
+        };
+        let template = prompt.template().clone();
+        let (request, metadata) = prompt.into_broker_request(
+            llm_type.clone(),
+            0.0,
+            // the event type looks like an inline edit, the eval should not get
+            // ahead of the requests from the editor though
+            HashMap::from([
+                ("event_type".to_owned(), "inline_edit_eval".to_owned()),
+                (
+                    PRIORITY_METADATA_KEY.to_owned(),
+                    RequestPriority::Background.as_str().to_owned(),
+                ),
+            ]),
+        );
+        let (sender, _receiver) = tokio::sync::mpsc::unbounded_channel();
+        let started = Instant::now();
+        let answer = self
+            .llm_broker
+            .stream_answer(
+                model.api_key().clone(),
+                model.provider().clone(),
+                request,
+                metadata,
+                sender,
+            )
+            .await;
+        let latency_ms = started.elapsed().as_secs_f64() * 1000.0;
+        let mut result = InLineEditEvalCaseResult {
+            case_id: case.id.to_owned(),
+            llm_type: llm_type.clone(),
+            template,
+            output: None,
+            exact_match: case.expected.as_ref().map(|_| false),
+            similarity: case.expected.as_ref().map(|_| 0.0),
+            check_passed: case.check_command.as_ref().map(|_| false),
+            check_output: None,
+            error: None,
+            latency_ms,
+            llm_data_id: None,
+        };
+        let answer = match answer {
+            Ok(answer) => answer,
+            Err(e) => {
+                result.error = Some(e.to_string());
+                return Ok(result);
+            }
+        };
+        result.llm_data_id = Some(answer.llm_data_id());
+        let edit = extract_edit(answer.answer());
+        if let Some(expected) = case.expected.as_ref() {
+            result.exact_match = Some(is_exact_match(&edit, expected));
+            result.similarity = Some(line_similarity(&edit, expected));
+        }
+        if let Some(check_command) = case.check_command.as_ref() {
+            // the check dir goes away once the check is done, even when it fails
+            let check = async {
+                let check_dir = tempfile::Builder::new()
+                    .prefix("llm_prompts_inline_edit_eval_")
+                    .tempdir()?;
+                run_check(
+                    check_dir.path(),
+                    case,
+                    &case.apply_edit(&edit),
+                    check_command,
+                    config.check_timeout,
+                )
+                .await
+            }
+            .await;
+            match check {
+                Ok((passed, output)) => {
+                    result.check_passed = Some(passed);
+                    result.check_output = Some(output);
+                }
+                Err(e) => result.error = Some(format!("failed to run the check: {e}")),
+            }
+        }
+        result.output = Some(edit);
+        Ok(result)
+    }
+}
+
+/// Writes the files to the dir and runs the command in it, returns if it
+/// passed along with the end of its output
+async fn run_check(
+    check_dir: &Path,
+    case: &InLineEditEvalCase,
+    edited_file: &str,
+    check_command: &str,
+    timeout: Duration,
+) -> Result<(bool, String), std::io::Error> {
+    let files = case
+        .files
+        .iter()
+        .map(|(path, content)| (path.as_str(), content.as_str()))
+        .chain([(case.file_path.as_str(), edited_file)]);
+    for (path, content) in files {
+        let path = check_dir.join(path);
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        tokio::fs::write(path, content).await?;
+    }
+    let output = tokio::process::Command::new("sh")
+        .arg("-c")
+        .arg(check_command)
+        .current_dir(check_dir)
+        .kill_on_drop(true)
+        .output();
+    match tokio::time::timeout(timeout, output).await {
+        Ok(output) => {
+            let output = output?;
+            let text = format!(
+                "{}{}",
+                String::from_utf8_lossy(&output.stdout),
+                String::from_utf8_lossy(&output.stderr)
+            );
+            let skip = text.chars().count().saturating_sub(MAX_CHECK_OUTPUT_CHARS);
+            Ok((output.status.success(), text.chars().skip(skip).collect()))
+        }
+        Err(_) => Ok((false, format!("timed out after {}s", timeout.as_secs()))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use llm_client::{
+        broker::LLMBroker,
+        clients::{
+            mock::{MockLLMClient, MockResponse},
+            types::{LLMClientCapabilities, LLMType},
+        },
+        config::LLMBrokerConfiguration,
+        llm_data::LLMDataFilter,
+        provider::{LLMProvider, LLMProviderAPIKeys, TogetherAIProvider},
+        rate_limit::RequestPriority,
+    };
+
+    use super::{
+        extract_edit, InLineEditEvalConfig, InLineEditEvalDataset, InLineEditEvalError,
+        InLineEditEvalRunner,
+    };
+    use crate::{
+        eval::{results::InLineEditEvalStore, EvalModel},
+        templates::template::PROMPT_TEMPLATE_METADATA_KEY,
+    };
+
+    const DATASET: &str = r#"
+{"id": "add_one", "kind": "edit", "instruction": "add one to the result", "file_path": "src/lib.rs", "language": "rust", "file_content": "fn add(a: i32, b: i32) -> i32 {\n    a + b\n}\n", "start_line": 1, "end_line": 1, "expected": "    a + b + 1"}
+{"id": "fix_sub", "kind": "fix", "diagnostics": [{"range": {"start": {"line": 1, "character": 4}, "end": {"line": 1, "character": 9}}, "message": "sub should subtract"}], "file_path": "src/lib.rs", "language": "rust", "file_content": "fn sub(a: i32, b: i32) -> i32 {\n    a + b\n}\n", "start_line": 1, "end_line": 1, "check_command": "grep -q 'a - b' src/lib.rs && test -f Cargo.toml", "files": {"Cargo.toml": "[package]\nname = \"sub\"\n"}}
+{"id": "double", "kind": "edit", "instruction": "double the result", "file_path": "src/lib.rs", "language": "rust", "file_content": "fn double(a: i32) -> i32 {\n    a\n}\n", "start_line": 1, "end_line": 1, "expected": "    a * 2"}
+"#;
+
+    #[test]
+    fn test_extract_edit() {
+        assert_eq!(
+            extract_edit("    a + b + 1\n// END: ed8c6549bwf9\n```"),
+            "    a + b + 1"
+        );
+        assert_eq!(
+            extract_edit("Here you go:\n```rust\n// FILEPATH: src/lib.rs\n// BEGIN: ed8c6549bwf9\n    a\n    + 1\n// END: ed8c6549bwf9\n```"),
+            "    a\n    + 1"
+        );
+        assert_eq!(extract_edit("```rust\n    a\n```"), "    a");
+    }
+
+    #[tokio::test]
+    async fn test_inline_edit_eval_tracks_the_runs() {
+        let data_dir = tempfile::tempdir().expect("data dir to be created");
+        let mock = MockLLMClient::new(LLMProvider::TogetherAI)
+            .set_capabilities(LLMClientCapabilities::new(true, true))
+            .respond_when(
+                "add one to the result",
+                MockResponse::answer("    a + b + 1\n// END: ed8c6549bwf9\n```"),
+            )
+            .respond_when(
+                "sub should subtract",
+                MockResponse::answer("    a - b\n// END: ed8c6549bwf9\n```"),
+            )
+            .set_default_response(MockResponse::answer("    a + a\n// END: ed8c6549bwf9\n```"));
+        let llm_broker = LLMBroker::new(LLMBrokerConfiguration::new(data_dir.path().to_owned()))
+            .await
+            .expect("broker to startup")
+            .add_provider(LLMProvider::TogetherAI, Box::new(mock));
+        let store = InLineEditEvalStore::init(data_dir.path())
+            .await
+            .expect("store to init");
+        let llm_broker = Arc::new(llm_broker);
+        let runner = InLineEditEvalRunner::new(llm_broker.clone(), store);
+        let dataset = InLineEditEvalDataset::from_jsonl("inline_edit".to_owned(), DATASET)
+            .expect("dataset to load");
+        let config = InLineEditEvalConfig::new(vec![EvalModel::new(
+            LLMType::Mixtral,
+            LLMProvider::TogetherAI,
+            LLMProviderAPIKeys::TogetherAI(TogetherAIProvider::new("key".to_owned())),
+        )]);
+
+        let report = runner.run(&dataset, &config).await.expect("eval to run");
+        assert_eq!(report.results.len(), 3);
+        let add_one = &report.results[0];
+        assert_eq!(add_one.exact_match, Some(true));
+        assert_eq!(add_one.similarity, Some(1.0));
+        assert_eq!(add_one.check_passed, None);
+        assert_eq!(add_one.template.name(), "in_line_edit.mistral.edit");
+        // the template goes to llm_data along with the answer
+        let rows = llm_broker
+            .llm_data(&LLMDataFilter::new())
+            .await
+            .expect("rows to be fetched");
+        let row = rows
+            .iter()
+            .find(|row| Some(row.id()) == add_one.llm_data_id)
+            .expect("row to be logged");
+        assert_eq!(
+            row.metadata().get(PROMPT_TEMPLATE_METADATA_KEY),
+            Some(&"in_line_edit.mistral.edit".to_owned())
+        );
+        assert_eq!(
+            row.metadata().get("event_type"),
+            Some(&"inline_edit_eval".to_owned())
+        );
+        assert_eq!(
+            RequestPriority::from_metadata(&row.metadata()),
+            RequestPriority::Background
+        );
+        let fix_sub = &report.results[1];
+        assert_eq!(
+            fix_sub.check_passed,
+            Some(true),
+            "{:?}",
+            fix_sub.check_output
+        );
+        assert_eq!(fix_sub.exact_match, None);
+        let double = &report.results[2];
+        assert_eq!(double.exact_match, Some(false));
+        assert_eq!(double.similarity, Some(0.0));
+        assert!(report.results.iter().all(|result| result.error.is_none()));
+
+        runner.run(&dataset, &config).await.expect("eval to run");
+        let trends = runner
+            .store()
+            .trends("inline_edit")
+            .await
+            .expect("trends to load");
+        // one row per run and template, the fix prompt has its own template
+        assert_eq!(trends.len(), 4);
+        assert!(trends[0].run_id < trends[2].run_id);
+        let edit_trend = trends
+            .iter()
+            .find(|trend| trend.template.name() == "in_line_edit.mistral.edit")
+            .expect("edit trend");
+        assert_eq!(edit_trend.cases, 2);
+        assert_eq!(edit_trend.failed, 0);
+        assert_eq!(edit_trend.exact_match_rate, Some(0.5));
+        assert_eq!(edit_trend.mean_similarity, Some(0.5));
+        assert_eq!(edit_trend.check_pass_rate, None);
+        let fix_trend = trends
+            .iter()
+            .find(|trend| trend.template.name() != "in_line_edit.mistral.edit")
+            .expect("fix trend");
+        assert_eq!(fix_trend.check_pass_rate, Some(1.0));
+        assert_eq!(fix_trend.exact_match_rate, None);
+
+        assert!(matches!(
+            InLineEditEvalDataset::from_jsonl(
+                "invalid".to_owned(),
+                r#"{"id": "case", "kind": "edit", "instruction": "edit", "file_path": "../lib.rs", "language": "rust", "file_content": "a\n", "start_line": 0, "end_line": 0, "expected": "b"}"#
+            ),
+            Err(InLineEditEvalError::InvalidCase(_, _))
+        ));
+    }
+}
@@ -0,0 +1,130 @@
+//! The metrics for the evals. For the rankings relevance is binary, an id is
+//! either in the gold set or it is not.
+
+use std::collections::HashSet;
+
+/// Normalized discounted cumulative gain over the first `k` ids of the ranking
+pub fn ndcg_at_k(ranked: &[String], relevant: &HashSet<String>, k: usize) -> f64 {
+    let gain = |position: usize| 1.0 / ((position + 2) as f64).log2();
+    let dcg: f64 = ranked
+        .iter()
+        .take(k)
+        .enumerate()
+        .filter(|(_, id)| relevant.contains(*id))
+        .map(|(position, _)| gain(position))
+        .sum();
+    let ideal_dcg: f64 = (0..relevant.len().min(k)).map(gain).sum();
+    if ideal_dcg == 0.0 {
+        0.0
+    } else {
+        dcg / ideal_dcg
+    }
+}
+
+/// One over the position of the first relevant id, 0 when there is none
+pub fn reciprocal_rank(ranked: &[String], relevant: &HashSet<String>) -> f64 {
+    ranked
+        .iter()
+        .position(|id| relevant.contains(id))
+        .map(|position| 1.0 / (position + 1) as f64)
+        .unwrap_or_default()
+}
+
+/// How many of the relevant ids made it into the first `limit` ids
+pub fn recall_at(ranked: &[String], relevant: &HashSet<String>, limit: usize) -> f64 {
+    if relevant.is_empty() {
+        return 0.0;
+    }
+    let found = ranked
+        .iter()
+        .take(limit)
+        .filter(|id| relevant.contains(*id))
+        .collect::<HashSet<_>>()
+        .len();
+    found as f64 / relevant.len() as f64
+}
+
+pub fn mean(values: &[f64]) -> f64 {
+    if values.is_empty() {
+        0.0
+    } else {
+        values.iter().sum::<f64>() / values.len() as f64
+    }
+}
+
+/// Nearest rank percentile, `percentile` goes from 0 to 100
+pub fn percentile(values: &[f64], percentile: f64) -> f64 {
+    if values.is_empty() {
+        return 0.0;
+    }
+    let mut values = values.to_vec();
+    values.sort_by(|first, second| first.total_cmp(second));
+    let rank = (percentile / 100.0 * values.len() as f64).ceil() as usize;
+    values[rank.clamp(1, values.len()) - 1]
+}
+
+/// How close two texts are line by line, 2 * the longest common subsequence
+/// of lines over the total number of lines: 1.0 when they are the same
+pub fn line_similarity(first: &str, second: &str) -> f64 {
+    let first = first
+        .lines()
+        .map(|line| line.trim_end())
+        .collect::<Vec<_>>();
+    let second = second
+        .lines()
+        .map(|line| line.trim_end())
+        .collect::<Vec<_>>();
+    if first.is_empty() && second.is_empty() {
+        return 1.0;
+    }
+    let mut previous = vec![0; second.len() + 1];
+    for first_line in first.iter() {
+        let mut current = vec![0; second.len() + 1];
+        for (index, second_line) in second.iter().enumerate() {
+            current[index + 1] = if first_line == second_line {
+                previous[index] + 1
+            } else {
+                current[index].max(previous[index + 1])
+            };
+        }
+        previous = current;
+    }
+    2.0 * previous[second.len()] as f64 / (first.len() + second.len()) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::{line_similarity, ndcg_at_k, percentile, recall_at, reciprocal_rank};
+
+    #[test]
+    fn test_ranking_metrics() {
+        let ranked = ["a", "b", "c", "d"].map(|id| id.to_owned()).to_vec();
+        let relevant = ["b", "d", "e"]
+            .map(|id| id.to_owned())
+            .into_iter()
+            .collect::<HashSet<_>>();
+        assert_eq!(reciprocal_rank(&ranked, &relevant), 0.5);
+        assert_eq!(recall_at(&ranked, &relevant, 2), 1.0 / 3.0);
+        assert_eq!(recall_at(&ranked, &relevant, 10), 2.0 / 3.0);
+        // b at rank 2 and d at rank 4 against b, d, e at ranks 1 to 3
+        let expected =
+            (1.0 / 3f64.log2() + 1.0 / 5f64.log2()) / (1.0 + 1.0 / 3f64.log2() + 1.0 / 4f64.log2());
+        assert!((ndcg_at_k(&ranked, &relevant, 5) - expected).abs() < 1e-9);
+        assert_eq!(ndcg_at_k(&ranked, &relevant, 1), 0.0);
+        assert_eq!(ndcg_at_k(&ranked[1..], &relevant, 1), 1.0);
+
+        assert_eq!(percentile(&[4.0, 1.0, 3.0, 2.0], 50.0), 2.0);
+        assert_eq!(percentile(&[4.0, 1.0, 3.0, 2.0], 95.0), 4.0);
+    }
+
+    #[test]
+    fn test_line_similarity() {
+        assert_eq!(line_similarity("a\nb\nc", "a\nb  \nc"), 1.0);
+        // a and c are common, 2 * 2 / 6
+        assert_eq!(line_similarity("a\nb\nc", "a\nx\nc"), 2.0 / 3.0);
+        assert_eq!(line_similarity("a", ""), 0.0);
+        assert_eq!(line_similarity("", ""), 1.0);
+    }
+}
@@ -0,0 +1,57 @@
+//! Offline evals for the prompts, so we can tell if a prompt change made
+//! things better or worse before it ships.
+
+pub mod in_line_edit;
+pub mod metrics;
+pub mod reranking;
+pub mod results;
+pub mod usage;
+
+use llm_client::{
+    clients::types::LLMType,
+    provider::{LLMProvider, LLMProviderAPIKeys},
+};
+
+use self::usage::ModelPricing;
+
+/// A model we run the evals against
+#[derive(Debug, Clone)]
+pub struct EvalModel {
+    llm_type: LLMType,
+    provider: LLMProvider,
+    api_key: LLMProviderAPIKeys,
+    pricing: Option<ModelPricing>,
+}
+
+impl EvalModel {
+    pub fn new(llm_type: LLMType, provider: LLMProvider, api_key: LLMProviderAPIKeys) -> Self {
+        Self {
+            llm_type,
+            provider,
+            api_key,
+            pricing: None,
+        }
+    }
+
+    /// Without the pricing we only report the tokens
+    pub fn set_pricing(mut self, pricing: ModelPricing) -> Self {
+        self.pricing = Some(pricing);
+        self
+    }
+
+    pub fn llm_type(&self) -> &LLMType {
+        &self.llm_type
+    }
+
+    pub fn provider(&self) -> &LLMProvider {
+        &self.provider
+    }
+
+    pub fn api_key(&self) -> &LLMProviderAPIKeys {
+        &self.api_key
+    }
+
+    pub fn pricing(&self) -> Option<&ModelPricing> {
+        self.pricing.as_ref()
+    }
+}
@@ -0,0 +1,603 @@
+//! Offline evals for the reranking prompts. A dataset is a JSONL file, one
+//! case per line with the query, the candidate code spans and the ids of the
+//! ones which are relevant:
+//!
+//! ```text
+//! {"id": "client", "query": "where do we create the client?", "relevant": ["a"], "candidates": [{"id": "a", "file_path": "src/client.rs", "start_line": 0, "end_line": 10, "data": "..."}]}
+//! ```
+//!
+//! The runner reranks every case with each model and strategy through the
+//! [`LLMBroker`] it is given, give it a [`ReplayLLMClient`] so the evals run
+//! offline and the numbers are the same between runs.
+//!
+//! [`ReplayLLMClient`]: llm_client::clients::replay::ReplayLLMClient
+
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+    sync::Arc,
+    time::Instant,
+};
+
+use llm_client::{
+    broker::LLMBroker,
+    clients::types::{LLMClientError, LLMType},
+    tokenizer::tokenizer::LLMTokenizer,
+};
+
+use super::{
+    metrics::{mean, ndcg_at_k, percentile, recall_at, reciprocal_rank},
+    usage::{LLMUsage, ModelPricing, UsageTracker},
+    EvalModel,
+};
+use crate::reranking::{
+    broker::ReRankBroker,
+    types::{CodeSpan, ReRankCodeSpanRequest, ReRankStrategy},
+};
+
+#[derive(thiserror::Error, Debug)]
+pub enum RerankEvalError {
+    #[error("failed to read the dataset: {0}")]
+    IOError(#[from] std::io::Error),
+
+    #[error("serde failed: {0}")]
+    SerdeError(#[from] serde_json::Error),
+
+    #[error("invalid case {0}: {1}")]
+    InvalidCase(String, String),
+
+    #[error("LLMClientError: {0}")]
+    LLMClientError(#[from] LLMClientError),
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct RerankEvalCandidate {
+    pub id: String,
+    pub file_path: String,
+    pub start_line: u64,
+    pub end_line: u64,
+    pub data: String,
+}
+
+impl RerankEvalCandidate {
+    fn code_span(&self) -> CodeSpan {
+        CodeSpan::new(
+            self.file_path.to_owned(),
+            self.start_line,
+            self.end_line,
+            self.data.to_owned(),
+        )
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct RerankEvalCase {
+    pub id: String,
+    pub query: String,
+    pub candidates: Vec<RerankEvalCandidate>,
+    /// The ids of the candidates which answer the query
+    pub relevant: Vec<String>,
+}
+
+impl RerankEvalCase {
+    /// We get code spans back from the reranker and find the candidate by its
+    /// location, so the locations have to be unique along with the ids
+    fn validate(&self) -> Result<(), RerankEvalError> {
+        let invalid = |reason: String| RerankEvalError::InvalidCase(self.id.to_owned(), reason);
+        let mut ids = HashSet::new();
+        let mut locations = HashSet::new();
+        for candidate in self.candidates.iter() {
+            if !ids.insert(candidate.id.as_str()) {
+                return Err(invalid(format!("duplicate candidate id {}", candidate.id)));
+            }
+            if !locations.insert((
+                candidate.file_path.as_str(),
+                candidate.start_line,
+                candidate.end_line,
+            )) {
+                return Err(invalid(format!(
+                    "candidate {} has the same location as another one",
+                    candidate.id
+                )));
+            }
+        }
+        if let Some(relevant) = self
+            .relevant
+            .iter()
+            .find(|relevant| !ids.contains(relevant.as_str()))
+        {
+            return Err(invalid(format!(
+                "relevant id {relevant} is not a candidate"
+            )));
+        }
+        Ok(())
+    }
+
+    /// The candidate ids in the order of the reranked code spans
+    fn ranked_ids(&self, code_spans: &[CodeSpan]) -> Vec<String> {
+        let ids = self
+            .candidates
+            .iter()
+            .map(|candidate| {
+                (
+                    (
+                        candidate.file_path.as_str(),
+                        candidate.start_line,
+                        candidate.end_line,
+                    ),
+                    candidate.id.as_str(),
+                )
+            })
+            .collect::<HashMap<_, _>>();
+        code_spans
+            .iter()
+            .filter_map(|code_span| {
+                ids.get(&(
+                    code_span.file_path(),
+                    code_span.start_line(),
+                    code_span.end_line(),
+                ))
+                .map(|id| id.to_string())
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RerankEvalDataset {
+    name: String,
+    cases: Vec<RerankEvalCase>,
+}
+
+impl RerankEvalDataset {
+    /// Loads the JSONL file, the dataset is named after the file
+    pub fn load(path: &Path) -> Result<Self, RerankEvalError> {
+        let name = path
+            .file_stem()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        Self::from_jsonl(name, &std::fs::read_to_string(path)?)
+    }
+
+    pub fn from_jsonl(name: String, jsonl: &str) -> Result<Self, RerankEvalError> {
+        let cases = jsonl
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(serde_json::from_str::<RerankEvalCase>)
+            .collect::<Result<Vec<_>, _>>()?;
+        Self::new(name, cases)
+    }
+
+    pub fn new(name: String, cases: Vec<RerankEvalCase>) -> Result<Self, RerankEvalError> {
+        cases.iter().try_for_each(|case| case.validate())?;
+        Ok(Self { name, cases })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn cases(&self) -> &[RerankEvalCase] {
+        self.cases.as_slice()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RerankEvalConfig {
+    models: Vec<EvalModel>,
+    strategies: Vec<ReRankStrategy>,
+    k: usize,
+    limit: usize,
+    token_limit: i64,
+}
+
+impl RerankEvalConfig {
+    pub fn new(models: Vec<EvalModel>) -> Self {
+        Self {
+            models,
+            strategies: vec![ReRankStrategy::ListWise, ReRankStrategy::PointWise],
+            k: 5,
+            limit: 5,
+            // the rerankers skip the llm when the spans fit in the token
+            // limit, we always want to go through it
+            token_limit: 0,
+        }
+    }
+
+    pub fn set_strategies(mut self, strategies: Vec<ReRankStrategy>) -> Self {
+        self.strategies = strategies;
+        self
+    }
+
+    /// The cut off for NDCG
+    pub fn set_k(mut self, k: usize) -> Self {
+        self.k = k;
+        self
+    }
+
+    /// How many code spans we ask the reranker for, this is also the cut off
+    /// for recall
+    pub fn set_limit(mut self, limit: usize) -> Self {
+        self.limit = limit;
+        self
+    }
+
+    pub fn set_token_limit(mut self, token_limit: i64) -> Self {
+        self.token_limit = token_limit;
+        self
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct RerankEvalCaseResult {
+    pub case_id: String,
+    pub ranked: Vec<String>,
+    pub ndcg: f64,
+    pub reciprocal_rank: f64,
+    pub recall: f64,
+    pub latency_ms: f64,
+    pub usage: LLMUsage,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct RerankEvalSummary {
+    pub cases: usize,
+    pub failed: usize,
+    pub ndcg: f64,
+    pub mrr: f64,
+    pub recall: f64,
+    pub usage: LLMUsage,
+    pub cost: Option<f64>,
+    pub latency_mean_ms: f64,
+    pub latency_p50_ms: f64,
+    pub latency_p95_ms: f64,
+}
+
+impl RerankEvalSummary {
+    /// The failed cases count as 0 for the metrics, so a run which errors
+    /// does not look better than one which ranks badly
+    fn new(cases: &[RerankEvalCaseResult], pricing: Option<&ModelPricing>) -> Self {
+        let metric = |value: fn(&RerankEvalCaseResult) -> f64| {
+            mean(&cases.iter().map(value).collect::<Vec<_>>())
+        };
+        let latencies = cases.iter().map(|case| case.latency_ms).collect::<Vec<_>>();
+        let mut usage = LLMUsage::default();
+        cases.iter().for_each(|case| usage.add(&case.usage));
+        Self {
+            cases: cases.len(),
+            failed: cases.iter().filter(|case| case.error.is_some()).count(),
+            ndcg: metric(|case| case.ndcg),
+            mrr: metric(|case| case.reciprocal_rank),
+            recall: metric(|case| case.recall),
+            cost: pricing.map(|pricing| pricing.cost(usage.input_tokens, usage.output_tokens)),
+            usage,
+            latency_mean_ms: mean(&latencies),
+            latency_p50_ms: percentile(&latencies, 50.0),
+            latency_p95_ms: percentile(&latencies, 95.0),
+        }
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct RerankEvalRun {
+    pub llm_type: LLMType,
+    pub strategy: ReRankStrategy,
+    pub summary: RerankEvalSummary,
+    pub cases: Vec<RerankEvalCaseResult>,
+}
+
+impl RerankEvalRun {
+    fn label(&self) -> String {
+        format!("{} {:?}", self.llm_type, self.strategy)
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct RerankEvalReport {
+    pub dataset: String,
+    pub k: usize,
+    pub limit: usize,
+    pub runs: Vec<RerankEvalRun>,
+}
+
+impl RerankEvalReport {
+    pub fn load(path: &Path) -> Result<Self, RerankEvalError> {
+        Ok(serde_json::from_str(&std::fs::read_to_string(path)?)?)
+    }
+
+    pub fn write_json(&self, path: &Path) -> Result<(), RerankEvalError> {
+        std::fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    /// A table with a row for each model and strategy, with a baseline report
+    /// (say from before a prompt change) we also show how the metrics moved
+    pub fn to_markdown(&self, baseline: Option<&RerankEvalReport>) -> String {
+        let baseline_runs = baseline
+            .map(|baseline| {
+                baseline
+                    .runs
+                    .iter()
+                    .map(|run| (run.label(), &run.summary))
+                    .collect::<HashMap<_, _>>()
+            })
+            .unwrap_or_default();
+        let k = self.k;
+        let limit = self.limit;
+        let mut markdown = format!(
+            "# Reranking eval: {}\n\n| run | templates | NDCG@{k} | MRR | recall@{limit} | failed | requests | tokens (in/out) | cost | latency mean/p50/p95 (ms) |\n|---|---|---|---|---|---|---|---|---|---|\n",
+            self.dataset
+        );
+        for run in self.runs.iter() {
+            let summary = &run.summary;
+            let baseline = baseline_runs.get(&run.label());
+            let metric = |value: fn(&RerankEvalSummary) -> f64| match baseline {
+                Some(baseline) => format!(
+                    "{:.3} ({:+.3})",
+                    value(summary),
+                    value(summary) - value(baseline)
+                ),
+                None => format!("{:.3}", value(summary)),
+            };
+            markdown.push_str(&format!(
+                "| {} | {} | {} | {} | {} | {}/{} | {} | {}/{} | {} | {:.0}/{:.0}/{:.0} |\n",
+                run.label(),
+                summary
+                    .usage
+                    .templates
+                    .iter()
+                    .cloned()
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                metric(|summary| summary.ndcg),
+                metric(|summary| summary.mrr),
+                metric(|summary| summary.recall),
+                summary.failed,
+                summary.cases,
+                summary.usage.requests,
+                summary.usage.input_tokens,
+                summary.usage.output_tokens,
+                summary
+                    .cost
+                    .map(|cost| format!("${cost:.4}"))
+                    .unwrap_or_else(|| "-".to_owned()),
+                summary.latency_mean_ms,
+                summary.latency_p50_ms,
+                summary.latency_p95_ms,
+            ));
+        }
+        markdown
+    }
+}
+
+pub struct RerankEvalRunner {
+    rerank_broker: ReRankBroker,
+    llm_broker: Arc<LLMBroker>,
+    tokenizer: Arc<LLMTokenizer>,
+}
+
+impl RerankEvalRunner {
+    pub fn new(llm_broker: Arc<LLMBroker>, tokenizer: Arc<LLMTokenizer>) -> Self {
+        Self {
+            rerank_broker: ReRankBroker::new(),
+            llm_broker,
+            tokenizer,
+        }
+    }
+
+    /// Evaluates the prompts of this broker, built with
+    /// [`ReRankBroker::with_templates`] this compares the prompt versions
+    pub fn set_rerank_broker(mut self, rerank_broker: ReRankBroker) -> Self {
+        self.rerank_broker = rerank_broker;
+        self
+    }
+
+    /// Runs the cases one after the other, so the latencies and the usage we
+    /// read back from `llm_data` belong to a single case
+    pub async fn run(
+        &self,
+        dataset: &RerankEvalDataset,
+        config: &RerankEvalConfig,
+    ) -> Result<RerankEvalReport, RerankEvalError> {
+        let mut usage_tracker =
+            UsageTracker::new(self.llm_broker.clone(), self.tokenizer.clone()).await?;
+        let mut runs = vec![];
+        for model in config.models.iter() {
+            for strategy in config.strategies.iter() {
+                let mut cases = vec![];
+                for case in dataset.cases() {
+                    let request = ReRankCodeSpanRequest::new(
+                        case.query.to_owned(),
+                        config.limit,
+                        config.token_limit,
+                        case.candidates
+                            .iter()
+                            .map(|candidate| candidate.code_span())
+                            .collect(),
+                        strategy.clone(),
+                        model.llm_type().clone(),
+                    );
+                    let started = Instant::now();
+                    let result = self
+                        .rerank_broker
+                        .rerank(
+                            model.api_key().clone(),
+                            model.provider().clone(),
+                            request,
+                            self.llm_broker.clone(),
+                            self.tokenizer.clone(),
+                        )
+                        .await;
+                    let latency_ms = started.elapsed().as_secs_f64() * 1000.0;
+                    let usage = usage_tracker.take(model.llm_type()).await?;
+                    let (ranked, error) = match result {
+                        Ok(code_spans) => (case.ranked_ids(&code_spans), None),
+                        Err(e) => (vec![], Some(e.to_string())),
+                    };
+                    let relevant = case.relevant.iter().cloned().collect::<HashSet<_>>();
+                    cases.push(RerankEvalCaseResult {
+                        case_id: case.id.to_owned(),
+                        ndcg: ndcg_at_k(&ranked, &relevant, config.k),
+                        reciprocal_rank: reciprocal_rank(&ranked, &relevant),
+                        recall: recall_at(&ranked, &relevant, config.limit),
+                        ranked,
+                        latency_ms,
+                        usage,
+                        error,
+                    });
+                }
+                runs.push(RerankEvalRun {
+                    llm_type: model.llm_type().clone(),
+                    strategy: strategy.clone(),
+                    summary: RerankEvalSummary::new(&cases, model.pricing()),
+                    cases,
+                });
+            }
+        }
+        Ok(RerankEvalReport {
+            dataset: dataset.name().to_owned(),
+            k: config.k,
+            limit: config.limit,
+            runs,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use llm_client::{
+        broker::LLMBroker,
+        clients::{
+            mock::{MockLLMClient, MockResponse},
+            replay::{ReplayLLMClient, ReplayMode},
+            types::{LLMClient, LLMClientCapabilities, LLMType},
+        },
+        config::LLMBrokerConfiguration,
+        provider::{LLMProvider, LLMProviderAPIKeys, TogetherAIProvider},
+        tokenizer::tokenizer::LLMTokenizer,
+    };
+
+    use super::{
+        RerankEvalConfig, RerankEvalDataset, RerankEvalError, RerankEvalReport, RerankEvalRunner,
+    };
+    use crate::{
+        eval::{usage::ModelPricing, EvalModel},
+        reranking::types::ReRankStrategy,
+    };
+
+    const DATASET: &str = r#"
+{"id": "single", "query": "where is the relevant snippet?", "relevant": ["relevant"], "candidates": [{"id": "noise", "file_path": "src/noise.rs", "start_line": 0, "end_line": 1, "data": "fn noise() {}"}, {"id": "relevant", "file_path": "src/relevant.rs", "start_line": 0, "end_line": 1, "data": "fn relevant_snippet() {}"}, {"id": "other", "file_path": "src/other.rs", "start_line": 4, "end_line": 5, "data": "fn other() {}"}]}
+{"id": "pair", "query": "where are the relevant snippets?", "relevant": ["first", "second"], "candidates": [{"id": "first", "file_path": "src/relevant.rs", "start_line": 0, "end_line": 1, "data": "fn relevant_snippet_again() {}"}, {"id": "second", "file_path": "src/second.rs", "start_line": 2, "end_line": 3, "data": "fn also_needed() {}"}]}
+"#;
+
+    async fn run_eval(
+        client: Box<dyn LLMClient + Send + Sync>,
+        dataset: &RerankEvalDataset,
+    ) -> RerankEvalReport {
+        let data_dir = tempfile::tempdir().expect("data dir to be created");
+        let llm_broker = LLMBroker::new(LLMBrokerConfiguration::new(data_dir.path().to_owned()))
+            .await
+            .expect("broker to startup")
+            .add_provider(LLMProvider::TogetherAI, client);
+        let config = RerankEvalConfig::new(vec![EvalModel::new(
+            LLMType::Mixtral,
+            LLMProvider::TogetherAI,
+            LLMProviderAPIKeys::TogetherAI(TogetherAIProvider::new("key".to_owned())),
+        )
+        .set_pricing(ModelPricing::new(1.0, 2.0))])
+        .set_limit(2)
+        .set_strategies(vec![ReRankStrategy::PointWise, ReRankStrategy::ListWise]);
+        RerankEvalRunner::new(
+            Arc::new(llm_broker),
+            Arc::new(LLMTokenizer::new().expect("tokenizer to load")),
+        )
+        .run(dataset, &config)
+        .await
+        .expect("eval to run")
+    }
+
+    #[tokio::test]
+    async fn test_rerank_eval_replays_the_recording() {
+        let dataset =
+            RerankEvalDataset::from_jsonl("rerank".to_owned(), DATASET).expect("dataset to load");
+        let fixture_dir = tempfile::tempdir().expect("fixture dir to be created");
+        let mock = MockLLMClient::new(LLMProvider::TogetherAI)
+            .set_capabilities(LLMClientCapabilities::new(true, true))
+            .respond_when(
+                "ordering the code snippets",
+                MockResponse::answer("<id>\nrelevant.rs::0\n</id>\n</reranking>"),
+            )
+            .respond_when("fn relevant_snippet", MockResponse::answer(" Yes"))
+            .set_default_response(MockResponse::answer(" No"));
+        let recorded = run_eval(
+            Box::new(ReplayLLMClient::record(
+                fixture_dir.path(),
+                ReplayMode::Record,
+                Box::new(mock),
+            )),
+            &dataset,
+        )
+        .await;
+
+        let pointwise = &recorded.runs[0];
+        assert_eq!(pointwise.strategy, ReRankStrategy::PointWise);
+        assert_eq!(pointwise.summary.failed, 0);
+        assert_eq!(pointwise.cases[0].ranked, vec!["relevant"]);
+        assert_eq!(pointwise.cases[0].reciprocal_rank, 1.0);
+        assert_eq!(pointwise.cases[1].ranked, vec!["first"]);
+        assert_eq!(pointwise.cases[1].recall, 0.5);
+        assert_eq!(pointwise.summary.mrr, 1.0);
+        assert_eq!(pointwise.summary.recall, 0.75);
+        // one request for each candidate
+        assert_eq!(pointwise.summary.usage.requests, 5);
+        assert!(pointwise
+            .summary
+            .usage
+            .templates
+            .contains("reranking.mistral.pointwise v1"));
+        assert!(pointwise.summary.cost.is_some_and(|cost| cost > 0.0));
+
+        // the replay has no client to fall back to, so it only works offline
+        // if every request was recorded
+        let replayed = run_eval(
+            Box::new(ReplayLLMClient::replay(
+                fixture_dir.path(),
+                LLMProvider::TogetherAI,
+            )),
+            &dataset,
+        )
+        .await;
+        for (recorded, replayed) in recorded.runs.iter().zip(replayed.runs.iter()) {
+            assert_eq!(replayed.summary.failed, 0);
+            assert_eq!(recorded.summary.ndcg, replayed.summary.ndcg);
+            assert_eq!(recorded.summary.usage, replayed.summary.usage);
+            assert_eq!(
+                recorded
+                    .cases
+                    .iter()
+                    .map(|case| &case.ranked)
+                    .collect::<Vec<_>>(),
+                replayed
+                    .cases
+                    .iter()
+                    .map(|case| &case.ranked)
+                    .collect::<Vec<_>>()
+            );
+        }
+        let markdown = replayed.to_markdown(Some(&recorded));
+        assert!(markdown.contains(
+            "| Mixtral PointWise | reranking.mistral.pointwise v1 | 0.807 (+0.000) | 1.000 (+0.000) |"
+        ));
+
+        assert!(matches!(
+            RerankEvalDataset::from_jsonl(
+                "invalid".to_owned(),
+                r#"{"id": "case", "query": "query", "relevant": ["missing"], "candidates": []}"#
+            ),
+            Err(RerankEvalError::InvalidCase(_, _))
+        ));
+    }
+}
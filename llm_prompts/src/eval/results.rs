@@ -0,0 +1,134 @@
+//! Where we keep the inline edit eval results, one row per case along with
+//! the model and the prompt template version, so we can see how the scores
+//! move between runs.
+
+use std::path::Path;
+
+use llm_client::clients::types::LLMType;
+use sqlx::{types::chrono::NaiveDateTime, SqlitePool};
+
+use super::in_line_edit::InLineEditEvalCaseResult;
+use crate::{in_line_edit::storage::StorageError, templates::template::TemplateId};
+
+/// The scores of a model and template in a run, the rates are `None` when
+/// none of the cases were scored that way
+#[derive(Debug, Clone, PartialEq)]
+pub struct InLineEditEvalTrend {
+    pub run_id: i64,
+    pub created_at: NaiveDateTime,
+    pub llm_type: LLMType,
+    pub template: TemplateId,
+    pub cases: i64,
+    pub failed: i64,
+    pub exact_match_rate: Option<f64>,
+    pub mean_similarity: Option<f64>,
+    pub check_pass_rate: Option<f64>,
+}
+
+pub struct InLineEditEvalStore {
+    db: SqlitePool,
+}
+
+impl InLineEditEvalStore {
+    pub async fn init(data_dir: &Path) -> Result<Self, StorageError> {
+        let data_dir = data_dir.to_string_lossy().to_string();
+        let url = format!("sqlite://{data_dir}/inline_edit_evals.data?mode=rwc");
+        let db = SqlitePool::connect(&url).await?;
+        Self::from_pool(db).await
+    }
+
+    pub async fn from_pool(db: SqlitePool) -> Result<Self, StorageError> {
+        if let Err(e) = sqlx::migrate!().run(&db).await {
+            db.close().await;
+            return Err(e.into());
+        }
+        Ok(Self { db })
+    }
+
+    pub async fn create_run(&self, dataset: &str) -> Result<i64, StorageError> {
+        let result = sqlx::query! {
+            r#"
+            INSERT INTO inline_edit_eval_runs (dataset)
+            VALUES ($1)
+            "#,
+            dataset,
+        }
+        .execute(&self.db)
+        .await?;
+        Ok(result.last_insert_rowid())
+    }
+
+    pub async fn add_result(
+        &self,
+        run_id: i64,
+        result: &InLineEditEvalCaseResult,
+    ) -> Result<i64, StorageError> {
+        let llm_type = serde_json::to_string(&result.llm_type)?;
+        let template_name = result.template.name();
+        let template_version = result.template.version();
+        let result = sqlx::query! {
+            r#"
+            INSERT INTO inline_edit_eval_results (run_id, case_id, llm_type, template_name, template_version,
+                output, exact_match, similarity, check_passed, check_output, error, latency_ms, llm_data_id)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13)
+            "#,
+            run_id,
+            result.case_id,
+            llm_type,
+            template_name,
+            template_version,
+            result.output,
+            result.exact_match,
+            result.similarity,
+            result.check_passed,
+            result.check_output,
+            result.error,
+            result.latency_ms,
+            result.llm_data_id,
+        }
+        .execute(&self.db)
+        .await?;
+        Ok(result.last_insert_rowid())
+    }
+
+    /// The scores of every run of the dataset, oldest run first
+    pub async fn trends(&self, dataset: &str) -> Result<Vec<InLineEditEvalTrend>, StorageError> {
+        let rows = sqlx::query! {
+            r#"
+            SELECT runs.id as "run_id!", runs.created_at as "created_at: NaiveDateTime",
+                results.llm_type, results.template_name, results.template_version,
+                COUNT(*) as "cases!: i64",
+                SUM(results.error IS NOT NULL) as "failed!: i64",
+                AVG(results.exact_match) as "exact_match_rate: f64",
+                AVG(results.similarity) as "mean_similarity: f64",
+                AVG(results.check_passed) as "check_pass_rate: f64"
+            FROM inline_edit_eval_results results
+            JOIN inline_edit_eval_runs runs ON runs.id = results.run_id
+            WHERE runs.dataset = $1
+            GROUP BY runs.id, results.llm_type, results.template_name, results.template_version
+            ORDER BY runs.id ASC, results.llm_type ASC, results.template_name ASC
+            "#,
+            dataset,
+        }
+        .fetch_all(&self.db)
+        .await?;
+        rows.into_iter()
+            .map(|row| {
+                let template_version = u32::try_from(row.template_version).map_err(|_| {
+                    StorageError::InvalidColumnValue(row.template_version.to_string())
+                })?;
+                Ok(InLineEditEvalTrend {
+                    run_id: row.run_id,
+                    created_at: row.created_at,
+                    llm_type: serde_json::from_str(&row.llm_type)?,
+                    template: TemplateId::new(row.template_name, template_version),
+                    cases: row.cases,
+                    failed: row.failed,
+                    exact_match_rate: row.exact_match_rate,
+                    mean_similarity: row.mean_similarity,
+                    check_pass_rate: row.check_pass_rate,
+                })
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,135 @@
+//! What an eval case cost us, read back from the `llm_data` rows the broker
+//! wrote while we ran the case.
+
+use std::{collections::BTreeSet, sync::Arc};
+
+use chrono::{Duration, NaiveDateTime, Utc};
+use llm_client::{
+    broker::LLMBroker,
+    clients::types::{LLMClientError, LLMType},
+    llm_data::{LLMDataFilter, LLMDataRow},
+    tokenizer::tokenizer::{LLMTokenizer, LLMTokenizerInput},
+};
+
+use crate::templates::template::{
+    PROMPT_TEMPLATE_METADATA_KEY, PROMPT_TEMPLATE_VERSION_METADATA_KEY,
+};
+
+/// The price of a model in dollars per million tokens
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct ModelPricing {
+    input_per_million: f64,
+    output_per_million: f64,
+}
+
+impl ModelPricing {
+    pub fn new(input_per_million: f64, output_per_million: f64) -> Self {
+        Self {
+            input_per_million,
+            output_per_million,
+        }
+    }
+
+    pub fn cost(&self, input_tokens: usize, output_tokens: usize) -> f64 {
+        (input_tokens as f64 * self.input_per_million
+            + output_tokens as f64 * self.output_per_million)
+            / 1_000_000.0
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct LLMUsage {
+    pub requests: usize,
+    pub input_tokens: usize,
+    pub output_tokens: usize,
+    /// The prompt templates the requests were made with, as `name v<version>`
+    pub templates: BTreeSet<String>,
+}
+
+impl LLMUsage {
+    pub fn add(&mut self, other: &LLMUsage) {
+        self.requests += other.requests;
+        self.input_tokens += other.input_tokens;
+        self.output_tokens += other.output_tokens;
+        self.templates.extend(other.templates.iter().cloned());
+    }
+}
+
+/// Keeps track of the `llm_data` rows we have already seen, so each case only
+/// picks up the requests it made. The cases have to run one after the other.
+pub struct UsageTracker {
+    llm_broker: Arc<LLMBroker>,
+    tokenizer: Arc<LLMTokenizer>,
+    // the created_at column only has second precision, the ids tell the rows
+    // apart within the same second
+    since: NaiveDateTime,
+    last_id: Option<i64>,
+}
+
+impl UsageTracker {
+    pub async fn new(
+        llm_broker: Arc<LLMBroker>,
+        tokenizer: Arc<LLMTokenizer>,
+    ) -> Result<Self, LLMClientError> {
+        let since = Utc::now().naive_utc() - Duration::minutes(1);
+        let last_id = llm_broker
+            .llm_data(&LLMDataFilter::new().set_since(since))
+            .await?
+            .last()
+            .map(|row| row.id());
+        Ok(Self {
+            llm_broker,
+            tokenizer,
+            since,
+            last_id,
+        })
+    }
+
+    /// The usage of the requests made since the last time we were called
+    pub async fn take(&mut self, llm_type: &LLMType) -> Result<LLMUsage, LLMClientError> {
+        let rows = self
+            .llm_broker
+            .llm_data(&LLMDataFilter::new().set_since(self.since))
+            .await?
+            .into_iter()
+            .filter(|row| self.last_id.is_none_or(|last_id| row.id() > last_id))
+            .collect::<Vec<_>>();
+        self.last_id = rows.last().map(|row| row.id()).or(self.last_id);
+        let mut usage = LLMUsage::default();
+        for row in rows.iter() {
+            usage.add(&self.row_usage(llm_type, row));
+        }
+        Ok(usage)
+    }
+
+    fn row_usage(&self, llm_type: &LLMType, row: &LLMDataRow) -> LLMUsage {
+        let llm_type = row.llm_type().unwrap_or_else(|| llm_type.clone());
+        let input = match (row.chat_messages(), row.prompt()) {
+            (Some(messages), _) => LLMTokenizerInput::Messages(messages),
+            (None, prompt) => LLMTokenizerInput::Prompt(prompt.unwrap_or_default().to_owned()),
+        };
+        let metadata = row.metadata();
+        let templates = match (
+            metadata.get(PROMPT_TEMPLATE_METADATA_KEY),
+            metadata.get(PROMPT_TEMPLATE_VERSION_METADATA_KEY),
+        ) {
+            (Some(name), Some(version)) => BTreeSet::from([format!("{name} v{version}")]),
+            _ => BTreeSet::new(),
+        };
+        LLMUsage {
+            requests: 1,
+            input_tokens: self
+                .tokenizer
+                .count_tokens_or_estimate(&llm_type, input)
+                .count(),
+            output_tokens: self
+                .tokenizer
+                .count_tokens_or_estimate(
+                    &llm_type,
+                    LLMTokenizerInput::Prompt(row.response().unwrap_or_default().to_owned()),
+                )
+                .count(),
+            templates,
+        }
+    }
+}
@@ -1,15 +1,18 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, sync::Arc};
 
 use llm_client::clients::types::LLMType;
 
 use super::{
+    doc_helpers::{extract_documentation, DocumentationBlock},
     mistral::MistralLineEditPrompt,
     openai::OpenAILineEditPrompt,
+    test_generation::{parse_inline_tests_output, InLineTestResponse},
     types::{
         InLineDocRequest, InLineEditPrompt, InLineEditPromptError, InLineEditRequest,
-        InLineFixRequest, InLinePromptResponse,
+        InLineFixRequest, InLinePromptResponse, InLineTestRequest,
     },
 };
+use crate::templates::registry::PromptTemplates;
 
 pub struct InLineEditPromptBroker {
     prompt_generators: HashMap<LLMType, Box<dyn InLineEditPrompt + Send + Sync>>,
@@ -17,18 +20,22 @@ pub struct InLineEditPromptBroker {
 
 impl InLineEditPromptBroker {
     pub fn new() -> Self {
+        Self::with_templates(PromptTemplates::defaults())
+    }
+
+    /// Uses these prompt templates instead of the embedded ones
+    pub fn with_templates(templates: Arc<PromptTemplates>) -> Self {
+        let openai = || Box::new(OpenAILineEditPrompt::with_templates(templates.clone()));
+        let mistral = || Box::new(MistralLineEditPrompt::with_templates(templates.clone()));
         let broker = Self {
             prompt_generators: HashMap::new(),
         };
         broker
-            .insert_prompt_generator(LLMType::GPT3_5_16k, Box::new(OpenAILineEditPrompt::new()))
-            .insert_prompt_generator(LLMType::Gpt4, Box::new(OpenAILineEditPrompt::new()))
-            .insert_prompt_generator(LLMType::Gpt4_32k, Box::new(OpenAILineEditPrompt::new()))
-            .insert_prompt_generator(
-                LLMType::MistralInstruct,
-                Box::new(MistralLineEditPrompt::new()),
-            )
-            .insert_prompt_generator(LLMType::Mixtral, Box::new(MistralLineEditPrompt::new()))
+            .insert_prompt_generator(LLMType::GPT3_5_16k, openai())
+            .insert_prompt_generator(LLMType::Gpt4, openai())
+            .insert_prompt_generator(LLMType::Gpt4_32k, openai())
+            .insert_prompt_generator(LLMType::MistralInstruct, mistral())
+            .insert_prompt_generator(LLMType::Mixtral, mistral())
     }
 
     pub fn insert_prompt_generator(
@@ -75,4 +82,32 @@ impl InLineEditPromptBroker {
         let prompt_generator = self.get_prompt_generator(llm_type)?;
         Ok(prompt_generator.inline_doc(request))
     }
+
+    /// The comment block to insert from the answer to the doc prompt, this
+    /// depends on the language and not on the model
+    pub fn parse_doc_output(
+        &self,
+        request: &InLineDocRequest,
+        llm_output: &str,
+    ) -> Result<DocumentationBlock, InLineEditPromptError> {
+        extract_documentation(request, llm_output)
+    }
+
+    pub fn get_tests_prompt(
+        &self,
+        llm_type: &LLMType,
+        request: InLineTestRequest,
+    ) -> Result<InLinePromptResponse, InLineEditPromptError> {
+        let prompt_generator = self.get_prompt_generator(llm_type)?;
+        Ok(prompt_generator.inline_tests(request))
+    }
+
+    /// The tests and the file they go in from the answer to the tests prompt
+    pub fn parse_tests_output(
+        &self,
+        request: &InLineTestRequest,
+        llm_output: &str,
+    ) -> Result<InLineTestResponse, InLineEditPromptError> {
+        parse_inline_tests_output(request, llm_output)
+    }
 }
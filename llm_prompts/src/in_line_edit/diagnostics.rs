@@ -0,0 +1,426 @@
+//! Diagnostics which are passed to the inline fix prompts, these are modelled
+//! on the LSP `Diagnostic` type so the editor can forward them as is:
+//! https://microsoft.github.io/language-server-protocol/specifications/lsp/3.17/specification/#diagnostic
+//! All the lines and characters are 0 indexed as they are in LSP, we convert them
+//! to 1 indexed values only when rendering them for the prompt.
+
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(try_from = "u8", into = "u8")]
+pub enum DiagnosticSeverity {
+    Error,
+    Warning,
+    Information,
+    Hint,
+}
+
+impl TryFrom<u8> for DiagnosticSeverity {
+    type Error = String;
+
+    fn try_from(value: u8) -> Result<Self, String> {
+        match value {
+            1 => Ok(DiagnosticSeverity::Error),
+            2 => Ok(DiagnosticSeverity::Warning),
+            3 => Ok(DiagnosticSeverity::Information),
+            4 => Ok(DiagnosticSeverity::Hint),
+            _ => Err(format!("unknown diagnostic severity: {value}")),
+        }
+    }
+}
+
+impl From<DiagnosticSeverity> for u8 {
+    fn from(severity: DiagnosticSeverity) -> Self {
+        match severity {
+            DiagnosticSeverity::Error => 1,
+            DiagnosticSeverity::Warning => 2,
+            DiagnosticSeverity::Information => 3,
+            DiagnosticSeverity::Hint => 4,
+        }
+    }
+}
+
+impl fmt::Display for DiagnosticSeverity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DiagnosticSeverity::Error => write!(f, "error"),
+            DiagnosticSeverity::Warning => write!(f, "warning"),
+            DiagnosticSeverity::Information => write!(f, "info"),
+            DiagnosticSeverity::Hint => write!(f, "hint"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct DiagnosticPosition {
+    line: usize,
+    character: usize,
+}
+
+impl DiagnosticPosition {
+    pub fn new(line: usize, character: usize) -> Self {
+        Self { line, character }
+    }
+
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    pub fn character(&self) -> usize {
+        self.character
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct DiagnosticRange {
+    start: DiagnosticPosition,
+    end: DiagnosticPosition,
+}
+
+impl DiagnosticRange {
+    pub fn new(start: DiagnosticPosition, end: DiagnosticPosition) -> Self {
+        Self { start, end }
+    }
+
+    pub fn start(&self) -> &DiagnosticPosition {
+        &self.start
+    }
+
+    pub fn end(&self) -> &DiagnosticPosition {
+        &self.end
+    }
+}
+
+/// LSP allows the code to be either a number or a string
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(untagged)]
+pub enum DiagnosticCode {
+    Number(i64),
+    String(String),
+}
+
+impl fmt::Display for DiagnosticCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DiagnosticCode::Number(code) => write!(f, "{code}"),
+            DiagnosticCode::String(code) => write!(f, "{code}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct DiagnosticLocation {
+    uri: String,
+    range: DiagnosticRange,
+}
+
+impl DiagnosticLocation {
+    pub fn new(uri: String, range: DiagnosticRange) -> Self {
+        Self { uri, range }
+    }
+
+    pub fn uri(&self) -> &str {
+        &self.uri
+    }
+
+    pub fn range(&self) -> &DiagnosticRange {
+        &self.range
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct DiagnosticRelatedInformation {
+    location: DiagnosticLocation,
+    message: String,
+}
+
+impl DiagnosticRelatedInformation {
+    pub fn new(location: DiagnosticLocation, message: String) -> Self {
+        Self { location, message }
+    }
+
+    pub fn location(&self) -> &DiagnosticLocation {
+        &self.location
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Diagnostic {
+    range: DiagnosticRange,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    severity: Option<DiagnosticSeverity>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    code: Option<DiagnosticCode>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    source: Option<String>,
+    message: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    related_information: Vec<DiagnosticRelatedInformation>,
+}
+
+impl Diagnostic {
+    pub fn new(range: DiagnosticRange, message: String) -> Self {
+        Self {
+            range,
+            severity: None,
+            code: None,
+            source: None,
+            message,
+            related_information: vec![],
+        }
+    }
+
+    pub fn set_severity(mut self, severity: DiagnosticSeverity) -> Self {
+        self.severity = Some(severity);
+        self
+    }
+
+    pub fn set_code(mut self, code: DiagnosticCode) -> Self {
+        self.code = Some(code);
+        self
+    }
+
+    pub fn set_source(mut self, source: String) -> Self {
+        self.source = Some(source);
+        self
+    }
+
+    pub fn add_related_information(
+        mut self,
+        related_information: DiagnosticRelatedInformation,
+    ) -> Self {
+        self.related_information.push(related_information);
+        self
+    }
+
+    /// Parses the diagnostics from the raw LSP JSON, we accept both a single
+    /// diagnostic and an array of diagnostics (as sent in `publishDiagnostics`)
+    pub fn from_lsp_json(lsp_json: &str) -> Result<Vec<Self>, serde_json::Error> {
+        let value = serde_json::from_str::<serde_json::Value>(lsp_json)?;
+        Self::from_lsp_value(value)
+    }
+
+    pub fn from_lsp_value(value: serde_json::Value) -> Result<Vec<Self>, serde_json::Error> {
+        if value.is_array() {
+            serde_json::from_value(value)
+        } else {
+            Ok(vec![serde_json::from_value(value)?])
+        }
+    }
+
+    pub fn range(&self) -> &DiagnosticRange {
+        &self.range
+    }
+
+    pub fn severity(&self) -> Option<&DiagnosticSeverity> {
+        self.severity.as_ref()
+    }
+
+    pub fn code(&self) -> Option<&DiagnosticCode> {
+        self.code.as_ref()
+    }
+
+    pub fn source(&self) -> Option<&str> {
+        self.source.as_deref()
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    pub fn related_information(&self) -> &[DiagnosticRelatedInformation] {
+        self.related_information.as_slice()
+    }
+
+    /// The header for the diagnostic which looks like: error[E0308](rustc)
+    fn header(&self) -> String {
+        let severity = self
+            .severity
+            .map(|severity| severity.to_string())
+            .unwrap_or("error".to_owned());
+        let code = self
+            .code
+            .as_ref()
+            .map(|code| format!("[{code}]"))
+            .unwrap_or_default();
+        let source = self
+            .source
+            .as_ref()
+            .map(|source| format!("({source})"))
+            .unwrap_or_default();
+        format!("{severity}{code}{source}")
+    }
+
+    /// Renders the diagnostic as a single block of text for the prompt, the
+    /// lines are 1 indexed here since that's what the LLM expects
+    pub fn to_prompt(&self, file_path: &str) -> String {
+        let header = self.header();
+        let start = self.range.start();
+        let end = self.range.end();
+        let message = &self.message;
+        let mut prompt = format!(
+            "{header}: {message}\n  --> {file_path}:{}:{}-{}:{}",
+            start.line() + 1,
+            start.character() + 1,
+            end.line() + 1,
+            end.character() + 1,
+        );
+        self.related_information
+            .iter()
+            .for_each(|related_information| {
+                let location = related_information.location();
+                let related_message = related_information.message();
+                prompt.push_str(&format!(
+                    "\n  related: {}:{}: {related_message}",
+                    location.uri(),
+                    location.range().start().line() + 1,
+                ));
+            });
+        prompt
+    }
+}
+
+/// LSP positions count UTF-16 code units, this turns `character` into the
+/// index of the char it points at in the line, clamped to the end of the line
+pub fn utf16_to_char_index(line: &str, character: usize) -> usize {
+    let mut utf16_offset = 0;
+    for (index, c) in line.chars().enumerate() {
+        if utf16_offset >= character {
+            return index;
+        }
+        utf16_offset += c.len_utf16();
+    }
+    line.chars().count()
+}
+
+/// Renders the selection with line numbers and markers pointing at the exact
+/// columns which have a diagnostic, similar to how rustc shows errors:
+/// ```text
+/// 13 |     let a: u32 = "a";
+///    |                  ^^^ error[E0308]: mismatched types
+/// ```
+/// `selection_start_line` is the 0 indexed line in the file where the selection
+/// starts, diagnostics which do not overlap with the selection are skipped.
+pub fn annotate_selection(
+    in_range: &str,
+    selection_start_line: usize,
+    diagnostics: &[Diagnostic],
+) -> String {
+    let lines = in_range.lines().collect::<Vec<_>>();
+    let selection_end_line = selection_start_line + lines.len();
+    let gutter_width = selection_end_line.to_string().len();
+    let empty_gutter = " ".repeat(gutter_width);
+    let mut annotated_lines = vec![];
+    for (index, line) in lines.iter().enumerate() {
+        let line_number = selection_start_line + index;
+        annotated_lines.push(format!("{:>gutter_width$} | {line}", line_number + 1,));
+        diagnostics
+            .iter()
+            .filter(|diagnostic| {
+                let range = diagnostic.range();
+                range.start().line() <= line_number && line_number <= range.end().line()
+            })
+            .for_each(|diagnostic| {
+                let range = diagnostic.range();
+                let line_length = line.chars().count();
+                let start_character = if range.start().line() == line_number {
+                    utf16_to_char_index(line, range.start().character())
+                } else {
+                    0
+                };
+                let end_character = if range.end().line() == line_number {
+                    utf16_to_char_index(line, range.end().character())
+                } else {
+                    line_length
+                };
+                let marker_length = end_character.saturating_sub(start_character).max(1);
+                // we only show the message on the first line of the diagnostic
+                // so multiline diagnostics do not repeat it
+                let message = if range.start().line() == line_number {
+                    format!(" {}: {}", diagnostic.header(), diagnostic.message())
+                } else {
+                    String::new()
+                };
+                annotated_lines.push(format!(
+                    "{empty_gutter} | {}{}{message}",
+                    " ".repeat(start_character),
+                    "^".repeat(marker_length),
+                ));
+            });
+    }
+    annotated_lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        annotate_selection, utf16_to_char_index, Diagnostic, DiagnosticCode, DiagnosticSeverity,
+    };
+
+    #[test]
+    fn test_parsing_lsp_json() {
+        let lsp_json = r#"[{
+            "range": {"start": {"line": 1, "character": 17}, "end": {"line": 1, "character": 20}},
+            "severity": 1,
+            "code": "E0308",
+            "source": "rustc",
+            "message": "mismatched types",
+            "relatedInformation": [{
+                "location": {
+                    "uri": "file:///src/lib.rs",
+                    "range": {"start": {"line": 0, "character": 0}, "end": {"line": 0, "character": 3}}
+                },
+                "message": "expected due to this"
+            }]
+        }]"#;
+        let diagnostics = Diagnostic::from_lsp_json(lsp_json).expect("to parse");
+        assert_eq!(diagnostics.len(), 1);
+        let diagnostic = &diagnostics[0];
+        assert_eq!(diagnostic.severity(), Some(&DiagnosticSeverity::Error));
+        assert_eq!(
+            diagnostic.code(),
+            Some(&DiagnosticCode::String("E0308".to_owned()))
+        );
+        assert_eq!(
+            diagnostic.to_prompt("src/lib.rs"),
+            "error[E0308](rustc): mismatched types\n  --> src/lib.rs:2:18-2:21\n  related: file:///src/lib.rs:1: expected due to this"
+        );
+    }
+
+    #[test]
+    fn test_annotate_selection() {
+        let diagnostic = Diagnostic::from_lsp_json(
+            r#"{"range": {"start": {"line": 11, "character": 17}, "end": {"line": 11, "character": 20}}, "severity": 2, "code": 12, "message": "unused"}"#,
+        )
+        .expect("to parse")
+        .remove(0);
+        let in_range = "fn main() {\n    let a: u32 = bar;\n}";
+        assert_eq!(
+            annotate_selection(in_range, 10, &[diagnostic]),
+            "11 | fn main() {\n12 |     let a: u32 = bar;\n   |                  ^^^ warning[12]: unused\n13 | }"
+        );
+    }
+
+    #[test]
+    fn test_annotate_selection_with_utf16_positions() {
+        // the emoji is 2 UTF-16 code units, so the LSP puts `bar` at 15 while
+        // it is the 14th char
+        let line = "let s = \"😀\" + bar;";
+        assert_eq!(utf16_to_char_index(line, 15), 14);
+        assert_eq!(utf16_to_char_index(line, 100), line.chars().count());
+        let diagnostic = Diagnostic::from_lsp_json(
+            r#"{"range": {"start": {"line": 0, "character": 15}, "end": {"line": 0, "character": 18}}, "message": "unknown"}"#,
+        )
+        .expect("to parse")
+        .remove(0);
+        assert_eq!(
+            annotate_selection(line, 0, &[diagnostic]),
+            format!("1 | {line}\n  | {}^^^ error: unknown", " ".repeat(14))
+        );
+    }
+}
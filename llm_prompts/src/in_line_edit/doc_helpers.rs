@@ -1,22 +1,151 @@
-use super::types::InLineDocRequest;
+use super::types::{InLineDocRequest, InLineEditPromptError};
+
+/// Where the documentation goes relative to the symbol
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DocumentationPlacement {
+    /// Directly above the symbol (rustdoc, JSDoc, Go, Javadoc)
+    AboveNode,
+    /// As the first statement in the body of the symbol (python docstrings)
+    InsideBody,
+}
+
+/// How the documentation comment is written for a language
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommentSyntax {
+    /// Every line starts with the prefix, like `///` or `//`
+    Line(&'static str),
+    /// The comment starts and ends with the delimiters, like `/**` and `*/`
+    Block {
+        start: &'static str,
+        end: &'static str,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DocumentationStyle {
+    /// The name we use for the comment in the prompt
+    comment_type: &'static str,
+    syntax: CommentSyntax,
+    placement: DocumentationPlacement,
+    /// Extra guidance we pass to the LLM about the conventions
+    guidance: &'static str,
+}
+
+const RUST_STYLE: DocumentationStyle = DocumentationStyle {
+    comment_type: "Rustdoc comment",
+    syntax: CommentSyntax::Line("///"),
+    placement: DocumentationPlacement::AboveNode,
+    guidance: "Use /// line comments placed directly above the symbol, write in markdown and add # Errors or # Panics sections only when they apply.",
+};
+
+const PYTHON_STYLE: DocumentationStyle = DocumentationStyle {
+    comment_type: "docstring",
+    syntax: CommentSyntax::Block {
+        start: "\"\"\"",
+        end: "\"\"\"",
+    },
+    placement: DocumentationPlacement::InsideBody,
+    guidance: "Use a triple quoted \"\"\" docstring placed as the first statement inside the body, describe the Args, Returns and Raises when they apply.",
+};
+
+const TYPESCRIPT_STYLE: DocumentationStyle = DocumentationStyle {
+    comment_type: "TSDoc comment",
+    syntax: CommentSyntax::Block {
+        start: "/**",
+        end: "*/",
+    },
+    placement: DocumentationPlacement::AboveNode,
+    guidance: "Use a /** */ block comment placed directly above the symbol, with @param and @returns tags when they apply.",
+};
+
+const JAVASCRIPT_STYLE: DocumentationStyle = DocumentationStyle {
+    comment_type: "JSDoc comment",
+    syntax: CommentSyntax::Block {
+        start: "/**",
+        end: "*/",
+    },
+    placement: DocumentationPlacement::AboveNode,
+    guidance: "Use a /** */ block comment placed directly above the symbol, with @param {type} and @returns {type} tags when they apply.",
+};
+
+const GO_STYLE: DocumentationStyle = DocumentationStyle {
+    comment_type: "Go doc comment",
+    syntax: CommentSyntax::Line("//"),
+    placement: DocumentationPlacement::AboveNode,
+    guidance: "Use // line comments placed directly above the symbol, the first sentence must start with the name of the symbol.",
+};
+
+const JAVA_STYLE: DocumentationStyle = DocumentationStyle {
+    comment_type: "Javadoc comment",
+    syntax: CommentSyntax::Block {
+        start: "/**",
+        end: "*/",
+    },
+    placement: DocumentationPlacement::AboveNode,
+    guidance: "Use a /** */ block comment placed directly above the symbol, with @param, @return and @throws tags when they apply.",
+};
+
+const DEFAULT_STYLE: DocumentationStyle = DocumentationStyle {
+    comment_type: "documentation comment",
+    syntax: CommentSyntax::Line("//"),
+    placement: DocumentationPlacement::AboveNode,
+    guidance: "Place the documentation comment directly above the symbol.",
+};
+
+impl DocumentationStyle {
+    pub fn for_language(language: &str) -> Self {
+        match language {
+            "rust" => RUST_STYLE,
+            "python" => PYTHON_STYLE,
+            "typescript" | "typescriptreact" => TYPESCRIPT_STYLE,
+            "javascript" | "javascriptreact" => JAVASCRIPT_STYLE,
+            "go" => GO_STYLE,
+            "java" => JAVA_STYLE,
+            _ => DEFAULT_STYLE,
+        }
+    }
+
+    pub fn comment_type(&self) -> &str {
+        self.comment_type
+    }
+
+    pub fn syntax(&self) -> &CommentSyntax {
+        &self.syntax
+    }
+
+    pub fn placement(&self) -> DocumentationPlacement {
+        self.placement
+    }
+
+    pub fn guidance(&self) -> &str {
+        self.guidance
+    }
+}
+
+/// The comment block generated by the LLM after we have cleaned it up
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DocumentationBlock {
+    comment: String,
+    placement: DocumentationPlacement,
+}
+
+impl DocumentationBlock {
+    pub fn comment(&self) -> &str {
+        &self.comment
+    }
+
+    pub fn placement(&self) -> DocumentationPlacement {
+        self.placement
+    }
+}
 
 pub fn documentation_type(identifier_node: &InLineDocRequest) -> String {
-    let language = identifier_node.language();
-    let is_identifier = identifier_node.is_identifier_node();
-    let comment_type = match language {
-        "typescript" | "typescriptreact" => match is_identifier {
-            true => "a TSDoc comment".to_owned(),
-            false => "TSDoc comment".to_owned(),
-        },
-        "javascript" | "javascriptreact" => match is_identifier {
-            true => "a JSDoc comment".to_owned(),
-            false => "JSDoc comment".to_owned(),
-        },
-        "python" => "docstring".to_owned(),
-        "rust" => "Rustdoc comment".to_owned(),
-        _ => "documentation comment".to_owned(),
-    };
-    comment_type
+    let comment_type = DocumentationStyle::for_language(identifier_node.language()).comment_type;
+    if identifier_node.is_identifier_node() && comment_type != "docstring" {
+        format!("a {comment_type}")
+    } else {
+        comment_type.to_owned()
+    }
 }
 
 pub fn selection_type(identifier_node: &InLineDocRequest) -> String {
@@ -27,15 +156,211 @@ pub fn selection_type(identifier_node: &InLineDocRequest) -> String {
     }
 }
 
+pub fn documentation_style_guidance(identifier_node: &InLineDocRequest) -> String {
+    DocumentationStyle::for_language(identifier_node.language())
+        .guidance
+        .to_owned()
+}
+
 pub fn document_symbol_metadata(identifier_node: &InLineDocRequest) -> String {
-    let is_identifier = identifier_node.is_identifier_node();
-    let language = identifier_node.language();
     let comment_type = documentation_type(identifier_node);
+    let guidance = documentation_style_guidance(identifier_node);
     let identifier_node_str = identifier_node.identifier_node_str();
     match identifier_node_str {
         Some(identifier_node) => {
-            format!("Please add {comment_type} for {identifier_node}")
+            format!("Please add {comment_type} for {identifier_node}. {guidance}")
         }
-        None => format!("Please add {comment_type} for the selection"),
+        None => format!("Please add {comment_type} for the selection. {guidance}"),
+    }
+}
+
+fn leading_whitespace(line: &str) -> &str {
+    &line[..line.len() - line.trim_start().len()]
+}
+
+/// The indentation of the first non empty line of the selection, which is the
+/// indentation of the symbol we are documenting
+fn node_indentation(in_range: &str) -> String {
+    in_range
+        .lines()
+        .find(|line| !line.trim().is_empty())
+        .map(|line| leading_whitespace(line).to_owned())
+        .unwrap_or_default()
+}
+
+/// The indentation of the body of the symbol, we look at the first line which
+/// is indented more than the symbol and fallback to 4 spaces
+fn body_indentation(in_range: &str) -> String {
+    let node_indentation = node_indentation(in_range);
+    in_range
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(leading_whitespace)
+        .find(|indentation| indentation.len() > node_indentation.len())
+        .map(|indentation| indentation.to_owned())
+        .unwrap_or(format!("{node_indentation}    "))
+}
+
+/// Removes the code fences and the markers which we ask the LLM to generate
+fn is_scaffolding_line(line: &str) -> bool {
+    let line = line.trim();
+    line.starts_with("```")
+        || line.starts_with("// FILEPATH:")
+        || line.starts_with("// BEGIN:")
+        || line.starts_with("// END:")
+}
+
+fn extract_comment_lines<'a>(lines: &[&'a str], syntax: &CommentSyntax) -> Option<Vec<&'a str>> {
+    match syntax {
+        CommentSyntax::Line(prefix) => {
+            let start = lines
+                .iter()
+                .position(|line| line.trim_start().starts_with(prefix))?;
+            Some(
+                lines[start..]
+                    .iter()
+                    .take_while(|line| line.trim_start().starts_with(prefix))
+                    .copied()
+                    .collect(),
+            )
+        }
+        CommentSyntax::Block { start, end } => {
+            let start_index = lines
+                .iter()
+                .position(|line| line.trim_start().starts_with(start))?;
+            // the comment might be on a single line: """Returns the sum."""
+            let first_line = lines[start_index].trim_start();
+            if first_line[start.len()..].trim_end().ends_with(end) {
+                return Some(vec![lines[start_index]]);
+            }
+            let end_index = lines[start_index + 1..]
+                .iter()
+                .position(|line| line.trim_end().ends_with(end))?;
+            Some(lines[start_index..=start_index + 1 + end_index].to_vec())
+        }
+    }
+}
+
+/// Takes the output of the LLM and returns only the comment block, validated
+/// against the comment syntax of the language and re-indented to the symbol
+/// so the editor can insert it as is
+pub fn extract_documentation(
+    request: &InLineDocRequest,
+    llm_output: &str,
+) -> Result<DocumentationBlock, InLineEditPromptError> {
+    let style = DocumentationStyle::for_language(request.language());
+    let lines = llm_output
+        .lines()
+        .filter(|line| !is_scaffolding_line(line))
+        .collect::<Vec<_>>();
+    let comment_lines = extract_comment_lines(&lines, &style.syntax).ok_or(
+        InLineEditPromptError::InvalidDocumentation(style.comment_type.to_owned()),
+    )?;
+    let indentation = match style.placement {
+        DocumentationPlacement::AboveNode => node_indentation(request.in_range()),
+        DocumentationPlacement::InsideBody => body_indentation(request.in_range()),
+    };
+    // strip the indentation the LLM used and apply the one from the symbol, we
+    // keep the relative indentation between the lines (for ` * ` in JSDoc etc)
+    // counted in chars, the whitespace might be multi byte
+    let common_indentation = comment_lines
+        .iter()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| leading_whitespace(line).chars().count())
+        .min()
+        .unwrap_or_default();
+    let comment = comment_lines
+        .into_iter()
+        .map(|line| {
+            if line.trim().is_empty() {
+                String::new()
+            } else {
+                let start = line
+                    .char_indices()
+                    .nth(common_indentation)
+                    .map(|(index, _)| index)
+                    .unwrap_or(line.len());
+                format!("{indentation}{}", line[start..].trim_end())
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    Ok(DocumentationBlock {
+        comment,
+        placement: style.placement,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{extract_documentation, DocumentationPlacement};
+    use crate::in_line_edit::types::{InLineDocNode, InLineDocRequest};
+
+    #[test]
+    fn test_extract_rust_documentation() {
+        let request = InLineDocRequest::new(
+            "    pub fn add(a: i32, b: i32) -> i32 {\n        a + b\n    }".to_owned(),
+            InLineDocNode::Node("add".to_owned()),
+            "rust".to_owned(),
+            "src/lib.rs".to_owned(),
+        );
+        let llm_output = "```rust\n// FILEPATH: src/lib.rs\n// BEGIN: ed8c6549bwf9\n/// Adds two numbers.\n///\n/// Returns the sum.\npub fn add(a: i32, b: i32) -> i32 {\n    a + b\n}\n// END: ed8c6549bwf9\n```";
+        let documentation = extract_documentation(&request, llm_output).expect("to work");
+        assert_eq!(documentation.placement(), DocumentationPlacement::AboveNode);
+        assert_eq!(
+            documentation.comment(),
+            "    /// Adds two numbers.\n    ///\n    /// Returns the sum."
+        );
+    }
+
+    #[test]
+    fn test_extract_python_docstring() {
+        let request = InLineDocRequest::new(
+            "def add(a, b):\n  return a + b".to_owned(),
+            InLineDocNode::Node("add".to_owned()),
+            "python".to_owned(),
+            "add.py".to_owned(),
+        );
+        let llm_output =
+            "def add(a, b):\n    \"\"\"Adds two numbers.\n\n    Returns the sum.\n    \"\"\"\n    return a + b";
+        let documentation = extract_documentation(&request, llm_output).expect("to work");
+        assert_eq!(
+            documentation.placement(),
+            DocumentationPlacement::InsideBody
+        );
+        assert_eq!(
+            documentation.comment(),
+            "  \"\"\"Adds two numbers.\n\n  Returns the sum.\n  \"\"\""
+        );
+    }
+
+    #[test]
+    fn test_extract_with_multi_byte_indentation() {
+        let request = InLineDocRequest::new(
+            "fn add(a: i32, b: i32) -> i32 {\n    a + b\n}".to_owned(),
+            InLineDocNode::Node("add".to_owned()),
+            "rust".to_owned(),
+            "src/lib.rs".to_owned(),
+        );
+        // an ideographic space is 3 bytes but a single char
+        let llm_output =
+            " /// Adds two numbers.\n\u{3000}/// Returns the sum.\nfn add(a: i32, b: i32) -> i32 {";
+        let documentation = extract_documentation(&request, llm_output).expect("to work");
+        assert_eq!(
+            documentation.comment(),
+            "/// Adds two numbers.\n/// Returns the sum."
+        );
+    }
+
+    #[test]
+    fn test_extract_fails_on_wrong_syntax() {
+        let request = InLineDocRequest::new(
+            "function add(a, b) {\n  return a + b;\n}".to_owned(),
+            InLineDocNode::Node("add".to_owned()),
+            "javascript".to_owned(),
+            "add.js".to_owned(),
+        );
+        let llm_output = "// Adds two numbers\nfunction add(a, b) {\n  return a + b;\n}";
+        assert!(extract_documentation(&request, llm_output).is_err());
     }
 }
@@ -1,20 +1,44 @@
+use std::sync::Arc;
+
+use super::doc_helpers::documentation_style_guidance;
 use super::doc_helpers::documentation_type;
 use super::doc_helpers::selection_type;
+use super::test_generation::default_test_file_path;
+use super::test_generation::test_examples_prompt;
+use super::test_generation::test_framework;
 use super::types::InLineDocRequest;
 use super::types::InLineEditPrompt;
 use super::types::InLineEditRequest;
 use super::types::InLineFixRequest;
 use super::types::InLinePromptResponse;
+use super::types::InLineTestRequest;
+use crate::templates::registry::PromptTemplates;
+use crate::templates::template::{TemplateId, TemplateValues};
 
-pub struct MistralLineEditPrompt {}
+pub struct MistralLineEditPrompt {
+    templates: Arc<PromptTemplates>,
+}
 
 impl MistralLineEditPrompt {
     pub fn new() -> Self {
-        Self {}
+        Self::with_templates(PromptTemplates::defaults())
+    }
+
+    pub fn with_templates(templates: Arc<PromptTemplates>) -> Self {
+        Self { templates }
     }
 }
 
 impl MistralLineEditPrompt {
+    /// The templates are checked against the variables we fill in when they
+    /// are loaded, so rendering them does not fail
+    fn render(&self, name: &str, values: TemplateValues) -> (String, TemplateId) {
+        self.templates
+            .render(name, &values)
+            .expect("template variables to be checked on load")
+            .into_parts()
+    }
+
     fn extra_code_context(&self, extra_data: &[String]) -> String {
         if extra_data.is_empty() {
             String::new()
@@ -63,87 +87,83 @@ impl InLineEditPrompt for MistralLineEditPrompt {
     fn inline_edit(&self, request: InLineEditRequest) -> InLinePromptResponse {
         let extra_data_context = self.extra_code_context(request.extra_data());
         let code_context = self.code_context(request.above(), request.below());
-        let user_query = request.user_query();
-        let language = request.language();
-        let file_path = request.file_path();
-        let (selection_context, extra_instruction) = if let Some(in_range_code_context) =
-            request.in_range()
-        {
-            (
-                format!(
-                    r#"Your task is to rewrite the code below following the instruction: {user_query}
-Code you have to edit:
-{in_range_code_context}"#
-                ),
-                "Rewrite the code [/INST]",
-            )
-        } else {
-            (
-                format!(r#"Follow the user instruction and generate code: {user_query}"#),
-                "Generate the code [/INST]",
-            )
+        let values = TemplateValues::new()
+            .optional("extra_data_context", Some(extra_data_context))
+            .optional("code_context", Some(code_context))
+            .text("user_query", request.user_query())
+            .text("language", request.language())
+            .text("file_path", request.file_path());
+        // We either rewrite the selection or generate code where the cursor is
+        let (prompt, template) = match request.in_range() {
+            Some(in_range) => self.render(
+                "in_line_edit.mistral.edit",
+                values.text("in_range", in_range),
+            ),
+            None => self.render("in_line_edit.mistral.generate", values),
         };
-        // Now we want to create the prompt for the LLM
-        let prompt = format!(
-            r#"[INST] You are an expert software engineer. You have been given some code context below:
-{extra_data_context}
-{code_context}
-{selection_context}
-
-{extra_instruction}
-```{language}
-// FILEPATH: {file_path}
-// BEGIN: ed8c6549bwf9
-"#
-        );
-        InLinePromptResponse::completion(prompt)
+        InLinePromptResponse::completion(prompt, template)
     }
 
     fn inline_fix(&self, request: InLineFixRequest) -> InLinePromptResponse {
         let code_context = self.code_context(request.above(), request.below());
-        let language = request.language();
-        let errors = request.diagnostics_prompts().join("\n");
-        let in_range_code_context = request.in_range();
-        let file_path = request.file_path();
-        let selection_context = format!(
-            r#"Your task is to fix the errors in the code using the errors provided
-{errors}
-
-Code you have to edit:
-{in_range_code_context}"#
+        // without diagnostics there is nothing to point at
+        let annotated_errors = (!request.diagnostics().is_empty()).then(|| {
+            format!(
+                "The errors are marked with ^ under the lines they are present on:\n{}\n\n",
+                request.annotated_in_range()
+            )
+        });
+        let (prompt, template) = self.render(
+            "in_line_edit.mistral.fix",
+            TemplateValues::new()
+                .optional("code_context", Some(code_context))
+                .text("errors", request.diagnostics_prompts().join("\n"))
+                .optional("annotated_errors", annotated_errors)
+                .text("in_range", request.in_range())
+                .text("language", request.language())
+                .text("file_path", request.file_path()),
         );
-        let prompt = format!(
-            r#"[INST] You are an expert software engineer. You have to fix the errors present in the code, the context is given below:
-{code_context}
-
-{selection_context}
+        InLinePromptResponse::completion(prompt, template)
+    }
 
-You have to fix the code below, generate the code without any explanation [/INST]
-```{language}
-// FILEPATH: {file_path}
-// BEGIN: ed8c6549bwf9
-"#
+    fn inline_doc(&self, request: InLineDocRequest) -> InLinePromptResponse {
+        let (prompt, template) = self.render(
+            "in_line_edit.mistral.doc",
+            TemplateValues::new()
+                .text("comment_type", documentation_type(&request))
+                .text("selection_type", selection_type(&request))
+                .text("style_guidance", documentation_style_guidance(&request))
+                .text("in_range", request.in_range())
+                .text("language", request.language())
+                .text("file_path", request.file_path()),
         );
-        InLinePromptResponse::completion(prompt)
+        InLinePromptResponse::completion(prompt, template)
     }
 
-    fn inline_doc(&self, request: InLineDocRequest) -> InLinePromptResponse {
-        let comment_type = documentation_type(&request);
-        let selection_type = selection_type(&request);
-        let in_range = request.in_range();
-        let language = request.language();
+    fn inline_tests(&self, request: InLineTestRequest) -> InLinePromptResponse {
         let file_path = request.file_path();
-        let prompt = format!(
-            r#"[INST] You are an expert software engineer. You have to generate {comment_type} for {selection_type}, the {selection_type} is given below:
-{in_range}
-
-Add {comment_type} and generate the selected code, do not for the // END marker [/INST]
-```{language}
-// FILEPATH: {file_path}
-// BEGIN: ed8c6549bwf9
+        let file_context = request.file_content().map(|file_content| {
+            format!(
+                r#"The code is present in the file {file_path}:
+{file_content}
 "#
+            )
+        });
+        let test_examples =
+            test_examples_prompt(&request).map(|test_examples| format!("{test_examples}\n"));
+        let (prompt, template) = self.render(
+            "in_line_edit.mistral.tests",
+            TemplateValues::new()
+                .optional("file_context", file_context)
+                .optional("test_examples", test_examples)
+                .text("test_framework", test_framework(&request))
+                .text("suggested_test_file_path", default_test_file_path(&request))
+                .text("symbol", request.symbol())
+                .text("in_range", request.in_range())
+                .text("language", request.language())
+                .text("file_path", file_path),
         );
-        InLinePromptResponse::Completion(prompt)
+        InLinePromptResponse::completion(prompt, template)
     }
 }
 
@@ -152,11 +172,12 @@ mod tests {
 
     use super::InLineEditPrompt;
     use super::InLineEditRequest;
+    use super::InLineFixRequest;
     use super::MistralLineEditPrompt;
 
     #[test]
     fn test_inline_edit_prompt() {
-        let prompt = MistralLineEditPrompt {};
+        let prompt = MistralLineEditPrompt::new();
         let request = InLineEditRequest::new(
             Some("above_context".to_owned()),
             Some("below_context".to_owned()),
@@ -190,4 +211,20 @@ Rewrite the code without any explanation [/INST]
             expected_output
         );
     }
+
+    #[test]
+    fn test_inline_fix_prompt_without_diagnostics() {
+        let prompt = MistralLineEditPrompt::new().inline_fix(InLineFixRequest::new(
+            None,
+            None,
+            "let a = 1".to_owned(),
+            0,
+            vec![],
+            "rust".to_owned(),
+            "src/lib.rs".to_owned(),
+        ));
+        let prompt = prompt.get_completion().expect("to be a completion prompt");
+        assert!(!prompt.contains("marked with ^"));
+        assert!(prompt.contains("\n\nCode you have to edit:\nlet a = 1\n"));
+    }
 }
@@ -1,5 +1,8 @@
 pub mod broker;
-mod doc_helpers;
+pub mod diagnostics;
+pub mod doc_helpers;
 pub mod mistral;
 pub mod openai;
+pub mod storage;
+pub mod test_generation;
 pub mod types;
@@ -1,87 +1,79 @@
+use std::sync::Arc;
+
 use llm_client::clients::types::LLMClientMessage;
 
 use crate::in_line_edit::doc_helpers::document_symbol_metadata;
+use crate::in_line_edit::test_generation::{
+    default_test_file_path, test_examples_prompt, test_framework,
+};
 
 use super::types::InLineDocRequest;
 use super::types::InLineEditPrompt;
 use super::types::InLineEditRequest;
 use super::types::InLineFixRequest;
 use super::types::InLinePromptResponse;
+use super::types::InLineTestRequest;
+use crate::templates::registry::PromptTemplates;
+use crate::templates::template::{TemplateId, TemplateValues};
 
-pub struct OpenAILineEditPrompt {}
+pub struct OpenAILineEditPrompt {
+    templates: Arc<PromptTemplates>,
+}
 
 impl OpenAILineEditPrompt {
     pub fn new() -> Self {
-        Self {}
+        Self::with_templates(PromptTemplates::defaults())
+    }
+
+    pub fn with_templates(templates: Arc<PromptTemplates>) -> Self {
+        Self { templates }
     }
 }
 
 impl OpenAILineEditPrompt {
-    fn system_message_inline_edit(&self, language: &str) -> String {
-        format!(
-            r#"You are an AI programming assistant.
-When asked for your name, you must respond with "Aide".
-Follow the user's requirements carefully & to the letter.
-- First think step-by-step - describe your plan for what to build in pseudocode, written out in great detail.
-- Then output the code in a single code block.
-- Minimize any other prose.
-- Each code block starts with ``` and // FILEPATH.
-- If you suggest to run a terminal command, use a code block that starts with ```bash.
-- You always answer with {language} code.
-- Modify the code or create new code.
-- Unless directed otherwise, the user is expecting for you to edit their selected code.
-- Make sure to ALWAYS INCLUDE the BEGIN and END markers in your generated code with // BEGIN and then // END which is present in the code selection given by the user
-You must decline to answer if the question is not related to a developer.
-If the question is related to a developer, you must respond with content related to a developer."#
+    /// The templates are checked against the variables we fill in when they
+    /// are loaded, so rendering them does not fail
+    fn render(&self, name: &str, values: TemplateValues) -> (String, TemplateId) {
+        self.templates
+            .render(name, &values)
+            .expect("template variables to be checked on load")
+            .into_parts()
+    }
+
+    fn system_message_inline_edit(&self, language: &str) -> (String, TemplateId) {
+        self.render(
+            "in_line_edit.openai.edit_system",
+            TemplateValues::new().text("language", language),
         )
     }
 
-    fn system_message_fix(&self, language: &str) -> String {
-        format!(
-            r#"You are an AI programming assistant.
-When asked for your name, you must respond with "Aide".
-Follow the user's requirements carefully & to the letter.
-- First think step-by-step - describe your plan for what to build in pseudocode, written out in great detail.
-- Then output the code in a single code block.
-- Minimize any other prose.
-- Each code block starts with ``` and // FILEPATH.
-- If you suggest to run a terminal command, use a code block that starts with ```bash.
-- You always answer with {language} code.
-- Modify the code or create new code.
-- Unless directed otherwise, the user is expecting for you to edit their selected code.
-You must decline to answer if the question is not related to a developer.
-If the question is related to a developer, you must respond with content related to a developer."#
+    fn system_message_fix(&self, language: &str) -> (String, TemplateId) {
+        self.render(
+            "in_line_edit.openai.fix_system",
+            TemplateValues::new().text("language", language),
         )
     }
 
-    fn documentation_system_prompt(&self, language: &str, is_identifier_node: bool) -> String {
-        if is_identifier_node {
-            let system_prompt = format!(
-                r#"You are an AI programming assistant.
-When asked for your name, you must respond with "Aide".
-Follow the user's requirements carefully & to the letter.
-- Each code block must ALWAYS STARTS and include ```{language} and // FILEPATH
-- You always answer with {language} code.
-- When the user asks you to document something, you must answer in the form of a {language} code block.
-- Your documentation should not include just the name of the function, think about what the function is really doing.
-- When generating the documentation, be sure to understand what the function is doing and include that as part of the documentation and then generate the documentation.
-- DO NOT modify the code which you will be generating"#
-            );
-            system_prompt.to_owned()
+    fn documentation_system_prompt(
+        &self,
+        language: &str,
+        is_identifier_node: bool,
+    ) -> (String, TemplateId) {
+        let name = if is_identifier_node {
+            "in_line_edit.openai.doc_node_system"
         } else {
-            let system_prompt = format!(
-                r#"You are an AI programming assistant.
-When asked for your name, you must respond with "Aide".
-Follow the user's requirements carefully & to the letter.
-- Each code block must ALWAYS STARTS and include ```{language} and // FILEPATH
-- You always answer with {language} code.
-- When the user asks you to document something, you must answer in the form of a {language} code block.
-- Your documentation should not include just the code selection, think about what the selection is really doing.
-- When generating the documentation, be sure to understand what the selection is doing and include that as part of the documentation and then generate the documentation.
-- DO NOT modify the code which you will be generating"#
-            );
-            system_prompt.to_owned()
-        }
+            "in_line_edit.openai.doc_selection_system"
+        };
+        self.render(name, TemplateValues::new().text("language", language))
+    }
+
+    fn system_message_tests(&self, language: &str, test_framework: &str) -> (String, TemplateId) {
+        self.render(
+            "in_line_edit.openai.tests_system",
+            TemplateValues::new()
+                .text("language", language)
+                .text("test_framework", test_framework),
+        )
     }
 
     fn above_selection(&self, above_context: Option<&String>) -> Option<String> {
@@ -117,10 +109,9 @@ impl InLineEditPrompt for OpenAILineEditPrompt {
         let in_range = request.in_range();
         let language = request.language();
 
+        let (system_message, template) = self.system_message_inline_edit(language);
         let mut messages = vec![];
-        messages.push(LLMClientMessage::system(
-            self.system_message_inline_edit(language),
-        ));
+        messages.push(LLMClientMessage::system(system_message));
         if let Some(above) = self.above_selection(above) {
             messages.push(LLMClientMessage::user(above));
         }
@@ -135,7 +126,7 @@ impl InLineEditPrompt for OpenAILineEditPrompt {
         messages.push(LLMClientMessage::system(format!(
             r#"Make sure to ALWAYS INCLUDE the BEGIN and END markers in your generated code with // BEGIN and then // END which is present in the code selection given by me"#
         )));
-        InLinePromptResponse::Chat(messages)
+        InLinePromptResponse::chat(messages, template)
     }
 
     fn inline_fix(&self, request: InLineFixRequest) -> InLinePromptResponse {
@@ -144,8 +135,9 @@ impl InLineEditPrompt for OpenAILineEditPrompt {
         let in_range = request.in_range();
         let language = request.language();
 
+        let (system_message, template) = self.system_message_fix(language);
         let mut messages = vec![];
-        messages.push(LLMClientMessage::system(self.system_message_fix(language)));
+        messages.push(LLMClientMessage::system(system_message));
         if let Some(above) = self.above_selection(above) {
             messages.push(LLMClientMessage::user(above));
         }
@@ -153,26 +145,65 @@ impl InLineEditPrompt for OpenAILineEditPrompt {
             messages.push(LLMClientMessage::user(below));
         }
         messages.push(LLMClientMessage::user(in_range.to_owned()));
+        if !request.diagnostics().is_empty() {
+            let annotated_in_range = request.annotated_in_range();
+            messages.push(LLMClientMessage::user(format!(
+                r#"The errors are marked with ^ under the lines they are present on in my selection:
+{annotated_in_range}"#
+            )));
+        }
         messages.extend(
             request
                 .diagnostics_prompts()
                 .into_iter()
-                .map(|diagnostic_prompt| LLMClientMessage::user(diagnostic_prompt.to_owned())),
+                .map(LLMClientMessage::user),
         );
         messages.push(
             LLMClientMessage::user("Do not forget to include the // BEGIN and // END markers in your generated code. Only change the code inside of the selection, delimited by the markers: // BEGIN: ed8c6549bwf9 and // END: ed8c6549bwf9".to_owned())
         );
-        InLinePromptResponse::Chat(messages)
+        InLinePromptResponse::chat(messages, template)
     }
 
     fn inline_doc(&self, request: InLineDocRequest) -> InLinePromptResponse {
-        let system_prompt =
+        let (system_prompt, template) =
             self.documentation_system_prompt(request.language(), request.is_identifier_node());
         let mut messages = vec![];
         messages.push(LLMClientMessage::system(system_prompt));
         messages.push(LLMClientMessage::user(request.in_range().to_owned()));
         messages.push(LLMClientMessage::user(document_symbol_metadata(&request)));
         messages.push(LLMClientMessage::user("Do not forget to the include the // BEGIN and // END markers in your generated code. Only change the code provided to you in the selection".to_owned()));
-        InLinePromptResponse::Chat(messages)
+        InLinePromptResponse::chat(messages, template)
+    }
+
+    fn inline_tests(&self, request: InLineTestRequest) -> InLinePromptResponse {
+        let language = request.language();
+        let file_path = request.file_path();
+        let symbol = request.symbol();
+        let test_framework = test_framework(&request);
+        let suggested_test_file_path = default_test_file_path(&request);
+        let (system_message, template) = self.system_message_tests(language, &test_framework);
+        let mut messages = vec![];
+        messages.push(LLMClientMessage::system(system_message));
+        if let Some(file_content) = request.file_content() {
+            messages.push(LLMClientMessage::user(format!(
+                r#"This is the file {file_path} which contains the code:
+{file_content}"#
+            )));
+        }
+        if let Some(test_examples) = test_examples_prompt(&request) {
+            messages.push(LLMClientMessage::user(test_examples));
+        }
+        let in_range = request.in_range();
+        messages.push(LLMClientMessage::user(format!(
+            r#"Write tests for {symbol}:
+```{language}
+// FILEPATH: {file_path}
+{in_range}
+```"#
+        )));
+        messages.push(LLMClientMessage::user(format!(
+            "Do not forget to start the code block with the FILEPATH of the test file, if you are not sure use {suggested_test_file_path}"
+        )));
+        InLinePromptResponse::chat(messages, template)
     }
 }
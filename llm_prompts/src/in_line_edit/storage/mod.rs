@@ -1 +1,336 @@
 //! Crate for storage layer for storing to a sqlite DB as the backend
+//! We keep the inline edit and chat sessions here along with every turn, the
+//! prompt we sent to the LLM, the response we got back and what the user did
+//! with the edit, so sessions can be resumed after the editor restarts.
+
+pub mod types;
+
+use std::path::Path;
+
+use llm_client::clients::types::LLMType;
+use sqlx::{types::chrono::NaiveDateTime, SqlitePool};
+
+use self::types::{
+    EditStatus, FileRange, NewSessionTurn, ResumedSession, SessionType, StoredSession, StoredTurn,
+};
+
+#[derive(thiserror::Error, Debug)]
+pub enum StorageError {
+    #[error("sqlx error: {0}")]
+    SqlxError(#[from] sqlx::Error),
+
+    #[error("failed to run the migrations: {0}")]
+    MigrationError(#[from] sqlx::migrate::MigrateError),
+
+    #[error("serde error: {0}")]
+    SerdeError(#[from] serde_json::Error),
+
+    #[error("session {0} was not found")]
+    SessionNotFound(i64),
+
+    #[error("turn {0} was not found")]
+    TurnNotFound(i64),
+
+    #[error("invalid value in the DB: {0}")]
+    InvalidColumnValue(String),
+}
+
+pub struct InLineEditStorage {
+    db: SqlitePool,
+}
+
+impl InLineEditStorage {
+    pub async fn init(data_dir: &Path) -> Result<Self, StorageError> {
+        let data_dir = data_dir.to_string_lossy().to_string();
+        let url = format!("sqlite://{data_dir}/inline_edit_sessions.data?mode=rwc");
+        let db = SqlitePool::connect(&url).await?;
+        Self::from_pool(db).await
+    }
+
+    /// Runs the migrations on the pool, useful when the DB is shared with
+    /// something else
+    pub async fn from_pool(db: SqlitePool) -> Result<Self, StorageError> {
+        if let Err(e) = sqlx::migrate!().run(&db).await {
+            db.close().await;
+            return Err(e.into());
+        }
+        Ok(Self { db })
+    }
+
+    pub async fn create_session(
+        &self,
+        session_type: SessionType,
+        file_path: Option<&str>,
+        language: Option<&str>,
+    ) -> Result<i64, StorageError> {
+        let session_type = session_type.to_string();
+        let result = sqlx::query! {
+            r#"
+            INSERT INTO sessions (session_type, file_path, language)
+            VALUES ($1, $2, $3)
+            "#,
+            session_type,
+            file_path,
+            language,
+        }
+        .execute(&self.db)
+        .await?;
+        Ok(result.last_insert_rowid())
+    }
+
+    /// Adds the turn to the session and bumps the session so it shows up
+    /// first in the recent sessions
+    pub async fn add_turn(
+        &self,
+        session_id: i64,
+        turn: NewSessionTurn,
+    ) -> Result<i64, StorageError> {
+        let llm_type = serde_json::to_string(turn.llm_type())?;
+        let user_query = turn.user_query();
+        let prompt = turn.prompt();
+        let response = turn.response();
+        let file_path = turn.file_range().map(|file_range| file_range.file_path());
+        let start_line = turn.file_range().map(|file_range| file_range.start_line());
+        let end_line = turn.file_range().map(|file_range| file_range.end_line());
+        let mut tx = self.db.begin().await?;
+        let updated = sqlx::query! {
+            r#"
+            UPDATE sessions SET updated_at = CURRENT_TIMESTAMP WHERE id = $1
+            "#,
+            session_id,
+        }
+        .execute(&mut *tx)
+        .await?;
+        if updated.rows_affected() == 0 {
+            return Err(StorageError::SessionNotFound(session_id));
+        }
+        let result = sqlx::query! {
+            r#"
+            INSERT INTO session_turns (session_id, user_query, prompt, response, llm_type, file_path, start_line, end_line)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+            "#,
+            session_id,
+            user_query,
+            prompt,
+            response,
+            llm_type,
+            file_path,
+            start_line,
+            end_line,
+        }
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(result.last_insert_rowid())
+    }
+
+    /// Stores the response once the LLM is done streaming it
+    pub async fn set_response(&self, turn_id: i64, response: &str) -> Result<(), StorageError> {
+        let result = sqlx::query! {
+            r#"
+            UPDATE session_turns SET response = $1 WHERE id = $2
+            "#,
+            response,
+            turn_id,
+        }
+        .execute(&self.db)
+        .await?;
+        if result.rows_affected() == 0 {
+            return Err(StorageError::TurnNotFound(turn_id));
+        }
+        Ok(())
+    }
+
+    pub async fn set_edit_status(
+        &self,
+        turn_id: i64,
+        edit_status: EditStatus,
+    ) -> Result<(), StorageError> {
+        let (status, accepted_edit) = edit_status.to_columns();
+        let result = sqlx::query! {
+            r#"
+            UPDATE session_turns SET edit_status = $1, accepted_edit = $2 WHERE id = $3
+            "#,
+            status,
+            accepted_edit,
+            turn_id,
+        }
+        .execute(&self.db)
+        .await?;
+        if result.rows_affected() == 0 {
+            return Err(StorageError::TurnNotFound(turn_id));
+        }
+        Ok(())
+    }
+
+    pub async fn get_session(&self, session_id: i64) -> Result<StoredSession, StorageError> {
+        let row = sqlx::query! {
+            r#"
+            SELECT id as "id!", session_type, file_path, language,
+                created_at as "created_at: NaiveDateTime",
+                updated_at as "updated_at: NaiveDateTime"
+            FROM sessions WHERE id = $1
+            "#,
+            session_id,
+        }
+        .fetch_optional(&self.db)
+        .await?
+        .ok_or(StorageError::SessionNotFound(session_id))?;
+        Ok(StoredSession {
+            id: row.id,
+            session_type: row.session_type.parse()?,
+            file_path: row.file_path,
+            language: row.language,
+            created_at: row.created_at,
+            updated_at: row.updated_at,
+        })
+    }
+
+    /// Loads the session along with all of its turns so the editor can pick
+    /// up where the user left off
+    pub async fn resume_session(&self, session_id: i64) -> Result<ResumedSession, StorageError> {
+        let session = self.get_session(session_id).await?;
+        let rows = sqlx::query! {
+            r#"
+            SELECT id as "id!", session_id, user_query, prompt, response, llm_type,
+                file_path, start_line, end_line, edit_status, accepted_edit,
+                created_at as "created_at: NaiveDateTime"
+            FROM session_turns WHERE session_id = $1
+            ORDER BY id ASC
+            "#,
+            session_id,
+        }
+        .fetch_all(&self.db)
+        .await?;
+        let turns = rows
+            .into_iter()
+            .map(|row| {
+                let file_range = match (row.file_path, row.start_line, row.end_line) {
+                    (Some(file_path), Some(start_line), Some(end_line)) => {
+                        Some(FileRange::new(file_path, start_line, end_line))
+                    }
+                    _ => None,
+                };
+                Ok(StoredTurn {
+                    id: row.id,
+                    session_id: row.session_id,
+                    user_query: row.user_query,
+                    prompt: row.prompt,
+                    response: row.response,
+                    llm_type: serde_json::from_str::<LLMType>(&row.llm_type)?,
+                    file_range,
+                    edit_status: EditStatus::from_columns(&row.edit_status, row.accepted_edit)?,
+                    created_at: row.created_at,
+                })
+            })
+            .collect::<Result<Vec<_>, StorageError>>()?;
+        Ok(ResumedSession::new(session, turns))
+    }
+
+    /// The sessions for the file, most recently updated first
+    pub async fn recent_sessions_for_file(
+        &self,
+        file_path: &str,
+        limit: i64,
+    ) -> Result<Vec<StoredSession>, StorageError> {
+        let rows = sqlx::query! {
+            r#"
+            SELECT id as "id!", session_type, file_path, language,
+                created_at as "created_at: NaiveDateTime",
+                updated_at as "updated_at: NaiveDateTime"
+            FROM sessions WHERE file_path = $1
+            ORDER BY updated_at DESC, id DESC
+            LIMIT $2
+            "#,
+            file_path,
+            limit,
+        }
+        .fetch_all(&self.db)
+        .await?;
+        rows.into_iter()
+            .map(|row| {
+                Ok(StoredSession {
+                    id: row.id,
+                    session_type: row.session_type.parse()?,
+                    file_path: row.file_path,
+                    language: row.language,
+                    created_at: row.created_at,
+                    updated_at: row.updated_at,
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use llm_client::clients::types::LLMType;
+
+    use super::{
+        types::{EditStatus, FileRange, NewSessionTurn, SessionType},
+        InLineEditStorage,
+    };
+
+    #[tokio::test]
+    async fn test_resume_and_list_recent_sessions() {
+        let data_dir = tempfile::tempdir().expect("data dir to be created");
+        let storage = InLineEditStorage::init(data_dir.path())
+            .await
+            .expect("storage to init");
+        let first_session = storage
+            .create_session(SessionType::InLineEdit, Some("src/lib.rs"), Some("rust"))
+            .await
+            .expect("to work");
+        let second_session = storage
+            .create_session(SessionType::Chat, Some("src/lib.rs"), Some("rust"))
+            .await
+            .expect("to work");
+        let turn_id = storage
+            .add_turn(
+                first_session,
+                NewSessionTurn::new(
+                    "add docs".to_owned(),
+                    "[prompt]".to_owned(),
+                    LLMType::MistralInstruct,
+                )
+                .set_file_range(FileRange::new("src/lib.rs".to_owned(), 10, 20)),
+            )
+            .await
+            .expect("to work");
+        storage
+            .set_response(turn_id, "/// Adds two numbers")
+            .await
+            .expect("to work");
+        storage
+            .set_edit_status(turn_id, EditStatus::Accepted("/// Adds numbers".to_owned()))
+            .await
+            .expect("to work");
+
+        let resumed = storage
+            .resume_session(first_session)
+            .await
+            .expect("to work");
+        assert_eq!(resumed.session().session_type(), SessionType::InLineEdit);
+        let turn = &resumed.turns()[0];
+        assert_eq!(turn.response(), Some("/// Adds two numbers"));
+        assert_eq!(turn.llm_type(), &LLMType::MistralInstruct);
+        assert_eq!(turn.file_range().map(|range| range.start_line()), Some(10));
+        assert_eq!(
+            turn.edit_status(),
+            &EditStatus::Accepted("/// Adds numbers".to_owned())
+        );
+        assert_eq!(resumed.chat_turns()[0].user_message(), "add docs");
+
+        let recent_sessions = storage
+            .recent_sessions_for_file("src/lib.rs", 10)
+            .await
+            .expect("to work");
+        let recent_ids = recent_sessions
+            .iter()
+            .map(|session| session.id())
+            .collect::<Vec<_>>();
+        assert!(recent_ids.contains(&first_session));
+        assert!(recent_ids.contains(&second_session));
+        assert!(storage.resume_session(i64::MAX).await.is_err());
+    }
+}
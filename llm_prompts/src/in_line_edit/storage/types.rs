@@ -0,0 +1,287 @@
+use std::{fmt, str::FromStr};
+
+use llm_client::clients::types::{LLMClientCompletionRequest, LLMType};
+use sqlx::types::chrono::NaiveDateTime;
+
+use crate::chat::types::ChatTurn;
+
+use super::StorageError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionType {
+    InLineEdit,
+    Chat,
+}
+
+impl fmt::Display for SessionType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SessionType::InLineEdit => write!(f, "inline_edit"),
+            SessionType::Chat => write!(f, "chat"),
+        }
+    }
+}
+
+impl FromStr for SessionType {
+    type Err = StorageError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "inline_edit" => Ok(SessionType::InLineEdit),
+            "chat" => Ok(SessionType::Chat),
+            _ => Err(StorageError::InvalidColumnValue(s.to_owned())),
+        }
+    }
+}
+
+/// What the user did with the edit which the LLM generated for a turn
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EditStatus {
+    Pending,
+    /// The user accepted the edit, this is the content which ended up in the
+    /// file since the user might have modified it before accepting
+    Accepted(String),
+    Rejected,
+}
+
+impl EditStatus {
+    fn status(&self) -> &'static str {
+        match self {
+            EditStatus::Pending => "pending",
+            EditStatus::Accepted(_) => "accepted",
+            EditStatus::Rejected => "rejected",
+        }
+    }
+
+    pub(crate) fn to_columns(&self) -> (&'static str, Option<String>) {
+        match self {
+            EditStatus::Accepted(accepted_edit) => (self.status(), Some(accepted_edit.to_owned())),
+            _ => (self.status(), None),
+        }
+    }
+
+    pub(crate) fn from_columns(
+        status: &str,
+        accepted_edit: Option<String>,
+    ) -> Result<Self, StorageError> {
+        match status {
+            "pending" => Ok(EditStatus::Pending),
+            "accepted" => Ok(EditStatus::Accepted(accepted_edit.unwrap_or_default())),
+            "rejected" => Ok(EditStatus::Rejected),
+            _ => Err(StorageError::InvalidColumnValue(status.to_owned())),
+        }
+    }
+}
+
+/// The range in the file the turn was about, lines are 0 indexed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileRange {
+    file_path: String,
+    start_line: i64,
+    end_line: i64,
+}
+
+impl FileRange {
+    pub fn new(file_path: String, start_line: i64, end_line: i64) -> Self {
+        Self {
+            file_path,
+            start_line,
+            end_line,
+        }
+    }
+
+    pub fn file_path(&self) -> &str {
+        &self.file_path
+    }
+
+    pub fn start_line(&self) -> i64 {
+        self.start_line
+    }
+
+    pub fn end_line(&self) -> i64 {
+        self.end_line
+    }
+}
+
+/// A turn which we are about to store, the response can be added later once
+/// the LLM is done streaming
+#[derive(Debug, Clone)]
+pub struct NewSessionTurn {
+    user_query: String,
+    prompt: String,
+    response: Option<String>,
+    llm_type: LLMType,
+    file_range: Option<FileRange>,
+}
+
+impl NewSessionTurn {
+    /// `prompt` is the prompt exactly as we sent it to the LLM
+    pub fn new(user_query: String, prompt: String, llm_type: LLMType) -> Self {
+        Self {
+            user_query,
+            prompt,
+            response: None,
+            llm_type,
+            file_range: None,
+        }
+    }
+
+    /// Stores the messages of the chat request as the prompt
+    pub fn from_chat_request(
+        user_query: String,
+        request: &LLMClientCompletionRequest,
+    ) -> Result<Self, StorageError> {
+        let prompt = serde_json::to_string(request.messages())?;
+        Ok(Self::new(user_query, prompt, request.model().clone()))
+    }
+
+    pub fn set_response(mut self, response: String) -> Self {
+        self.response = Some(response);
+        self
+    }
+
+    pub fn set_file_range(mut self, file_range: FileRange) -> Self {
+        self.file_range = Some(file_range);
+        self
+    }
+
+    pub fn user_query(&self) -> &str {
+        &self.user_query
+    }
+
+    pub fn prompt(&self) -> &str {
+        &self.prompt
+    }
+
+    pub fn response(&self) -> Option<&str> {
+        self.response.as_deref()
+    }
+
+    pub fn llm_type(&self) -> &LLMType {
+        &self.llm_type
+    }
+
+    pub fn file_range(&self) -> Option<&FileRange> {
+        self.file_range.as_ref()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct StoredTurn {
+    pub(crate) id: i64,
+    pub(crate) session_id: i64,
+    pub(crate) user_query: String,
+    pub(crate) prompt: String,
+    pub(crate) response: Option<String>,
+    pub(crate) llm_type: LLMType,
+    pub(crate) file_range: Option<FileRange>,
+    pub(crate) edit_status: EditStatus,
+    pub(crate) created_at: NaiveDateTime,
+}
+
+impl StoredTurn {
+    pub fn id(&self) -> i64 {
+        self.id
+    }
+
+    pub fn session_id(&self) -> i64 {
+        self.session_id
+    }
+
+    pub fn user_query(&self) -> &str {
+        &self.user_query
+    }
+
+    pub fn prompt(&self) -> &str {
+        &self.prompt
+    }
+
+    pub fn response(&self) -> Option<&str> {
+        self.response.as_deref()
+    }
+
+    pub fn llm_type(&self) -> &LLMType {
+        &self.llm_type
+    }
+
+    pub fn file_range(&self) -> Option<&FileRange> {
+        self.file_range.as_ref()
+    }
+
+    pub fn edit_status(&self) -> &EditStatus {
+        &self.edit_status
+    }
+
+    pub fn created_at(&self) -> &NaiveDateTime {
+        &self.created_at
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct StoredSession {
+    pub(crate) id: i64,
+    pub(crate) session_type: SessionType,
+    pub(crate) file_path: Option<String>,
+    pub(crate) language: Option<String>,
+    pub(crate) created_at: NaiveDateTime,
+    pub(crate) updated_at: NaiveDateTime,
+}
+
+impl StoredSession {
+    pub fn id(&self) -> i64 {
+        self.id
+    }
+
+    pub fn session_type(&self) -> SessionType {
+        self.session_type
+    }
+
+    pub fn file_path(&self) -> Option<&str> {
+        self.file_path.as_deref()
+    }
+
+    pub fn language(&self) -> Option<&str> {
+        self.language.as_deref()
+    }
+
+    pub fn created_at(&self) -> &NaiveDateTime {
+        &self.created_at
+    }
+
+    pub fn updated_at(&self) -> &NaiveDateTime {
+        &self.updated_at
+    }
+}
+
+/// A session along with all of its turns, from the oldest to the newest
+#[derive(Debug, Clone)]
+pub struct ResumedSession {
+    session: StoredSession,
+    turns: Vec<StoredTurn>,
+}
+
+impl ResumedSession {
+    pub(crate) fn new(session: StoredSession, turns: Vec<StoredTurn>) -> Self {
+        Self { session, turns }
+    }
+
+    pub fn session(&self) -> &StoredSession {
+        &self.session
+    }
+
+    pub fn turns(&self) -> &[StoredTurn] {
+        self.turns.as_slice()
+    }
+
+    /// The history we can pass to the chat prompt, turns which never got a
+    /// response are skipped
+    pub fn chat_turns(&self) -> Vec<ChatTurn> {
+        self.turns
+            .iter()
+            .filter_map(|turn| {
+                turn.response()
+                    .map(|response| ChatTurn::new(turn.user_query.to_owned(), response.to_owned()))
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,236 @@
+use std::path::Path;
+
+use super::types::{InLineEditPromptError, InLineTestRequest};
+
+/// The test framework we fallback to when the editor was not able to detect
+/// one for the project
+pub fn test_framework(request: &InLineTestRequest) -> String {
+    if let Some(test_framework) = request.test_framework() {
+        return test_framework.to_owned();
+    }
+    match request.language() {
+        "rust" => "the built-in rust test harness (#[test])".to_owned(),
+        "python" => "pytest".to_owned(),
+        "typescript" | "typescriptreact" | "javascript" | "javascriptreact" => "jest".to_owned(),
+        "go" => "the go testing package".to_owned(),
+        "java" => "JUnit 5".to_owned(),
+        _ => "the test framework used in the project".to_owned(),
+    }
+}
+
+/// The file where the tests should go by the conventions of the language,
+/// we use this when the LLM does not suggest a path
+pub fn default_test_file_path(request: &InLineTestRequest) -> String {
+    let file_path = Path::new(request.file_path());
+    let parent = file_path.parent().unwrap_or(Path::new(""));
+    let file_stem = file_path
+        .file_stem()
+        .map(|file_stem| file_stem.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let extension = file_path
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let test_file_name = match request.language() {
+        // rust tests live in the same file in the tests module
+        "rust" => return request.file_path().to_owned(),
+        "python" => format!("test_{file_stem}.{extension}"),
+        "typescript" | "typescriptreact" | "javascript" | "javascriptreact" => {
+            format!("{file_stem}.test.{extension}")
+        }
+        "go" => format!("{file_stem}_test.{extension}"),
+        "java" => format!("{file_stem}Test.{extension}"),
+        _ => format!("{file_stem}_test.{extension}"),
+    };
+    parent.join(test_file_name).to_string_lossy().to_string()
+}
+
+/// Renders the existing tests from the repository so the LLM can follow the
+/// same conventions
+pub fn test_examples_prompt(request: &InLineTestRequest) -> Option<String> {
+    if request.test_examples().is_empty() {
+        return None;
+    }
+    let language = request.language();
+    let test_examples = request
+        .test_examples()
+        .iter()
+        .map(|test_example| {
+            let file_path = test_example.file_path();
+            let content = test_example.content();
+            format!("```{language}\n// FILEPATH: {file_path}\n{content}\n```")
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    Some(format!(
+        r#"These are some of the existing tests in the repository, follow the same conventions:
+{test_examples}"#
+    ))
+}
+
+/// The tests generated by the LLM along with the file they should go in
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InLineTestResponse {
+    test_code: String,
+    target_file_path: String,
+}
+
+impl InLineTestResponse {
+    pub fn test_code(&self) -> &str {
+        &self.test_code
+    }
+
+    pub fn target_file_path(&self) -> &str {
+        &self.target_file_path
+    }
+}
+
+/// The marker the edit prompts put around the selection
+const SELECTION_MARKER: &str = "ed8c6549bwf9";
+
+/// The text of a comment line, the comment prefix depends on the language
+fn comment_text(line: &str) -> Option<&str> {
+    let line = line.trim();
+    ["//", "#", "--", ";"]
+        .iter()
+        .find_map(|prefix| line.strip_prefix(prefix))
+        .map(|text| text.trim())
+}
+
+/// Grabs the path from a `// FILEPATH: {path}` comment line
+fn file_path_from_line(line: &str) -> Option<String> {
+    let file_path = comment_text(line)?.strip_prefix("FILEPATH:")?.trim();
+    if file_path.is_empty() {
+        None
+    } else {
+        Some(file_path.to_owned())
+    }
+}
+
+/// The `// BEGIN: ed8c6549bwf9` and `// END: ed8c6549bwf9` lines, the LLM
+/// sometimes copies them over from the other prompts
+fn is_selection_marker(line: &str) -> bool {
+    comment_text(line).is_some_and(|text| {
+        ["BEGIN:", "END:"].iter().any(|marker| {
+            text.strip_prefix(marker)
+                .is_some_and(|rest| rest.trim() == SELECTION_MARKER)
+        })
+    })
+}
+
+/// Parses the output of the LLM, we ask the LLM to generate a single code block
+/// which starts with the FILEPATH marker, the completion models do not include
+/// the opening code fence since it's part of the prompt
+pub fn parse_inline_tests_output(
+    request: &InLineTestRequest,
+    llm_output: &str,
+) -> Result<InLineTestResponse, InLineEditPromptError> {
+    let lines = llm_output.lines().collect::<Vec<_>>();
+    let code_fences = lines
+        .iter()
+        .enumerate()
+        .filter(|(_, line)| line.trim_start().starts_with("```"))
+        .map(|(index, _)| index)
+        .collect::<Vec<_>>();
+    // with a single code fence we have to figure out if its the opening one
+    // (```python) or the closing one, which happens with completion models
+    // since the opening fence is part of the prompt
+    let code_lines = match code_fences.as_slice() {
+        [] => &lines[..],
+        [fence] if lines[*fence].trim() == "```" => &lines[..*fence],
+        [fence] => &lines[fence + 1..],
+        [start, end, ..] => &lines[start + 1..*end],
+    };
+    let mut target_file_path = None;
+    let test_code = code_lines
+        .iter()
+        .copied()
+        .filter(|line| {
+            if let Some(file_path) = file_path_from_line(line) {
+                if target_file_path.is_none() {
+                    target_file_path = Some(file_path);
+                }
+                false
+            } else {
+                !is_selection_marker(line)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    let test_code = test_code.trim_matches('\n').to_owned();
+    if test_code.trim().is_empty() {
+        return Err(InLineEditPromptError::NoTestCodeFound);
+    }
+    Ok(InLineTestResponse {
+        test_code,
+        target_file_path: target_file_path.unwrap_or_else(|| default_test_file_path(request)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{default_test_file_path, parse_inline_tests_output};
+    use crate::in_line_edit::types::InLineTestRequest;
+
+    fn request(language: &str, file_path: &str) -> InLineTestRequest {
+        InLineTestRequest::new(
+            "add".to_owned(),
+            "def add(a, b):\n    return a + b".to_owned(),
+            None,
+            None,
+            vec![],
+            language.to_owned(),
+            file_path.to_owned(),
+        )
+    }
+
+    #[test]
+    fn test_default_test_file_path() {
+        assert_eq!(
+            default_test_file_path(&request("python", "src/math/add.py")),
+            "src/math/test_add.py"
+        );
+        assert_eq!(
+            default_test_file_path(&request("typescript", "src/add.ts")),
+            "src/add.test.ts"
+        );
+        assert_eq!(
+            default_test_file_path(&request("go", "add.go")),
+            "add_test.go"
+        );
+    }
+
+    #[test]
+    fn test_parse_inline_tests_output() {
+        let request = request("python", "src/add.py");
+        let llm_output = "Here are the tests:\n```python\n# FILEPATH: tests/test_add.py\nfrom src.add import add\n\ndef test_add():\n    assert add(1, 2) == 3\n```\nThese tests cover the happy path.";
+        let response = parse_inline_tests_output(&request, llm_output).expect("to work");
+        assert_eq!(response.target_file_path(), "tests/test_add.py");
+        assert_eq!(
+            response.test_code(),
+            "from src.add import add\n\ndef test_add():\n    assert add(1, 2) == 3"
+        );
+    }
+
+    #[test]
+    fn test_parse_inline_tests_output_without_path() {
+        let request = request("python", "src/add.py");
+        let response =
+            parse_inline_tests_output(&request, "def test_add():\n    assert add(1, 2) == 3\n```")
+                .expect("to work");
+        assert_eq!(response.target_file_path(), "src/test_add.py");
+        assert!(parse_inline_tests_output(&request, "```python\n```").is_err());
+    }
+
+    #[test]
+    fn test_parse_inline_tests_output_keeps_code_with_marker_words() {
+        let request = request("typescript", "src/parse.ts");
+        let llm_output = "```typescript\n// FILEPATH: src/parse.test.ts\n// BEGIN: ed8c6549bwf9\nit('reads the header', () => {\n  expect(parse('FILEPATH: a.ts')).toBe('a.ts');\n  expect(markers('BEGIN: x\\nEND: y')).toHaveLength(2); // END: of input\n});\n// END: ed8c6549bwf9\n```";
+        let response = parse_inline_tests_output(&request, llm_output).expect("to work");
+        assert_eq!(response.target_file_path(), "src/parse.test.ts");
+        assert_eq!(
+            response.test_code(),
+            "it('reads the header', () => {\n  expect(parse('FILEPATH: a.ts')).toBe('a.ts');\n  expect(markers('BEGIN: x\\nEND: y')).toHaveLength(2); // END: of input\n});"
+        );
+    }
+}
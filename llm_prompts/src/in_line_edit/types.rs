@@ -2,7 +2,15 @@
 //! chat. We take care to send the data here properly (after filtering/reranking etc)
 //! and let the LLM decide what we want to do with it
 
-use llm_client::clients::types::LLMClientMessage;
+use std::collections::HashMap;
+
+use futures::future::Either;
+use llm_client::clients::types::{
+    LLMClientCompletionRequest, LLMClientCompletionStringRequest, LLMClientMessage, LLMType,
+};
+
+use super::diagnostics::{annotate_selection, Diagnostic};
+use crate::templates::template::TemplateId;
 
 pub enum InLineDocNode {
     /// This might just be a selection of code
@@ -64,11 +72,104 @@ impl InLineDocRequest {
     }
 }
 
+/// An existing test from the repository which we show to the LLM so the
+/// generated tests follow the same conventions
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct InLineTestExample {
+    file_path: String,
+    content: String,
+}
+
+impl InLineTestExample {
+    pub fn new(file_path: String, content: String) -> Self {
+        Self { file_path, content }
+    }
+
+    pub fn file_path(&self) -> &str {
+        &self.file_path
+    }
+
+    pub fn content(&self) -> &str {
+        &self.content
+    }
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct InLineTestRequest {
+    /// The name of the symbol we are writing tests for
+    symbol: String,
+    /// The code of the selected symbol
+    in_range: String,
+    /// The content of the file which contains the symbol
+    file_content: Option<String>,
+    /// The test framework which the editor detected for the project, like
+    /// pytest, jest, go test or cargo test
+    test_framework: Option<String>,
+    test_examples: Vec<InLineTestExample>,
+    language: String,
+    file_path: String,
+}
+
+impl InLineTestRequest {
+    pub fn new(
+        symbol: String,
+        in_range: String,
+        file_content: Option<String>,
+        test_framework: Option<String>,
+        test_examples: Vec<InLineTestExample>,
+        language: String,
+        file_path: String,
+    ) -> Self {
+        Self {
+            symbol,
+            in_range,
+            file_content,
+            test_framework,
+            test_examples,
+            language,
+            file_path,
+        }
+    }
+
+    pub fn symbol(&self) -> &str {
+        &self.symbol
+    }
+
+    pub fn in_range(&self) -> &str {
+        &self.in_range
+    }
+
+    pub fn file_content(&self) -> Option<&String> {
+        self.file_content.as_ref()
+    }
+
+    pub fn test_framework(&self) -> Option<&String> {
+        self.test_framework.as_ref()
+    }
+
+    pub fn test_examples(&self) -> &[InLineTestExample] {
+        self.test_examples.as_slice()
+    }
+
+    pub fn language(&self) -> &str {
+        &self.language
+    }
+
+    pub fn file_path(&self) -> &str {
+        &self.file_path
+    }
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
 pub struct InLineFixRequest {
     above: Option<String>,
     below: Option<String>,
     in_range: String,
-    diagnostics_prompts: Vec<String>,
+    /// The 0 indexed line in the file where the selection starts, we need this
+    /// to place the diagnostics (which use file positions) inside the selection
+    selection_start_line: usize,
+    /// Diagnostics as sent by the language server
+    diagnostics: Vec<Diagnostic>,
     language: String,
     file_path: String,
 }
@@ -78,7 +179,8 @@ impl InLineFixRequest {
         above: Option<String>,
         below: Option<String>,
         in_range: String,
-        diagnostics_prompts: Vec<String>,
+        selection_start_line: usize,
+        diagnostics: Vec<Diagnostic>,
         language: String,
         file_path: String,
     ) -> Self {
@@ -86,12 +188,36 @@ impl InLineFixRequest {
             above,
             below,
             in_range,
-            diagnostics_prompts,
+            selection_start_line,
+            diagnostics,
             language,
             file_path,
         }
     }
 
+    /// Creates the request from the raw LSP diagnostics JSON which the editor
+    /// gets from the language server
+    pub fn from_lsp_diagnostics(
+        above: Option<String>,
+        below: Option<String>,
+        in_range: String,
+        selection_start_line: usize,
+        lsp_diagnostics: &str,
+        language: String,
+        file_path: String,
+    ) -> Result<Self, InLineEditPromptError> {
+        let diagnostics = Diagnostic::from_lsp_json(lsp_diagnostics)?;
+        Ok(Self::new(
+            above,
+            below,
+            in_range,
+            selection_start_line,
+            diagnostics,
+            language,
+            file_path,
+        ))
+    }
+
     pub fn above(&self) -> Option<&String> {
         self.above.as_ref()
     }
@@ -104,8 +230,26 @@ impl InLineFixRequest {
         self.in_range.as_ref()
     }
 
-    pub fn diagnostics_prompts(&self) -> &[String] {
-        &self.diagnostics_prompts
+    pub fn selection_start_line(&self) -> usize {
+        self.selection_start_line
+    }
+
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        self.diagnostics.as_slice()
+    }
+
+    /// Each diagnostic rendered as a standalone prompt
+    pub fn diagnostics_prompts(&self) -> Vec<String> {
+        self.diagnostics
+            .iter()
+            .map(|diagnostic| diagnostic.to_prompt(&self.file_path))
+            .collect()
+    }
+
+    /// The selection with line numbers and markers under the lines which
+    /// have diagnostics
+    pub fn annotated_in_range(&self) -> String {
+        annotate_selection(&self.in_range, self.selection_start_line, &self.diagnostics)
     }
 
     pub fn language(&self) -> &str {
@@ -182,25 +326,75 @@ impl InLineEditRequest {
 }
 
 /// We might end up calling the chat or the completion endpoint for a LLM,
-/// its important that we support both
+/// its important that we support both. The template goes in the metadata of
+/// the request so we can compare the versions of a prompt
 #[derive(Debug)]
 pub enum InLinePromptResponse {
-    Completion(String),
-    Chat(Vec<LLMClientMessage>),
+    Completion {
+        prompt: String,
+        template: TemplateId,
+    },
+    Chat {
+        messages: Vec<LLMClientMessage>,
+        template: TemplateId,
+    },
 }
 
 impl InLinePromptResponse {
-    pub fn completion(completion: String) -> Self {
-        InLinePromptResponse::Completion(completion)
+    pub fn completion(prompt: String, template: TemplateId) -> Self {
+        InLinePromptResponse::Completion { prompt, template }
+    }
+
+    pub fn chat(messages: Vec<LLMClientMessage>, template: TemplateId) -> Self {
+        InLinePromptResponse::Chat { messages, template }
     }
 
     pub fn get_completion(self) -> Option<String> {
-        if let InLinePromptResponse::Completion(completion) = self {
-            Some(completion)
+        if let InLinePromptResponse::Completion { prompt, .. } = self {
+            Some(prompt)
         } else {
             None
         }
     }
+
+    pub fn template(&self) -> &TemplateId {
+        match self {
+            InLinePromptResponse::Completion { template, .. }
+            | InLinePromptResponse::Chat { template, .. } => template,
+        }
+    }
+
+    /// The metadata to send along with the request to the llm broker
+    pub fn metadata(&self) -> HashMap<String, String> {
+        self.template().metadata().into_iter().collect()
+    }
+
+    /// The request and the metadata for [`LLMBroker::stream_answer`], the
+    /// template goes in the metadata so the row in llm_data tells us which
+    /// version of the prompt produced the answer
+    ///
+    /// [`LLMBroker::stream_answer`]: llm_client::broker::LLMBroker::stream_answer
+    pub fn into_broker_request(
+        self,
+        llm_type: LLMType,
+        temperature: f32,
+        mut metadata: HashMap<String, String>,
+    ) -> (
+        Either<LLMClientCompletionRequest, LLMClientCompletionStringRequest>,
+        HashMap<String, String>,
+    ) {
+        metadata.extend(self.metadata());
+        let request = match self {
+            InLinePromptResponse::Completion { prompt, .. } => Either::Right(
+                LLMClientCompletionStringRequest::new(llm_type, prompt, temperature, None),
+            ),
+            InLinePromptResponse::Chat { messages, .. } => Either::Left(
+                LLMClientCompletionRequest::from_messages(messages, llm_type)
+                    .set_temperature(temperature),
+            ),
+        };
+        (request, metadata)
+    }
 }
 
 /// Should we send context here as the above, below and in line context, or do we
@@ -211,6 +405,8 @@ pub trait InLineEditPrompt {
     fn inline_fix(&self, request: InLineFixRequest) -> InLinePromptResponse;
 
     fn inline_doc(&self, request: InLineDocRequest) -> InLinePromptResponse;
+
+    fn inline_tests(&self, request: InLineTestRequest) -> InLinePromptResponse;
 }
 
 /// The error type which we will return if we do not support that model yet
@@ -218,4 +414,13 @@ pub trait InLineEditPrompt {
 pub enum InLineEditPromptError {
     #[error("Model not supported yet")]
     ModelNotSupported,
+
+    #[error("Failed to parse diagnostics: {0}")]
+    DiagnosticsParseError(#[from] serde_json::Error),
+
+    #[error("Generated documentation is not a valid {0}")]
+    InvalidDocumentation(String),
+
+    #[error("No test code found in the output")]
+    NoTestCodeFound,
 }
@@ -1,4 +1,7 @@
 pub mod answer_model;
+pub mod autocomplete;
 pub mod chat;
+pub mod eval;
 pub mod in_line_edit;
 pub mod reranking;
+pub mod templates;
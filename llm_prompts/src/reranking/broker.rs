@@ -4,12 +4,14 @@ use std::{
     sync::Arc,
 };
 
+use futures::future::Either;
 use futures::stream;
 use futures::StreamExt;
 use llm_client::{
     broker::LLMBroker,
     clients::types::LLMType,
     provider::{LLMProvider, LLMProviderAPIKeys},
+    rate_limit::{RequestPriority, PRIORITY_METADATA_KEY},
     tokenizer::tokenizer::LLMTokenizer,
 };
 
@@ -21,6 +23,7 @@ use super::{
         ReRankCodeSpanResponse, ReRankListWiseResponse, ReRankStrategy,
     },
 };
+use crate::templates::registry::PromptTemplates;
 
 const SLIDING_WINDOW: i64 = 10;
 const TOP_K: i64 = 5;
@@ -31,12 +34,19 @@ pub struct ReRankBroker {
 
 impl ReRankBroker {
     pub fn new() -> Self {
+        Self::with_templates(PromptTemplates::defaults())
+    }
+
+    /// Uses these prompt templates instead of the embedded ones
+    pub fn with_templates(templates: Arc<PromptTemplates>) -> Self {
+        let openai = || Box::new(OpenAIReRank::with_templates(templates.clone()));
+        let mistral = || Box::new(MistralReRank::with_templates(templates.clone()));
         let mut rerankers: HashMap<LLMType, Box<dyn ReRankCodeSpan + Send + Sync>> = HashMap::new();
-        rerankers.insert(LLMType::GPT3_5_16k, Box::new(OpenAIReRank::new()));
-        rerankers.insert(LLMType::Gpt4, Box::new(OpenAIReRank::new()));
-        rerankers.insert(LLMType::Gpt4_32k, Box::new(OpenAIReRank::new()));
-        rerankers.insert(LLMType::MistralInstruct, Box::new(MistralReRank::new()));
-        rerankers.insert(LLMType::Mixtral, Box::new(MistralReRank::new()));
+        rerankers.insert(LLMType::GPT3_5_16k, openai());
+        rerankers.insert(LLMType::Gpt4, openai());
+        rerankers.insert(LLMType::Gpt4_32k, openai());
+        rerankers.insert(LLMType::MistralInstruct, mistral());
+        rerankers.insert(LLMType::Mixtral, mistral());
         Self { rerankers }
     }
 
@@ -48,28 +58,30 @@ impl ReRankBroker {
         reranker.rerank_prompt(request)
     }
 
-    fn measure_tokens(
+    /// Counts on the blocking pool, the spans are often the same between
+    /// requests so most of these come from the tokenizer cache
+    async fn measure_tokens(
         &self,
         llm_type: &LLMType,
         code_digests: &[CodeSpanDigest],
         tokenizer: Arc<LLMTokenizer>,
     ) -> Result<usize, ReRankCodeSpanError> {
-        let total_tokens: usize = code_digests
-            .into_iter()
+        let prompts = code_digests
+            .iter()
             .map(|code_digest| {
                 let file_path = code_digest.file_path();
                 let data = code_digest.data();
-                let prompt = format!(
+                format!(
                     r#"FILEPATH: {file_path}
 ```
 {data}
 ```"#
-                );
-                tokenizer.count_tokens_using_tokenizer(llm_type, &prompt)
+                )
             })
-            .collect::<Vec<_>>()
-            .into_iter()
-            .collect::<Result<Vec<_>, _>>()?
+            .collect::<Vec<_>>();
+        let total_tokens = tokenizer
+            .count_prompts_async(llm_type.clone(), prompts)
+            .await?
             .into_iter()
             .sum();
         Ok(total_tokens)
@@ -108,7 +120,9 @@ impl ReRankBroker {
         // First we check if we need to do a sliding window here by measuring
         // against the token limit we have
         if request.token_limit()
-            >= self.measure_tokens(request.llm_type(), &digests, tokenizer)? as i64
+            >= self
+                .measure_tokens(request.llm_type(), &digests, tokenizer)
+                .await? as i64
         {
             return Ok(digests
                 .into_iter()
@@ -150,12 +164,20 @@ impl ReRankBroker {
                         api_keys.clone(),
                         provider.clone(),
                         prompt,
-                        vec![("event_type".to_owned(), "listwise_reranking".to_owned())]
-                            .into_iter()
-                            .collect(),
+                        vec![
+                            ("event_type".to_owned(), "listwise_reranking".to_owned()),
+                            (
+                                PRIORITY_METADATA_KEY.to_owned(),
+                                RequestPriority::Background.as_str().to_owned(),
+                            ),
+                        ]
+                        .into_iter()
+                        .chain(listwise_request.template.metadata())
+                        .collect(),
                         sender,
                     )
-                    .await?;
+                    .await?
+                    .into_answer();
 
                 // We have the updated list
                 let updated_list =
@@ -208,7 +230,9 @@ impl ReRankBroker {
 
         // We first measure if we are within the token limit
         if request.token_limit()
-            >= self.measure_tokens(request.llm_type(), &digests, tokenizer)? as i64
+            >= self
+                .measure_tokens(request.llm_type(), &digests, tokenizer)
+                .await? as i64
         {
             return Ok(digests
                 .into_iter()
@@ -231,7 +255,48 @@ impl ReRankBroker {
         let prompt = self.rerank_prompt(request)?;
 
         if let ReRankCodeSpanResponse::PointWise(pointwise_prompts) = prompt {
-            let response_with_code_digests = stream::iter(pointwise_prompts.into_iter())
+            let metadata: HashMap<String, String> = vec![
+                ("event_type".to_owned(), "pointwise_reranking".to_owned()),
+                (
+                    PRIORITY_METADATA_KEY.to_owned(),
+                    RequestPriority::Background.as_str().to_owned(),
+                ),
+            ]
+            .into_iter()
+            .chain(
+                pointwise_prompts
+                    .first()
+                    .into_iter()
+                    .flat_map(|pointwise_prompt| pointwise_prompt.template.metadata()),
+            )
+            .collect();
+            // The string completions can be batched by the broker when the
+            // provider supports it, the chat ones go one by one
+            let (string_prompts, message_prompts): (Vec<_>, Vec<_>) = pointwise_prompts
+                .into_iter()
+                .partition(|pointwise_prompt| matches!(pointwise_prompt.prompt, Either::Right(_)));
+            let (string_requests, string_code_digests): (Vec<_>, Vec<_>) = string_prompts
+                .into_iter()
+                .filter_map(|pointwise_prompt| match pointwise_prompt.prompt {
+                    Either::Right(request) => Some((request, pointwise_prompt.code_span_digest)),
+                    Either::Left(_) => None,
+                })
+                .unzip();
+            let batched_responses = client_broker
+                .batch_string_completion(
+                    api_keys.clone(),
+                    string_requests,
+                    provider.clone(),
+                    metadata.clone(),
+                )
+                .await
+                .into_iter()
+                .zip(string_code_digests)
+                .map(|(response, code_digest)| {
+                    response.map(|response| (response.into_answer(), code_digest))
+                })
+                .collect::<Vec<_>>();
+            let message_responses = stream::iter(message_prompts.into_iter())
                 .map(|pointwise_prompt| async {
                     let prompt = pointwise_prompt.prompt;
                     let code_digest = pointwise_prompt.code_span_digest;
@@ -241,28 +306,28 @@ impl ReRankBroker {
                             api_keys.clone(),
                             provider.clone(),
                             prompt,
-                            vec![("event_type".to_owned(), "pointwise_reranking".to_owned())]
-                                .into_iter()
-                                .collect(),
+                            metadata.clone(),
                             sender,
                         )
                         .await
-                        .map(|response| (response, code_digest))
-                })
-                .buffer_unordered(25)
-                .filter_map(|response| {
-                    if let Ok((response, code_digest)) = response {
-                        if response.trim().to_lowercase() == "yes" {
-                            futures::future::ready(Some(code_digest))
-                        } else {
-                            futures::future::ready(None)
-                        }
-                    } else {
-                        futures::future::ready(None)
-                    }
+                        .map(|response| (response.into_answer(), code_digest))
                 })
+                // the broker rate limits these, so this only bounds how many
+                // wait in its queue at once. The answers keep the order of the
+                // candidates
+                .buffered(25)
                 .collect::<Vec<_>>()
                 .await;
+            let response_with_code_digests = batched_responses
+                .into_iter()
+                .chain(message_responses)
+                .filter_map(|response| match response {
+                    Ok((response, code_digest)) if response.trim().to_lowercase() == "yes" => {
+                        Some(code_digest)
+                    }
+                    _ => None,
+                })
+                .collect::<Vec<_>>();
             // Now we only keep the code spans from the start until the length
             // of the limit we have
             let mut response_with_code_digests = response_with_code_digests
@@ -313,3 +378,71 @@ impl ReRankBroker {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use llm_client::{
+        broker::LLMBroker,
+        clients::{
+            mock::{MockLLMClient, MockResponse},
+            types::{LLMClientCapabilities, LLMType},
+        },
+        config::LLMBrokerConfiguration,
+        provider::{LLMProvider, LLMProviderAPIKeys, TogetherAIProvider},
+        tokenizer::tokenizer::LLMTokenizer,
+    };
+
+    use super::ReRankBroker;
+    use crate::reranking::types::{CodeSpan, ReRankCodeSpanRequest, ReRankStrategy};
+
+    #[tokio::test]
+    async fn test_pointwise_reranking_with_mock_provider() {
+        let data_dir = tempfile::tempdir().expect("data dir to be created");
+        let llm_broker = LLMBroker::new(LLMBrokerConfiguration::new(data_dir.path().to_owned()))
+            .await
+            .expect("broker to startup")
+            .add_provider(
+                LLMProvider::TogetherAI,
+                Box::new(
+                    MockLLMClient::new(LLMProvider::TogetherAI)
+                        .set_capabilities(LLMClientCapabilities::new(true, true).set_batching(true))
+                        .respond_when("fn relevant_snippet", MockResponse::answer(" Yes"))
+                        .set_default_response(MockResponse::answer(" No")),
+                ),
+            );
+        let code_span = |file_path: &str, data: &str| {
+            CodeSpan::new(file_path.to_owned(), 0, 1, data.to_owned())
+        };
+        let request = ReRankCodeSpanRequest::new(
+            "where is the relevant snippet?".to_owned(),
+            5,
+            0,
+            vec![
+                code_span("first.rs", "fn relevant_snippet() {}"),
+                code_span("second.rs", "fn unrelated() {}"),
+                code_span("third.rs", "fn relevant_snippet_again() {}"),
+            ],
+            ReRankStrategy::PointWise,
+            LLMType::Mixtral,
+        );
+        let code_spans = ReRankBroker::new()
+            .rerank(
+                LLMProviderAPIKeys::TogetherAI(TogetherAIProvider::new("key".to_owned())),
+                LLMProvider::TogetherAI,
+                request,
+                Arc::new(llm_broker),
+                Arc::new(LLMTokenizer::new().expect("tokenizer to load")),
+            )
+            .await
+            .expect("reranking to work");
+        assert_eq!(
+            code_spans
+                .iter()
+                .map(|code_span| code_span.file_path())
+                .collect::<Vec<_>>(),
+            vec!["first.rs", "third.rs"]
+        );
+    }
+}
@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, sync::Arc};
 
 use llm_client::clients::types::LLMClientCompletionStringRequest;
 
@@ -6,18 +6,33 @@ use super::types::{
     CodeSpan, CodeSpanDigest, ReRankCodeSpan, ReRankCodeSpanError, ReRankCodeSpanRequest,
     ReRankCodeSpanResponse, ReRankListWiseResponse, ReRankPointWisePrompt, ReRankStrategy,
 };
+use crate::templates::{registry::PromptTemplates, template::TemplateValues};
 
-#[derive(Default)]
-pub struct MistralReRank {}
+pub struct MistralReRank {
+    templates: Arc<PromptTemplates>,
+}
+
+impl Default for MistralReRank {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 impl MistralReRank {
     pub fn new() -> Self {
-        Default::default()
+        Self::with_templates(PromptTemplates::defaults())
+    }
+
+    pub fn with_templates(templates: Arc<PromptTemplates>) -> Self {
+        Self { templates }
     }
 }
 
 impl MistralReRank {
-    pub fn pointwise_reranking(&self, request: ReRankCodeSpanRequest) -> ReRankCodeSpanResponse {
+    pub fn pointwise_reranking(
+        &self,
+        request: ReRankCodeSpanRequest,
+    ) -> Result<ReRankCodeSpanResponse, ReRankCodeSpanError> {
         let code_span_digests = CodeSpan::to_digests(request.code_spans().to_vec());
         // Now we query the LLM for the pointwise reranking here
         let user_query = request.user_query().to_owned();
@@ -27,61 +42,37 @@ impl MistralReRank {
                 let user_query = user_query.to_owned();
                 let hash = code_span_digest.hash();
                 let data = code_span_digest.data();
-                let prompt = format!(r#"<s>[INST] You are an expert software developer responsible for helping detect whether the retrieved snippet of code is relevant to the query. For a given input, you need to output a single word: "Yes" or "No" indicating the retrieved snippet is relevant to the query.
-Query: Where is the client for OpenAI defined?
-Code Snippet:
-```/Users/skcd/client/openai.rs
-pub struct OpenAIClient {{}}
-
-impl OpenAIClient {{
-    pub fn new() -> Self {{
-        Self {{}}
-    }}
-```
-Relevant: Yes
-
-Query: Where do we handle the errors in the webview?
-Snippet:
-```/Users/skcd/algorithm/dfs.rs
-pub fn dfs(graph: &Graph, start: NodeId) -> Vec<NodeId> {{
-    let mut visited = HashSet::new();
-    let mut stack = vec![start];
-    let mut result = vec![];
-    while let Some(node) = stack.pop() {{
-        if visited.contains(&node) {{
-            continue;
-        }}
-        visited.insert(node);
-        result.push(node);
-        for neighbor in graph.neighbors(node) {{
-            stack.push(neighbor);
-        }}
-    }}
-    result
-}}
-```
-Relevant: No
-
-Query: {user_query}
-Snippet:
-```{hash}
-{data}
-``` [/INST]
-Relevant: "#);
+                let (prompt, template) = self
+                    .templates
+                    .render(
+                        "reranking.mistral.pointwise",
+                        &TemplateValues::new()
+                            .text("user_query", user_query)
+                            .text("hash", hash)
+                            .text("data", data),
+                    )?
+                    .into_parts();
                 let prompt = LLMClientCompletionStringRequest::new(
                     request.llm_type().clone(),
                     prompt,
                     0.0,
                     None,
                 );
-                ReRankPointWisePrompt::new_string_completion(prompt, code_span_digest)
+                Ok(ReRankPointWisePrompt::new_string_completion(
+                    prompt,
+                    code_span_digest,
+                    template,
+                ))
             })
-            .collect();
+            .collect::<Result<Vec<_>, ReRankCodeSpanError>>()?;
 
-        ReRankCodeSpanResponse::PointWise(prompts)
+        Ok(ReRankCodeSpanResponse::PointWise(prompts))
     }
 
-    pub fn listwise_reranking(&self, request: ReRankCodeSpanRequest) -> ReRankCodeSpanResponse {
+    pub fn listwise_reranking(
+        &self,
+        request: ReRankCodeSpanRequest,
+    ) -> Result<ReRankCodeSpanResponse, ReRankCodeSpanError> {
         // First we get the code spans which are present here cause they are important
         let code_spans = request.code_spans().to_vec();
         let user_query = request.user_query().to_owned();
@@ -100,65 +91,23 @@ Relevant: "#);
             .collect::<Vec<String>>()
             .join("\n");
         // Now we create the prompt for this reranking
-        let prompt = format!(
-            r#"<s>[INST] You are an expert at ordering the code snippets from the most relevant to the least relevant for the user query. You have the order the list of code snippets from the most relevant to the least relevant. As an example
-<code_snippets>
-<id>
-subtract.rs::0
-</id>
-<snippet>
-```
-fn subtract(a: i32, b: i32) -> i32 {{
-    a - b
-}}
-```
-</snippet>
-
-<id>
-add.rs::0
-</id>
-<snippet>
-```
-fn add(a: i32, b: i32) -> i32 {{
-    a + b
-}}
-```
-</snippet>
-</code_snippets>
-
-And if you thought the code snippet with id add.rs::0 is more relevant than subtract.rs::0 then you would rank it as:
-<ranking>
-<id>
-add.rs::0
-</id>
-<id>
-subtract.rs::0
-</id>
-</ranking>
-
-Now for the actual query.
-The user has asked the following query:
-<user_query>
-{user_query}
-</user_query>
-
-The code snippets along with their ids are given below:
-<code_snippets>
-{code_snippets}
-</code_snippets>
-
-As a reminder the user question is:
-<user_query>
-{user_query}
-</user_query>
-You have to order all the code snippets from the most relevant to the least relevant to the user query, all the code snippet ids should be present in your final reordered list. Only output the ids of the code snippets.
-[/INST]<ranking>
-<id>
-"#
-        );
+        let (prompt, template) = self
+            .templates
+            .render(
+                "reranking.mistral.listwise",
+                &TemplateValues::new()
+                    .text("user_query", user_query)
+                    .text("code_snippets", code_snippets),
+            )?
+            .into_parts();
         let prompt =
-            LLMClientCompletionStringRequest::new(request.llm_type().clone(), prompt, 0.0, None);
-        ReRankCodeSpanResponse::listwise_completion(prompt, code_span_digests)
+            LLMClientCompletionStringRequest::new(request.llm_type().clone(), prompt, 0.0, None)
+                .set_stop_words(vec!["</ranking>".to_owned()]);
+        Ok(ReRankCodeSpanResponse::listwise_completion(
+            prompt,
+            code_span_digests,
+            template,
+        ))
     }
 
     fn parse_listwise_output(
@@ -176,6 +125,10 @@ You have to order all the code snippets from the most relevant to the least rele
         // so we split the string on \n and ignore the values which are <id> or
         // </id> and only parse until we get the </reranking> tag
         let mut output = output.split("\n");
+        let original_order = code_span_digests
+            .iter()
+            .map(|code_span_digest| code_span_digest.hash().to_owned())
+            .collect::<Vec<_>>();
         let mut code_span_digests_mapping: HashMap<String, CodeSpanDigest> = code_span_digests
             .into_iter()
             .map(|code_span_digest| (code_span_digest.hash().to_owned(), code_span_digest))
@@ -194,12 +147,13 @@ You have to order all the code snippets from the most relevant to the least rele
             }
         }
 
-        // Add all the remaining code spans to the end of the list
-        code_span_digests_mapping
-            .into_iter()
-            .for_each(|(_, code_span)| {
-                code_spans_reordered_list.push(code_span);
-            });
+        // Add all the remaining code spans to the end of the list, in the order
+        // we got them so the ranking is the same for the same output
+        code_spans_reordered_list.extend(
+            original_order
+                .iter()
+                .filter_map(|hash| code_span_digests_mapping.remove(hash)),
+        );
 
         // Now that we have the possible ids in the list, we get the list of ranked
         // code span digests in the same manner
@@ -212,13 +166,13 @@ impl ReRankCodeSpan for MistralReRank {
         &self,
         request: ReRankCodeSpanRequest,
     ) -> Result<ReRankCodeSpanResponse, ReRankCodeSpanError> {
-        Ok(match request.strategy() {
+        match request.strategy() {
             ReRankStrategy::ListWise => self.listwise_reranking(request),
             ReRankStrategy::PointWise => {
                 // We need to generate the prompt for this
                 self.pointwise_reranking(request)
             }
-        })
+        }
     }
 
     fn parse_listwise_output(
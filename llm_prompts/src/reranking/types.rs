@@ -8,6 +8,8 @@ use llm_client::{
     tokenizer::tokenizer::LLMTokenizerError,
 };
 
+use crate::templates::template::{PromptTemplateError, TemplateId};
+
 #[derive(Clone, Debug, PartialEq)]
 pub struct CodeSpan {
     file_path: String,
@@ -181,7 +183,7 @@ impl CodeSpan {
 /// these are the best ones
 /// list wise reading material here: https://arxiv.org/pdf/2312.02724.pdf
 /// point wise reading material here: https://cookbook.openai.com/examples/search_reranking_with_cross-encoders
-#[derive(Clone)]
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
 pub enum ReRankStrategy {
     ListWise,
     // This works best with logits enabled, if logits are not provied by the
@@ -245,31 +247,38 @@ impl ReRankCodeSpanRequest {
 pub struct ReRankListWiseResponse {
     pub prompt: Either<LLMClientCompletionRequest, LLMClientCompletionStringRequest>,
     pub code_span_digests: Vec<CodeSpanDigest>,
+    /// The prompt template we used, this goes in the llm data metadata
+    pub template: TemplateId,
 }
 
 pub struct ReRankPointWisePrompt {
     pub prompt: Either<LLMClientCompletionRequest, LLMClientCompletionStringRequest>,
     pub code_span_digest: CodeSpanDigest,
+    pub template: TemplateId,
 }
 
 impl ReRankPointWisePrompt {
     pub fn new_message_request(
         prompt: LLMClientCompletionRequest,
         code_span_digest: CodeSpanDigest,
+        template: TemplateId,
     ) -> Self {
         Self {
             prompt: Either::Left(prompt),
             code_span_digest,
+            template,
         }
     }
 
     pub fn new_string_completion(
         prompt: LLMClientCompletionStringRequest,
         code_span_digest: CodeSpanDigest,
+        template: TemplateId,
     ) -> Self {
         Self {
             prompt: Either::Right(prompt),
             code_span_digest,
+            template,
         }
     }
 }
@@ -283,20 +292,24 @@ impl ReRankCodeSpanResponse {
     pub fn listwise_message(
         request: LLMClientCompletionRequest,
         code_span_digests: Vec<CodeSpanDigest>,
+        template: TemplateId,
     ) -> Self {
         Self::ListWise(ReRankListWiseResponse {
             prompt: Either::Left(request),
             code_span_digests,
+            template,
         })
     }
 
     pub fn listwise_completion(
         request: LLMClientCompletionStringRequest,
         code_span_digests: Vec<CodeSpanDigest>,
+        template: TemplateId,
     ) -> Self {
         Self::ListWise(ReRankListWiseResponse {
             prompt: Either::Right(request),
             code_span_digests,
+            template,
         })
     }
 
@@ -318,6 +331,9 @@ pub enum ReRankCodeSpanError {
 
     #[error("LLMClientError: {0}")]
     LLMClientError(#[from] LLMClientError),
+
+    #[error("prompt template error: {0}")]
+    PromptTemplateError(#[from] PromptTemplateError),
 }
 
 /// The rerank code span will take in a list of code spans and generate a prompt
@@ -0,0 +1,300 @@
+//! All the prompt templates we know about. The defaults are embedded in the
+//! binary, a directory of `.prompt` files can replace them or add new versions
+//! of them. We use the latest version of a template unless it is pinned, this
+//! is how we try out a new version of a prompt against the current one.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::Path,
+    sync::{Arc, OnceLock},
+};
+
+use super::template::{
+    PromptTemplate, PromptTemplateError, TemplateId, TemplateValues, TemplateVariableType,
+};
+
+const DEFAULT_TEMPLATES: &[(&str, &str)] = &[
+    (
+        "reranking_openai_listwise.prompt",
+        include_str!("defaults/reranking_openai_listwise.prompt"),
+    ),
+    (
+        "reranking_openai_pointwise.prompt",
+        include_str!("defaults/reranking_openai_pointwise.prompt"),
+    ),
+    (
+        "reranking_mistral_listwise.prompt",
+        include_str!("defaults/reranking_mistral_listwise.prompt"),
+    ),
+    (
+        "reranking_mistral_pointwise.prompt",
+        include_str!("defaults/reranking_mistral_pointwise.prompt"),
+    ),
+    (
+        "in_line_edit_openai_edit_system.prompt",
+        include_str!("defaults/in_line_edit_openai_edit_system.prompt"),
+    ),
+    (
+        "in_line_edit_openai_fix_system.prompt",
+        include_str!("defaults/in_line_edit_openai_fix_system.prompt"),
+    ),
+    (
+        "in_line_edit_openai_doc_node_system.prompt",
+        include_str!("defaults/in_line_edit_openai_doc_node_system.prompt"),
+    ),
+    (
+        "in_line_edit_openai_doc_selection_system.prompt",
+        include_str!("defaults/in_line_edit_openai_doc_selection_system.prompt"),
+    ),
+    (
+        "in_line_edit_openai_tests_system.prompt",
+        include_str!("defaults/in_line_edit_openai_tests_system.prompt"),
+    ),
+    (
+        "in_line_edit_mistral_edit.prompt",
+        include_str!("defaults/in_line_edit_mistral_edit.prompt"),
+    ),
+    (
+        "in_line_edit_mistral_generate.prompt",
+        include_str!("defaults/in_line_edit_mistral_generate.prompt"),
+    ),
+    (
+        "in_line_edit_mistral_fix.prompt",
+        include_str!("defaults/in_line_edit_mistral_fix.prompt"),
+    ),
+    (
+        "in_line_edit_mistral_doc.prompt",
+        include_str!("defaults/in_line_edit_mistral_doc.prompt"),
+    ),
+    (
+        "in_line_edit_mistral_tests.prompt",
+        include_str!("defaults/in_line_edit_mistral_tests.prompt"),
+    ),
+];
+
+static DEFAULTS: OnceLock<Arc<PromptTemplates>> = OnceLock::new();
+
+#[derive(Debug, Clone)]
+pub struct PromptTemplates {
+    templates: HashMap<String, BTreeMap<u32, PromptTemplate>>,
+    pinned_versions: HashMap<String, u32>,
+}
+
+impl PromptTemplates {
+    /// Only the templates which are embedded in the binary
+    pub fn embedded() -> Self {
+        let mut templates = Self {
+            templates: HashMap::new(),
+            pinned_versions: HashMap::new(),
+        };
+        for (source, template) in DEFAULT_TEMPLATES {
+            let template =
+                PromptTemplate::parse(source, template).expect("embedded templates to be valid");
+            templates.insert(template);
+        }
+        templates
+    }
+
+    /// The embedded templates, shared by the prompt builders which are not
+    /// given their own templates
+    pub fn defaults() -> Arc<Self> {
+        DEFAULTS.get_or_init(|| Arc::new(Self::embedded())).clone()
+    }
+
+    /// The embedded templates along with the ones in the override directory
+    pub fn load(override_dir: Option<&Path>) -> Result<Self, PromptTemplateError> {
+        let templates = Self::embedded();
+        match override_dir {
+            Some(override_dir) => templates.load_dir(override_dir),
+            None => Ok(templates),
+        }
+    }
+
+    /// Loads the `.prompt` files in the directory, a template with the same
+    /// name and version as one we have replaces it. The variables have to be
+    /// the same as the ones of the embedded template since the prompt builders
+    /// fill those in.
+    pub fn load_dir(mut self, dir: &Path) -> Result<Self, PromptTemplateError> {
+        let mut paths = std::fs::read_dir(dir)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<Result<Vec<_>, _>>()?;
+        paths.retain(|path| {
+            path.extension()
+                .is_some_and(|extension| extension == "prompt")
+        });
+        paths.sort();
+        for path in paths {
+            let template =
+                PromptTemplate::parse(&path.to_string_lossy(), &std::fs::read_to_string(&path)?)?;
+            if let Some(expected) = Self::expected_variables(template.name()) {
+                let variables = template
+                    .variables()
+                    .iter()
+                    .map(|(variable, variable_type)| (variable.to_owned(), *variable_type))
+                    .collect::<Vec<_>>();
+                if variables != expected {
+                    return Err(PromptTemplateError::VariablesMismatch(
+                        template.name().to_owned(),
+                        template.version(),
+                        variables,
+                        expected,
+                    ));
+                }
+            }
+            self.insert(template);
+        }
+        Ok(self)
+    }
+
+    /// Always use this version of the template, instead of the latest one
+    pub fn pin_version(mut self, name: &str, version: u32) -> Result<Self, PromptTemplateError> {
+        self.versions(name)?
+            .get(&version)
+            .ok_or_else(|| PromptTemplateError::VersionNotFound(name.to_owned(), version))?;
+        self.pinned_versions.insert(name.to_owned(), version);
+        Ok(self)
+    }
+
+    pub fn get(&self, name: &str) -> Result<&PromptTemplate, PromptTemplateError> {
+        let versions = self.versions(name)?;
+        let template = match self.pinned_versions.get(name) {
+            Some(version) => versions.get(version),
+            None => versions.values().next_back(),
+        };
+        template.ok_or_else(|| PromptTemplateError::TemplateNotFound(name.to_owned()))
+    }
+
+    pub fn render(
+        &self,
+        name: &str,
+        values: &TemplateValues,
+    ) -> Result<RenderedPrompt, PromptTemplateError> {
+        let template = self.get(name)?;
+        Ok(RenderedPrompt {
+            prompt: template.render(values)?,
+            template: template.id().clone(),
+        })
+    }
+
+    /// The names of the templates along with their versions
+    pub fn list(&self) -> Vec<TemplateId> {
+        let mut templates = self
+            .templates
+            .values()
+            .flat_map(|versions| versions.values().map(|template| template.id().clone()))
+            .collect::<Vec<_>>();
+        templates.sort_by(|first, second| {
+            (first.name(), first.version()).cmp(&(second.name(), second.version()))
+        });
+        templates
+    }
+
+    fn versions(&self, name: &str) -> Result<&BTreeMap<u32, PromptTemplate>, PromptTemplateError> {
+        self.templates
+            .get(name)
+            .ok_or_else(|| PromptTemplateError::TemplateNotFound(name.to_owned()))
+    }
+
+    /// The variables of the embedded template with this name
+    fn expected_variables(name: &str) -> Option<Vec<(String, TemplateVariableType)>> {
+        let defaults = Self::defaults();
+        let template = defaults.templates.get(name)?.values().next_back()?;
+        Some(
+            template
+                .variables()
+                .iter()
+                .map(|(variable, variable_type)| (variable.to_owned(), *variable_type))
+                .collect(),
+        )
+    }
+
+    fn insert(&mut self, template: PromptTemplate) {
+        self.templates
+            .entry(template.name().to_owned())
+            .or_default()
+            .insert(template.version(), template);
+    }
+}
+
+/// The prompt along with the template it came from
+#[derive(Debug, Clone)]
+pub struct RenderedPrompt {
+    prompt: String,
+    template: TemplateId,
+}
+
+impl RenderedPrompt {
+    pub fn prompt(&self) -> &str {
+        &self.prompt
+    }
+
+    pub fn template(&self) -> &TemplateId {
+        &self.template
+    }
+
+    pub fn into_parts(self) -> (String, TemplateId) {
+        (self.prompt, self.template)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PromptTemplates;
+    use crate::templates::template::{PromptTemplateError, TemplateValues};
+
+    #[test]
+    fn test_override_dir_adds_versions() {
+        let dir = tempfile::tempdir().expect("dir to be created");
+        let dir = dir.path();
+        let values = TemplateValues::new()
+            .text("user_query", "where is the client?")
+            .text("hash", "client.rs::0")
+            .text("data", "struct Client {}");
+        assert_eq!(
+            PromptTemplates::embedded()
+                .render("reranking.openai.pointwise", &values)
+                .expect("template to render")
+                .template()
+                .version(),
+            1
+        );
+
+        std::fs::write(
+            dir.join("pointwise.prompt"),
+            "name: reranking.openai.pointwise\nversion: 2\nvariables: user_query: text, hash: text, data: text\n---\nIs {{hash}} relevant to {{user_query}}?\n{{data}}\n",
+        )
+        .expect("template to be written");
+        let templates = PromptTemplates::load(Some(dir)).expect("templates to load");
+        let rendered = templates
+            .render("reranking.openai.pointwise", &values)
+            .expect("template to render");
+        assert_eq!(
+            rendered.prompt(),
+            "Is client.rs::0 relevant to where is the client??\nstruct Client {}"
+        );
+        assert_eq!(rendered.template().version(), 2);
+        // pinning goes back to the embedded version
+        let templates = templates
+            .pin_version("reranking.openai.pointwise", 1)
+            .expect("version to exist");
+        assert_eq!(
+            templates
+                .render("reranking.openai.pointwise", &values)
+                .expect("template to render")
+                .template()
+                .version(),
+            1
+        );
+
+        // the prompt builders only fill in the variables of the embedded one
+        std::fs::write(
+            dir.join("pointwise.prompt"),
+            "name: reranking.openai.pointwise\nversion: 3\nvariables: user_query: text, data: text\n---\n{{user_query}} {{data}}\n",
+        )
+        .expect("template to be written");
+        assert!(matches!(
+            PromptTemplates::load(Some(dir)),
+            Err(PromptTemplateError::VariablesMismatch(_, 3, _, _))
+        ));
+    }
+}
@@ -0,0 +1,398 @@
+//! A prompt template is a text file with a small header and the prompt below
+//! it, the variables go in as `{{name}}`:
+//!
+//! ```text
+//! name: reranking.openai.listwise
+//! version: 1
+//! variables: user_query: text, code_snippets: text
+//! ---
+//! The user has asked the following query: {{user_query}}
+//! ...
+//! ```
+//!
+//! Everything after the `---` line is the prompt, except for the last new line
+//! of the file. A `{{` which is not followed by a variable name and `}}` is
+//! left as it is.
+
+use std::collections::{BTreeMap, HashMap};
+
+#[derive(thiserror::Error, Debug)]
+pub enum PromptTemplateError {
+    #[error("template {0} is missing the --- line after the header")]
+    MissingBody(String),
+
+    #[error("template {0} has an invalid header line: {1}")]
+    InvalidHeader(String, String),
+
+    #[error("template {0} is missing the {1} in the header")]
+    MissingHeaderField(String, &'static str),
+
+    #[error("template {0} has an unknown variable type {1}")]
+    UnknownVariableType(String, String),
+
+    #[error("template {0} declares the variable {1} more than once")]
+    DuplicateVariable(String, String),
+
+    #[error("template {0} uses the variable {1} which is not declared")]
+    UndeclaredVariable(String, String),
+
+    #[error("template {0} declares the variable {1} which is not used")]
+    UnusedVariable(String, String),
+
+    #[error("template {0} version {1} has the variables {2:?} but we expect {3:?}")]
+    VariablesMismatch(
+        String,
+        u32,
+        Vec<(String, TemplateVariableType)>,
+        Vec<(String, TemplateVariableType)>,
+    ),
+
+    #[error("template {0} needs a value for {1}")]
+    MissingValue(String, String),
+
+    #[error("template {0} has no variable {1}")]
+    UnknownValue(String, String),
+
+    #[error("template {0} expects {1} to be {2:?}")]
+    WrongValueType(String, String, TemplateVariableType),
+
+    #[error("template {0} was not found")]
+    TemplateNotFound(String),
+
+    #[error("template {0} has no version {1}")]
+    VersionNotFound(String, u32),
+
+    #[error("failed to read templates: {0}")]
+    IOError(#[from] std::io::Error),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TemplateVariableType {
+    /// Has to be present, it can still be an empty string
+    Text,
+    /// Renders as an empty string when it is not present
+    OptionalText,
+    Number,
+}
+
+impl TemplateVariableType {
+    fn from_str(variable_type: &str) -> Option<Self> {
+        match variable_type {
+            "text" => Some(Self::Text),
+            "optional" => Some(Self::OptionalText),
+            "number" => Some(Self::Number),
+            _ => None,
+        }
+    }
+}
+
+/// Identifies the prompt we sent, this is what we log along with the request
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct TemplateId {
+    name: String,
+    version: u32,
+}
+
+impl TemplateId {
+    pub fn new(name: String, version: u32) -> Self {
+        Self { name, version }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// The metadata we attach to the requests so the llm data rows say which
+    /// prompt they were made with
+    pub fn metadata(&self) -> [(String, String); 2] {
+        [
+            (
+                PROMPT_TEMPLATE_METADATA_KEY.to_owned(),
+                self.name.to_owned(),
+            ),
+            (
+                PROMPT_TEMPLATE_VERSION_METADATA_KEY.to_owned(),
+                self.version.to_string(),
+            ),
+        ]
+    }
+}
+
+pub const PROMPT_TEMPLATE_METADATA_KEY: &str = "prompt_template";
+pub const PROMPT_TEMPLATE_VERSION_METADATA_KEY: &str = "prompt_template_version";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TemplateValue {
+    Text(String),
+    Number(i64),
+}
+
+/// The values for rendering a template
+#[derive(Debug, Clone, Default)]
+pub struct TemplateValues {
+    values: HashMap<String, TemplateValue>,
+}
+
+impl TemplateValues {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn text(mut self, name: &str, value: impl Into<String>) -> Self {
+        self.values
+            .insert(name.to_owned(), TemplateValue::Text(value.into()));
+        self
+    }
+
+    /// Leaves the variable out when there is no value
+    pub fn optional(self, name: &str, value: Option<impl Into<String>>) -> Self {
+        match value {
+            Some(value) => self.text(name, value),
+            None => self,
+        }
+    }
+
+    pub fn number(mut self, name: &str, value: i64) -> Self {
+        self.values
+            .insert(name.to_owned(), TemplateValue::Number(value));
+        self
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PromptTemplate {
+    id: TemplateId,
+    variables: BTreeMap<String, TemplateVariableType>,
+    body: String,
+}
+
+impl PromptTemplate {
+    /// Parses the template and checks that the variables it declares are the
+    /// ones it uses, `source` is where it came from for the errors
+    pub fn parse(source: &str, template: &str) -> Result<Self, PromptTemplateError> {
+        let (header, body) = template
+            .split_once("\n---\n")
+            .ok_or_else(|| PromptTemplateError::MissingBody(source.to_owned()))?;
+        let body = body.strip_suffix('\n').unwrap_or(body);
+        let mut name = None;
+        let mut version = None;
+        let mut variables = BTreeMap::new();
+        for line in header.lines().filter(|line| !line.trim().is_empty()) {
+            let invalid_header =
+                || PromptTemplateError::InvalidHeader(source.to_owned(), line.to_owned());
+            let (key, value) = line.split_once(':').ok_or_else(invalid_header)?;
+            let value = value.trim();
+            match key.trim() {
+                "name" => name = Some(value.to_owned()),
+                "version" => version = Some(value.parse::<u32>().map_err(|_| invalid_header())?),
+                "variables" => {
+                    for variable in value.split(',').filter(|variable| !variable.is_empty()) {
+                        let (variable, variable_type) =
+                            variable.split_once(':').ok_or_else(invalid_header)?;
+                        let (variable, variable_type) = (variable.trim(), variable_type.trim());
+                        let variable_type = TemplateVariableType::from_str(variable_type)
+                            .ok_or_else(|| {
+                                PromptTemplateError::UnknownVariableType(
+                                    source.to_owned(),
+                                    variable_type.to_owned(),
+                                )
+                            })?;
+                        if variables
+                            .insert(variable.to_owned(), variable_type)
+                            .is_some()
+                        {
+                            return Err(PromptTemplateError::DuplicateVariable(
+                                source.to_owned(),
+                                variable.to_owned(),
+                            ));
+                        }
+                    }
+                }
+                _ => return Err(invalid_header()),
+            }
+        }
+        let name =
+            name.ok_or_else(|| PromptTemplateError::MissingHeaderField(source.to_owned(), "name"))?;
+        let version = version
+            .ok_or_else(|| PromptTemplateError::MissingHeaderField(source.to_owned(), "version"))?;
+
+        let used = placeholders(body)
+            .into_iter()
+            .map(|(_, variable)| variable)
+            .collect::<Vec<_>>();
+        if let Some(variable) = used
+            .iter()
+            .find(|variable| !variables.contains_key(**variable))
+        {
+            return Err(PromptTemplateError::UndeclaredVariable(
+                name,
+                variable.to_string(),
+            ));
+        }
+        if let Some(variable) = variables
+            .keys()
+            .find(|variable| !used.contains(&variable.as_str()))
+        {
+            return Err(PromptTemplateError::UnusedVariable(
+                name,
+                variable.to_owned(),
+            ));
+        }
+        Ok(Self {
+            id: TemplateId::new(name, version),
+            variables,
+            body: body.to_owned(),
+        })
+    }
+
+    pub fn id(&self) -> &TemplateId {
+        &self.id
+    }
+
+    pub fn name(&self) -> &str {
+        self.id.name()
+    }
+
+    pub fn version(&self) -> u32 {
+        self.id.version()
+    }
+
+    pub fn variables(&self) -> &BTreeMap<String, TemplateVariableType> {
+        &self.variables
+    }
+
+    pub fn render(&self, values: &TemplateValues) -> Result<String, PromptTemplateError> {
+        let name = || self.name().to_owned();
+        if let Some(value) = values
+            .values
+            .keys()
+            .find(|value| !self.variables.contains_key(*value))
+        {
+            return Err(PromptTemplateError::UnknownValue(name(), value.to_owned()));
+        }
+        let mut rendered_values = HashMap::new();
+        for (variable, variable_type) in self.variables.iter() {
+            let rendered = match (variable_type, values.values.get(variable)) {
+                (TemplateVariableType::Text, Some(TemplateValue::Text(value)))
+                | (TemplateVariableType::OptionalText, Some(TemplateValue::Text(value))) => {
+                    value.to_owned()
+                }
+                (TemplateVariableType::OptionalText, None) => String::new(),
+                (TemplateVariableType::Number, Some(TemplateValue::Number(value))) => {
+                    value.to_string()
+                }
+                (_, None) => {
+                    return Err(PromptTemplateError::MissingValue(
+                        name(),
+                        variable.to_owned(),
+                    ))
+                }
+                (variable_type, Some(_)) => {
+                    return Err(PromptTemplateError::WrongValueType(
+                        name(),
+                        variable.to_owned(),
+                        *variable_type,
+                    ))
+                }
+            };
+            rendered_values.insert(variable.as_str(), rendered);
+        }
+        let mut prompt = String::with_capacity(self.body.len());
+        let mut last_end = 0;
+        for (range, variable) in placeholders(&self.body) {
+            prompt.push_str(&self.body[last_end..range.start]);
+            prompt.push_str(&rendered_values[variable]);
+            last_end = range.end;
+        }
+        prompt.push_str(&self.body[last_end..]);
+        Ok(prompt)
+    }
+}
+
+/// The `{{variable}}` in the text along with where they are
+fn placeholders(text: &str) -> Vec<(std::ops::Range<usize>, &str)> {
+    let mut placeholders = vec![];
+    let mut search_from = 0;
+    while let Some(start) = text[search_from..]
+        .find("{{")
+        .map(|start| search_from + start)
+    {
+        let variable_start = start + 2;
+        let variable = text[variable_start..]
+            .find("}}")
+            .map(|end| &text[variable_start..variable_start + end])
+            .filter(|variable| {
+                !variable.is_empty()
+                    && variable
+                        .chars()
+                        .all(|character| character.is_ascii_alphanumeric() || character == '_')
+            });
+        match variable {
+            Some(variable) => {
+                let end = variable_start + variable.len() + 2;
+                placeholders.push((start..end, variable));
+                search_from = end;
+            }
+            None => search_from = start + 1,
+        }
+    }
+    placeholders
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PromptTemplate, PromptTemplateError, TemplateValues};
+
+    #[test]
+    fn test_template_parse_and_render() {
+        let template = PromptTemplate::parse(
+            "test.prompt",
+            "name: test\nversion: 2\nvariables: query: text, context: optional, limit: number\n---\n{{context}}Find {{limit}} snippets for {{query}} in {{{{query}}}}\nfn a() {{}}\n",
+        )
+        .expect("template to parse");
+        assert_eq!(template.version(), 2);
+        let values = TemplateValues::new()
+            .text("query", "errors")
+            .number("limit", 3);
+        assert_eq!(
+            template.render(&values).expect("template to render"),
+            "Find 3 snippets for errors in {{errors}}\nfn a() {{}}"
+        );
+        assert!(matches!(
+            template.render(&values.clone().text("limit", "3")),
+            Err(PromptTemplateError::WrongValueType(_, _, _))
+        ));
+        assert!(matches!(
+            template.render(&values.clone().text("extra", "")),
+            Err(PromptTemplateError::UnknownValue(_, _))
+        ));
+        assert!(matches!(
+            template.render(&TemplateValues::new().number("limit", 3)),
+            Err(PromptTemplateError::MissingValue(_, _))
+        ));
+
+        // the declared variables have to match the used ones
+        assert!(matches!(
+            PromptTemplate::parse(
+                "test.prompt",
+                "name: test\nversion: 1\nvariables: query: text\n---\n{{query}} {{limit}}"
+            ),
+            Err(PromptTemplateError::UndeclaredVariable(_, variable)) if variable == "limit"
+        ));
+        assert!(matches!(
+            PromptTemplate::parse(
+                "test.prompt",
+                "name: test\nversion: 1\nvariables: query: text, limit: number\n---\n{{query}}"
+            ),
+            Err(PromptTemplateError::UnusedVariable(_, variable)) if variable == "limit"
+        ));
+        assert!(matches!(
+            PromptTemplate::parse("test.prompt", "name: test\nvariables:\n---\nhello"),
+            Err(PromptTemplateError::MissingHeaderField(_, "version"))
+        ));
+    }
+}